@@ -1,6 +1,6 @@
 use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
 
-use crate::{Partition, State, BOOT_MAGIC, SWAP_MAGIC};
+use crate::{ImageVerifier, Partition, State, VerifyError, BOOT_MAGIC, SWAP_MAGIC, VERIFY_FAILED_MAGIC};
 
 /// Errors returned by bootloader
 #[derive(PartialEq, Eq, Debug)]
@@ -176,6 +176,34 @@ impl BootLoader {
     /// +-----------+--------------+--------+--------+--------+--------+
     ///
     pub fn prepare_boot<P: FlashConfig>(&mut self, p: &mut P, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        self.prepare(p, aligned_buf, None)
+    }
+
+    /// Perform the boot preparations like [`BootLoader::prepare_boot`], but check the dfu image with `verifier`
+    /// before swapping it in.
+    ///
+    /// If the check fails, the swap is refused and [`State::Boot`] is returned, so that the active partition is
+    /// booted. The reason is recorded in the state partition, where the application can read it with the
+    /// `get_verify_error` methods of the [`FirmwareUpdater`](crate::FirmwareUpdater), until the next update or the
+    /// boot is marked.
+    ///
+    /// A swap that was interrupted is continued without verifying, as the dfu partition then partly holds the
+    /// active image.
+    pub fn prepare_boot_verified<P: FlashConfig>(
+        &mut self,
+        p: &mut P,
+        aligned_buf: &mut [u8],
+        verifier: &mut impl ImageVerifier<P::DFU>,
+    ) -> Result<State, BootError> {
+        self.prepare(p, aligned_buf, Some(verifier))
+    }
+
+    fn prepare<P: FlashConfig>(
+        &mut self,
+        p: &mut P,
+        aligned_buf: &mut [u8],
+        verifier: Option<&mut dyn ImageVerifier<P::DFU>>,
+    ) -> Result<State, BootError> {
         // Ensure we have enough progress pages to store copy progress
         assert_eq!(0, P::page_size() % aligned_buf.len() as u32);
         assert_eq!(0, P::page_size() % P::ACTIVE::WRITE_SIZE as u32);
@@ -195,6 +223,13 @@ impl BootLoader {
             // since the app has failed to mark boot as successful
             //
             if !self.is_swapped(p, aligned_buf)? {
+                if let Some(verifier) = verifier {
+                    if let Some(error) = self.verify_dfu(p, aligned_buf, verifier)? {
+                        trace!("Refusing to swap, verification failed");
+                        self.record_verify_failure(p, aligned_buf, error)?;
+                        return Ok(State::Boot);
+                    }
+                }
                 trace!("Swapping");
                 self.swap(p, aligned_buf)?;
                 trace!("Swapping done");
@@ -221,6 +256,42 @@ impl BootLoader {
         Ok(state)
     }
 
+    /// Verify the dfu image with `verifier`, if the swap did not start yet.
+    fn verify_dfu<P: FlashConfig>(
+        &mut self,
+        p: &mut P,
+        aligned_buf: &mut [u8],
+        verifier: &mut dyn ImageVerifier<P::DFU>,
+    ) -> Result<Option<VerifyError>, BootError> {
+        if self.current_progress(p, aligned_buf)? > 0 {
+            return Ok(None);
+        }
+        let image = Partition::new(self.dfu.from, self.dfu.from + self.active.size());
+        Ok(verifier.verify(p.dfu(), image)?)
+    }
+
+    /// Replace the swap request with the reason the dfu image was refused.
+    fn record_verify_failure<P: FlashConfig>(
+        &mut self,
+        p: &mut P,
+        aligned_buf: &mut [u8],
+        error: VerifyError,
+    ) -> Result<(), BootError> {
+        let state_flash = p.state();
+        let state_word = &mut aligned_buf[..P::STATE::WRITE_SIZE];
+
+        // A power loss after the wipe leaves the state erased, which boots the active partition as well
+        self.state.wipe_blocking(state_flash)?;
+
+        state_word.fill(error.code());
+        self.state
+            .write_blocking(state_flash, P::STATE::WRITE_SIZE as u32, state_word)?;
+
+        state_word.fill(VERIFY_FAILED_MAGIC);
+        self.state.write_blocking(state_flash, 0, state_word)?;
+        Ok(())
+    }
+
     fn is_swapped<P: FlashConfig>(&mut self, p: &mut P, aligned_buf: &mut [u8]) -> Result<bool, BootError> {
         let page_count = (self.active.size() / P::page_size()) as usize;
         let progress = self.current_progress(p, aligned_buf)?;
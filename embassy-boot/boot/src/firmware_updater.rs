@@ -3,7 +3,7 @@ use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
 #[cfg(feature = "nightly")]
 use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
 
-use crate::{Partition, State, BOOT_MAGIC, SWAP_MAGIC};
+use crate::{Partition, State, VerifyError, BOOT_MAGIC, SWAP_MAGIC, VERIFY_FAILED_MAGIC};
 
 /// Errors returned by FirmwareUpdater
 #[derive(Debug)]
@@ -95,6 +95,24 @@ impl FirmwareUpdater {
         }
     }
 
+    /// Obtain the reason why the bootloader refused the last update, if it did.
+    ///
+    /// The reason is recorded by [`BootLoader::prepare_boot_verified`](crate::BootLoader::prepare_boot_verified),
+    /// and is kept until the next update or the boot is marked.
+    #[cfg(feature = "nightly")]
+    pub async fn get_verify_error<F: AsyncNorFlash>(
+        &mut self,
+        state_flash: &mut F,
+        aligned: &mut [u8],
+    ) -> Result<Option<VerifyError>, FirmwareUpdaterError> {
+        self.state.read(state_flash, 0, aligned).await?;
+        if aligned.iter().any(|&b| b != VERIFY_FAILED_MAGIC) {
+            return Ok(None);
+        }
+        self.state.read(state_flash, F::WRITE_SIZE as u32, aligned).await?;
+        Ok(VerifyError::from_word(aligned))
+    }
+
     /// Verify the DFU given a public key. If there is an error then DO NOT
     /// proceed with updating the firmware as it must be signed with a
     /// corresponding private key (otherwise it could be malicious firmware).
@@ -320,6 +338,23 @@ impl FirmwareUpdater {
         }
     }
 
+    /// Obtain the reason why the bootloader refused the last update, if it did.
+    ///
+    /// The reason is recorded by [`BootLoader::prepare_boot_verified`](crate::BootLoader::prepare_boot_verified),
+    /// and is kept until the next update or the boot is marked.
+    pub fn get_verify_error_blocking<F: NorFlash>(
+        &mut self,
+        state_flash: &mut F,
+        aligned: &mut [u8],
+    ) -> Result<Option<VerifyError>, FirmwareUpdaterError> {
+        self.state.read_blocking(state_flash, 0, aligned)?;
+        if aligned.iter().any(|&b| b != VERIFY_FAILED_MAGIC) {
+            return Ok(None);
+        }
+        self.state.read_blocking(state_flash, F::WRITE_SIZE as u32, aligned)?;
+        Ok(VerifyError::from_word(aligned))
+    }
+
     /// Verify the DFU given a public key. If there is an error then DO NOT
     /// proceed with updating the firmware as it must be signed with a
     /// corresponding private key (otherwise it could be malicious firmware).
@@ -9,13 +9,16 @@ mod digest_adapters;
 mod firmware_updater;
 mod mem_flash;
 mod partition;
+mod verify;
 
 pub use boot_loader::{BootError, BootFlash, BootLoader, FlashConfig, MultiFlashConfig, SingleFlashConfig};
 pub use firmware_updater::{FirmwareUpdater, FirmwareUpdaterError};
 pub use partition::Partition;
+pub use verify::{ImageVerifier, VerifyError};
 
 pub(crate) const BOOT_MAGIC: u8 = 0xD0;
 pub(crate) const SWAP_MAGIC: u8 = 0xF0;
+pub(crate) const VERIFY_FAILED_MAGIC: u8 = 0xB0;
 
 /// The state of the bootloader after running prepare.
 #[derive(PartialEq, Eq, Debug)]
@@ -210,6 +213,148 @@ mod tests {
         dfu.assert_eq(DFU.from + 4096, &original);
     }
 
+    mod verify_before_swap {
+        use super::*;
+        use crate::mem_flash::MemFlashError;
+
+        const STATE: Partition = Partition::new(0, 4096);
+        const ACTIVE: Partition = Partition::new(4096, 61440);
+        const DFU: Partition = Partition::new(61440, 122880);
+
+        type Flash = MemFlash<131072, 4096, 4>;
+
+        fn image(seed: u8) -> [u8; ACTIVE.size() as usize] {
+            core::array::from_fn(|i| (i as u8).wrapping_mul(7).wrapping_add(seed))
+        }
+
+        /// Accepts the image only if it is `expected`, like a digest check, and counts the checks.
+        struct Expect<'a> {
+            expected: &'a [u8],
+            error: VerifyError,
+            checks: usize,
+        }
+
+        impl<'a> Expect<'a> {
+            fn new(expected: &'a [u8], error: VerifyError) -> Self {
+                Self {
+                    expected,
+                    error,
+                    checks: 0,
+                }
+            }
+        }
+
+        impl ImageVerifier<Flash> for Expect<'_> {
+            fn verify(&mut self, flash: &mut Flash, image: Partition) -> Result<Option<VerifyError>, MemFlashError> {
+                assert_eq!(ACTIVE.size(), image.size());
+                self.checks += 1;
+                let mut buf = [0; 4096];
+                for offset in (0..image.size()).step_by(buf.len()) {
+                    image.read_blocking(flash, offset, &mut buf)?;
+                    if buf[..] != self.expected[offset as usize..offset as usize + buf.len()] {
+                        return Ok(Some(self.error));
+                    }
+                }
+                Ok(None)
+            }
+        }
+
+        /// Write `update` to the dfu partition and request a swap.
+        fn mark_update(flash: &mut Flash, update: &[u8]) {
+            let mut updater = FirmwareUpdater::new(DFU, STATE);
+            updater.write_firmware_blocking(0, update, flash).unwrap();
+            // Like `mark_updated_blocking`, which is not available when signatures are verified
+            STATE.wipe_blocking(flash).unwrap();
+            STATE.write_blocking(flash, 0, &[SWAP_MAGIC; 4]).unwrap();
+        }
+
+        fn prepare(flash: &mut Flash, verifier: &mut Expect) -> Result<State, BootError> {
+            let mut bootloader = BootLoader::new(ACTIVE, DFU, STATE);
+            let mut page = [0; 1024];
+            bootloader.prepare_boot_verified(&mut SingleFlashConfig::new(flash), &mut page, verifier)
+        }
+
+        fn verify_error(flash: &mut Flash) -> Option<VerifyError> {
+            let mut updater = FirmwareUpdater::new(DFU, STATE);
+            updater.get_verify_error_blocking(flash, &mut [0; 4]).unwrap()
+        }
+
+        #[test]
+        fn swaps_verified_image() {
+            let mut flash = Flash::default();
+            let original = image(1);
+            let update = image(2);
+            flash.program(ACTIVE.from, &original).unwrap();
+
+            mark_update(&mut flash, &update);
+            let mut verifier = Expect::new(&update, VerifyError::DigestMismatch);
+            assert_eq!(State::Swap, prepare(&mut flash, &mut verifier).unwrap());
+            assert_eq!(1, verifier.checks);
+            flash.assert_eq(ACTIVE.from, &update);
+            assert_eq!(None, verify_error(&mut flash));
+        }
+
+        #[test]
+        fn refuses_unverified_image() {
+            let original = image(1);
+            let update = image(2);
+            let mut corrupt = update;
+            corrupt[12345] ^= 0x10;
+
+            for error in [
+                VerifyError::MissingDigest,
+                VerifyError::InvalidLength,
+                VerifyError::DigestMismatch,
+            ] {
+                let mut flash = Flash::default();
+                flash.program(ACTIVE.from, &original).unwrap();
+                mark_update(&mut flash, &corrupt);
+
+                let mut verifier = Expect::new(&update, error);
+                assert_eq!(State::Boot, prepare(&mut flash, &mut verifier).unwrap());
+                flash.assert_eq(ACTIVE.from, &original);
+
+                // The reason stays readable by the application, and the next boot doesn't swap either
+                assert_eq!(Some(error), verify_error(&mut flash));
+                let mut updater = FirmwareUpdater::new(DFU, STATE);
+                assert_eq!(
+                    State::Boot,
+                    updater.get_state_blocking(&mut flash, &mut [0; 4]).unwrap()
+                );
+                assert_eq!(State::Boot, prepare(&mut flash, &mut verifier).unwrap());
+                assert_eq!(1, verifier.checks);
+                flash.assert_eq(ACTIVE.from, &original);
+
+                // A repaired download is swapped in once it is marked again
+                mark_update(&mut flash, &update);
+                assert_eq!(None, verify_error(&mut flash));
+                assert_eq!(State::Swap, prepare(&mut flash, &mut verifier).unwrap());
+                flash.assert_eq(ACTIVE.from, &update);
+            }
+        }
+
+        #[test]
+        fn resumes_interrupted_swap_without_verifying() {
+            let mut flash = Flash::default();
+            let original = image(1);
+            let update = image(2);
+            flash.program(ACTIVE.from, &original).unwrap();
+            mark_update(&mut flash, &update);
+
+            // The power is cut during the swap, after the image was verified
+            let mut verifier = Expect::new(&update, VerifyError::DigestMismatch);
+            flash.pending_write_successes = Some(100);
+            assert!(prepare(&mut flash, &mut verifier).is_err());
+            flash.pending_write_successes = None;
+            assert_eq!(1, verifier.checks);
+
+            // The dfu partition now partly holds the active image, which would not verify
+            assert_eq!(State::Swap, prepare(&mut flash, &mut verifier).unwrap());
+            assert_eq!(1, verifier.checks);
+            flash.assert_eq(ACTIVE.from, &update);
+        }
+    }
+
     #[test]
     #[cfg(all(feature = "nightly", feature = "_verify"))]
     fn test_verify() {
@@ -0,0 +1,54 @@
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::Partition;
+
+/// Reason why the bootloader refused to swap in an update.
+///
+/// See [`BootLoader::prepare_boot_verified`](crate::BootLoader::prepare_boot_verified).
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VerifyError {
+    /// No valid metadata was found, so there is no digest to compare against.
+    MissingDigest,
+    /// The image length in the metadata is zero or exceeds the active partition.
+    InvalidLength,
+    /// The digest of the DFU partition doesn't match the metadata, e.g. because the download was corrupt or
+    /// truncated.
+    DigestMismatch,
+}
+
+impl VerifyError {
+    pub(crate) fn code(self) -> u8 {
+        match self {
+            VerifyError::MissingDigest => 0x01,
+            VerifyError::InvalidLength => 0x02,
+            VerifyError::DigestMismatch => 0x03,
+        }
+    }
+
+    /// Decode the reason recorded in a state word, if it holds one.
+    pub(crate) fn from_word(word: &[u8]) -> Option<Self> {
+        let code = *word.first()?;
+        if word.iter().any(|&b| b != code) {
+            return None;
+        }
+        match code {
+            0x01 => Some(VerifyError::MissingDigest),
+            0x02 => Some(VerifyError::InvalidLength),
+            0x03 => Some(VerifyError::DigestMismatch),
+            _ => None,
+        }
+    }
+}
+
+/// Checks the image in the DFU partition before the bootloader swaps it in.
+///
+/// How the image is checked is up to the implementation, e.g. by comparing its CRC or SHA-256 digest with a
+/// metadata block written by the application. `embassy-boot-stm32` implements it for the `FirmwareInfo` block of
+/// the embassy-stm32 flash driver.
+pub trait ImageVerifier<F: NorFlash> {
+    /// Check the image in `image` of `flash`, and return the reason if it must not be swapped in.
+    ///
+    /// `image` is the start of the DFU partition that is swapped in, as large as the active partition.
+    fn verify(&mut self, flash: &mut F, image: Partition) -> Result<Option<VerifyError>, F::Error>;
+}
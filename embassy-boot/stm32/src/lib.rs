@@ -3,7 +3,12 @@
 #![doc = include_str!("../README.md")]
 mod fmt;
 
-pub use embassy_boot::{AlignedBuffer, BootFlash, FirmwareUpdater, FlashConfig, Partition, SingleFlashConfig, State};
+pub use embassy_boot::{
+    AlignedBuffer, BootFlash, FirmwareUpdater, FlashConfig, ImageVerifier, Partition, SingleFlashConfig, State,
+    VerifyError,
+};
+use embassy_stm32::flash::{Error, FirmwareInfo, ImageCheck, ImageError};
+use embedded_storage::nor_flash::NorFlash;
 
 /// A bootloader for STM32 devices.
 pub struct BootLoader<const BUFFER_SIZE: usize> {
@@ -29,6 +34,23 @@ impl<const BUFFER_SIZE: usize> BootLoader<BUFFER_SIZE> {
         }
     }
 
+    /// Like [`BootLoader::prepare`], but check the firmware with `verifier` before swapping it in.
+    ///
+    /// See [`embassy_boot::BootLoader::prepare_boot_verified`].
+    pub fn prepare_verified<F: FlashConfig>(
+        &mut self,
+        flash: &mut F,
+        verifier: &mut impl ImageVerifier<F::DFU>,
+    ) -> usize {
+        match self
+            .boot
+            .prepare_boot_verified(flash, self.aligned_buf.as_mut(), verifier)
+        {
+            Ok(_) => embassy_stm32::flash::FLASH_BASE + self.boot.boot_address(),
+            Err(_) => panic!("boot prepare error!"),
+        }
+    }
+
     /// Boots the application.
     ///
     /// # Safety
@@ -46,6 +68,34 @@ impl<const BUFFER_SIZE: usize> BootLoader<BUFFER_SIZE> {
     }
 }
 
+/// Verifies the dfu image against the [`FirmwareInfo`] block that the application wrote with it.
+///
+/// The block is read at `offset` of the dfu flash, outside of the dfu partition, and the image is checked with
+/// [`FirmwareInfo::check_image`].
+pub struct FirmwareInfoVerifier {
+    offset: u32,
+    check: ImageCheck,
+}
+
+impl FirmwareInfoVerifier {
+    /// Create a verifier for the block at `offset` of the dfu flash, comparing the digest selected by `check`.
+    pub const fn new(offset: u32, check: ImageCheck) -> Self {
+        Self { offset, check }
+    }
+}
+
+impl<F: NorFlash<Error = Error>> ImageVerifier<F> for FirmwareInfoVerifier {
+    fn verify(&mut self, flash: &mut F, image: Partition) -> Result<Option<VerifyError>, Error> {
+        match FirmwareInfo::check_image(flash, self.offset, image.from, image.size(), self.check) {
+            Ok(_) => Ok(None),
+            Err(ImageError::Flash(e)) => Err(e),
+            Err(ImageError::MissingDigest) => Ok(Some(VerifyError::MissingDigest)),
+            Err(ImageError::InvalidLength) => Ok(Some(VerifyError::InvalidLength)),
+            Err(ImageError::DigestMismatch) => Ok(Some(VerifyError::DigestMismatch)),
+        }
+    }
+}
+
 #[cfg(target_os = "none")]
 impl<const BUFFER_SIZE: usize> Default for BootLoader<BUFFER_SIZE> {
     /// Create a new bootloader instance using parameters from linker script
@@ -0,0 +1,380 @@
+//! Shared flash implementations
+//!
+//! # Example
+//!
+//! ```rust
+//! use embassy_embedded_hal::flash::SharedFlash;
+//! use embassy_sync::mutex::Mutex;
+//! use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+//!
+//! static FLASH: StaticCell<Mutex<ThreadModeRawMutex, Flash<'static>>> = StaticCell::new();
+//! let flash = FLASH.init(Mutex::new(Flash::new(p.FLASH)));
+//!
+//! // Each task gets its own part of the flash
+//! let flash = SharedFlash::new(flash, 256 * 1024);
+//! let config = flash.partition(0x3_0000, 0x4000);
+//! let log = flash.partition(0x3_4000, 0xC000);
+//! ```
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+
+/// Error returned by [`SharedFlash`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SharedFlashError<E> {
+    /// An operation on the inner flash failed.
+    Flash(E),
+    /// The operation is not within the bounds of the partition.
+    OutOfBounds,
+    /// The flash is used by another user. Only returned by the blocking traits, which can't wait.
+    Busy,
+}
+
+impl<E> NorFlashError for SharedFlashError<E>
+where
+    E: NorFlashError,
+{
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Self::Flash(e) => e.kind(),
+            Self::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Self::Busy => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Flash device shared by multiple users, restricted to a partition of it.
+///
+/// The flash is locked for each operation and released as soon as the operation completes, so users can
+/// hold on to their `SharedFlash` across awaits without blocking the others. Offsets are relative to the start
+/// of the partition.
+///
+/// The async traits wait for the flash to be released. The blocking traits can't, and return
+/// [`SharedFlashError::Busy`] if an operation of another user is in progress.
+pub struct SharedFlash<'a, M: RawMutex, F> {
+    flash: &'a Mutex<M, F>,
+    offset: u32,
+    size: u32,
+}
+
+impl<'a, M: RawMutex, F> SharedFlash<'a, M, F> {
+    /// Create a new `SharedFlash` covering the first `size` bytes of `flash`.
+    pub fn new(flash: &'a Mutex<M, F>, size: u32) -> Self {
+        Self { flash, offset: 0, size }
+    }
+
+    /// Create a `SharedFlash` restricted to `len` bytes at `offset` of this one.
+    ///
+    /// `offset` and `len` should be multiples of the erase size of the flash, otherwise not all of the
+    /// partition can be erased.
+    ///
+    /// # Panics
+    /// Panics if the partition is not within this one.
+    pub fn partition(&self, offset: u32, len: u32) -> Self {
+        assert!(offset as u64 + len as u64 <= self.size as u64);
+        Self {
+            flash: self.flash,
+            offset: self.offset + offset,
+            size: len,
+        }
+    }
+
+    /// The offset of the partition in the flash.
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// Translate `len` bytes at `offset` of the partition to the offset in the flash.
+    fn check<E>(&self, offset: u32, len: usize) -> Result<u32, SharedFlashError<E>> {
+        if offset as u64 + len as u64 > self.size as u64 {
+            return Err(SharedFlashError::OutOfBounds);
+        }
+        Ok(self.offset + offset)
+    }
+
+    /// Translate the erase range `from..to` of the partition to the range in the flash.
+    fn check_erase<E>(&self, from: u32, to: u32) -> Result<(u32, u32), SharedFlashError<E>> {
+        if from > to || to > self.size {
+            return Err(SharedFlashError::OutOfBounds);
+        }
+        Ok((self.offset + from, self.offset + to))
+    }
+}
+
+impl<M: RawMutex, F> Clone for SharedFlash<'_, M, F> {
+    fn clone(&self) -> Self {
+        Self {
+            flash: self.flash,
+            offset: self.offset,
+            size: self.size,
+        }
+    }
+}
+
+impl<M: RawMutex, F: ErrorType> ErrorType for SharedFlash<'_, M, F> {
+    type Error = SharedFlashError<F::Error>;
+}
+
+impl<M: RawMutex, F: ReadNorFlash> ReadNorFlash for SharedFlash<'_, M, F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = self.check(offset, bytes.len())?;
+        let mut flash = self.flash.try_lock().map_err(|_| SharedFlashError::Busy)?;
+        ReadNorFlash::read(&mut *flash, offset, bytes).map_err(SharedFlashError::Flash)
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl<M: RawMutex, F: NorFlash> NorFlash for SharedFlash<'_, M, F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let (from, to) = self.check_erase(from, to)?;
+        let mut flash = self.flash.try_lock().map_err(|_| SharedFlashError::Busy)?;
+        NorFlash::erase(&mut *flash, from, to).map_err(SharedFlashError::Flash)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = self.check(offset, bytes.len())?;
+        let mut flash = self.flash.try_lock().map_err(|_| SharedFlashError::Busy)?;
+        NorFlash::write(&mut *flash, offset, bytes).map_err(SharedFlashError::Flash)
+    }
+}
+
+#[cfg(feature = "nightly")]
+impl<M: RawMutex, F: embedded_storage_async::nor_flash::ReadNorFlash> embedded_storage_async::nor_flash::ReadNorFlash
+    for SharedFlash<'_, M, F>
+{
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = self.check(offset, bytes.len())?;
+        let mut flash = self.flash.lock().await;
+        embedded_storage_async::nor_flash::ReadNorFlash::read(&mut *flash, offset, bytes)
+            .await
+            .map_err(SharedFlashError::Flash)
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+
+#[cfg(feature = "nightly")]
+impl<M: RawMutex, F: embedded_storage_async::nor_flash::NorFlash> embedded_storage_async::nor_flash::NorFlash
+    for SharedFlash<'_, M, F>
+{
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let (from, to) = self.check_erase(from, to)?;
+        let mut flash = self.flash.lock().await;
+        embedded_storage_async::nor_flash::NorFlash::erase(&mut *flash, from, to)
+            .await
+            .map_err(SharedFlashError::Flash)
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = self.check(offset, bytes.len())?;
+        let mut flash = self.flash.lock().await;
+        embedded_storage_async::nor_flash::NorFlash::write(&mut *flash, offset, bytes)
+            .await
+            .map_err(SharedFlashError::Flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+
+    const SIZE: usize = 1024;
+
+    /// In-memory flash that logs the partition of each write, and checks that operations don't overlap.
+    struct MockFlash {
+        mem: [u8; SIZE],
+        busy: bool,
+        log: [u8; 32],
+        log_len: usize,
+    }
+
+    impl MockFlash {
+        fn new() -> Self {
+            Self {
+                mem: [0xFF; SIZE],
+                busy: false,
+                log: [0; 32],
+                log_len: 0,
+            }
+        }
+
+        fn log(&self) -> &[u8] {
+            &self.log[..self.log_len]
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct MockError;
+
+    impl NorFlashError for MockError {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::Other
+        }
+    }
+
+    impl ErrorType for MockFlash {
+        type Error = MockError;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.mem[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            SIZE
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = 256;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            self.mem[from as usize..to as usize].fill(0xFF);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            assert!(!self.busy);
+            let offset = offset as usize;
+            self.mem[offset..offset + bytes.len()].copy_from_slice(bytes);
+            self.log[self.log_len] = (offset / Self::ERASE_SIZE) as u8;
+            self.log_len += 1;
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "nightly")]
+    impl embedded_storage_async::nor_flash::ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 1;
+
+        async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            ReadNorFlash::read(self, offset, bytes)
+        }
+
+        fn capacity(&self) -> usize {
+            SIZE
+        }
+    }
+
+    #[cfg(feature = "nightly")]
+    impl embedded_storage_async::nor_flash::NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = 256;
+
+        async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            NorFlash::erase(self, from, to)
+        }
+
+        async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            // Programming takes a while, other users must not get the flash in the meantime
+            assert!(!self.busy);
+            self.busy = true;
+            embassy_futures::yield_now().await;
+            self.busy = false;
+            NorFlash::write(self, offset, bytes)
+        }
+    }
+
+    #[test]
+    fn can_restrict_to_partition() {
+        let mutex = Mutex::<NoopRawMutex, _>::new(MockFlash::new());
+        let flash = SharedFlash::new(&mutex, SIZE as u32);
+        let mut partition = flash.partition(256, 512).partition(256, 256);
+        assert_eq!(512, partition.offset());
+        assert_eq!(256, ReadNorFlash::capacity(&partition));
+
+        NorFlash::write(&mut partition, 252, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(
+            Err(SharedFlashError::OutOfBounds),
+            NorFlash::write(&mut partition, 256, &[1, 2, 3, 4])
+        );
+        assert_eq!(
+            Err(SharedFlashError::OutOfBounds),
+            NorFlash::erase(&mut partition, 0, 512)
+        );
+        NorFlash::erase(&mut partition, 0, 256).unwrap();
+
+        let mut buf = [0; 4];
+        ReadNorFlash::read(&mut partition, 252, &mut buf).unwrap();
+        assert_eq!([0xFF; 4], buf);
+        assert_eq!(
+            Err(SharedFlashError::OutOfBounds),
+            ReadNorFlash::read(&mut partition, u32::MAX, &mut buf)
+        );
+        assert_eq!(&[2], mutex.try_lock().unwrap().log());
+    }
+
+    #[test]
+    #[should_panic]
+    fn can_reject_partition_outside_flash() {
+        let mutex = Mutex::<NoopRawMutex, _>::new(MockFlash::new());
+        SharedFlash::new(&mutex, SIZE as u32).partition(768, 512);
+    }
+
+    #[test]
+    fn can_reject_blocking_access_while_busy() {
+        let mutex = Mutex::<NoopRawMutex, _>::new(MockFlash::new());
+        let mut flash = SharedFlash::new(&mutex, SIZE as u32);
+
+        let guard = mutex.try_lock().unwrap();
+        assert_eq!(Err(SharedFlashError::Busy), NorFlash::write(&mut flash, 0, &[0; 4]));
+        drop(guard);
+        NorFlash::write(&mut flash, 0, &[0; 4]).unwrap();
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn can_share_between_concurrent_users() {
+        use embassy_futures::block_on;
+        use embassy_futures::join::join3;
+        use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+
+        let mutex = Mutex::<NoopRawMutex, _>::new(MockFlash::new());
+        let flash = SharedFlash::new(&mutex, SIZE as u32);
+
+        let user = |index: u32| {
+            let mut partition = flash.partition(index * 256, 256);
+            async move {
+                for i in 0..4 {
+                    partition.write(i * 4, &[index as u8; 4]).await.unwrap();
+                    // Other work between the operations must not hold the flash
+                    embassy_futures::yield_now().await;
+                }
+            }
+        };
+        block_on(join3(user(0), user(1), user(2)));
+
+        let flash = mutex.try_lock().unwrap();
+        for index in 0..3 {
+            let start = index * 256;
+            assert!(flash.mem[start..start + 16].iter().all(|&b| b == index as u8));
+        }
+        // The users take turns, none of them has to wait for another to finish all its writes
+        assert_eq!(&[0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1, 2], flash.log());
+    }
+}
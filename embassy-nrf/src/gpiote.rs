@@ -221,6 +221,36 @@ impl<'d, C: Channel, T: GpioPin> InputChannel<'d, C, T> {
         .await;
     }
 
+    /// Asynchronously wait for an event in this channel, returning immediately if one occurred since the event was
+    /// last cleared.
+    pub(crate) async fn wait_pending(&self) {
+        let g = regs();
+        let num = self.ch.number();
+
+        // Enable interrupt
+        g.intenset.write(|w| unsafe { w.bits(1 << num) });
+
+        poll_fn(|cx| {
+            CHANNEL_WAKERS[num].register(cx.waker());
+
+            if g.events_in[num].read().bits() != 0 {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+    }
+
+    /// Returns whether an event occurred since the event was last cleared.
+    pub(crate) fn is_pending(&self) -> bool {
+        regs().events_in[self.ch.number()].read().bits() != 0
+    }
+
+    /// Clears the IN event.
+    pub(crate) fn clear_pending(&self) {
+        regs().events_in[self.ch.number()].reset();
+    }
     /// Returns the IN event, for use with PPI.
     pub fn event_in(&self) -> Event {
         let g = regs();
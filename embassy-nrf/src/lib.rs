@@ -30,7 +30,11 @@ compile_error!("feature `nfc-pins-as-gpio` is only valid for nRF52, or nRF53's a
 pub(crate) mod fmt;
 pub(crate) mod util;
 
+#[cfg(all(feature = "time-driver-rtc1", feature = "time-driver-timer"))]
+compile_error!("Only one of the features `time-driver-rtc1` and `time-driver-timer` can be enabled.");
+
 #[cfg(feature = "_time-driver")]
+#[cfg_attr(feature = "time-driver-timer", path = "time_driver_timer.rs")]
 mod time_driver;
 
 pub mod buffered_uarte;
@@ -535,7 +539,7 @@ pub fn init(config: config::Config) -> Peripherals {
     #[cfg(feature = "gpiote")]
     gpiote::init(config.gpiote_interrupt_priority);
 
-    // init RTC time driver
+    // init RTC or TIMER time driver
     #[cfg(feature = "_time-driver")]
     time_driver::init(config.time_interrupt_priority);
 
@@ -0,0 +1,293 @@
+use core::cell::Cell;
+use core::sync::atomic::{compiler_fence, AtomicU32, AtomicU8, Ordering};
+use core::{mem, ptr};
+
+use critical_section::CriticalSection;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::CriticalSectionMutex as Mutex;
+use embassy_time::driver::{AlarmHandle, Driver};
+
+use crate::interrupt;
+use crate::interrupt::{Interrupt, InterruptExt};
+use crate::timerv2::{self, TimerInstance};
+
+const INSTANCE: TimerInstance = TimerInstance::TIMER1;
+
+/// CC register that fires at the start of every period.
+const PERIOD_CC: usize = 0;
+/// CC register the counter is captured in, the timer has no COUNTER register.
+const CAPTURE_CC: usize = 3;
+
+fn timer() -> &'static timerv2::RegisterBlock {
+    INSTANCE.regs()
+}
+
+/// Calculate the timestamp from the period count and the tick count.
+///
+/// The timer runs at 1 MHz in 32-bit mode, so it overflows every ~71 minutes. As in the RTC driver, a "period" is
+/// half an overflow cycle, 2^31 ticks, so that `now()` can't race an overflow:
+///
+/// - `period` is incremented on overflow (at counter value 0)
+/// - `period` is incremented "midway" between overflows (at counter value 0x8000_0000)
+///
+/// Therefore, when `period` is even, counter is in 0..0x7FFF_FFFF. When odd, counter is in 0x8000_0000..0xFFFF_FFFF.
+/// If the counter value doesn't match the expected range for the `period` parity, a new period start has raced us
+/// between reading `period` and `counter`, so the `counter` value corresponds to the next period.
+///
+/// Unlike the RTC, the timer has no overflow event. A single CC register holds the start of the next period, and is
+/// moved to the following one on every period.
+///
+/// `period` is a 32bit integer, so it overflows on 2^32 * 2^31 / 1_000_000 seconds of uptime, which is ~292 000
+/// years.
+fn calc_now(period: u32, counter: u32) -> u64 {
+    ((period as u64) << 31) + ((counter ^ ((period & 1) << 31)) as u64)
+}
+
+fn compare_n(n: usize) -> u32 {
+    1 << (n + 16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_calc_now() {
+        assert_eq!(calc_now(0, 0x0000_0000), 0x0_0000_0000);
+        assert_eq!(calc_now(0, 0x0000_0001), 0x0_0000_0001);
+        assert_eq!(calc_now(0, 0x7FFF_FFFF), 0x0_7FFF_FFFF);
+        assert_eq!(calc_now(1, 0x7FFF_FFFF), 0x1_7FFF_FFFF);
+        assert_eq!(calc_now(0, 0x8000_0000), 0x0_8000_0000);
+        assert_eq!(calc_now(1, 0x8000_0000), 0x0_8000_0000);
+        assert_eq!(calc_now(1, 0x8000_0001), 0x0_8000_0001);
+        assert_eq!(calc_now(1, 0xFFFF_FFFF), 0x0_FFFF_FFFF);
+        assert_eq!(calc_now(2, 0xFFFF_FFFF), 0x1_FFFF_FFFF);
+        assert_eq!(calc_now(1, 0x0000_0000), 0x1_0000_0000);
+        assert_eq!(calc_now(2, 0x0000_0000), 0x1_0000_0000);
+    }
+}
+
+struct AlarmState {
+    timestamp: Cell<u64>,
+
+    // This is really a Option<(fn(*mut ()), *mut ())>
+    // but fn pointers aren't allowed in const yet
+    callback: Cell<*const ()>,
+    ctx: Cell<*mut ()>,
+}
+
+unsafe impl Send for AlarmState {}
+
+impl AlarmState {
+    const fn new() -> Self {
+        Self {
+            timestamp: Cell::new(u64::MAX),
+            callback: Cell::new(ptr::null()),
+            ctx: Cell::new(ptr::null_mut()),
+        }
+    }
+}
+
+/// Alarm `n` uses CC register `n + 1`.
+const ALARM_COUNT: usize = 2;
+
+fn alarm_cc(n: usize) -> usize {
+    n + 1
+}
+
+struct TimerDriver {
+    /// Number of 2^31 periods elapsed since boot.
+    period: AtomicU32,
+    alarm_count: AtomicU8,
+    /// Timestamp at which to fire alarm. u64::MAX if no alarm is scheduled.
+    alarms: Mutex<[AlarmState; ALARM_COUNT]>,
+}
+
+const ALARM_STATE_NEW: AlarmState = AlarmState::new();
+embassy_time::time_driver_impl!(static DRIVER: TimerDriver = TimerDriver {
+    period: AtomicU32::new(0),
+    alarm_count: AtomicU8::new(0),
+    alarms: Mutex::const_new(CriticalSectionRawMutex::new(), [ALARM_STATE_NEW; ALARM_COUNT]),
+});
+
+impl TimerDriver {
+    fn init(&'static self, irq_prio: crate::interrupt::Priority) {
+        // Nobody else may use the timer.
+        timerv2::reserve(INSTANCE);
+
+        let r = timer();
+        r.tasks_stop.write(|w| unsafe { w.bits(1) });
+        r.mode.write(|w| w.mode().timer());
+        r.bitmode.write(|w| w.bitmode()._32bit());
+        // 1 MHz
+        r.prescaler.write(|w| unsafe { w.prescaler().bits(4) });
+        r.shorts.reset();
+        r.intenclr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+        for n in 0..INSTANCE.cc_count() {
+            r.events_compare[n].reset();
+        }
+
+        r.cc[PERIOD_CC].write(|w| unsafe { w.bits(0x8000_0000) });
+        r.intenset.write(|w| unsafe { w.bits(compare_n(PERIOD_CC)) });
+
+        r.tasks_clear.write(|w| unsafe { w.bits(1) });
+        r.tasks_start.write(|w| unsafe { w.bits(1) });
+
+        let irq = unsafe { interrupt::TIMER1::steal() };
+        irq.set_priority(irq_prio);
+        irq.enable();
+    }
+
+    fn on_interrupt(&self) {
+        let r = timer();
+        if r.events_compare[PERIOD_CC].read().bits() == 1 {
+            r.events_compare[PERIOD_CC].write(|w| w);
+            self.next_period();
+        }
+
+        for n in 0..ALARM_COUNT {
+            if r.events_compare[alarm_cc(n)].read().bits() == 1 {
+                r.events_compare[alarm_cc(n)].write(|w| w);
+                critical_section::with(|cs| {
+                    self.trigger_alarm(n, cs);
+                })
+            }
+        }
+    }
+
+    fn next_period(&self) {
+        critical_section::with(|cs| {
+            let r = timer();
+            let period = self.period.fetch_add(1, Ordering::Relaxed) + 1;
+            let t = (period as u64) << 31;
+
+            // Move the CC register to the start of the next period, 0x8000_0000 or 0 (the overflow).
+            r.cc[PERIOD_CC].write(|w| unsafe { w.bits((period.wrapping_add(1) & 1) << 31) });
+
+            for n in 0..ALARM_COUNT {
+                let alarm = &self.alarms.borrow(cs)[n];
+                let at = alarm.timestamp.get();
+
+                if at < t + 0xC000_0000 {
+                    // just enable it. `set_alarm` has already set the correct CC val.
+                    r.intenset.write(|w| unsafe { w.bits(compare_n(alarm_cc(n))) });
+                }
+            }
+        })
+    }
+
+    fn get_alarm<'a>(&'a self, cs: CriticalSection<'a>, alarm: AlarmHandle) -> &'a AlarmState {
+        // safety: we're allowed to assume the AlarmState is created by us, and
+        // we never create one that's out of bounds.
+        unsafe { self.alarms.borrow(cs).get_unchecked(alarm.id() as usize) }
+    }
+
+    fn trigger_alarm(&self, n: usize, cs: CriticalSection) {
+        let r = timer();
+        r.intenclr.write(|w| unsafe { w.bits(compare_n(alarm_cc(n))) });
+
+        let alarm = &self.alarms.borrow(cs)[n];
+        alarm.timestamp.set(u64::MAX);
+
+        // Call after clearing alarm, so the callback can set another alarm.
+
+        // safety:
+        // - we can ignore the possiblity of `f` being unset (null) because of the safety contract of `allocate_alarm`.
+        // - other than that we only store valid function pointers into alarm.callback
+        let f: fn(*mut ()) = unsafe { mem::transmute(alarm.callback.get()) };
+        f(alarm.ctx.get());
+    }
+}
+
+impl Driver for TimerDriver {
+    fn now(&self) -> u64 {
+        // `period` MUST be read before `counter`, see comment at the top for details.
+        let period = self.period.load(Ordering::Relaxed);
+        compiler_fence(Ordering::Acquire);
+        let r = timer();
+        r.tasks_capture[CAPTURE_CC].write(|w| unsafe { w.bits(1) });
+        let counter = r.cc[CAPTURE_CC].read().bits();
+        calc_now(period, counter)
+    }
+
+    unsafe fn allocate_alarm(&self) -> Option<AlarmHandle> {
+        let id = self.alarm_count.fetch_update(Ordering::AcqRel, Ordering::Acquire, |x| {
+            if x < ALARM_COUNT as u8 {
+                Some(x + 1)
+            } else {
+                None
+            }
+        });
+
+        match id {
+            Ok(id) => Some(AlarmHandle::new(id)),
+            Err(_) => None,
+        }
+    }
+
+    fn set_alarm_callback(&self, alarm: AlarmHandle, callback: fn(*mut ()), ctx: *mut ()) {
+        critical_section::with(|cs| {
+            let alarm = self.get_alarm(cs, alarm);
+
+            alarm.callback.set(callback as *const ());
+            alarm.ctx.set(ctx);
+        })
+    }
+
+    fn set_alarm(&self, alarm: AlarmHandle, timestamp: u64) -> bool {
+        critical_section::with(|cs| {
+            let n = alarm.id() as usize;
+            let alarm = self.get_alarm(cs, alarm);
+            alarm.timestamp.set(timestamp);
+
+            let r = timer();
+            let disarm = || {
+                r.intenclr.write(|w| unsafe { w.bits(compare_n(alarm_cc(n))) });
+                r.events_compare[alarm_cc(n)].write(|w| w);
+                alarm.timestamp.set(u64::MAX);
+            };
+
+            let t = self.now();
+            if timestamp <= t {
+                // If alarm timestamp has passed the alarm will not fire.
+                // Disarm the alarm and return `false` to indicate that.
+                disarm();
+                return false;
+            }
+
+            // If it hasn't triggered yet, setup it in the compare channel.
+
+            // Write the CC value regardless of whether we're going to enable it now or not.
+            // This way, when we enable it later, the right value is already set.
+            r.events_compare[alarm_cc(n)].write(|w| w);
+            r.cc[alarm_cc(n)].write(|w| unsafe { w.bits(timestamp as u32) });
+
+            let diff = timestamp - t;
+            if diff < 0xC000_0000 {
+                r.intenset.write(|w| unsafe { w.bits(compare_n(alarm_cc(n))) });
+            } else {
+                // If it's too far in the future, don't setup the compare channel yet.
+                // It will be setup later by `next_period`.
+                r.intenclr.write(|w| unsafe { w.bits(compare_n(alarm_cc(n))) });
+            }
+
+            // Unlike the RTC, the timer ticks many times while this runs, so the counter may have passed the CC value
+            // before it was written, in which case the COMPARE event would only fire after the overflow.
+            if self.now() >= timestamp {
+                disarm();
+                return false;
+            }
+
+            true
+        })
+    }
+}
+
+#[interrupt]
+fn TIMER1() {
+    DRIVER.on_interrupt()
+}
+
+pub(crate) fn init(irq_prio: crate::interrupt::Priority) {
+    DRIVER.init(irq_prio)
+}
@@ -1,8 +1,24 @@
 use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use core::task::Poll;
 
-use nrf52832_pac as pac;
+use embassy_hal_common::drop::OnDrop;
+use embassy_sync::waitqueue::AtomicWaker;
+use futures::future::poll_fn;
+pub(crate) use pac::timer0::RegisterBlock;
 
-use crate::ppi::Task;
+#[cfg(feature = "gpiote")]
+use crate::gpio::{Level, Pin as GpioPin};
+#[cfg(all(feature = "gpiote", feature = "_ppi"))]
+use crate::gpiote::InputChannel;
+#[cfg(feature = "gpiote")]
+use crate::gpiote::{self, OutputChannel};
+use crate::interrupt::{self, Interrupt, InterruptExt};
+use crate::ppi::{ConfigurableChannel, Event, Ppi, Task};
+#[cfg(all(feature = "gpiote", feature = "_ppi"))]
+use crate::ppi::{Group, PpiGroup};
+use crate::{pac, peripherals, Peripheral};
 
 /// Note:
 /// PRESCALER on page 239 and the BITMODE on page 239 must only be updated when the timer
@@ -10,6 +26,8 @@ use crate::ppi::Task;
 /// behavior.
 
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Frequency {
     // I'd prefer not to prefix these with `F`, but Rust identifiers can't start with digits.
     F16MHz = 0,
@@ -24,6 +42,60 @@ pub enum Frequency {
     F31250Hz = 9,
 }
 
+impl Frequency {
+    /// The number of timer ticks per second at this frequency.
+    pub const fn ticks_per_second(self) -> u32 {
+        16_000_000 >> (self as u8)
+    }
+}
+
+/// Error returned when a value doesn't fit the timer's bitmode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ValueTooLarge;
+
+/// Converts `micros` to ticks at `ticks_per_second`, rounded up to at least one tick, checking that they fit in
+/// `mask`.
+fn micros_to_ticks(micros: u64, ticks_per_second: u32, mask: u32) -> Result<u32, ValueTooLarge> {
+    let ticks = (micros as u128 * ticks_per_second as u128 + 999_999) / 1_000_000;
+    match ticks.max(1) {
+        ticks if ticks <= mask as u128 => Ok(ticks as u32),
+        _ => Err(ValueTooLarge),
+    }
+}
+
+/// Converts `micros` to ticks at `ticks_per_second`, rounded up, for a delay that may take multiple compare rounds.
+fn delay_ticks(micros: u64, ticks_per_second: u32) -> u64 {
+    let ticks = (micros as u128 * ticks_per_second as u128 + 999_999) / 1_000_000;
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// The counting mode of a [`Timer<CounterType>`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CounterMode {
+    /// Counter mode, which keeps requesting the 16 MHz clock while the counter runs.
+    ///
+    /// This draws more current, but doesn't drop fast edges, e.g. when counting external pulses through PPI.
+    Normal,
+    /// Low power counter mode, which only requests the 16 MHz clock while a COUNT task is being processed.
+    ///
+    /// This saves current, but can miss COUNT tasks that arrive faster than the clock starts up.
+    LowPower,
+}
+
+/// Converts `ticks` at `frequency` to whole microseconds, rounded down.
+fn ticks_to_micros(ticks: u32, frequency: Frequency) -> u64 {
+    ticks as u64 * 1_000_000 / frequency.ticks_per_second() as u64
+}
+
+/// The number of ticks from `earlier` to `now` of a counter that wraps at `mask`.
+fn wrapping_ticks(now: u32, earlier: u32, mask: u32) -> u32 {
+    now.wrapping_sub(earlier) & mask
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Bitmode {
     B8 = 1,
     B16 = 0,
@@ -31,12 +103,185 @@ pub enum Bitmode {
     B32 = 3,
 }
 
-pub enum TimerInstance {
-    TIMER0,
-    TIMER1,
-    TIMER2,
-    // TIMER3, // TODO: Only the 4 CC register timers for now.
-    // TIMER4,
+impl Bitmode {
+    /// The mask of the counter bits that are valid in this bitmode.
+    fn mask(&self) -> u32 {
+        match self {
+            Bitmode::B8 => 0xFF,
+            Bitmode::B16 => 0xFFFF,
+            Bitmode::B24 => 0xFF_FFFF,
+            Bitmode::B32 => 0xFFFF_FFFF,
+        }
+    }
+}
+
+/// Declares the timer instances of the selected chip once, from the `impl_timer!` data of the chip file: each
+/// instance's register block, CC count and interrupt come from [`crate::timer::Instance`].
+macro_rules! timer_instances {
+    ($($(#[cfg($cfg:meta)])? $type:ident = $n:literal,)*) => {
+        /// A timer peripheral.
+        ///
+        /// The instances are those of the selected chip: all chips have `TIMER0` to `TIMER2`, the nRF52820 adds
+        /// `TIMER3`, and the nRF52832, nRF52833 and nRF52840 add `TIMER3` and `TIMER4`, which are extended timers with
+        /// 6 CC registers. With the `softdevice` feature, `TIMER0` is not available, as the SoftDevice owns it.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+        pub enum TimerInstance {
+            $(
+                $(#[cfg($cfg)])?
+                $type = $n,
+            )*
+        }
+
+        impl TimerInstance {
+            pub(crate) fn regs(self) -> &'static pac::timer0::RegisterBlock {
+                match self {
+                    $(
+                        $(#[cfg($cfg)])?
+                        TimerInstance::$type => <peripherals::$type as crate::timer::sealed::Instance>::regs(),
+                    )*
+                }
+            }
+
+            /// The number of CC registers of the instance.
+            pub const fn cc_count(self) -> usize {
+                match self {
+                    $(
+                        $(#[cfg($cfg)])?
+                        TimerInstance::$type => <peripherals::$type as crate::timer::sealed::Instance>::CCS,
+                    )*
+                }
+            }
+
+            /// Enables the instance's interrupt in the NVIC.
+            fn enable_irq(self) {
+                match self {
+                    $(
+                        $(#[cfg($cfg)])?
+                        TimerInstance::$type => unsafe {
+                            <peripherals::$type as crate::timer::Instance>::Interrupt::steal()
+                        }
+                        .enable(),
+                    )*
+                }
+            }
+        }
+
+        $(
+            $(#[cfg($cfg)])?
+            impl sealed::Instance for peripherals::$type {}
+            $(#[cfg($cfg)])?
+            impl Instance for peripherals::$type {
+                const INSTANCE: TimerInstance = TimerInstance::$type;
+                const CC_COUNT: usize = <peripherals::$type as crate::timer::sealed::Instance>::CCS;
+                type Interrupt = <peripherals::$type as crate::timer::Instance>::Interrupt;
+            }
+        )*
+    };
+}
+
+timer_instances! {
+    #[cfg(not(feature = "softdevice"))]
+    TIMER0 = 0,
+    TIMER1 = 1,
+    TIMER2 = 2,
+    #[cfg(any(feature = "nrf52820", feature = "nrf52832", feature = "nrf52833", feature = "nrf52840"))]
+    TIMER3 = 3,
+    #[cfg(any(feature = "nrf52832", feature = "nrf52833", feature = "nrf52840"))]
+    TIMER4 = 4,
+}
+
+const INSTANCE_COUNT: usize = 5;
+/// The number of CC registers of a basic timer.
+const BASIC_CC_COUNT: usize = 4;
+/// The number of CC registers of an extended timer.
+const MAX_CC_COUNT: usize = 6;
+
+const NEW_AW: AtomicWaker = AtomicWaker::new();
+const NEW_AWS: [AtomicWaker; MAX_CC_COUNT] = [NEW_AW; MAX_CC_COUNT];
+static WAKERS: [[AtomicWaker; MAX_CC_COUNT]; INSTANCE_COUNT] = [NEW_AWS; INSTANCE_COUNT];
+
+/// Set while a [`Timer`] owns the instance.
+const NEW_TAKEN: AtomicBool = AtomicBool::new(false);
+static TAKEN: [AtomicBool; INSTANCE_COUNT] = [NEW_TAKEN; INSTANCE_COUNT];
+
+/// The CC registers of each instance that a [`Cc`] owns, bit `n` for register `n`.
+const NEW_CHANNELS: AtomicU8 = AtomicU8::new(0);
+static CHANNELS: [AtomicU8; INSTANCE_COUNT] = [NEW_CHANNELS; INSTANCE_COUNT];
+
+/// Set while a [`Ticker`] runs on the instance, so that the interrupt counts its periods.
+static TICKING: [AtomicBool; INSTANCE_COUNT] = [NEW_TAKEN; INSTANCE_COUNT];
+/// The number of periods of a [`Ticker`] that [`Ticker::next`] didn't return yet.
+const NEW_TICKS: AtomicU32 = AtomicU32::new(0);
+static TICKS: [AtomicU32; INSTANCE_COUNT] = [NEW_TICKS; INSTANCE_COUNT];
+
+/// Incremented whenever a [`Timer`] releases the instance, so that a [`Cc`] of a previous owner can tell.
+static OWNERS: [AtomicU32; INSTANCE_COUNT] = [NEW_TICKS; INSTANCE_COUNT];
+
+/// The current configuration of each instance, which its [`Cc`]s use: the mask of the bitmode and the ticks per
+/// second.
+static MASKS: [AtomicU32; INSTANCE_COUNT] = [NEW_TICKS; INSTANCE_COUNT];
+static TICK_RATES: [AtomicU32; INSTANCE_COUNT] = [NEW_TICKS; INSTANCE_COUNT];
+
+/// Publishes the configuration of the owner of `instance` to its [`Cc`]s.
+fn publish_config(instance: TimerInstance, bitmode: Bitmode, frequency: Frequency) {
+    MASKS[instance as usize].store(bitmode.mask(), Ordering::Release);
+    TICK_RATES[instance as usize].store(frequency.ticks_per_second(), Ordering::Release);
+}
+
+/// Marks `instance` as owned for good, e.g. by the time driver, so that no [`Timer`] can take it.
+#[cfg(feature = "time-driver-timer")]
+pub(crate) fn reserve(instance: TimerInstance) {
+    if TAKEN[instance as usize].swap(true, Ordering::AcqRel) {
+        panic!("Timer instance {} is already taken.", instance as usize);
+    }
+}
+
+/// Interrupt handler.
+///
+/// Bind it to the interrupt of every instance whose async methods are used, with
+/// [`bind_interrupts!`](crate::bind_interrupts), and pass the bindings to [`Timer::new_with_irq`] to prove it. Only
+/// the [`Async`] timers it returns have the async methods.
+pub struct InterruptHandler<T: Instance> {
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Instance> interrupt::Handler<T::Interrupt> for InterruptHandler<T> {
+    unsafe fn on_interrupt() {
+        on_interrupt(T::INSTANCE)
+    }
+}
+
+pub(crate) mod sealed {
+    pub trait Instance {}
+}
+
+/// Timer peripheral instance.
+pub trait Instance: Peripheral<P = Self> + sealed::Instance + 'static + Send {
+    /// The instance, which maps to the register block.
+    const INSTANCE: TimerInstance;
+    /// The number of CC registers of the instance: 4 for a normal timer, 6 for an extended timer.
+    const CC_COUNT: usize;
+    /// Interrupt for this peripheral.
+    type Interrupt: Interrupt;
+}
+
+fn on_interrupt(instance: TimerInstance) {
+    let regs = instance.regs();
+    for n in 0..instance.cc_count() {
+        if n == 0 && TICKING[instance as usize].load(Ordering::Acquire) {
+            if regs.events_compare[0].read().bits() != 0 {
+                // Keep the interrupt enabled, every period is counted.
+                regs.events_compare[0].reset();
+                TICKS[instance as usize].fetch_add(1, Ordering::AcqRel);
+                WAKERS[instance as usize][0].wake();
+            }
+        } else if regs.events_compare[n].read().bits() != 0 {
+            // Disable the interrupt, the waiting future clears the event.
+            regs.intenclr.write(|w| unsafe { w.bits(1 << (16 + n)) });
+            WAKERS[instance as usize][n].wake();
+        }
+    }
 }
 
 pub enum Prescaler {}
@@ -45,51 +290,159 @@ pub struct NotConfigured;
 pub struct CounterType;
 pub struct TimerType;
 
-pub struct Timer<MODE> {
-    // periph: pac::TIMER0,
+/// A timer created without proof that its interrupt is bound, e.g. with [`Timer::new`]. It has no async methods.
+pub struct Blocking;
+/// A timer created with [`Timer::new_with_irq`], whose interrupt is bound to [`InterruptHandler`]. Its async
+/// methods, e.g. [`Cc::wait`] and [`Timer::delay_us`], rely on it.
+pub struct Async;
+
+pub struct Timer<'d, MODE, IRQ = Blocking> {
     _instance: TimerInstance,
     _base: &'static pac::timer0::RegisterBlock,
     _mode: PhantomData<MODE>,
+    _irq: PhantomData<IRQ>,
+    _p: PhantomData<&'d mut ()>,
     bitmode: Bitmode,
+    frequency: Frequency,
+}
+
+impl<'d> Timer<'d, NotConfigured> {
+    /// Takes the `timer` peripheral and initializes it.
+    ///
+    /// # Panics
+    /// Panics if the instance is reserved, e.g. by the time driver, or owned by a `Timer` created with
+    /// [`Timer::new_unchecked`].
+    pub fn new<T: Instance>(_timer: impl Peripheral<P = T> + 'd) -> Self {
+        Self::new_unchecked(T::INSTANCE)
+    }
+
+    /// Takes the `timer` peripheral and initializes it, like [`Timer::new`], as an [`Async`] timer.
+    ///
+    /// The bindings prove that the interrupt is bound to [`InterruptHandler`], which the async methods rely on.
+    pub fn new_with_irq<T: Instance>(
+        _timer: impl Peripheral<P = T> + 'd,
+        _irq: impl interrupt::Binding<T::Interrupt, InterruptHandler<T>>,
+    ) -> Timer<'d, NotConfigured, Async> {
+        Timer::take(T::INSTANCE)
+    }
+}
+
+impl<'d, MODE> Timer<'d, MODE> {
+    /// Takes the timer `instance` without its peripheral and initializes it.
+    ///
+    /// Only the instance is checked, at runtime: this conflicts with any other driver that owns the peripheral.
+    ///
+    /// # Panics
+    /// Panics if another `Timer` owns the instance, see [`Timer::try_new_unchecked`].
+    pub fn new_unchecked(instance: TimerInstance) -> Self {
+        Self::take(instance)
+    }
+
+    /// Takes the timer `instance` without its peripheral and initializes it, or returns `None` if another `Timer`
+    /// owns the instance.
+    ///
+    /// The instance is owned until the `Timer` is dropped or released with [`Timer::free`].
+    pub fn try_new_unchecked(instance: TimerInstance) -> Option<Self> {
+        Self::try_take(instance)
+    }
 }
 
 /// These functions may be used by any timer
-impl<MODE> Timer<MODE> {
-    // TODO: Timer0 cannot be used if softdevice is enabled. How do we specify that?
-    pub fn new(instance: TimerInstance) -> Self {
-        let base = unsafe {
-            &*(match instance {
-                TimerInstance::TIMER0 => pac::TIMER0::ptr(),
-                TimerInstance::TIMER1 => pac::TIMER1::ptr(),
-                TimerInstance::TIMER2 => pac::TIMER2::ptr(),
-            } as *const pac::timer0::RegisterBlock)
-        };
+impl<'d, MODE, IRQ> Timer<'d, MODE, IRQ> {
+    fn take(instance: TimerInstance) -> Self {
+        match Self::try_take(instance) {
+            Some(timer) => timer,
+            None => panic!("Timer instance {} is already taken.", instance as usize),
+        }
+    }
+
+    fn try_take(instance: TimerInstance) -> Option<Self> {
+        if TAKEN[instance as usize].swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        // A previous owner's `Cc`s no longer own their registers.
+        CHANNELS[instance as usize].store(0, Ordering::Release);
+        let base = instance.regs();
 
         let timer = Timer {
             _base: base,
             _instance: instance,
-            _mode: PhantomData,    // basically a placeholder for MODE.
+            _mode: PhantomData, // basically a placeholder for MODE.
+            _irq: PhantomData,
+            _p: PhantomData,
             bitmode: Bitmode::B24, // The default bitmode
+            frequency: Frequency::F1MHz,
         };
         timer.stop(); // Initialize the counter at 0.
         timer.clear(); // Appearently necessary for proper functioning!
+                       // Match the registers with the stored configuration, a previous owner may have changed them.
+        timer.set_bitmode(&timer.bitmode);
+        timer.set_prescaler(timer.frequency);
+        publish_config(instance, timer.bitmode, timer.frequency);
 
         // Not really necessary...
-        for n in 0..4 {
-            let cc = timer.cc(n);
+        for n in 0..timer._instance.cc_count() {
             // Initialize all the shorts as disabled.
             // cc.unshort_compare_clear();
             // cc.unshort_compare_stop();
             // Initialize the CC registers as 0.
-            cc.write(0);
+            base.cc[n].write(|w| unsafe { w.cc().bits(0) });
+            base.events_compare[n].reset();
         }
-        timer
+        Some(timer)
+    }
+
+    /// Shuts the timer down, resets its configuration and releases its instance, so that it can be taken again.
+    ///
+    /// Dropping the `Timer` releases the instance too, but leaves the timer running as it is.
+    pub fn free(self) -> TimerInstance {
+        self.reset();
+        self._instance
+    }
+
+    /// Shuts the timer down and returns the registers to their reset values: no shorts, no interrupts, timer mode,
+    /// 16-bit bitmode, a 1 MHz prescaler and CC registers at 0.
+    fn reset(&self) {
+        let regs = self._base;
+        self.stop();
+        self.shutdown();
+        regs.shorts.reset();
+        regs.intenclr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+        regs.mode.reset();
+        regs.bitmode.reset();
+        regs.prescaler.reset();
+        for n in 0..self._instance.cc_count() {
+            regs.cc[n].reset();
+            regs.events_compare[n].reset();
+        }
+    }
+
+    /// Moves the ownership of the instance into a `Timer` of another mode, with the given configuration.
+    fn retype<M>(self, bitmode: Bitmode, frequency: Frequency) -> Timer<'d, M, IRQ> {
+        let this = ManuallyDrop::new(self);
+        publish_config(this._instance, bitmode, frequency);
+        Timer {
+            _instance: this._instance,
+            _base: this._base,
+            _mode: PhantomData,
+            _irq: PhantomData,
+            _p: PhantomData,
+            bitmode,
+            frequency,
+        }
+    }
+
+    /// Resets the timer, like [`Timer::free`], but keeps its instance.
+    fn into_not_configured(self) -> Timer<'d, NotConfigured, IRQ> {
+        self.reset();
+        self.retype(Bitmode::B16, Frequency::F1MHz)
     }
 
     /// Adjusts the bitmode of the current timer.
-    pub fn with_bitmode(self, bitmode: Bitmode) -> Timer<MODE> {
+    pub fn with_bitmode(self, bitmode: Bitmode) -> Timer<'d, MODE, IRQ> {
         self.set_bitmode(&bitmode);
-        Timer { bitmode, ..self }
+        let frequency = self.frequency;
+        self.retype(bitmode, frequency)
     }
 
     /// Sets the bitmode of the timer.
@@ -104,6 +457,16 @@ impl<MODE> Timer<MODE> {
         });
     }
 
+    /// Sets the prescaler of the timer.
+    fn set_prescaler(&self, frequency: Frequency) {
+        self.stop();
+        self._base
+            .prescaler
+            // SAFETY: `frequency` is a variant of `Frequency`,
+            // whose values are all in the range of 0-9 (the valid range of `prescaler`).
+            .write(|w| unsafe { w.prescaler().bits(frequency as u8) });
+    }
+
     /// Starts the timer.
     pub fn start(&self) {
         self._base.tasks_start.write(|w| unsafe { w.bits(1) });
@@ -149,15 +512,191 @@ impl<MODE> Timer<MODE> {
         Task::from_reg(&self._base.tasks_clear)
     }
 
-    /// Returns this timer's `n`th CC register.
+    /// Returns the current value of the timer's counter.
+    ///
+    /// The counter is captured in the last CC register of the instance (3 for a normal timer, 5 for an extended
+    /// timer), which is taken for the duration of the call. The value is masked to the configured bitmode, as the
+    /// upper bits of the register are undefined in the narrower modes.
+    ///
+    /// # Panics
+    /// Panics if a [`Cc`] owns the last CC register.
+    pub fn read_counter(&self) -> u32 {
+        self.claim_cc(self._instance.cc_count() - 1).capture()
+    }
+
+    /// The instance of the timer.
+    pub fn instance(&self) -> TimerInstance {
+        self._instance
+    }
+
+    /// Returns a handle that starts, stops and clears the timer, e.g. from an interrupt handler.
+    pub fn control(&self) -> TimerControl {
+        TimerControl {
+            instance: self._instance,
+        }
+    }
+
+    /// The configured bitmode.
+    pub fn bitmode(&self) -> Bitmode {
+        self.bitmode
+    }
+
+    /// The configured frequency.
+    ///
+    /// Counters ignore it, they count COUNT tasks.
+    pub fn frequency(&self) -> Frequency {
+        self.frequency
+    }
+
+    /// Logs the timer's registers at the debug level: MODE, BITMODE, PRESCALER, SHORTS, INTEN and the CC registers.
+    ///
+    /// This only reads the registers, so it can be called while the timer runs, and doesn't capture the counter.
+    pub fn dump(&self) {
+        let regs = self._base;
+        debug!(
+            "TIMER{}: MODE={} BITMODE={} PRESCALER={} SHORTS={:#x} INTEN={:#x}",
+            self._instance as usize,
+            regs.mode.read().bits(),
+            regs.bitmode.read().bits(),
+            regs.prescaler.read().bits(),
+            regs.shorts.read().bits(),
+            regs.inten.read().bits()
+        );
+        for n in 0..self._instance.cc_count() {
+            debug!(
+                "TIMER{}: CC[{}]={:#x} EVENTS_COMPARE[{}]={}",
+                self._instance as usize,
+                n,
+                regs.cc[n].read().bits(),
+                n,
+                regs.events_compare[n].read().bits()
+            );
+        }
+    }
+
+    /// The largest value the timer's counter reaches in the configured bitmode, before it wraps to 0.
+    pub fn max_value(&self) -> u32 {
+        self.bitmode.mask()
+    }
+
+    /// Takes this timer's `n`th CC register, or returns `None` if a [`Cc`] owns it already.
+    ///
+    /// The register is owned until the `Cc` is dropped, which disables its interrupt. See [`Timer::split`] to take
+    /// all registers at once.
+    ///
+    /// # Panics
+    /// Panics if `n` >= the number of CC registers this timer has (4 for a normal timer, 6 for an extended timer).
+    pub fn cc(&self, n: usize) -> Option<Cc<MODE, IRQ>> {
+        self.check_cc(n);
+        self.try_cc(n)
+    }
+
+    /// Takes this timer's `n`th CC register, or returns `None` if a [`Cc`] owns it already or the timer doesn't have
+    /// it.
+    ///
+    /// This is [`Timer::cc`] without the panic.
+    pub fn try_cc(&self, n: usize) -> Option<Cc<MODE, IRQ>> {
+        if n >= self._instance.cc_count() {
+            return None;
+        }
+        let bit = 1 << n;
+        if CHANNELS[self._instance as usize].fetch_or(bit, Ordering::AcqRel) & bit != 0 {
+            return None;
+        }
+        Some(Cc {
+            n,
+            _base: self._base,
+            _instance: self._instance,
+            _mode: PhantomData,
+            _irq: PhantomData,
+            owner: OWNERS[self._instance as usize].load(Ordering::Acquire),
+        })
+    }
+
+    /// Takes this timer's `N`th CC register, or returns `None` if a [`Cc`] owns it already.
+    ///
+    /// `T` is the instance the timer was created from, e.g. `cc_const::<peripherals::TIMER1, 2>()`. This fails to
+    /// compile if `N` >= `T`'s number of CC registers, so that e.g. `N` = 4 is rejected for a normal timer.
+    ///
+    /// # Panics
+    /// Panics if the timer wasn't created from `T`.
+    pub fn cc_const<T: Instance, const N: usize>(&self) -> Option<Cc<MODE, IRQ>> {
+        #[allow(clippy::let_unit_value)]
+        let () = CheckCc::<T, N>::OK;
+        if T::INSTANCE != self._instance {
+            panic!(
+                "TIMER{} is not TIMER{}, which cc_const was given.",
+                self._instance as usize,
+                T::INSTANCE as usize
+            );
+        }
+        self.try_cc(N)
+    }
+
+    /// Splits the timer into its CC registers, each owned by one [`Cc`].
+    ///
+    /// The returned timer keeps the timer-level tasks, e.g. [`Timer::start`] and [`Timer::task_clear`].
+    ///
+    /// # Panics
+    /// Panics if a `Cc` owns one of the registers already.
+    pub fn split(self) -> (Timer<'d, MODE, IRQ>, CcChannels<MODE, IRQ>) {
+        let take = |n| self.claim_cc(n);
+        let extended = self._instance.cc_count() > BASIC_CC_COUNT;
+        let channels = CcChannels {
+            cc0: take(0),
+            cc1: take(1),
+            cc2: take(2),
+            cc3: take(3),
+            cc4: extended.then(|| take(4)),
+            cc5: extended.then(|| take(5)),
+        };
+        (self, channels)
+    }
+
+    /// Returns the COMPARE event of this timer's `n`th CC register, for use with PPI.
+    ///
+    /// This is the same as [`Cc::event_compare`], without taking the register.
+    ///
+    /// # Panics
+    /// Panics if `n` >= the number of CC registers this timer has (4 for a normal timer, 6 for an extended timer).
+    pub fn event_compare(&self, n: usize) -> Event {
+        self.check_cc(n);
+        Event::from_reg(&self._base.events_compare[n])
+    }
+
+    /// Returns the CAPTURE task of this timer's `n`th CC register, for use with PPI.
+    ///
+    /// This is the same as [`Cc::task_capture`], without taking the register.
     ///
     /// # Panics
     /// Panics if `n` >= the number of CC registers this timer has (4 for a normal timer, 6 for an extended timer).
-    pub fn cc(&self, n: usize) -> Cc {
-        if n >= 4 {
-            panic!("Cannot get CC register {} of timer with {} CC registers.", n, 4);
+    pub fn task_capture(&self, n: usize) -> Task {
+        self.check_cc(n);
+        Task::from_reg(&self._base.tasks_capture[n])
+    }
+
+    /// Takes CC register `n`, for a method that uses it internally.
+    ///
+    /// # Panics
+    /// Panics if a [`Cc`] owns the register already, or the timer doesn't have it.
+    fn claim_cc(&self, n: usize) -> Cc<MODE, IRQ> {
+        match self.cc(n) {
+            Some(cc) => cc,
+            None => panic!(
+                "CC register {} of TIMER{} is already taken.",
+                n, self._instance as usize
+            ),
+        }
+    }
+
+    fn check_cc(&self, n: usize) {
+        let ccs = self._instance.cc_count();
+        if n >= ccs {
+            panic!(
+                "Cannot get CC register {} of TIMER{}, which has {} CC registers.",
+                n, self._instance as usize, ccs
+            );
         }
-        Cc { n, _base: self._base }
     }
 
     // pub(crate) fn new() -> Self {
@@ -168,110 +707,1338 @@ impl<MODE> Timer<MODE> {
     // }
 }
 
-/// These functions may only be used on Timers (so not counters).
-impl Timer<TimerType> {
-    /// Change the timer's frequency.
-    ///
-    /// This will stop the timer if it isn't already stopped,
-    /// because the timer may exhibit 'unpredictable behaviour' if it's frequency is changed while it's running.
-    pub fn with_frequency(self, frequency: Frequency) -> Timer<TimerType> {
-        self.stop();
-        self._base
-            .prescaler
-            // SAFETY: `frequency` is a variant of `Frequency`,
-            // whose values are all in the range of 0-9 (the valid range of `prescaler`).
-            .write(|w| unsafe { w.prescaler().bits(frequency as u8) });
+/// Compile-time check of the index of [`Timer::cc_const`].
+struct CheckCc<T, const N: usize>(PhantomData<T>);
 
-        Timer { ..self }
-    }
+impl<T: Instance, const N: usize> CheckCc<T, N> {
+    const OK: () = assert!(
+        N < T::CC_COUNT,
+        "The timer instance doesn't have that many CC registers."
+    );
 }
 
-/// These functions may only be used on Counters (so not timers).
-impl Timer<CounterType> {
-    /// Returns the COUNT task, for use with PPI.
-    ///
-    /// When triggered, this task increments the counter.
-    pub fn task_count(&self) -> Task {
-        Task::from_reg(&self._base.tasks_count)
-    }
+/// Timer configuration, applied at once by [`Timer::new_timer`] and [`Timer::new_counter`].
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct TimerConfig {
+    /// Frequency of a timer, ignored by a counter.
+    pub frequency: Frequency,
+    /// Bitmode.
+    pub bitmode: Bitmode,
+    /// Counting mode of a counter, ignored by a timer.
+    pub counter_mode: CounterMode,
+    /// The CC registers whose COMPARE event clears the counter, bit `n` for register `n`.
+    pub compare_clear: u8,
+    /// The CC registers whose COMPARE event stops the timer, bit `n` for register `n`.
+    pub compare_stop: u8,
+    /// Start the timer once it's configured.
+    pub start: bool,
 }
 
-/// These functions may only be used on non-configured timers.
-impl Timer<NotConfigured> {
-    pub fn into_counter(self) -> Timer<CounterType> {
-        self._base.mode.write(|w| w.mode().low_power_counter());
-
-        Timer {
-            _mode: PhantomData,
-            _instance: self._instance,
-            _base: self._base,
-            bitmode: self.bitmode,
+impl Default for TimerConfig {
+    fn default() -> Self {
+        Self {
+            frequency: Frequency::F1MHz,
+            bitmode: Bitmode::B24,
+            counter_mode: CounterMode::LowPower,
+            compare_clear: 0,
+            compare_stop: 0,
+            start: false,
         }
     }
+}
 
-    pub fn into_timer(self) -> Timer<TimerType> {
-        self._base.mode.write(|w| w.mode().timer());
+impl<'d, IRQ> Timer<'d, NotConfigured, IRQ> {
+    /// Applies `config` while the timer is stopped, then starts it if requested.
+    fn with_config<MODE>(
+        self,
+        config: &TimerConfig,
+        mode: impl FnOnce(&pac::timer0::RegisterBlock),
+    ) -> Timer<'d, MODE, IRQ> {
+        let instance = self._instance;
+        let valid = (1u32 << instance.cc_count()) - 1;
+        assert!(
+            (config.compare_clear as u32 | config.compare_stop as u32) & !valid == 0,
+            "TIMER{} has {} CC registers.",
+            instance as usize,
+            instance.cc_count()
+        );
 
-        Timer {
-            _mode: PhantomData,
-            _instance: self._instance,
-            _base: self._base,
-            bitmode: self.bitmode,
+        let regs = self._base;
+        mode(regs);
+        self.set_bitmode(&config.bitmode);
+        self.set_prescaler(config.frequency);
+        regs.shorts
+            .write(|w| unsafe { w.bits(config.compare_clear as u32 | (config.compare_stop as u32) << 8) });
+        let timer = self.retype(config.bitmode, config.frequency);
+        if config.start {
+            timer.start();
         }
+        timer
     }
 }
 
-/// A representation of a timer's Capture/Compare (CC) register.
+/// A copyable handle that triggers the START, STOP and CLEAR tasks of a timer, created with [`Timer::control`].
 ///
-/// A CC register holds a 32-bit value.
-/// This is used either to store a capture of the timer's current count, or to specify the value for the timer to compare against.
+/// Triggering a task is a single store to its register, so the handle can be used from any task or interrupt handler
+/// concurrently with the [`Timer`]. It is `Send` and `Sync`, and holds only the instance, so it can be stored in a
+/// `static`. It doesn't configure the timer, as the bitmode and prescaler must not change while it runs.
 ///
-/// The timer will fire the register's COMPARE event when its counter reaches the value stored in the register.
-/// When the register's CAPTURE task is triggered, the timer will store the current value of its counter in the register
-pub struct Cc {
-    // _baseReg: pac::generic::Reg<CC_SPEC>,
-    _base: &'static pac::timer0::RegisterBlock,
-    n: usize,
+/// The handle stays valid after the `Timer` is freed, and then controls whichever owner takes the instance next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TimerControl {
+    instance: TimerInstance,
 }
 
-impl Cc {
-    /// Get the current value stored in the register.
-    pub fn read(&self) -> u32 {
-        self._base.cc[self.n].read().cc().bits()
+impl TimerControl {
+    /// Starts the timer.
+    pub fn start(&self) {
+        self.instance.regs().tasks_start.write(|w| unsafe { w.bits(1) });
     }
 
-    /// Set the value stored in the register.
-    ///
-    /// `event_compare` will fire when the timer's counter reaches this value.
-    pub fn write(&self, value: u32) {
-        // SAFETY: there are no invalid values for the CC register.
-        self._base.cc[self.n].write(|w| unsafe { w.cc().bits(value) })
+    /// Stops the timer.
+    pub fn stop(&self) {
+        self.instance.regs().tasks_stop.write(|w| unsafe { w.bits(1) });
     }
 
-    /// Capture the current value of the timer's counter in this register, and return it.
-    pub fn capture(&self) -> u32 {
-        self._base.tasks_capture[self.n].write(|w| unsafe { w.bits(1) });
-        self.read()
+    /// Reset the timer's counter to 0.
+    pub fn clear(&self) {
+        self.instance.regs().tasks_clear.write(|w| unsafe { w.bits(1) });
     }
 
-    /// Disable the shortcut between this CC register's COMPARE event and the timer's CLEAR task.
-    pub fn unshort_compare_clear(&self) {
-        self._base
-            .shorts
-            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << self.n)) })
+    /// The instance of the timer.
+    pub fn instance(&self) -> TimerInstance {
+        self.instance
     }
-    /// Disable the shortcut between this CC register's COMPARE event and the timer's STOP task.
-    pub fn unshort_compare_stop(&self) {
-        self._base
-            .shorts
-            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << (8 + self.n))) })
+}
+
+/// These functions may only be used on Timers (so not counters).
+impl<'d> Timer<'d, TimerType> {
+    /// Takes the `timer` peripheral and configures it as a timer with `config`, in one go.
+    ///
+    /// Everything is applied while the timer is stopped. It is started afterwards if [`TimerConfig::start`] is set,
+    /// and left stopped otherwise.
+    ///
+    /// # Panics
+    /// Panics like [`Timer::new`], or if the shorts name CC registers the instance doesn't have.
+    pub fn new_timer<T: Instance>(timer: impl Peripheral<P = T> + 'd, config: TimerConfig) -> Self {
+        Timer::<NotConfigured>::new(timer).with_config(&config, |regs| regs.mode.write(|w| w.mode().timer()))
     }
 }
 
-impl Drop for Cc {
-    fn drop(&mut self) {
-        self._base
-            .intenclr
-            .modify(|r, w| unsafe { w.bits(r.bits() | (1 << (16 + self.n))) });
+impl<'d, IRQ> Timer<'d, TimerType, IRQ> {
+    /// Change the timer's frequency.
+    ///
+    /// This will stop the timer if it isn't already stopped,
+    /// because the timer may exhibit 'unpredictable behaviour' if it's frequency is changed while it's running.
+    /// The timer is left stopped, start it again with [`Timer::start`], or configure it at once with
+    /// [`Timer::new_timer`].
+    pub fn with_frequency(self, frequency: Frequency) -> Timer<'d, TimerType, IRQ> {
+        self.set_prescaler(frequency);
+        let bitmode = self.bitmode;
+        self.retype(bitmode, frequency)
+    }
+
+    /// The number of ticks per second at the configured frequency.
+    pub fn ticks_per_second(&self) -> u32 {
+        self.frequency.ticks_per_second()
+    }
+
+    /// Converts `ticks` at the configured frequency to whole microseconds, rounded down.
+    pub fn ticks_to_micros(&self, ticks: u32) -> u64 {
+        ticks_to_micros(ticks, self.frequency)
+    }
+
+    /// Converts `ticks` at the configured frequency to a duration, rounded down.
+    #[cfg(feature = "time")]
+    pub fn ticks_to_duration(&self, ticks: u32) -> embassy_time::Duration {
+        embassy_time::Duration::from_micros(self.ticks_to_micros(ticks))
+    }
+
+    /// Converts the duration `d` to ticks at the configured frequency, rounded up to at least one tick.
+    ///
+    /// Returns [`ValueTooLarge`] if the ticks don't fit the bitmode.
+    #[cfg(feature = "time")]
+    pub fn duration_to_ticks(&self, d: embassy_time::Duration) -> Result<u32, ValueTooLarge> {
+        micros_to_ticks(d.as_micros(), self.ticks_per_second(), self.bitmode.mask())
+    }
+
+    /// Returns the number of ticks since the counter was at `earlier`, e.g. a value returned by
+    /// [`Timer::read_counter`] or [`Cc::capture`].
+    ///
+    /// The counter wraps at [`Timer::max_value`], which is accounted for as long as less than one wrap elapsed.
+    pub fn elapsed_ticks_since(&self, earlier: u32) -> u32 {
+        wrapping_ticks(self.read_counter(), earlier, self.bitmode.mask())
+    }
+
+    /// Returns the time since the counter was at `earlier`, see [`Timer::elapsed_ticks_since`].
+    #[cfg(feature = "time")]
+    pub fn elapsed_since(&self, earlier: u32) -> embassy_time::Duration {
+        self.ticks_to_duration(self.elapsed_ticks_since(earlier))
+    }
+
+    /// Shuts the timer down and returns it to its unconfigured state, keeping the instance.
+    ///
+    /// The shorts and interrupts are disabled, the bitmode and prescaler are reset to 16 bits and 1 MHz, and the CC
+    /// registers are zeroed. The timer can then be configured again, e.g. as a counter.
+    pub fn deconfigure(self) -> Timer<'d, NotConfigured, IRQ> {
+        self.into_not_configured()
+    }
+
+    /// Blocks for `micros` microseconds at the configured frequency, rounded up to whole ticks.
+    ///
+    /// This takes CC register 0 for the duration of the delay, restarts the counter from 0, and leaves the timer
+    /// stopped. Delays longer than the bitmode allows are split into multiple compare rounds.
+    ///
+    /// # Panics
+    /// Panics if a [`Cc`] owns CC register 0.
+    fn blocking_delay_micros(&self, micros: u64) {
+        let cc = self.claim_cc(0);
+        let mut ticks = delay_ticks(micros, self.ticks_per_second());
+        while ticks > 0 {
+            let round = ticks.min(self.bitmode.mask() as u64) as u32;
+            self.stop();
+            self.clear();
+            cc.write(round);
+            cc.clear_event();
+            self.start();
+            while !cc.event_fired() {}
+            self.stop();
+            cc.clear_event();
+            ticks -= round as u64;
+        }
+    }
+}
+
+/// These functions rely on the interrupt, so they may only be used on timers created with [`Timer::new_with_irq`].
+impl<'d> Timer<'d, TimerType, Async> {
+    /// Waits for `us` microseconds at the configured frequency, rounded up to whole ticks.
+    ///
+    /// This takes CC register 0 for the duration of the delay, and uses the shortcut between its COMPARE event and the
+    /// STOP task, and its interrupt, which must be bound to [`InterruptHandler`]. The counter is cleared first and the
+    /// timer is left stopped. Delays longer than the bitmode allows are split into multiple compare rounds. Dropping
+    /// the future stops the timer and disables the interrupt.
+    ///
+    /// # Panics
+    /// Panics if a [`Cc`] owns CC register 0.
+    pub async fn delay_us(&mut self, us: u32) {
+        self.delay_micros(us as u64).await
+    }
+
+    /// Waits for `ms` milliseconds at the configured frequency.
+    ///
+    /// See [`Timer::delay_us`].
+    pub async fn delay_ms(&mut self, ms: u32) {
+        self.delay_micros(ms as u64 * 1000).await
+    }
+
+    async fn delay_micros(&mut self, micros: u64) {
+        let cc = self.claim_cc(0);
+        let mut ticks = delay_ticks(micros, self.ticks_per_second());
+
+        let _on_drop = OnDrop::new(|| {
+            self.stop();
+            cc.unshort_compare_stop();
+            cc.clear_event();
+        });
+        cc.short_compare_stop();
+
+        while ticks > 0 {
+            let round = ticks.min(self.bitmode.mask() as u64) as u32;
+            self.stop();
+            self.clear();
+            cc.write(round);
+            cc.clear_event();
+            self.start();
+            cc.wait().await;
+            ticks -= round as u64;
+        }
+    }
+
+    /// Turns the timer into a [`Ticker`] with a period of `period_ticks` ticks at the configured frequency.
+    ///
+    /// The ticker takes CC register 0. Returns [`ValueTooLarge`] if the period doesn't fit the timer's bitmode.
+    ///
+    /// # Panics
+    /// Panics if `period_ticks` is 0, or a [`Cc`] owns CC register 0.
+    pub fn into_ticker(self, period_ticks: u32) -> Result<Ticker<'d>, ValueTooLarge> {
+        assert!(period_ticks > 0);
+        if period_ticks > self.bitmode.mask() {
+            return Err(ValueTooLarge);
+        }
+        Ok(Ticker::new(self, period_ticks))
+    }
+}
+
+/// A periodic source of ticks, driven by the timer hardware.
+///
+/// The timer's counter is compared to the period on CC register 0, whose COMPARE event clears the counter through
+/// the shortcut, so the periods are exact multiples of the timer's ticks and independent of the time driver. The
+/// interrupt counts the periods, so none are lost while the task is busy.
+///
+/// The timer must be an [`Async`] timer, created with [`Timer::new_with_irq`]. Dropping the ticker stops the timer and
+/// disables the interrupt, use [`Ticker::into_timer`] to reuse the timer.
+pub struct Ticker<'d> {
+    // Dropped before the timer, which releases the instance.
+    cc: Cc<TimerType, Async>,
+    timer: Timer<'d, TimerType, Async>,
+    period: u32,
+}
+
+impl<'d> Ticker<'d> {
+    fn new(timer: Timer<'d, TimerType, Async>, period: u32) -> Self {
+        let cc = timer.claim_cc(0);
+        let instance = timer._instance as usize;
+
+        timer.stop();
+        timer.clear();
+        cc.write(period);
+        cc.clear_event();
+        TICKS[instance].store(0, Ordering::Release);
+        TICKING[instance].store(true, Ordering::Release);
+        cc.short_compare_clear();
+        cc.enable_interrupt();
+        timer.start();
+
+        Self { cc, timer, period }
+    }
+
+    /// The period in ticks of the timer.
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+
+    /// Wait for the next period to elapse, and return the number of periods that elapsed since the last call.
+    ///
+    /// This is 1 unless the task was busy for longer than a period, in which case this returns immediately with the
+    /// number of missed periods included.
+    pub async fn next(&mut self) -> u32 {
+        let instance = self.timer._instance as usize;
+        poll_fn(|cx| {
+            WAKERS[instance][0].register(cx.waker());
+            match TICKS[instance].swap(0, Ordering::AcqRel) {
+                0 => Poll::Pending,
+                periods => Poll::Ready(periods),
+            }
+        })
+        .await
+    }
+
+    /// Stops the ticker and returns the timer.
+    pub fn into_timer(self) -> Timer<'d, TimerType, Async> {
+        let this = ManuallyDrop::new(self);
+        this.halt();
+        // SAFETY: `this` is not dropped, so every field is moved out only once.
+        unsafe {
+            drop(core::ptr::read(&this.cc));
+            core::ptr::read(&this.timer)
+        }
+    }
+
+    fn halt(&self) {
+        self.timer.stop();
+        self.cc.disable_interrupt();
+        self.cc.unshort_compare_clear();
+        self.cc.clear_event();
+        TICKING[self.timer._instance as usize].store(false, Ordering::Release);
+    }
+}
+
+impl<'d> Drop for Ticker<'d> {
+    fn drop(&mut self) {
+        self.halt();
+    }
+}
+
+/// A 64-bit tick counter, made of two timers chained through a PPI channel.
+///
+/// The low timer counts ticks in 32-bit mode, and is cleared by the COMPARE event of CC register 0 at
+/// [`WideTimer::LOW_PERIOD`] ticks. The PPI channel connects that event to the COUNT task of the high timer, which
+/// counts the periods of the low one. At 16 MHz, the counter wraps after more than 36 000 years.
+pub struct WideTimer<'d, C: ConfigurableChannel> {
+    low: Timer<'d, TimerType>,
+    high: Timer<'d, CounterType>,
+    compare: Cc<TimerType>,
+    ppi: Ppi<'d, C, 1, 1>,
+}
+
+impl<'d, C: ConfigurableChannel> WideTimer<'d, C> {
+    /// The number of ticks after which the low timer is cleared.
+    pub const LOW_PERIOD: u32 = u32::MAX;
+
+    /// Chains the timers `low` and `high` through the PPI channel `ch`, and starts counting at `frequency`.
+    ///
+    /// # Panics
+    /// Panics if CC register 0 of `low` is taken.
+    pub fn new(
+        low: Timer<'d, NotConfigured>,
+        high: Timer<'d, NotConfigured>,
+        ch: impl Peripheral<P = C> + 'd,
+        frequency: Frequency,
+    ) -> Self {
+        let low = low.into_timer().with_bitmode(Bitmode::B32).with_frequency(frequency);
+        let high = high.into_counter(CounterMode::LowPower).with_bitmode(Bitmode::B32);
+
+        let compare = match low.cc(0) {
+            Some(cc) => cc,
+            None => panic!("CC register 0 is already taken."),
+        };
+        compare.write(Self::LOW_PERIOD);
+        compare.short_compare_clear();
+
+        let mut ppi = Ppi::new_one_to_one(ch, compare.event_compare(), high.task_count());
+        ppi.enable();
+
+        low.clear();
+        high.clear();
+        high.start();
+        low.start();
+
+        Self {
+            low,
+            high,
+            compare,
+            ppi,
+        }
+    }
+
+    /// Returns the number of ticks since the counter was started.
+    ///
+    /// The high timer is read before and after the low one. If the low timer was cleared in between, the low timer
+    /// is read again, so both halves are consistent.
+    pub fn now(&self) -> u64 {
+        let mut high = self.high.read_counter();
+        loop {
+            let low = self.low.read_counter();
+            let high_again = self.high.read_counter();
+            if high == high_again {
+                return high as u64 * Self::LOW_PERIOD as u64 + low as u64;
+            }
+            high = high_again;
+        }
+    }
+
+    /// The number of ticks per second at the configured frequency.
+    pub fn ticks_per_second(&self) -> u32 {
+        self.low.ticks_per_second()
+    }
+
+    /// Returns the number of whole microseconds since the counter was started.
+    pub fn now_micros(&self) -> u64 {
+        (self.now() as u128 * 1_000_000 / self.ticks_per_second() as u128) as u64
+    }
+
+    /// Returns the number of whole seconds since the counter was started.
+    pub fn now_secs(&self) -> u64 {
+        self.now() / self.ticks_per_second() as u64
+    }
+
+    /// Stops the counter and releases the PPI channel and both timers.
+    pub fn free(self) -> (TimerInstance, TimerInstance) {
+        let Self {
+            low,
+            high,
+            compare,
+            ppi,
+        } = self;
+        low.stop();
+        high.stop();
+        compare.unshort_compare_clear();
+        drop(compare);
+        drop(ppi);
+        (low.free(), high.free())
+    }
+}
+
+/// A PWM output generated by a timer, a GPIOTE channel and two PPI channels.
+///
+/// CC register 0 holds the period, and its COMPARE event clears the counter through the shortcut and sets the pin
+/// through the first PPI channel. CC register 1 holds the duty, and its COMPARE event clears the pin through the
+/// second PPI channel. The pin is high for `duty` ticks of every period.
+///
+/// This frees the PWM peripherals for other pins, at the cost of a GPIOTE channel and two PPI channels.
+#[cfg(feature = "gpiote")]
+pub struct SoftPwm<'d, C: gpiote::Channel, T: GpioPin, P0: ConfigurableChannel, P1: ConfigurableChannel> {
+    timer: Timer<'d, TimerType>,
+    period_cc: Cc<TimerType>,
+    duty_cc: Cc<TimerType>,
+    out: OutputChannel<'d, C, T>,
+    set_ppi: Ppi<'d, P0, 1, 1>,
+    clr_ppi: Ppi<'d, P1, 1, 1>,
+    period: u32,
+    duty: u32,
+}
+
+#[cfg(feature = "gpiote")]
+impl<'d, C: gpiote::Channel, T: GpioPin, P0: ConfigurableChannel, P1: ConfigurableChannel> SoftPwm<'d, C, T, P0, P1> {
+    /// Creates a PWM output on `out` with a period of `period` ticks at the timer's frequency and a duty of 0, and
+    /// starts the timer.
+    ///
+    /// `set_ch` and `clr_ch` are the PPI channels that set and clear the pin. Returns [`ValueTooLarge`] if the period
+    /// doesn't fit the timer's bitmode.
+    ///
+    /// # Panics
+    /// Panics if `period` is 0, or CC register 0 or 1 of the timer is taken.
+    pub fn new(
+        timer: Timer<'d, TimerType>,
+        out: OutputChannel<'d, C, T>,
+        set_ch: impl Peripheral<P = P0> + 'd,
+        clr_ch: impl Peripheral<P = P1> + 'd,
+        period: u32,
+    ) -> Result<Self, ValueTooLarge> {
+        assert!(period > 0);
+        if period > timer.bitmode.mask() {
+            return Err(ValueTooLarge);
+        }
+        let (period_cc, duty_cc) = match (timer.cc(0), timer.cc(1)) {
+            (Some(period_cc), Some(duty_cc)) => (period_cc, duty_cc),
+            _ => panic!("CC registers 0 and 1 must not be taken."),
+        };
+
+        timer.stop();
+        timer.clear();
+        period_cc.write(period);
+        period_cc.short_compare_clear();
+        duty_cc.write(0);
+        out.clear();
+
+        let set_ppi = Ppi::new_one_to_one(set_ch, period_cc.event_compare(), out.task_set());
+        let clr_ppi = Ppi::new_one_to_one(clr_ch, duty_cc.event_compare(), out.task_clr());
+        timer.start();
+
+        Ok(Self {
+            timer,
+            period_cc,
+            duty_cc,
+            out,
+            set_ppi,
+            clr_ppi,
+            period,
+            duty: 0,
+        })
+    }
+
+    /// The period in ticks.
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+
+    /// The duty in ticks.
+    pub fn duty(&self) -> u32 {
+        self.duty
+    }
+
+    /// Sets the number of ticks of every period during which the pin is high.
+    ///
+    /// The new duty applies while the timer runs. If the counter is already past it in the current period, the pin
+    /// is cleared right away instead of staying high until the end of the period. A duty of 0 keeps the pin low, a
+    /// duty of at least the period keeps it high.
+    pub fn set_duty(&mut self, duty: u32) {
+        self.duty = duty;
+        self.update();
+    }
+
+    /// Sets the period in ticks, keeping the duty in ticks.
+    ///
+    /// If the counter is already past the new period, a new period starts right away instead of after the counter
+    /// wrapped. Returns [`ValueTooLarge`] if the period doesn't fit the timer's bitmode.
+    ///
+    /// # Panics
+    /// Panics if `period` is 0.
+    pub fn set_period(&mut self, period: u32) -> Result<(), ValueTooLarge> {
+        assert!(period > 0);
+        if period > self.timer.bitmode.mask() {
+            return Err(ValueTooLarge);
+        }
+        self.period = period;
+        self.period_cc.write(period);
+        if self.timer.read_counter() >= period {
+            self.timer.clear();
+            if self.duty > 0 {
+                self.out.set();
+            }
+        }
+        self.update();
+        Ok(())
+    }
+
+    fn update(&mut self) {
+        if self.duty == 0 || self.duty >= self.period {
+            // Constant level, no edges.
+            self.set_ppi.disable();
+            self.clr_ppi.disable();
+            match self.duty {
+                0 => self.out.clear(),
+                _ => self.out.set(),
+            }
+            return;
+        }
+
+        self.duty_cc.write(self.duty);
+        if self.timer.read_counter() >= self.duty {
+            // The COMPARE event of this period was missed, or will only fire after the wrap.
+            self.out.clear();
+        }
+        self.set_ppi.enable();
+        self.clr_ppi.enable();
+    }
+
+    /// Stops the PWM output and drives the pin to `level`.
+    pub fn disable(&mut self, level: Level) {
+        self.timer.stop();
+        self.set_ppi.disable();
+        self.clr_ppi.disable();
+        match level {
+            Level::Low => self.out.clear(),
+            Level::High => self.out.set(),
+        }
+    }
+
+    /// Restarts the PWM output at the beginning of a period, after [`SoftPwm::disable`].
+    pub fn enable(&mut self) {
+        self.timer.clear();
+        if self.duty > 0 {
+            self.out.set();
+        }
+        self.update();
+        self.timer.start();
+    }
+}
+
+/// Error returned by [`InputCapture::next_capture`] when an edge was lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Overrun;
+
+/// Timestamps of the edges of a pin, captured in hardware.
+///
+/// The GPIOTE channel's IN event triggers the CAPTURE task of one CC register of the timer through a PPI channel, so
+/// the timestamp is taken without CPU involvement, in ticks at the timer's frequency. The PPI channel is in a group,
+/// whose DIS task it triggers through its fork, so it disables itself on the edge it captures: the timestamp can't be
+/// overwritten before [`InputCapture::next_capture`] returns it, and stays frozen until the next call.
+///
+/// A second PPI channel captures every edge in another CC register. The edges that arrive after the captured one,
+/// before the task notices it or while the register is frozen, are lost, and the two registers no longer match, so
+/// they are reported as [`Overrun`].
+///
+/// Only on the PPI of the nRF52, as the DPPI can't connect the IN event to two channels. Dropping the input capture
+/// stops the timer and releases the GPIOTE and PPI channels.
+#[cfg(all(feature = "gpiote", feature = "_ppi"))]
+pub struct InputCapture<'d, C: gpiote::Channel, T: GpioPin, P0: ConfigurableChannel, P1: ConfigurableChannel, G: Group>
+{
+    timer: Timer<'d, TimerType>,
+    cc: Cc<TimerType>,
+    latest_cc: Cc<TimerType>,
+    input: InputChannel<'d, C, T>,
+    ppi: Ppi<'d, P0, 1, 2>,
+    _latest_ppi: Ppi<'d, P1, 1, 1>,
+    _group: PpiGroup<'d, G>,
+    /// The PPI channel is enabled, so the next edge is captured.
+    armed: bool,
+    /// The latest edge when the captured one was returned.
+    seen: u32,
+}
+
+#[cfg(all(feature = "gpiote", feature = "_ppi"))]
+impl<'d, C: gpiote::Channel, T: GpioPin, P0: ConfigurableChannel, P1: ConfigurableChannel, G: Group>
+    InputCapture<'d, C, T, P0, P1, G>
+{
+    /// Captures the edges of `input` in CC register `n` of `timer`, through the PPI channel `ch` in `group`, and
+    /// every edge in CC register `latest_n`, through the PPI channel `latest_ch`, and starts the timer.
+    ///
+    /// # Panics
+    /// Panics if CC register `n` or `latest_n` of the timer is taken, or doesn't exist.
+    pub fn new(
+        timer: Timer<'d, TimerType>,
+        input: InputChannel<'d, C, T>,
+        ch: impl Peripheral<P = P0> + 'd,
+        latest_ch: impl Peripheral<P = P1> + 'd,
+        group: impl Peripheral<P = G> + 'd,
+        n: usize,
+        latest_n: usize,
+    ) -> Self {
+        let cc = timer.claim_cc(n);
+        let latest_cc = timer.claim_cc(latest_n);
+        let mut group = PpiGroup::new(group);
+        let mut ppi = Ppi::new_one_to_two(ch, input.event_in(), cc.task_capture(), group.task_disable_all());
+        let mut latest_ppi = Ppi::new_one_to_one(latest_ch, input.event_in(), latest_cc.task_capture());
+        group.add_channel(&ppi);
+
+        timer.stop();
+        timer.clear();
+        cc.write(0);
+        latest_cc.write(0);
+        input.clear_pending();
+        latest_ppi.enable();
+        ppi.enable();
+        timer.start();
+
+        Self {
+            timer,
+            cc,
+            latest_cc,
+            input,
+            ppi,
+            _latest_ppi: latest_ppi,
+            _group: group,
+            armed: true,
+            seen: 0,
+        }
+    }
+
+    /// Waits for the next edge and returns its timestamp in ticks.
+    ///
+    /// Returns [`Overrun`] if an edge was lost since the last call, and captures edges again. When the edge was lost
+    /// after the captured one, before this noticed it, the captured timestamp is still available from
+    /// [`InputCapture::last_capture`].
+    pub async fn next_capture(&mut self) -> Result<u32, Overrun> {
+        if !self.armed {
+            // An edge after the latest check either sets the IN event again, or is captured once armed.
+            self.input.clear_pending();
+            let lost = self.latest_cc.read() != self.seen;
+            self.ppi.enable();
+            self.armed = true;
+            if lost {
+                return Err(Overrun);
+            }
+        }
+
+        self.input.wait_pending().await;
+
+        // The PPI channel disabled itself on the edge, the register is frozen until the next call.
+        self.armed = false;
+        self.input.clear_pending();
+        self.seen = self.latest_cc.read();
+        match self.seen == self.cc.read() {
+            true => Ok(self.last_capture()),
+            false => Err(Overrun),
+        }
+    }
+
+    /// Returns the timestamp in ticks of the last captured edge.
+    pub fn last_capture(&self) -> u32 {
+        self.cc.read()
+    }
+
+    /// The number of ticks per second at the timer's frequency.
+    pub fn ticks_per_second(&self) -> u32 {
+        self.timer.ticks_per_second()
+    }
+}
+
+#[cfg(all(feature = "gpiote", feature = "_ppi"))]
+impl<'d, C: gpiote::Channel, T: GpioPin, P0: ConfigurableChannel, P1: ConfigurableChannel, G: Group> Drop
+    for InputCapture<'d, C, T, P0, P1, G>
+{
+    fn drop(&mut self) {
+        self.timer.stop();
+    }
+}
+
+/// A frequency counter, which counts the events of a signal during a gate time.
+///
+/// The signal's event is connected to the COUNT task of the counter through the first PPI channel. The gate timer's
+/// CC register 0 holds the gate time, and its COMPARE event stops the gate timer through the shortcut and the counter
+/// through the second PPI channel. The counter's CC register 0 detects when the count reaches its bitmode's maximum.
+///
+/// The gate timer must be an [`Async`] timer, created with [`Timer::new_with_irq`]. Dropping the frequency counter
+/// stops both timers and releases the PPI channels, use [`FrequencyCounter::free`] to reuse the timers.
+pub struct FrequencyCounter<'d, P0: ConfigurableChannel, P1: ConfigurableChannel> {
+    gate: Timer<'d, TimerType, Async>,
+    counter: Timer<'d, CounterType>,
+    gate_cc: Cc<TimerType, Async>,
+    overflow_cc: Cc<CounterType>,
+    count_ppi: Ppi<'d, P0, 1, 1>,
+    stop_ppi: Ppi<'d, P1, 1, 1>,
+}
+
+impl<'d, P0: ConfigurableChannel, P1: ConfigurableChannel> FrequencyCounter<'d, P0, P1> {
+    /// Creates a frequency counter of `signal`, e.g. a GPIOTE or COMP event.
+    ///
+    /// `count_ch` connects the signal to the counter, `stop_ch` connects the gate to the counter.
+    ///
+    /// # Panics
+    /// Panics if CC register 0 of the gate timer or of the counter is taken.
+    pub fn new(
+        gate: Timer<'d, TimerType, Async>,
+        counter: Timer<'d, CounterType>,
+        signal: Event,
+        count_ch: impl Peripheral<P = P0> + 'd,
+        stop_ch: impl Peripheral<P = P1> + 'd,
+    ) -> Self {
+        let (gate_cc, overflow_cc) = match (gate.cc(0), counter.cc(0)) {
+            (Some(gate_cc), Some(overflow_cc)) => (gate_cc, overflow_cc),
+            _ => panic!("CC register 0 of both timers must not be taken."),
+        };
+        gate.stop();
+        counter.stop();
+        gate_cc.short_compare_stop();
+        overflow_cc.write(counter.bitmode.mask());
+
+        let mut count_ppi = Ppi::new_one_to_one(count_ch, signal, counter.task_count());
+        let mut stop_ppi = Ppi::new_one_to_one(stop_ch, gate_cc.event_compare(), counter.task_stop());
+        count_ppi.enable();
+        stop_ppi.enable();
+
+        Self {
+            gate,
+            counter,
+            gate_cc,
+            overflow_cc,
+            count_ppi,
+            stop_ppi,
+        }
+    }
+
+    /// Counts the events of the signal during `micros` microseconds at the gate timer's frequency.
+    ///
+    /// Returns [`ValueTooLarge`] if the gate time doesn't fit the gate timer's bitmode, or if the count reaches the
+    /// maximum of the counter's bitmode, instead of wrapping. Dropping the future stops both timers.
+    pub async fn measure_micros(&mut self, micros: u64) -> Result<u32, ValueTooLarge> {
+        self.gate_cc.write_micros(micros)?;
+
+        let gate = &self.gate;
+        let counter = &self.counter;
+        let _on_drop = OnDrop::new(|| {
+            gate.stop();
+            counter.stop();
+        });
+
+        gate.stop();
+        counter.stop();
+        gate.clear();
+        counter.clear();
+        self.gate_cc.clear_event();
+        self.overflow_cc.clear_event();
+        counter.start();
+        gate.start();
+
+        self.gate_cc.wait().await;
+
+        let count = counter.read_counter();
+        match self.overflow_cc.event_fired() {
+            true => Err(ValueTooLarge),
+            false => Ok(count),
+        }
+    }
+
+    /// Counts the events of the signal during `gate`.
+    ///
+    /// See [`FrequencyCounter::measure_micros`].
+    #[cfg(feature = "time")]
+    pub async fn measure(&mut self, gate: embassy_time::Duration) -> Result<u32, ValueTooLarge> {
+        self.measure_micros(gate.as_micros()).await
+    }
+
+    /// Stops both timers, releases the PPI channels and returns the timers.
+    pub fn free(self) -> (Timer<'d, TimerType, Async>, Timer<'d, CounterType>) {
+        let this = ManuallyDrop::new(self);
+        this.halt();
+        // SAFETY: `this` is not dropped, so every field is moved out only once.
+        unsafe {
+            drop(core::ptr::read(&this.count_ppi));
+            drop(core::ptr::read(&this.stop_ppi));
+            drop(core::ptr::read(&this.gate_cc));
+            drop(core::ptr::read(&this.overflow_cc));
+            (core::ptr::read(&this.gate), core::ptr::read(&this.counter))
+        }
+    }
+
+    fn halt(&self) {
+        self.gate.stop();
+        self.counter.stop();
+        self.gate_cc.unshort_compare_stop();
+    }
+}
+
+impl<'d, P0: ConfigurableChannel, P1: ConfigurableChannel> Drop for FrequencyCounter<'d, P0, P1> {
+    fn drop(&mut self) {
+        self.halt();
+    }
+}
+
+/// These functions may only be used on Counters (so not timers).
+impl<'d> Timer<'d, CounterType> {
+    /// Takes the `timer` peripheral and configures it as a counter with `config`, in one go.
+    ///
+    /// See [`Timer::new_timer`].
+    pub fn new_counter<T: Instance>(timer: impl Peripheral<P = T> + 'd, config: TimerConfig) -> Self {
+        Timer::<NotConfigured>::new(timer).with_config(&config, |regs| {
+            regs.mode.write(|w| match config.counter_mode {
+                CounterMode::Normal => w.mode().counter(),
+                CounterMode::LowPower => w.mode().low_power_counter(),
+            })
+        })
+    }
+}
+
+impl<'d, IRQ> Timer<'d, CounterType, IRQ> {
+    /// Returns the COUNT task, for use with PPI.
+    ///
+    /// When triggered, this task increments the counter.
+    pub fn task_count(&self) -> Task {
+        Task::from_reg(&self._base.tasks_count)
+    }
+
+    /// Shuts the counter down and returns it to its unconfigured state, keeping the instance.
+    ///
+    /// See [`Timer::<TimerType>::deconfigure`].
+    pub fn deconfigure(self) -> Timer<'d, NotConfigured, IRQ> {
+        self.into_not_configured()
+    }
+}
+
+/// These functions rely on the interrupt, so they may only be used on counters created with [`Timer::new_with_irq`].
+impl<'d> Timer<'d, CounterType, Async> {
+    /// Waits until the counter reaches `n`, returning immediately if it's already at or past it.
+    ///
+    /// This takes CC register 0 for the duration of the wait, and uses its interrupt, which must be bound to
+    /// [`InterruptHandler`], so no CPU is used while the counter counts. Returns [`ValueTooLarge`] if `n` doesn't fit
+    /// the bitmode. Dropping the future disables the interrupt again.
+    ///
+    /// # Panics
+    /// Panics if a [`Cc`] owns CC register 0 or the last CC register, see [`Timer::read_counter`].
+    pub async fn wait_for_count(&mut self, n: u32) -> Result<(), ValueTooLarge> {
+        if n > self.bitmode.mask() {
+            return Err(ValueTooLarge);
+        }
+        let cc = self.claim_cc(0);
+        cc.write(n);
+        cc.clear_event();
+
+        // Armed before reading the counter, so a count between the two can't be missed.
+        if self.read_counter() >= n {
+            return Ok(());
+        }
+
+        cc.wait().await;
+        Ok(())
+    }
+}
+
+/// These functions may only be used on non-configured timers.
+impl<'d, IRQ> Timer<'d, NotConfigured, IRQ> {
+    /// Turns the timer into a counter, which counts its COUNT tasks in `mode`.
+    pub fn into_counter(self, mode: CounterMode) -> Timer<'d, CounterType, IRQ> {
+        self._base.mode.write(|w| match mode {
+            CounterMode::Normal => w.mode().counter(),
+            CounterMode::LowPower => w.mode().low_power_counter(),
+        });
+
+        let (bitmode, frequency) = (self.bitmode, self.frequency);
+        self.retype(bitmode, frequency)
+    }
+
+    pub fn into_timer(self) -> Timer<'d, TimerType, IRQ> {
+        self._base.mode.write(|w| w.mode().timer());
+
+        let (bitmode, frequency) = (self.bitmode, self.frequency);
+        self.retype(bitmode, frequency)
+    }
+}
+
+impl<'d, MODE, IRQ> Drop for Timer<'d, MODE, IRQ> {
+    fn drop(&mut self) {
+        OWNERS[self._instance as usize].fetch_add(1, Ordering::AcqRel);
+        TAKEN[self._instance as usize].store(false, Ordering::Release);
+    }
+}
+
+/// The CC registers of a timer, returned by [`Timer::split`].
+pub struct CcChannels<MODE, IRQ = Blocking> {
+    pub cc0: Cc<MODE, IRQ>,
+    pub cc1: Cc<MODE, IRQ>,
+    pub cc2: Cc<MODE, IRQ>,
+    pub cc3: Cc<MODE, IRQ>,
+    /// Only on an extended timer.
+    pub cc4: Option<Cc<MODE, IRQ>>,
+    /// Only on an extended timer.
+    pub cc5: Option<Cc<MODE, IRQ>>,
+}
+
+/// A representation of a timer's Capture/Compare (CC) register.
+///
+/// A CC register holds a 32-bit value.
+/// This is used either to store a capture of the timer's current count, or to specify the value for the timer to compare against.
+///
+/// The timer will fire the register's COMPARE event when its counter reaches the value stored in the register.
+/// When the register's CAPTURE task is triggered, the timer will store the current value of its counter in the register
+///
+/// A `Cc` owns its register, see [`Timer::cc`]. Dropping it disables the register's interrupt and releases it.
+///
+/// It follows the timer's current bitmode and frequency, e.g. after [`Timer::with_bitmode`]. Once the [`Timer`] is
+/// freed or dropped, the register belongs to the next owner of the instance, and using the `Cc` panics.
+pub struct Cc<MODE, IRQ = Blocking> {
+    // _baseReg: pac::generic::Reg<CC_SPEC>,
+    _base: &'static pac::timer0::RegisterBlock,
+    _instance: TimerInstance,
+    _mode: PhantomData<MODE>,
+    _irq: PhantomData<IRQ>,
+    n: usize,
+    /// The value of [`OWNERS`] while the `Timer` that created the `Cc` owned the instance.
+    owner: u32,
+}
+
+impl<MODE, IRQ> Cc<MODE, IRQ> {
+    /// Whether the `Timer` that created the `Cc` still owns the instance.
+    fn is_owned(&self) -> bool {
+        OWNERS[self._instance as usize].load(Ordering::Acquire) == self.owner
+    }
+
+    /// The registers of the timer.
+    ///
+    /// # Panics
+    /// Panics if the `Timer` that created the `Cc` was freed or dropped.
+    fn regs(&self) -> &'static pac::timer0::RegisterBlock {
+        if !self.is_owned() {
+            panic!(
+                "CC register {} of TIMER{} was used after its Timer was released.",
+                self.n, self._instance as usize
+            );
+        }
+        self._base
+    }
+
+    /// The mask of the timer's current bitmode.
+    fn mask(&self) -> u32 {
+        MASKS[self._instance as usize].load(Ordering::Acquire)
+    }
+
+    /// Get the current value stored in the register, masked to the timer's bitmode.
+    pub fn read(&self) -> u32 {
+        self.regs().cc[self.n].read().cc().bits() & self.mask()
+    }
+
+    /// Set the value stored in the register.
+    ///
+    /// `event_compare` will fire when the timer's counter reaches this value. The counter never reaches a value larger
+    /// than [`Cc::max_value`], see [`Cc::try_write`].
+    ///
+    /// # Panics
+    /// Panics in debug builds if `value` doesn't fit the timer's bitmode.
+    pub fn write(&self, value: u32) {
+        debug_assert!(
+            value <= self.mask(),
+            "CC value {} doesn't fit the bitmode, the maximum is {}.",
+            value,
+            self.mask()
+        );
+        // SAFETY: there are no invalid values for the CC register.
+        self.regs().cc[self.n].write(|w| unsafe { w.cc().bits(value) })
+    }
+
+    /// Set the value stored in the register, or return [`ValueTooLarge`] if it doesn't fit the timer's bitmode.
+    pub fn try_write(&self, value: u32) -> Result<(), ValueTooLarge> {
+        if value > self.mask() {
+            return Err(ValueTooLarge);
+        }
+        self.write(value);
+        Ok(())
+    }
+
+    /// The largest value the timer's counter reaches in its bitmode.
+    pub fn max_value(&self) -> u32 {
+        self.mask()
+    }
+
+    /// Capture the current value of the timer's counter in this register, and return it.
+    pub fn capture(&self) -> u32 {
+        self.regs().tasks_capture[self.n].write(|w| unsafe { w.bits(1) });
+        self.read()
+    }
+
+    /// Returns this CC register's COMPARE event, for use with PPI.
+    ///
+    /// This event will fire when the timer's counter reaches the value in this CC register. Connected to another
+    /// peripheral's task through a PPI channel, e.g. the SAADC's SAMPLE task, the task is triggered without any CPU
+    /// involvement.
+    pub fn event_compare(&self) -> Event {
+        Event::from_reg(&self.regs().events_compare[self.n])
+    }
+
+    /// Returns this CC register's CAPTURE task, for use with PPI.
+    ///
+    /// When triggered, this task stores the current value of the timer's counter in this register, e.g. to timestamp
+    /// a GPIOTE event in hardware. Read the timestamp with [`Cc::read`] afterwards.
+    pub fn task_capture(&self) -> Task {
+        Task::from_reg(&self.regs().tasks_capture[self.n])
+    }
+
+    /// Disable the interrupt of this register's COMPARE event.
+    ///
+    /// The interrupts of the other CC registers are not affected.
+    pub fn disable_interrupt(&self) {
+        self.regs().intenclr.write(|w| unsafe { w.bits(1 << (16 + self.n)) });
+    }
+
+    /// Clear this register's COMPARE event.
+    pub fn clear_event(&self) {
+        self.regs().events_compare[self.n].reset();
+    }
+
+    fn event_fired(&self) -> bool {
+        self.regs().events_compare[self.n].read().bits() != 0
+    }
+
+    /// Enable the shortcut between this CC register's COMPARE event and the timer's CLEAR task.
+    ///
+    /// This means that when the COMPARE event is fired, the CLEAR task will be triggered.
+    ///
+    /// So, when the timer's counter reaches the value stored in this register, the timer's counter will be reset to 0,
+    /// which makes the timer periodic.
+    pub fn short_compare_clear(&self) {
+        self.regs()
+            .shorts
+            .modify(|r, w| unsafe { w.bits(r.bits() | (1 << self.n)) })
+    }
+
+    /// Disable the shortcut between this CC register's COMPARE event and the timer's CLEAR task.
+    pub fn unshort_compare_clear(&self) {
+        self.regs()
+            .shorts
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << self.n)) })
+    }
+    /// Enable the shortcut between this CC register's COMPARE event and the timer's STOP task.
+    ///
+    /// This means that when the COMPARE event is fired, the STOP task will be triggered.
+    ///
+    /// So, when the timer's counter reaches the value stored in this register, the timer will stop counting up,
+    /// which makes the timer one-shot.
+    pub fn short_compare_stop(&self) {
+        self.regs()
+            .shorts
+            .modify(|r, w| unsafe { w.bits(r.bits() | (1 << (8 + self.n))) })
+    }
+
+    /// Disable the shortcut between this CC register's COMPARE event and the timer's STOP task.
+    pub fn unshort_compare_stop(&self) {
+        self.regs()
+            .shorts
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << (8 + self.n))) })
+    }
+}
+
+/// These functions rely on the interrupt, so they may only be used on CC registers of timers created with
+/// [`Timer::new_with_irq`].
+impl<MODE> Cc<MODE, Async> {
+    /// Enable the interrupt of this register's COMPARE event.
+    ///
+    /// This also enables the instance's interrupt in the NVIC, which must be bound to [`InterruptHandler`]. The
+    /// interrupts of the other CC registers are not affected. The handler disables the interrupt again when the event
+    /// fires.
+    pub fn enable_interrupt(&self) {
+        self._instance.enable_irq();
+        // Only this register's bit is written, INTENSET ignores zeroes.
+        self.regs().intenset.write(|w| unsafe { w.bits(1 << (16 + self.n)) });
+    }
+
+    /// Wait until the timer's counter reaches the value stored in the register.
+    ///
+    /// This enables the register's COMPARE interrupt and resolves when the COMPARE event fires. The event is cleared
+    /// before returning; if it already fired since it was last cleared, this returns immediately. Dropping the
+    /// future disables the interrupt again.
+    ///
+    /// The instance's interrupt must be bound to [`InterruptHandler`]. Different CC registers of the same timer can
+    /// be waited on concurrently.
+    pub async fn wait(&self) {
+        let regs = self.regs();
+        let n = self.n;
+        let waker = &WAKERS[self._instance as usize][n];
+
+        self._instance.enable_irq();
+        let _on_drop = OnDrop::new(|| {
+            regs.intenclr.write(|w| unsafe { w.bits(1 << (16 + n)) });
+        });
+
+        poll_fn(|cx| {
+            waker.register(cx.waker());
+            if regs.events_compare[n].read().bits() != 0 {
+                regs.events_compare[n].reset();
+                return Poll::Ready(());
+            }
+            regs.intenset.write(|w| unsafe { w.bits(1 << (16 + n)) });
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// These functions may only be used on CC registers of Timers (so not counters).
+impl<IRQ> Cc<TimerType, IRQ> {
+    /// Set the value stored in the register to `micros` microseconds at the timer's frequency, and return it.
+    ///
+    /// Durations shorter than a tick are rounded up to one tick. Returns [`ValueTooLarge`] if the ticks don't fit the
+    /// timer's bitmode, instead of wrapping.
+    pub fn write_micros(&self, micros: u64) -> Result<u32, ValueTooLarge> {
+        let ticks_per_second = TICK_RATES[self._instance as usize].load(Ordering::Acquire);
+        let ticks = micros_to_ticks(micros, ticks_per_second, self.mask())?;
+        self.write(ticks);
+        Ok(ticks)
+    }
+
+    /// Set the value stored in the register to the duration `d` at the timer's frequency, and return it.
+    ///
+    /// See [`Cc::write_micros`].
+    #[cfg(feature = "time")]
+    pub fn write_duration(&self, d: embassy_time::Duration) -> Result<u32, ValueTooLarge> {
+        self.write_micros(d.as_micros())
+    }
+}
+
+impl<MODE, IRQ> Drop for Cc<MODE, IRQ> {
+    fn drop(&mut self) {
+        // The register may belong to another owner already.
+        if self.is_owned() {
+            self.disable_interrupt();
+            CHANNELS[self._instance as usize].fetch_and(!(1 << self.n), Ordering::AcqRel);
+        }
+    }
+}
+
+mod eh02 {
+    use embedded_hal_02::blocking::delay::{DelayMs, DelayUs};
+
+    use super::*;
+
+    impl<'d, IRQ> DelayUs<u32> for Timer<'d, TimerType, IRQ> {
+        fn delay_us(&mut self, us: u32) {
+            self.blocking_delay_micros(us as u64)
+        }
+    }
+
+    impl<'d, IRQ> DelayUs<u16> for Timer<'d, TimerType, IRQ> {
+        fn delay_us(&mut self, us: u16) {
+            self.blocking_delay_micros(us as u64)
+        }
+    }
+
+    impl<'d, IRQ> DelayUs<u8> for Timer<'d, TimerType, IRQ> {
+        fn delay_us(&mut self, us: u8) {
+            self.blocking_delay_micros(us as u64)
+        }
+    }
+
+    impl<'d, IRQ> DelayMs<u32> for Timer<'d, TimerType, IRQ> {
+        fn delay_ms(&mut self, ms: u32) {
+            self.blocking_delay_micros(ms as u64 * 1000)
+        }
+    }
+
+    impl<'d, IRQ> DelayMs<u16> for Timer<'d, TimerType, IRQ> {
+        fn delay_ms(&mut self, ms: u16) {
+            self.blocking_delay_micros(ms as u64 * 1000)
+        }
+    }
+
+    impl<'d, IRQ> DelayMs<u8> for Timer<'d, TimerType, IRQ> {
+        fn delay_ms(&mut self, ms: u8) {
+            self.blocking_delay_micros(ms as u64 * 1000)
+        }
+    }
+}
+
+#[cfg(feature = "unstable-traits")]
+mod eh1 {
+    use super::*;
+
+    impl<'d, IRQ> embedded_hal_1::delay::DelayUs for Timer<'d, TimerType, IRQ> {
+        fn delay_us(&mut self, us: u32) {
+            self.blocking_delay_micros(us as u64)
+        }
+
+        fn delay_ms(&mut self, ms: u32) {
+            self.blocking_delay_micros(ms as u64 * 1000)
+        }
+    }
+}
+
+#[cfg(all(feature = "unstable-traits", feature = "nightly"))]
+mod eha {
+    use super::*;
+
+    impl<'d> embedded_hal_async::delay::DelayUs for Timer<'d, TimerType, Async> {
+        async fn delay_us(&mut self, us: u32) {
+            self.delay_micros(us as u64).await
+        }
+
+        async fn delay_ms(&mut self, ms: u32) {
+            self.delay_micros(ms as u64 * 1000).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_micros_to_ticks_rounds_up() {
+        let mask = Bitmode::B32.mask();
+        assert_eq!(micros_to_ticks(0, 16_000_000, mask), Ok(1));
+        assert_eq!(micros_to_ticks(1, 16_000_000, mask), Ok(16));
+        assert_eq!(micros_to_ticks(1_000, 1_000_000, mask), Ok(1_000));
+        assert_eq!(micros_to_ticks(1, 31_250, mask), Ok(1));
+        assert_eq!(micros_to_ticks(32, 31_250, mask), Ok(1));
+        assert_eq!(micros_to_ticks(33, 31_250, mask), Ok(2));
+    }
+
+    #[test]
+    fn test_micros_to_ticks_overflow() {
+        let mask = Bitmode::B16.mask();
+        assert_eq!(micros_to_ticks(65_535, 1_000_000, mask), Ok(0xFFFF));
+        assert_eq!(micros_to_ticks(65_536, 1_000_000, mask), Err(ValueTooLarge));
+
+        let mask = Bitmode::B32.mask();
+        assert_eq!(micros_to_ticks(268_435_455, 16_000_000, mask), Ok(0xFFFF_FFF0));
+        assert_eq!(micros_to_ticks(268_435_456, 16_000_000, mask), Err(ValueTooLarge));
+        assert_eq!(micros_to_ticks(u64::MAX, 16_000_000, mask), Err(ValueTooLarge));
+    }
+
+    #[test]
+    fn test_delay_ticks() {
+        assert_eq!(delay_ticks(0, 16_000_000), 0);
+        assert_eq!(delay_ticks(1, 31_250), 1);
+        assert_eq!(delay_ticks(33, 31_250), 2);
+        assert_eq!(delay_ticks(1_000_000_000, 16_000_000), 16_000_000_000);
+        assert_eq!(delay_ticks(u64::MAX, 16_000_000), u64::MAX);
+    }
+
+    #[test]
+    fn test_ticks_to_micros_rounds_down() {
+        assert_eq!(ticks_to_micros(0, Frequency::F16MHz), 0);
+        assert_eq!(ticks_to_micros(15, Frequency::F16MHz), 0);
+        assert_eq!(ticks_to_micros(16, Frequency::F16MHz), 1);
+        assert_eq!(ticks_to_micros(1, Frequency::F31250Hz), 32);
+        assert_eq!(ticks_to_micros(0xFFFF_FFFF, Frequency::F16MHz), 268_435_455);
+        assert_eq!(ticks_to_micros(0xFFFF_FFFF, Frequency::F31250Hz), 137_438_953_440);
+    }
+
+    #[test]
+    fn test_wrapping_ticks_b16() {
+        let mask = Bitmode::B16.mask();
+        assert_eq!(wrapping_ticks(0x1234, 0x1000, mask), 0x0234);
+        assert_eq!(wrapping_ticks(0x1000, 0x1000, mask), 0);
+        assert_eq!(wrapping_ticks(0x0005, 0xFFF0, mask), 0x0015);
+        assert_eq!(wrapping_ticks(0x0000, 0xFFFF, mask), 1);
+        assert_eq!(wrapping_ticks(0xFFFF, 0x0000, mask), 0xFFFF);
+    }
+
+    #[test]
+    fn test_wrapping_ticks_b24() {
+        let mask = Bitmode::B24.mask();
+        assert_eq!(wrapping_ticks(0x12_3456, 0x10_0000, mask), 0x02_3456);
+        assert_eq!(wrapping_ticks(0x00_0010, 0xFF_FFF0, mask), 0x20);
+        assert_eq!(wrapping_ticks(0x00_0000, 0xFF_FFFF, mask), 1);
+        assert_eq!(wrapping_ticks(0x00_FFFF, 0xFF_0000, mask), 0x01_FFFF);
+    }
+
+    #[test]
+    fn test_wrapping_ticks_b32() {
+        let mask = Bitmode::B32.mask();
+        assert_eq!(wrapping_ticks(0x8000_0000, 0x7FFF_FFFF, mask), 1);
+        assert_eq!(wrapping_ticks(0x0000_0003, 0xFFFF_FFFE, mask), 5);
+        assert_eq!(wrapping_ticks(0x0000_0000, 0xFFFF_FFFF, mask), 1);
+        assert_eq!(wrapping_ticks(0xFFFF_FFFF, 0x0000_0000, mask), 0xFFFF_FFFF);
     }
 }
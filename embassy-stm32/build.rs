@@ -5,7 +5,7 @@ use std::{env, fs};
 
 use proc_macro2::{Ident, TokenStream};
 use quote::{format_ident, quote};
-use stm32_metapac::metadata::{MemoryRegionKind, METADATA};
+use stm32_metapac::metadata::{MemoryRegion, MemoryRegionKind, METADATA};
 
 fn main() {
     let chip_name = match env::vars()
@@ -216,6 +216,85 @@ fn main() {
         });
     }
 
+    // Make sure the regions of the banks describe the whole flash in sectors that can be erased. The metadata is
+    // sorted by address, so a region that overlaps or precedes the previous one is a mistake in the tables.
+    let banks: Vec<_> = flash_memory_regions
+        .iter()
+        .filter(|region| region.name.starts_with("BANK_"))
+        .collect();
+    for region in banks.iter() {
+        let erase_size = region.settings.as_ref().unwrap().erase_size;
+        if region.address % erase_size != 0 {
+            panic!(
+                "Flash region {} is not aligned to its erase size {}",
+                region.name, erase_size
+            );
+        }
+        if region.size == 0 || region.size % erase_size != 0 {
+            panic!("Flash region {} is not a whole number of sectors", region.name);
+        }
+    }
+    for pair in banks.windows(2) {
+        let end = pair[0].address + pair[0].size;
+        if end > pair[1].address {
+            panic!(
+                "Flash regions {} and {} overlap or are not sorted",
+                pair[0].name, pair[1].name
+            );
+        }
+        // The regions of a bank follow each other, gaps are only allowed between the banks
+        let same_bank = pair[0].name[..6] == pair[1].name[..6];
+        if same_bank && end != pair[1].address {
+            panic!("Flash regions {} and {} are not contiguous", pair[0].name, pair[1].name);
+        }
+    }
+
+    // The F0 family backend only handles the 1 K and 2 K pages of the F0 variants
+    if chip_name.starts_with("stm32f0") {
+        for region in banks.iter() {
+            let erase_size = region.settings.as_ref().unwrap().erase_size;
+            if erase_size != 1024 && erase_size != 2048 {
+                panic!("Flash region {} has unsupported page size {}", region.name, erase_size);
+            }
+        }
+    }
+
+    let total_size: u32 = banks.iter().map(|region| region.size).sum();
+
+    // The L4 parts with a DBANK/DUALBANK option bit. The metadata describes the dual-bank layout, two banks of half
+    // the flash each. The page size in single-bank mode follows from it: it doubles on the L4R/L4S (RM0432), and
+    // stays the same on the L49x/L4Ax (RM0351).
+    let single_bank_pages = if chip_name.starts_with("stm32l4r") || chip_name.starts_with("stm32l4s") {
+        Some(2)
+    } else if chip_name.starts_with("stm32l49") || chip_name.starts_with("stm32l4a") {
+        Some(1)
+    } else {
+        None
+    };
+    if let Some(pages) = single_bank_pages {
+        let erase_size = |region: &MemoryRegion| region.settings.as_ref().unwrap().erase_size;
+        let is_dual_bank = banks.len() == 2
+            && banks[0].name == "BANK_1"
+            && banks[1].name == "BANK_2"
+            && banks.iter().all(|region| region.size == total_size / 2)
+            && erase_size(banks[0]) == erase_size(banks[1]);
+        if !is_dual_bank {
+            panic!("The flash of {} is not described as two equal banks", chip_name);
+        }
+        let dual_bank_page_size = erase_size(banks[0]);
+        let single_bank_erase_size = pages * dual_bank_page_size;
+        flash_regions.extend(quote! {
+            pub const SINGLE_BANK_ERASE_SIZE: u32 = #single_bank_erase_size;
+        });
+    }
+
+    flash_regions.extend(quote! {
+        const _: () = assert!(
+            #total_size as usize == crate::pac::FLASH_SIZE as usize,
+            "The flash regions don't add up to the flash size"
+        );
+    });
+
     let (fields, (inits, region_names)): (Vec<TokenStream>, (Vec<TokenStream>, Vec<Ident>)) = flash_memory_regions
         .iter()
         .map(|f| {
@@ -263,6 +342,15 @@ fn main() {
 
     g.extend(quote! { pub const MAX_ERASE_SIZE: usize = #max_erase_size as usize; });
 
+    // The runtime sized flash regions only implement the storage traits if MAX_ERASE_SIZE is the erase size of all
+    // of them
+    if banks
+        .iter()
+        .all(|region| region.settings.as_ref().unwrap().erase_size == max_erase_size)
+    {
+        println!("cargo:rustc-cfg=flash_uniform_erase_size");
+    }
+
     g.extend(quote! { pub mod flash_regions { #flash_regions } });
 
     // ========
@@ -693,6 +781,7 @@ fn main() {
         // SDMMCv1 uses the same channel for both directions, so just implement for RX
         (("sdmmc", "RX"), quote!(crate::sdmmc::SdmmcDma)),
         (("quadspi", "QUADSPI"), quote!(crate::qspi::QuadDma)),
+        (("hash", "IN"), quote!(crate::flash::HashDma)),
     ]
     .into();
 
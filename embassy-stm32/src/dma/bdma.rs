@@ -44,6 +44,7 @@ impl From<Dir> for vals::Dir {
         match raw {
             Dir::MemoryToPeripheral => Self::FROMMEMORY,
             Dir::PeripheralToMemory => Self::FROMPERIPHERAL,
+            Dir::MemoryToMemory => Self::FROMPERIPHERAL,
         }
     }
 }
@@ -251,6 +252,33 @@ impl<'a, C: Channel> Transfer<'a, C> {
         )
     }
 
+    /// Copies `src` to `dst` in memory, e.g. from the flash to RAM. Both buffers must have the same length.
+    pub unsafe fn new_copy_raw<W: Word>(
+        channel: impl Peripheral<P = C> + 'a,
+        src: *const [W],
+        dst: *mut [W],
+        options: TransferOptions,
+    ) -> Self {
+        into_ref!(channel);
+
+        let (src_ptr, len) = super::slice_ptr_parts(src);
+        let (dst_ptr, dst_len) = super::slice_ptr_parts_mut(dst);
+        assert!(len > 0 && len <= 0xFFFF && len == dst_len);
+
+        // In memory to memory mode, the peripheral port reads the source
+        Self::new_inner(
+            channel,
+            Request::default(),
+            Dir::MemoryToMemory,
+            src_ptr as *const u32,
+            dst_ptr as *mut u32,
+            len,
+            true,
+            W::size(),
+            options,
+        )
+    }
+
     unsafe fn new_inner(
         channel: PeripheralRef<'a, C>,
         _request: Request,
@@ -289,6 +317,10 @@ impl<'a, C: Channel> Transfer<'a, C> {
                 w.set_minc(vals::Inc::DISABLED);
             }
             w.set_dir(dir.into());
+            if dir == Dir::MemoryToMemory {
+                w.set_pinc(vals::Inc::ENABLED);
+                w.set_mem2mem(true);
+            }
             w.set_teie(true);
             w.set_tcie(true);
             w.set_en(true);
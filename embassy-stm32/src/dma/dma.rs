@@ -57,6 +57,7 @@ impl From<Dir> for vals::Dir {
         match raw {
             Dir::MemoryToPeripheral => Self::MEMORYTOPERIPHERAL,
             Dir::PeripheralToMemory => Self::PERIPHERALTOMEMORY,
+            Dir::MemoryToMemory => Self::MEMORYTOMEMORY,
         }
     }
 }
@@ -327,6 +328,35 @@ impl<'a, C: Channel> Transfer<'a, C> {
         )
     }
 
+    /// Copies `src` to `dst` in memory, e.g. from the flash to RAM. Both buffers must have the same length.
+    ///
+    /// Only the channels of DMA2 can access memory on both sides.
+    pub unsafe fn new_copy_raw<W: Word>(
+        channel: impl Peripheral<P = C> + 'a,
+        src: *const [W],
+        dst: *mut [W],
+        options: TransferOptions,
+    ) -> Self {
+        into_ref!(channel);
+
+        let (src_ptr, len) = super::slice_ptr_parts(src);
+        let (dst_ptr, dst_len) = super::slice_ptr_parts_mut(dst);
+        assert!(len > 0 && len <= 0xFFFF && len == dst_len);
+
+        // In memory to memory mode, the peripheral port reads the source
+        Self::new_inner(
+            channel,
+            Request::default(),
+            Dir::MemoryToMemory,
+            src_ptr as *const u32,
+            dst_ptr as *mut u32,
+            len,
+            true,
+            W::size(),
+            options,
+        )
+    }
+
     unsafe fn new_inner(
         channel: PeripheralRef<'a, C>,
         _request: Request,
@@ -352,8 +382,13 @@ impl<'a, C: Channel> Transfer<'a, C> {
         ch.par().write_value(peri_addr as u32);
         ch.m0ar().write_value(mem_addr as u32);
         ch.ndtr().write_value(regs::Ndtr(mem_len as _));
+        // The direct mode is not available for memory to memory transfers
+        let fifo_threshold = match dir {
+            Dir::MemoryToMemory => Some(options.fifo_threshold.unwrap_or(FifoThreshold::Full)),
+            _ => options.fifo_threshold,
+        };
         ch.fcr().write(|w| {
-            if let Some(fth) = options.fifo_threshold {
+            if let Some(fth) = fifo_threshold {
                 // FIFO mode
                 w.set_dmdis(vals::Dmdis::DISABLED);
                 w.set_fth(fth.into());
@@ -371,7 +406,10 @@ impl<'a, C: Channel> Transfer<'a, C> {
                 true => vals::Inc::INCREMENTED,
                 false => vals::Inc::FIXED,
             });
-            w.set_pinc(vals::Inc::FIXED);
+            w.set_pinc(match dir {
+                Dir::MemoryToMemory => vals::Inc::INCREMENTED,
+                _ => vals::Inc::FIXED,
+            });
             w.set_teie(true);
             w.set_tcie(true);
             #[cfg(dma_v1)]
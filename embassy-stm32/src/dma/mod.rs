@@ -37,6 +37,8 @@ pub use self::dmamux::*;
 enum Dir {
     MemoryToPeripheral,
     PeripheralToMemory,
+    #[cfg(not(gpdma))]
+    MemoryToMemory,
 }
 
 pub struct NoDma;
@@ -0,0 +1,100 @@
+use core::ops::Range;
+
+use embedded_storage::nor_flash::ReadNorFlash;
+
+use crate::crc::Crc;
+
+/// Polynomial of CRC-32/MPEG-2, the default of the CRC unit.
+const POLYNOMIAL: u32 = 0x04C1_1DB7;
+
+/// The CRC-32/MPEG-2 of `range` of `flash`, computed by the CRC unit.
+///
+/// `flash` is any readable flash, e.g. a `FlashRegion` or one of the parts of `Flash::into_partitions`.
+///
+/// The flash is read in small chunks through the bounds-checked read of the flash, and fed to the CRC unit word by
+/// word, most significant byte first. The bytes after the last whole word are added in software. The result is the
+/// CRC-32/MPEG-2 (polynomial 0x04C11DB7, initial value 0xFFFFFFFF, no reflection and no final XOR) of the bytes,
+/// so the CRC unit must be in its default configuration. It is reset before the range is fed.
+pub fn checksum<F: ReadNorFlash>(flash: &mut F, crc: &mut Crc<'_>, range: Range<u32>) -> Result<u32, F::Error> {
+    crc.reset();
+    checksum_with(flash, range, |word| crc.feed_word(word))
+}
+
+/// The CRC-32/MPEG-2 of `range` of `flash`, with the whole words fed to `feed_word`, which returns the CRC so far.
+fn checksum_with<F: ReadNorFlash>(
+    flash: &mut F,
+    range: Range<u32>,
+    mut feed_word: impl FnMut(u32) -> u32,
+) -> Result<u32, F::Error> {
+    // A multiple of the word size, so only the last chunk can end in a partial word
+    const CHUNK_SIZE: usize = 64;
+
+    let mut crc = 0xFFFF_FFFF;
+    let mut buf = [0; CHUNK_SIZE];
+    let mut offset = range.start;
+    while offset < range.end {
+        let len = core::cmp::min(CHUNK_SIZE as u32, range.end - offset) as usize;
+        flash.read(offset, &mut buf[..len])?;
+
+        let mut words = buf[..len].chunks_exact(4);
+        for word in &mut words {
+            crc = feed_word(u32::from_be_bytes(word.try_into().unwrap()));
+        }
+        crc = words.remainder().iter().fold(crc, |crc, &byte| update_byte(crc, byte));
+        offset += len as u32;
+    }
+    Ok(crc)
+}
+
+/// Add `byte` to the CRC-32/MPEG-2 `crc`.
+fn update_byte(mut crc: u32, byte: u8) -> u32 {
+    crc ^= (byte as u32) << 24;
+    for _ in 0..8 {
+        crc = (crc << 1) ^ (POLYNOMIAL & (crc >> 31).wrapping_neg());
+    }
+    crc
+}
+
+#[cfg(flash)]
+impl super::Flash<'_> {
+    /// The CRC-32/MPEG-2 of `range`, given as offsets from the flash base, computed by the CRC unit.
+    ///
+    /// See [`checksum`] for the format of the result.
+    pub fn checksum(&mut self, crc: &mut Crc<'_>, range: Range<u32>) -> Result<u32, super::Error> {
+        checksum(self, crc, range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mem_flash::MemFlash;
+
+    /// The CRC unit, emulated in software.
+    fn software_unit() -> impl FnMut(u32) -> u32 {
+        let mut crc = 0xFFFF_FFFF;
+        move |word: u32| {
+            crc = word.to_be_bytes().iter().fold(crc, |crc, &byte| update_byte(crc, byte));
+            crc
+        }
+    }
+
+    #[test]
+    fn can_checksum_flash_range() {
+        let mut flash = MemFlash::<256, 64, 4>::default();
+        flash.mem[10..19].copy_from_slice(b"123456789");
+
+        // The check value of CRC-32/MPEG-2
+        assert_eq!(0x0376_E6E7, checksum_with(&mut flash, 10..19, software_unit()).unwrap());
+        assert_eq!(0xFFFF_FFFF, checksum_with(&mut flash, 10..10, software_unit()).unwrap());
+
+        // Whole words and the bytes after them give the same CRC as computing every byte in software, also across
+        // chunks
+        for range in [0..200, 3..131, 64..68, 1..2] {
+            let expected = flash.mem[range.clone()]
+                .iter()
+                .fold(0xFFFF_FFFF, |crc, &byte| update_byte(crc, byte));
+            assert_eq!(expected, checksum_with(&mut flash, range, software_unit()).unwrap());
+        }
+    }
+}
@@ -1,234 +1,2825 @@
-use atomic_polyfill::{fence, Ordering};
+use core::marker::PhantomData;
+use core::ops::Range;
+
+use atomic_polyfill::{fence, AtomicBool, AtomicU8, Ordering};
+#[cfg(flash_f0)]
+use embassy_cortex_m::interrupt::InterruptExt;
 use embassy_hal_common::drop::OnDrop;
 use embassy_hal_common::{into_ref, PeripheralRef};
+#[cfg(feature = "time")]
+use embassy_time::{Duration, Instant};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
 
-use super::{family, Error, FlashLayout, FlashRegion, FlashSector, FLASH_BASE, FLASH_SIZE, MAX_ERASE_SIZE, WRITE_SIZE};
+use super::mapped::{self, Mapped};
+use super::observer::{self, FlashObserver};
+#[cfg(feature = "flash-stats")]
+use super::wear::WearLog;
+use super::{
+    family, Error, FlashLayout, FlashRegion, FlashSector, ProtectionCause, WriteUnit, FLASH_BASE, FLASH_SIZE,
+    MAX_ERASE_SIZE, WRITE_SIZE,
+};
 use crate::flash::FlashBank;
 use crate::Peripheral;
 
+/// Outcome of [`Flash::ensure_contains`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Provisioned {
+    /// The flash already contained the data, nothing was erased or programmed.
+    Unchanged,
+    /// At least one sector was (erased and) programmed.
+    Updated,
+}
+
+/// What [`Flash::overwrite`] does with the bytes of a partially covered sector outside of the written range.
+#[derive(Debug)]
+pub enum Preserve<'b> {
+    /// The bytes are erased along with the sector.
+    Nothing,
+    /// The bytes are saved in the buffer, which must be at least as large as the partially covered sectors.
+    ///
+    /// If power is lost while the sector is erased or reprogrammed, the saved bytes are lost.
+    Ram(&'b mut [u8]),
+    /// The new contents of the sector are staged in the scratch sector starting at this offset, which must be
+    /// at least as large as the partially covered sectors and is erased for each of them.
+    ///
+    /// If power is lost while the sector is erased or reprogrammed, the scratch sector still holds its complete
+    /// new contents. Nothing is recorded about which sector was being replaced, so an application that wants to
+    /// recover must store that itself before calling [`Flash::overwrite`], and copy the scratch sector back.
+    Scratch(u32),
+}
+
+/// Result of a successful [`Flash::self_test`], with the measured duration of each phase.
+#[cfg(feature = "time")]
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SelfTestReport {
+    /// Initial erase of the sector.
+    pub erase: Duration,
+    /// Blank check after the erase.
+    pub blank_check: Duration,
+    /// Programming the test pattern.
+    pub program: Duration,
+    /// Reading back the test pattern.
+    pub verify: Duration,
+    /// Overwriting the pattern with zeros, on families that allow programming non-erased words.
+    pub overwrite: Option<Duration>,
+    /// Final erase, leaving the sector blank.
+    pub final_erase: Duration,
+}
+
+/// Error returned by [`Flash::blocking_erase_range`], with the offset at which the erase stopped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EraseError {
+    /// Offset of the sector that failed to erase. If the range was rejected before erasing, this is the start of
+    /// the range and nothing was erased.
+    pub offset: u32,
+    /// The cause of the failure.
+    pub error: Error,
+}
+
+/// Token to erase flash that contains the running program, e.g. for a bootloader that erases the application it
+/// was started from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AllowSelfErase;
+
+/// Confirmation that the complete main flash may be destroyed by a mass erase, either directly with
+/// [`Flash::blocking_erase_all`] or by regressing the readout protection with [`Flash::regress_rdp`].
+#[cfg(any(flash_f0, flash_l4))]
+#[derive(Debug)]
+pub struct ConfirmMassErase(());
+
+#[cfg(any(flash_f0, flash_l4))]
+impl ConfirmMassErase {
+    /// Confirms that all contents of the main flash may be destroyed.
+    ///
+    /// # Safety
+    /// A mass erase destroys the complete main flash, including the running program if it is in the flash. Nothing
+    /// of the erased flash may be used afterwards, in particular no code may be executed from it; after a
+    /// regression of the readout protection, the device must only be reset or its option bytes reloaded.
+    pub unsafe fn new() -> Self {
+        Self(())
+    }
+}
+
+/// Readout protection level, as selected by the RDP option byte.
+///
+/// Read it with [`Flash::rdp_level`]. The level is raised with [`Flash::set_rdp_level`] or, permanently, with
+/// [`Flash::set_rdp_level2_permanent`], and only regressed with [`Flash::regress_rdp`].
+#[cfg(any(flash_f0, flash_l4))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RdpLevel {
+    /// No readout protection.
+    Level0,
+    /// The flash can't be read by a debugger or from RAM and system memory. Can be regressed to level 0,
+    /// which mass erases the flash.
+    Level1,
+    /// Like level 1, but the debug interface is disabled permanently and the option bytes can't be changed
+    /// anymore. This can't be undone.
+    Level2,
+}
+
+/// The option bytes were changed, but only take effect once they are reloaded.
+///
+/// After a regression of the readout protection with [`Flash::regress_rdp`], the flash is mass erased at the latest
+/// by the reload. It happens on [`NeedsReload::launch`], or on the next power-on reset.
+#[cfg(any(flash_f0, flash_l4))]
+#[must_use = "the option bytes only take effect after they are reloaded"]
+pub struct NeedsReload<'d> {
+    pub(super) flash: Flash<'d>,
+}
+
+#[cfg(any(flash_f0, flash_l4))]
+impl NeedsReload<'_> {
+    /// Reloads the option bytes, which resets the device.
+    pub fn launch(mut self) -> ! {
+        self.flash.launch_option_bytes()
+    }
+}
+
+/// What [`Flash::blocking_read`] does when it reads from the bank of an operation in progress.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SameBankRead {
+    /// Wait until the operation is done.
+    Wait,
+    /// Return [`Error::Busy`].
+    Fail,
+}
+
+/// How blocking erases handle a sector that fails to erase, see [`Flash::set_erase_options`].
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EraseOptions {
+    /// How often a sector is erased again if the end of operation flag was not set, or the sector didn't read back
+    /// blank. Only the F0 and F3 families can miss the end of operation flag.
+    pub retries: u8,
+}
+
+/// The retries of [`EraseOptions`], shared by the flash and all its regions.
+static ERASE_RETRIES: AtomicU8 = AtomicU8::new(0);
+
 pub struct Flash<'d> {
     inner: PeripheralRef<'d, crate::peripherals::FLASH>,
+    pending: Option<(FlashSector, PendingOperation)>,
+    same_bank_read: SameBankRead,
+    #[cfg(flash_f0)]
+    irq: Option<PeripheralRef<'d, crate::interrupt::FLASH>>,
+    #[cfg(any(hash_v2, hash_v3, hash_v4))]
+    pub(super) hash: Option<super::sha256::HashParts<'d>>,
 }
 
 impl<'d> Flash<'d> {
     pub fn new(p: impl Peripheral<P = crate::peripherals::FLASH> + 'd) -> Self {
         into_ref!(p);
-        Self { inner: p }
+        Self {
+            inner: p,
+            pending: None,
+            same_bank_read: SameBankRead::Wait,
+            #[cfg(flash_f0)]
+            irq: None,
+            #[cfg(any(hash_v2, hash_v3, hash_v4))]
+            hash: None,
+        }
+    }
+
+    /// Creates the driver with the FLASH interrupt, which the async operations wait on.
+    ///
+    /// Without the interrupt, the async operations poll the controller every time the executor polls them.
+    #[cfg(flash_f0)]
+    pub fn new_with_irq(
+        p: impl Peripheral<P = crate::peripherals::FLASH> + 'd,
+        irq: impl Peripheral<P = crate::interrupt::FLASH> + 'd,
+    ) -> Self {
+        into_ref!(irq);
+        irq.set_handler(|_| unsafe { family::on_interrupt() });
+        irq.unpend();
+        irq.enable();
+
+        Self {
+            irq: Some(irq),
+            ..Self::new(p)
+        }
+    }
+
+    /// Selects what reads do while an operation started with [`Flash::try_start_erase`] runs on the same bank.
+    ///
+    /// Reads from the other bank of a dual-bank device always proceed at once. On a single-bank device every
+    /// read is from the same bank, and would otherwise stall the bus until the operation is done.
+    pub fn set_same_bank_read(&mut self, same_bank_read: SameBankRead) {
+        self.same_bank_read = same_bank_read;
+    }
+
+    /// Sets how the blocking erases of the flash and its regions handle a sector that fails to erase.
+    ///
+    /// Without retries, which is the default, the error of the controller is returned. With retries, each erased
+    /// sector is also read back, and only counts as erased if it is blank. A missing end of operation flag on the
+    /// F0 and F3 families is retried as well, all other errors are returned at once. A sector that still fails
+    /// after all retries is reported as [`Error::Erase`]; [`Flash::blocking_erase_range`] reports its offset.
+    pub fn set_erase_options(&mut self, options: EraseOptions) {
+        ERASE_RETRIES.store(options.retries, Ordering::Relaxed);
     }
 
     pub fn into_regions(self) -> FlashLayout<'d> {
         FlashLayout::new(self.release())
     }
 
+    /// Splits the flash into independently owned parts, which can be moved into different tasks.
+    ///
+    /// Part `i` spans from the end of the previous part (or the flash base) to `ends[i]`, given as offsets from
+    /// the flash base. Flash after the last end is not accessible through any part.
+    ///
+    /// # Panics
+    /// Panics if the ends are not increasing, are not aligned to the erase size, or a part spans
+    /// multiple regions of the flash.
+    pub fn into_parts<const N: usize>(self, ends: [u32; N]) -> [FlashPart<'d>; N] {
+        let _flash = self.release();
+        split_parts(FLASH_BASE as u32, family::get_flash_regions(), ends).map(|region| FlashPart {
+            region,
+            _flash: PhantomData,
+        })
+    }
+
+    /// Splits the flash into independently owned parts for a layout defined at runtime, e.g. bootloader, active
+    /// image, DFU image and settings.
+    ///
+    /// Each part covers a range of offsets from the flash base, which must be aligned to the erase size and lie
+    /// in a single region of the flash. The parts may leave gaps, but must not overlap, see [`Error::Overlap`].
+    /// A part takes offsets from its own start, and rejects accesses outside of its range.
+    ///
+    /// If the layout is rejected, the flash is returned together with the error.
+    pub fn into_partitions<const N: usize>(self, ranges: [Range<u32>; N]) -> Result<[FlashPart<'d>; N], (Self, Error)> {
+        let regions = match partition_regions(FLASH_BASE as u32, family::get_flash_regions(), &ranges) {
+            Ok(regions) => regions,
+            Err(e) => return Err((self, e)),
+        };
+        let _flash = self.release();
+        Ok(regions.map(|region| FlashPart {
+            region,
+            _flash: PhantomData,
+        }))
+    }
+
+    /// Reads from the flash at `offset`.
+    ///
+    /// Besides the main flash, this can read the areas of the family that are readable but not writable, like the
+    /// system memory, option bytes, OTP area and calibration values. These are addressed relative to the flash base
+    /// as well, i.e. with `address - FLASH_BASE`.
+    ///
+    /// While an erase started with [`Flash::try_start_erase`] runs, reads from the same bank are handled as set
+    /// with [`Flash::set_same_bank_read`].
     pub fn blocking_read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        let address = (FLASH_BASE as u32).wrapping_add(offset);
+        if let Some((sector, _)) = &self.pending {
+            if reads_bank(family::get_flash_regions(), sector.bank, address, bytes.len()) {
+                match self.same_bank_read {
+                    SameBankRead::Wait => unsafe { family::wait_idle() },
+                    SameBankRead::Fail if family::is_busy() => return Err(Error::Busy),
+                    SameBankRead::Fail => {}
+                }
+            }
+        }
+        if offset as u64 + bytes.len() as u64 > FLASH_SIZE as u64
+            && is_readable(family::READ_ONLY_AREAS, address, bytes.len())
+        {
+            return blocking_read(address, bytes.len() as u32, 0, bytes);
+        }
         blocking_read(FLASH_BASE as u32, FLASH_SIZE as u32, offset, bytes)
     }
 
+    /// Returns `len` bytes at `offset` directly from the memory-mapped flash, without copying.
+    ///
+    /// The flash can't be written or erased while the returned guard is alive.
+    pub fn map(&self, offset: u32, len: usize) -> Result<Mapped<'_>, Error> {
+        let bytes = mapped::map(FLASH_BASE as u32, FLASH_SIZE as u32, offset, len)?;
+        Ok(Mapped::new(FLASH_BASE as u32 + offset, bytes))
+    }
+
     pub fn blocking_write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
-        unsafe { blocking_write(FLASH_BASE as u32, FLASH_SIZE as u32, offset, bytes) }
+        self.abandon_pending();
+        unsafe { blocking_write::<WRITE_SIZE>(FLASH_BASE as u32, FLASH_SIZE as u32, offset, bytes) }
+    }
+
+    /// Writes `bytes` at `offset` like [`Flash::blocking_write`], checking the destination before and the data after
+    /// programming.
+    ///
+    /// Fails with [`Error::NotErased`] without programming anything if any byte of the destination is not erased,
+    /// and with [`Error::Verify`] if the data read back differs from `bytes`, e.g. because of a brown-out or a
+    /// marginal cell that the status register didn't report.
+    pub fn blocking_write_verified(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        check_range(FLASH_SIZE as u32, offset, bytes.len())?;
+        let address = FLASH_BASE as u32 + offset;
+        let erase_value = family::get_flash_regions()
+            .iter()
+            .find(|region| address >= region.base && address < region.end())
+            .map_or(0xFF, |region| region.erase_value);
+
+        if let Some(mismatch) = self.find_mismatch(offset, bytes.len(), |_| erase_value)? {
+            let word = mismatch - mismatch % WRITE_SIZE as u32;
+            return Err(Error::NotErased {
+                address: FLASH_BASE as u32 + word,
+            });
+        }
+
+        self.blocking_write(offset, bytes)?;
+
+        match self.find_mismatch(offset, bytes.len(), |i| bytes[i])? {
+            Some(mismatch) => Err(Error::Verify { offset: mismatch }),
+            None => Ok(()),
+        }
+    }
+
+    /// The offset of the first byte of `offset..offset + len` that differs from `expected` of its index.
+    fn find_mismatch(&mut self, offset: u32, len: usize, expected: impl Fn(usize) -> u8) -> Result<Option<u32>, Error> {
+        let mut buf = [0; 32];
+        let mut pos = 0;
+        while pos < len {
+            let chunk = &mut buf[..core::cmp::min(32, len - pos)];
+            self.blocking_read(offset + pos as u32, chunk)?;
+            if let Some(i) = first_mismatch(chunk, |i| expected(pos + i)) {
+                return Ok(Some(offset + (pos + i) as u32));
+            }
+            pos += chunk.len();
+        }
+        Ok(None)
+    }
+
+    /// Writes `data` at `offset`, which may have any alignment and length.
+    ///
+    /// The write units that `data` covers only partially are read-modify-written: the bytes outside of `data` are
+    /// programmed with their current value. They must allow that, which is always the case if they are erased. On
+    /// families that can't program a write unit twice, e.g. F0, those bytes can't be written again until the sector
+    /// is erased, so consecutive unaligned writes into the same unit fail with [`Error::NotErased`] or
+    /// [`Error::Seq`]. The full units in between are programmed like with [`Flash::blocking_write`].
+    pub fn blocking_write_unaligned(&mut self, offset: u32, data: &[u8]) -> Result<(), Error> {
+        check_range(FLASH_SIZE as u32, offset, data.len())?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let padded = pad_unaligned::<WRITE_SIZE>(offset, data, |unit_offset| {
+            let mut unit = [0; WRITE_SIZE];
+            // The units of the main flash are always readable
+            blocking_read(FLASH_BASE as u32, FLASH_SIZE as u32, unit_offset, &mut unit).unwrap();
+            unit
+        });
+        let head = padded.head.as_ref().map(|unit| &unit[..]);
+        let tail = padded.tail.as_ref().map(|unit| &unit[..]);
+        self.blocking_write_iter(padded.start, head.into_iter().chain([padded.body]).chain(tail))
+    }
+
+    /// Writes the concatenation of `chunks` starting at `offset`.
+    ///
+    /// Only `offset` and the total length must be aligned to the write size, the individual
+    /// chunks may have any length.
+    pub fn blocking_write_iter<'a>(
+        &mut self,
+        offset: u32,
+        chunks: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<(), Error> {
+        self.abandon_pending();
+        unsafe { blocking_write_iter::<WRITE_SIZE>(FLASH_BASE as u32, FLASH_SIZE as u32, offset, chunks) }
+    }
+
+    /// Starts a [`StreamWriter`] session that programs data as it arrives, starting at `offset`.
+    ///
+    /// The controller is unlocked once for the whole session, and stays unlocked until the writer is finished or
+    /// dropped. `offset` must be aligned to the write size, and the written data must fit into the flash region
+    /// that contains it.
+    pub fn writer(&mut self, offset: u32) -> Result<StreamWriter<'_, 'd>, Error> {
+        self.abandon_pending();
+        check_range(FLASH_SIZE as u32, offset, 0)?;
+        if offset % WRITE_SIZE as u32 != 0 {
+            return Err(Error::Unaligned);
+        }
+        let address = FLASH_BASE as u32 + offset;
+        let region = family::get_flash_regions()
+            .iter()
+            .find(|region| address >= region.base && address < region.end())
+            .ok_or(Error::OutOfBounds)?;
+        observer::assert_not_observing();
+        super::check_clocks()?;
+
+        let guard = critical_section::with(|_| unsafe {
+            recover();
+            WriteGuard::begin_write()
+        });
+        Ok(StreamWriter {
+            _flash: self,
+            address,
+            end: region.end(),
+            buf: [0; WRITE_SIZE],
+            buffered: 0,
+            _guard: guard,
+        })
     }
 
+    /// Erases `from..to`. Fails with [`Error::WouldEraseSelf`] if the range contains the running program.
     pub fn blocking_erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
-        unsafe { blocking_erase(FLASH_BASE as u32, from, to) }
+        self.abandon_pending();
+        unsafe { blocking_erase(FLASH_BASE as u32, FLASH_SIZE as u32, from, to, false) }
     }
 
-    pub(crate) fn release(self) -> PeripheralRef<'d, crate::peripherals::FLASH> {
-        unsafe { self.inner.clone_unchecked() }
+    /// Erases `from..to` like [`Flash::blocking_erase`], but reports where the erase stopped on failure.
+    ///
+    /// The sectors are erased in ascending order, so all sectors before [`EraseError::offset`] are erased and the
+    /// rest of the range is left untouched.
+    pub fn blocking_erase_range(&mut self, from: u32, to: u32) -> Result<(), EraseError> {
+        self.abandon_pending();
+        unsafe { blocking_erase_range(FLASH_BASE as u32, FLASH_SIZE as u32, from, to, false) }
     }
-}
 
-fn blocking_read(base: u32, size: u32, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
-    if offset + bytes.len() as u32 > size {
-        return Err(Error::Size);
+    /// Erases `from..to`, also if the range contains the running program.
+    pub fn blocking_erase_self(&mut self, from: u32, to: u32, _allow: AllowSelfErase) -> Result<(), Error> {
+        self.abandon_pending();
+        unsafe { blocking_erase(FLASH_BASE as u32, FLASH_SIZE as u32, from, to, true) }
     }
 
-    let start_address = base + offset;
-    let flash_data = unsafe { core::slice::from_raw_parts(start_address as *const u8, bytes.len()) };
-    bytes.copy_from_slice(flash_data);
-    Ok(())
-}
+    /// Erases the complete main flash with a single mass erase, which is much faster than erasing it page by page.
+    ///
+    /// The running program must not be in the main flash, e.g. because it was linked to execute from RAM,
+    /// otherwise [`Error::WouldEraseSelf`] is returned. If any page is write protected, the controller refuses the
+    /// erase and [`Error::Protected`] is returned; nothing is erased then.
+    #[cfg(flash_f0)]
+    pub fn blocking_erase_all(&mut self, _confirm: ConfirmMassErase) -> Result<(), Error> {
+        self.abandon_pending();
+        unsafe { blocking_erase_all(false) }
+    }
 
-unsafe fn blocking_write(base: u32, size: u32, offset: u32, bytes: &[u8]) -> Result<(), Error> {
-    if offset + bytes.len() as u32 > size {
-        return Err(Error::Size);
+    /// Erases the complete main flash like [`Flash::blocking_erase_all`], also if it contains the running program.
+    ///
+    /// Only the location of the program is not checked, e.g. for a program running from RAM whose `.data` initial
+    /// values or vector table are still in flash.
+    #[cfg(flash_f0)]
+    pub fn blocking_erase_all_self(&mut self, _confirm: ConfirmMassErase, _allow: AllowSelfErase) -> Result<(), Error> {
+        self.abandon_pending();
+        unsafe { blocking_erase_all(true) }
     }
-    if offset % WRITE_SIZE as u32 != 0 || bytes.len() % WRITE_SIZE != 0 {
-        return Err(Error::Unaligned);
+
+    /// Programs exactly one write unit at `offset`, without the chunking of [`Flash::blocking_write`].
+    ///
+    /// `offset` must be aligned to the write size and lie in the main flash. The unit is only programmed if
+    /// the family allows programming it over the current contents, otherwise [`Error::NotErased`] is returned.
+    pub fn program_unit(&mut self, offset: u32, bytes: &[u8; WRITE_SIZE]) -> Result<(), Error> {
+        let regions = family::get_flash_regions();
+        let address = check_unit(FLASH_BASE as u32, FLASH_SIZE as u32, offset, regions)?;
+        check_erased(address, bytes, regions)?;
+
+        self.abandon_pending();
+        unsafe { program_unit(address, bytes) }
     }
 
-    let mut address = base + offset;
-    trace!("Writing {} bytes at 0x{:x}", bytes.len(), address);
+    /// Whether the flash controller is currently executing an operation.
+    pub fn is_busy(&self) -> bool {
+        family::is_busy()
+    }
+
+    /// Starts erasing the sector at `offset` without waiting for it to complete.
+    ///
+    /// Poll [`Flash::try_finish`] until it reports the result. Returns [`Error::Busy`] if the controller is
+    /// executing an operation, or a started erase was not finished yet.
+    ///
+    /// Starting a blocking operation before the erase is finished waits for it and discards its result.
+    ///
+    /// Nothing is started if `offset` is outside the flash ([`Error::OutOfBounds`]) or not the start of a sector
+    /// ([`Error::Unaligned`]), the sector contains the running program ([`Error::WouldEraseSelf`]), or a clock
+    /// the controller needs is not running ([`Error::ClockNotReady`]).
+    pub fn try_start_erase(&mut self, offset: u32) -> Result<(), Error> {
+        observer::assert_not_observing();
+        if self.pending.is_some() || family::is_busy() {
+            return Err(Error::Busy);
+        }
+
+        check_range(FLASH_SIZE as u32, offset, 1)?;
+        let regions = family::get_flash_regions();
+        let sector = find_sector(FLASH_BASE as u32 + offset, regions).ok_or(Error::OutOfBounds)?;
+        if FLASH_BASE as u32 + offset != sector.start {
+            return Err(Error::Unaligned);
+        }
+        validate_sector(&sector, regions)?;
+        check_self_erase(sector.start, sector.start + sector.size, false)?;
+        mapped::assert_not_mapped(sector.start, sector.size as usize);
+        super::check_clocks()?;
 
-    for chunk in bytes.chunks(WRITE_SIZE) {
-        critical_section::with(|_| {
+        let operation = PendingOperation::start();
+        critical_section::with(|_| unsafe {
+            recover();
             family::clear_all_err();
             fence(Ordering::SeqCst);
             family::unlock();
             fence(Ordering::SeqCst);
-            family::begin_write();
-            fence(Ordering::SeqCst);
+            observer::notify(|o| o.on_erase_start(&sector));
+            family::start_erase_sector(&sector);
+        });
+        self.pending = Some((sector, operation));
+        Ok(())
+    }
 
+    /// Completes an operation started with [`Flash::try_start_erase`].
+    ///
+    /// Returns `None` while the operation is in progress. Once it is done, the controller is cleaned up
+    /// and locked again, and the result of the operation is returned. If no operation was started,
+    /// `Some(Ok(()))` is returned.
+    pub fn try_finish(&mut self) -> Option<Result<(), Error>> {
+        if family::is_busy() {
+            return None;
+        }
+        let Some((sector, operation)) = self.pending.take() else {
+            return Some(Ok(()));
+        };
+
+        let result = critical_section::with(|_| unsafe {
             let _on_drop = OnDrop::new(|| {
-                family::end_write();
-                fence(Ordering::SeqCst);
                 family::lock();
             });
 
-            family::blocking_write(address, chunk.try_into().unwrap())
-        })?;
-        address += WRITE_SIZE as u32;
+            // Only the time spent finishing is known for a polled erase
+            let result = measure(0, 1, || family::finish_erase_sector(&sector));
+            observer::notify(|o| o.on_erase_end(&sector, result));
+            result.and_then(|()| record_wear(&mut Hardware, &sector, family::get_flash_regions()))
+        });
+        operation.complete();
+        Some(result)
     }
-    Ok(())
-}
 
-unsafe fn blocking_erase(base: u32, from: u32, to: u32) -> Result<(), Error> {
-    let start_address = base + from;
-    let end_address = base + to;
-    let regions = family::get_flash_regions();
+    /// Writes `bytes` at `offset`, waiting for each halfword to be programmed without blocking the executor.
+    ///
+    /// The controller still stalls the bus while code or data is fetched from the flash being programmed, so this
+    /// mainly helps while running from RAM. Dropping the future clears PG and locks the controller; the halfword
+    /// being programmed may or may not be complete.
+    #[cfg(flash_f0)]
+    pub async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        self.abandon_pending();
+        check_range(FLASH_SIZE as u32, offset, bytes.len())?;
+        if offset % WRITE_SIZE as u32 != 0 || bytes.len() % WRITE_SIZE != 0 {
+            return Err(Error::Unaligned);
+        }
 
-    // Test if the address range is aligned at sector base addresses
-    let mut address = start_address;
-    while address < end_address {
-        let sector = get_sector(address, regions);
+        let start_address = FLASH_BASE as u32 + offset;
+        let regions = family::get_flash_regions();
+        check_spanned_regions(start_address, start_address + bytes.len() as u32, regions)?;
+        observer::assert_not_observing();
+        mapped::assert_not_mapped(start_address, bytes.len());
+        super::check_clocks()?;
+        check_write_protection(start_address, start_address + bytes.len() as u32)?;
+        #[cfg(feature = "flash-erase-check")]
+        check_erased(start_address, bytes, regions)?;
+        trace!("Writing {} bytes at 0x{:x}", bytes.len(), start_address);
+
+        let operation = PendingOperation::start();
+        let guard = critical_section::with(|_| unsafe {
+            recover();
+            WriteGuard::begin_write()
+        });
+        let on_drop = OnDrop::new(|| unsafe {
+            family::end_async();
+            fence(Ordering::SeqCst);
+        });
+
+        let mut result = Ok(());
+        let mut address = start_address;
+        for word in bytes.chunks_exact(WRITE_SIZE) {
+            unsafe {
+                family::clear_all_err();
+                family::start_write(address, word.try_into().unwrap());
+            }
+            result = family::wait_ready(self.irq.is_some()).await;
+            if result.is_err() {
+                break;
+            }
+            address += WRITE_SIZE as u32;
+        }
+        observer::notify(|o| o.on_write(start_address, bytes.len(), result));
+
+        drop(on_drop);
+        drop(guard);
+        operation.complete();
+        result
+    }
+
+    /// Erases the sector at `offset`, waiting for the erase to complete without blocking the executor.
+    ///
+    /// As with [`Flash::write`], code running from the same flash still stalls. Dropping the future clears PER and
+    /// locks the controller, the erase itself can't be aborted and completes in the background.
+    #[cfg(flash_f0)]
+    pub async fn erase_sector(&mut self, offset: u32) -> Result<(), Error> {
+        self.abandon_pending();
+        let regions = family::get_flash_regions();
+        let address = FLASH_BASE as u32 + offset;
+        let sector = find_sector(address, regions).ok_or(Error::OutOfBounds)?;
         if sector.start != address {
             return Err(Error::Unaligned);
         }
-        address += sector.size;
-    }
-    if address != end_address {
-        return Err(Error::Unaligned);
+        validate_sector(&sector, regions)?;
+        check_self_erase(sector.start, sector.start + sector.size, false)?;
+        observer::assert_not_observing();
+        mapped::assert_not_mapped(sector.start, sector.size as usize);
+        super::check_clocks()?;
+        check_write_protection(sector.start, sector.start + sector.size)?;
+        trace!("Erasing sector: {:?}", sector);
+
+        let operation = PendingOperation::start();
+        let guard = critical_section::with(|_| unsafe {
+            recover();
+            let guard = WriteGuard::unlock();
+            observer::notify(|o| o.on_erase_start(&sector));
+            family::start_erase_sector(&sector);
+            guard
+        });
+        let on_drop = OnDrop::new(|| unsafe {
+            family::end_async();
+            fence(Ordering::SeqCst);
+        });
+
+        // Only the time spent finishing is known, as for a polled erase
+        let result = match family::wait_ready(self.irq.is_some()).await {
+            Ok(()) => measure(0, 1, || unsafe { family::finish_erase_sector(&sector) }),
+            Err(e) => Err(e),
+        };
+        observer::notify(|o| o.on_erase_end(&sector, result));
+        let result = result.and_then(|()| unsafe { record_wear(&mut Hardware, &sector, regions) });
+
+        drop(on_drop);
+        drop(guard);
+        operation.complete();
+        result
     }
 
-    trace!("Erasing from 0x{:x} to 0x{:x}", start_address, end_address);
+    /// Erases `from..to` sector by sector, waiting for each erase without blocking the executor.
+    ///
+    /// `from` and `to` must be sector boundaries, otherwise [`Error::Unaligned`] is returned before anything is
+    /// erased. The task yields between sectors, so other tasks keep running during a long erase. `progress` is
+    /// called with the number of erased sectors and the total number of sectors after each sector.
+    ///
+    /// Dropping the future cancels the erase after the current sector, which completes in the background as for
+    /// [`Flash::erase_sector`]. The sectors erased before stay erased, and the controller is locked.
+    #[cfg(flash_f0)]
+    pub async fn erase_range(&mut self, from: u32, to: u32, mut progress: impl FnMut(u32, u32)) -> Result<(), Error> {
+        let regions = family::get_flash_regions();
+        check_erase_range(FLASH_BASE as u32, FLASH_SIZE as u32, from, to, regions)?;
+        check_self_erase(FLASH_BASE as u32 + from, FLASH_BASE as u32 + to, false)?;
 
-    let mut address = start_address;
-    while address < end_address {
-        let sector = get_sector(address, regions);
-        trace!("Erasing sector: {:?}", sector);
+        let sector_size = |offset: u32| get_sector(FLASH_BASE as u32 + offset, regions).size;
+        let mut total = 0;
+        let mut offset = from;
+        while offset < to {
+            total += 1;
+            offset += sector_size(offset);
+        }
 
-        critical_section::with(|_| {
-            family::clear_all_err();
-            fence(Ordering::SeqCst);
-            family::unlock();
-            fence(Ordering::SeqCst);
+        let mut done = 0;
+        let mut offset = from;
+        while offset < to {
+            if done > 0 {
+                embassy_futures::yield_now().await;
+            }
+            self.erase_sector(offset).await?;
+            done += 1;
+            progress(done, total);
+            offset += sector_size(offset);
+        }
+        Ok(())
+    }
 
-            let _on_drop = OnDrop::new(|| {
-                family::lock();
-            });
+    /// Copies the contents of `src` to `dst`, erasing the destination one sector at a time.
+    ///
+    /// Both ranges are offsets from the flash base and must have the same length, which must be a
+    /// multiple of the write size. `dst` must start on a sector boundary. The last destination sector
+    /// is erased completely, even if `dst` ends before the end of that sector.
+    ///
+    /// The ranges may only overlap if `dst` lies below `src` by at least one erase size, so that erasing
+    /// a destination sector never destroys source data that is yet to be copied.
+    ///
+    /// Every copied chunk is read back and compared to the source. After each completed destination sector,
+    /// `progress` is called with the amount of bytes copied so far. A copy that was interrupted (e.g. by a reset)
+    /// can be continued by passing the last reported value as `resume_from`, so completed sectors are not copied again.
+    pub fn copy_region(
+        &mut self,
+        src: Range<u32>,
+        dst: Range<u32>,
+        resume_from: usize,
+        progress: impl FnMut(usize),
+    ) -> Result<(), Error> {
+        if src.start > src.end || dst.start > dst.end || src.len() != dst.len() {
+            return Err(Error::Size);
+        }
+        if resume_from > dst.len() {
+            return Err(Error::Size);
+        }
+        if src.end > FLASH_SIZE as u32 || dst.end > FLASH_SIZE as u32 {
+            return Err(Error::OutOfBounds);
+        }
+        if dst.len() % WRITE_SIZE != 0 {
+            return Err(Error::Unaligned);
+        }
+        let overlapping = src.start < dst.end && dst.start < src.end;
+        if overlapping && (dst.start > src.start || src.start - dst.start < MAX_ERASE_SIZE as u32) {
+            return Err(Error::Size);
+        }
 
-            family::blocking_erase_sector(&sector)
-        })?;
-        address += sector.size;
+        self.abandon_pending();
+        copy_sectors(
+            self,
+            FLASH_BASE as u32,
+            family::get_flash_regions(),
+            src,
+            dst,
+            resume_from,
+            progress,
+        )
     }
-    Ok(())
-}
 
-pub(crate) fn get_sector(address: u32, regions: &[&FlashRegion]) -> FlashSector {
-    let mut current_bank = FlashBank::Bank1;
-    let mut bank_offset = 0;
-    for region in regions {
-        if region.bank != current_bank {
-            current_bank = region.bank;
-            bank_offset = 0;
+    /// Makes sure the flash at `offset` contains `data`, only erasing and programming where it differs.
+    ///
+    /// Sectors that already hold the right bytes are left untouched. A differing sector whose target
+    /// bytes are erased is programmed without erasing. Otherwise the sector is erased first, which is
+    /// only allowed if `data` covers the complete sector; if it doesn't, [`Error::Unaligned`] is returned
+    /// rather than destroying the data around the target range.
+    pub fn ensure_contains(&mut self, offset: u32, data: &[u8]) -> Result<Provisioned, Error> {
+        let end = offset + data.len() as u32;
+        if end > FLASH_SIZE as u32 {
+            return Err(Error::OutOfBounds);
+        }
+        if offset % WRITE_SIZE as u32 != 0 || data.len() % WRITE_SIZE != 0 {
+            return Err(Error::Unaligned);
         }
 
-        if address < region.end() {
-            let index_in_region = (address - region.base) / region.erase_size;
-            return FlashSector {
-                bank: region.bank,
-                index_in_bank: bank_offset + index_in_region as u8,
-                start: region.base + index_in_region * region.erase_size,
-                size: region.erase_size,
-            };
+        self.abandon_pending();
+        provision(self, FLASH_BASE as u32, family::get_flash_regions(), offset, data)
+    }
+
+    /// Replaces the contents of the flash at `offset` with `data`, erasing the sectors the range touches.
+    ///
+    /// Sectors that are completely covered by `data` are erased and programmed. The bytes of the first and last
+    /// sector outside of the range are handled as given by `preserve`. With [`Preserve::Nothing`], `offset` and
+    /// the length of `data` must be aligned to the write size, with the other modes any range can be written.
+    ///
+    /// If `verify` is set, every sector is read back after programming and compared to its intended contents,
+    /// returning [`Error::Prog`] on a mismatch.
+    ///
+    /// The range, the alignment and the `preserve` buffer or scratch sector are checked before anything is
+    /// erased. An error while erasing or programming leaves the current sector in an undefined state.
+    ///
+    /// # Power loss
+    /// The flash is changed one sector at a time. If power is lost, the sectors before the current one contain
+    /// the new data, the sectors after it the old data. The current sector may be partially erased or programmed.
+    /// Only [`Preserve::Scratch`] keeps a copy of its contents in flash, see there.
+    pub fn overwrite(
+        &mut self,
+        offset: u32,
+        data: &[u8],
+        mut preserve: Preserve<'_>,
+        verify: bool,
+    ) -> Result<(), Error> {
+        const CHUNK_SIZE: usize = 64;
+
+        let regions = family::get_flash_regions();
+        let range = check_overwrite(
+            FLASH_BASE as u32,
+            FLASH_SIZE as u32,
+            offset,
+            data.len(),
+            &preserve,
+            regions,
+        )?;
+        check_self_erase(range.start, range.end, false)?;
+
+        self.abandon_pending();
+        let start = FLASH_BASE as u32 + offset;
+        let end = start + data.len() as u32;
+        let mut address = range.start;
+        while address < range.end {
+            let sector = get_sector(address, regions);
+            let sector_end = sector.start + sector.size;
+            let chunk = core::cmp::max(start, sector.start)..core::cmp::min(end, sector_end);
+            let target = &data[(chunk.start - start) as usize..(chunk.end - start) as usize];
+            let partial = chunk.start != sector.start || chunk.end != sector_end;
+
+            match &mut preserve {
+                Preserve::Ram(buf) if partial => {
+                    let image = &mut buf[..sector.size as usize];
+                    self.blocking_read(sector.start - FLASH_BASE as u32, image)?;
+                    image[(chunk.start - sector.start) as usize..(chunk.end - sector.start) as usize]
+                        .copy_from_slice(target);
+
+                    trace!("Overwriting sector from RAM: {:?}", sector);
+                    unsafe { erase_sector(&sector)? };
+                    self.blocking_write(sector.start - FLASH_BASE as u32, image)?;
+                    if verify && !contains(sector.start, image) {
+                        return Err(Error::Prog);
+                    }
+                }
+                Preserve::Scratch(scratch) if partial => {
+                    let scratch_sector = get_sector(FLASH_BASE as u32 + *scratch, regions);
+                    unsafe { erase_sector(&scratch_sector)? };
+
+                    // Stage the merged contents of the sector
+                    let mut buf = [0; CHUNK_SIZE];
+                    for pos in (sector.start..sector_end).step_by(CHUNK_SIZE) {
+                        let buf = &mut buf[..core::cmp::min(CHUNK_SIZE as u32, sector_end - pos) as usize];
+                        self.blocking_read(pos - FLASH_BASE as u32, buf)?;
+                        let overlap =
+                            core::cmp::max(pos, chunk.start)..core::cmp::min(pos + buf.len() as u32, chunk.end);
+                        if overlap.start < overlap.end {
+                            buf[(overlap.start - pos) as usize..(overlap.end - pos) as usize].copy_from_slice(
+                                &data[(overlap.start - start) as usize..(overlap.end - start) as usize],
+                            );
+                        }
+                        self.blocking_write(*scratch + (pos - sector.start), buf)?;
+                    }
+
+                    trace!("Overwriting sector from scratch: {:?}", sector);
+                    unsafe { erase_sector(&sector)? };
+                    for pos in (0..sector.size).step_by(CHUNK_SIZE) {
+                        let buf = &mut buf[..core::cmp::min(CHUNK_SIZE as u32, sector.size - pos) as usize];
+                        self.blocking_read(*scratch + pos, buf)?;
+                        self.blocking_write(sector.start - FLASH_BASE as u32 + pos, buf)?;
+                    }
+                    let staged =
+                        unsafe { core::slice::from_raw_parts(scratch_sector.start as *const u8, sector.size as usize) };
+                    if verify && !contains(sector.start, staged) {
+                        return Err(Error::Prog);
+                    }
+                }
+                _ => {
+                    trace!("Overwriting sector: {:?}", sector);
+                    unsafe { erase_sector(&sector)? };
+                    self.blocking_write(chunk.start - FLASH_BASE as u32, target)?;
+                    if verify && !contains(chunk.start, target) {
+                        return Err(Error::Prog);
+                    }
+                }
+            }
+
+            address = sector_end;
         }
 
-        bank_offset += region.sectors();
+        Ok(())
     }
 
-    panic!("Flash sector not found");
-}
+    /// Runs a destructive test on the sector starting at `sector_offset` and measures its timing.
+    ///
+    /// The sector is erased and checked for blankness, programmed with a test pattern which is read back,
+    /// overwritten with zeros (where supported) and finally erased again.
+    /// A mismatch in any of the checks returns [`Error::Prog`].
+    ///
+    /// The sector must lie completely within `scratch`, otherwise [`Error::OutOfBounds`] is returned without
+    /// touching the flash. Pass the range reserved for testing, so that a wrong sector offset can't
+    /// destroy the application.
+    #[cfg(feature = "time")]
+    pub fn self_test(&mut self, scratch: Range<u32>, sector_offset: u32) -> Result<SelfTestReport, Error> {
+        const CHUNK_SIZE: usize = 64;
 
-impl FlashRegion {
-    pub fn blocking_read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
-        blocking_read(self.base, self.size, offset, bytes)
+        if scratch.start > scratch.end || scratch.end > FLASH_SIZE as u32 || sector_offset < scratch.start {
+            return Err(Error::OutOfBounds);
+        }
+        let regions = family::get_flash_regions();
+        let sector = get_sector(FLASH_BASE as u32 + sector_offset, regions);
+        if sector.start != FLASH_BASE as u32 + sector_offset {
+            return Err(Error::Unaligned);
+        }
+        if sector_offset + sector.size > scratch.end {
+            return Err(Error::OutOfBounds);
+        }
+
+        self.abandon_pending();
+        let pattern: [u8; CHUNK_SIZE] = core::array::from_fn(|i| if i % 2 == 0 { 0x55 } else { 0xAA });
+        let mut readback = [0; CHUNK_SIZE];
+
+        let start = Instant::now();
+        unsafe { erase_sector(&sector)? };
+        let erase = start.elapsed();
+
+        let start = Instant::now();
+        if !is_erased(sector.start, sector.size as usize, regions) {
+            return Err(Error::Prog);
+        }
+        let blank_check = start.elapsed();
+
+        let start = Instant::now();
+        for offset in (0..sector.size).step_by(CHUNK_SIZE) {
+            self.blocking_write(sector_offset + offset, &pattern)?;
+        }
+        let program = start.elapsed();
+
+        let start = Instant::now();
+        for offset in (0..sector.size).step_by(CHUNK_SIZE) {
+            self.blocking_read(sector_offset + offset, &mut readback)?;
+            if readback != pattern {
+                return Err(Error::Prog);
+            }
+        }
+        let verify = start.elapsed();
+
+        // Programming zeros over already programmed words is only allowed on families without flash ECC
+        #[cfg(any(flash_f0, flash_f3, flash_f4, flash_f7))]
+        let overwrite = {
+            let start = Instant::now();
+            let zeros = [0; CHUNK_SIZE];
+            for offset in (0..sector.size).step_by(CHUNK_SIZE) {
+                self.blocking_write(sector_offset + offset, &zeros)?;
+                if !contains(sector.start + offset, &zeros) {
+                    return Err(Error::Prog);
+                }
+            }
+            Some(start.elapsed())
+        };
+        #[cfg(not(any(flash_f0, flash_f3, flash_f4, flash_f7)))]
+        let overwrite = None;
+
+        let start = Instant::now();
+        unsafe { erase_sector(&sector)? };
+        let final_erase = start.elapsed();
+        if !is_erased(sector.start, sector.size as usize, regions) {
+            return Err(Error::Prog);
+        }
+
+        Ok(SelfTestReport {
+            erase,
+            blank_check,
+            program,
+            verify,
+            overwrite,
+            final_erase,
+        })
     }
 
-    pub fn blocking_write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
-        unsafe { blocking_write(self.base, self.size, offset, bytes) }
+    /// Looks up why a write or erase at `offset` was rejected with [`Error::Protected`].
+    ///
+    /// On the F0, F3, F4 and F7, the option byte registers are inspected at the time of the call, so this reflects
+    /// the currently loaded protection settings. On the other families, the error flags of the rejected operation
+    /// are decoded, so this must be called before the next operation. Returns `None` if the sector is not protected.
+    pub fn protection_cause(&self, offset: u32) -> Option<ProtectionCause> {
+        family::protection_cause(FLASH_BASE as u32 + offset)
     }
 
-    pub fn blocking_erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
-        unsafe { blocking_erase(self.base, from, to) }
+    /// Counters of the program and erase operations of all flash users since boot or [`Flash::reset_stats`].
+    ///
+    /// With [`Flash::track_wear`], this also reports the most worn sector.
+    #[cfg(feature = "flash-stats")]
+    pub fn stats(&self) -> super::FlashStats {
+        let mut stats = super::stats::stats();
+        stats.most_worn =
+            with_wear_log(&mut Hardware, family::get_flash_regions(), |log| log.most_worn()).and_then(Result::ok);
+        stats
     }
-}
 
-impl embedded_storage::nor_flash::ErrorType for Flash<'_> {
-    type Error = Error;
-}
+    /// Reset all counters returned by [`Flash::stats`], except the erase counts of [`Flash::track_wear`].
+    #[cfg(feature = "flash-stats")]
+    pub fn reset_stats(&mut self) {
+        super::stats::reset_stats()
+    }
 
-impl embedded_storage::nor_flash::ReadNorFlash for Flash<'_> {
-    const READ_SIZE: usize = 1;
+    /// Counts every following erase of each sector in the accounting area `from..to`, in the format of
+    /// [`WearTracking`](super::WearTracking).
+    ///
+    /// Erases are counted whichever method started them, e.g. [`Flash::blocking_erase`], [`Flash::overwrite`],
+    /// [`Flash::copy_region`] or [`Flash::try_start_erase`], including the retries of [`Flash::set_erase_options`].
+    /// Not counted are the erases of the area itself and a mass erase, which also erases the counters. The counts
+    /// persist, so call this with the same area after each reset, before anything is erased. Other methods must not
+    /// write or erase the area.
+    ///
+    /// The area must consist of at least two sectors of [`MAX_ERASE_SIZE`] bytes, each of which must hold the
+    /// counters of all sectors of the flash. Otherwise [`Error::Size`] is returned, or [`Error::OutOfBounds`] and
+    /// [`Error::Unaligned`] if the area is outside the flash or doesn't consist of whole sectors.
+    #[cfg(feature = "flash-stats")]
+    pub fn track_wear(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        let regions = family::get_flash_regions();
+        check_erase_range(FLASH_BASE as u32, FLASH_SIZE as u32, from, to, regions)?;
+        check_wear_area(to - from, regions)?;
+        super::stats::set_wear_area(FLASH_BASE as u32 + from..FLASH_BASE as u32 + to);
+        Ok(())
+    }
 
-    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
-        self.blocking_read(offset, bytes)
+    /// The number of times the `sector`th sector was erased since [`Flash::track_wear`] was first called for its
+    /// area, counting the sectors of all regions from the flash base. Returns 0 if wear is not tracked.
+    ///
+    /// # Panics
+    /// Panics if the flash has no `sector`th sector.
+    #[cfg(feature = "flash-stats")]
+    pub fn wear(&self, sector: usize) -> Result<u32, Error> {
+        with_wear_log(&mut Hardware, family::get_flash_regions(), |log| log.wear(sector)).unwrap_or(Ok(0))
     }
 
-    fn capacity(&self) -> usize {
-        FLASH_SIZE
+    /// Reports all following program and erase operations of the flash and its regions to `observer`.
+    pub fn set_observer(&mut self, observer: &'static dyn FlashObserver) {
+        observer::set_observer(Some(observer));
     }
-}
 
-impl embedded_storage::nor_flash::NorFlash for Flash<'_> {
-    const WRITE_SIZE: usize = WRITE_SIZE;
-    const ERASE_SIZE: usize = MAX_ERASE_SIZE;
+    /// Stops reporting operations to the observer set with [`Flash::set_observer`].
+    pub fn clear_observer(&mut self) {
+        observer::set_observer(None);
+    }
 
-    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
-        self.blocking_write(offset, bytes)
+    /// Drop a pending non-blocking operation, so the next operation waits for it.
+    fn abandon_pending(&mut self) {
+        self.pending = None;
     }
 
-    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
-        self.blocking_erase(from, to)
+    /// Whether an erase started with [`Flash::try_start_erase`] was not finished yet.
+    pub(crate) fn has_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub(crate) fn release(self) -> PeripheralRef<'d, crate::peripherals::FLASH> {
+        unsafe { self.inner.clone_unchecked() }
     }
 }
 
-foreach_flash_region! {
-    ($type_name:ident, $write_size:literal, $erase_size:literal) => {
-        impl crate::_generated::flash_regions::$type_name<'_> {
-            pub fn blocking_read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
-                blocking_read(self.0.base, self.0.size, offset, bytes)
-            }
+/// Programs a stream of chunks of any length into one flash region, see [`Flash::writer`].
+///
+/// Bytes are collected until a complete write unit is available, which is then programmed. The controller stays
+/// unlocked with programming enabled for the whole session, but interrupts are only disabled while a single unit
+/// is programmed. [`StreamWriter::finish`] programs the final partial unit, padded with `0xFF`. Dropping the writer
+/// without finishing discards the buffered bytes, and locks the controller as well.
+///
+/// The flash only counts as busy for [`Flash::wait_idle`] while a unit is programmed,
+/// not while the writer waits for more data.
+///
+/// If programming a unit fails, the error is returned and the buffered bytes are discarded. Everything before the
+/// failed unit is programmed, and [`StreamWriter::offset`] returns the offset of the failed unit, so writing can be
+/// resumed with the data from there on.
+///
+/// The target area must have been erased beforehand.
+pub struct StreamWriter<'a, 'd> {
+    _flash: &'a mut Flash<'d>,
+    address: u32,
+    end: u32,
+    buf: [u8; WRITE_SIZE],
+    buffered: usize,
+    _guard: WriteGuard,
+}
 
-            pub fn blocking_write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
-                unsafe { blocking_write(self.0.base, self.0.size, offset, bytes) }
-            }
+impl<'a, 'd> StreamWriter<'a, 'd> {
+    /// The offset from the flash base at which the next byte is written.
+    pub fn offset(&self) -> u32 {
+        self.address + self.buffered as u32 - FLASH_BASE as u32
+    }
 
-            pub fn blocking_erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
-                unsafe { blocking_erase(self.0.base, from, to) }
+    /// Writes `data` after the previously written bytes.
+    ///
+    /// Fails with [`Error::OutOfBounds`] if `data` doesn't fit into the region, in which case none of it is written.
+    pub fn write(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        if data.len() as u32 > self.end - self.address - self.buffered as u32 {
+            return Err(Error::OutOfBounds);
+        }
+
+        if self.buffered > 0 {
+            data = &data[self.stage(data)..];
+            if self.buffered < WRITE_SIZE {
+                return Ok(());
             }
+            let unit = self.buf;
+            self.buffered = 0;
+            self.program(&unit)?;
         }
 
-        impl embedded_storage::nor_flash::ErrorType for crate::_generated::flash_regions::$type_name<'_> {
-            type Error = Error;
+        // Program all complete units directly from the caller's buffer
+        let aligned = data.len() - data.len() % WRITE_SIZE;
+        self.program(&data[..aligned])?;
+        self.stage(&data[aligned..]);
+        Ok(())
+    }
+
+    /// Programs the buffered partial unit, padded with `0xFF`. Writing continues at the next unit.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.buffered > 0 {
+            self.buf[self.buffered..].fill(0xFF);
+            let unit = self.buf;
+            self.buffered = 0;
+            self.program(&unit)?;
         }
+        Ok(())
+    }
 
-        impl embedded_storage::nor_flash::ReadNorFlash for crate::_generated::flash_regions::$type_name<'_> {
-            const READ_SIZE: usize = 1;
+    /// Flushes the final partial unit and locks the controller again.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.flush()
+    }
 
-            fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
-                self.blocking_read(offset, bytes)
-            }
+    /// Copy as much of `data` into the staging buffer as fits, returning the amount of bytes taken.
+    fn stage(&mut self, data: &[u8]) -> usize {
+        let n = core::cmp::min(WRITE_SIZE - self.buffered, data.len());
+        self.buf[self.buffered..self.buffered + n].copy_from_slice(&data[..n]);
+        self.buffered += n;
+        n
+    }
 
-            fn capacity(&self) -> usize {
-                self.0.size as usize
-            }
+    /// Programs complete units at the current address, and advances it past them.
+    fn program(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if bytes.is_empty() {
+            return Ok(());
         }
+        let start_address = self.address;
+        mapped::assert_not_mapped(start_address, bytes.len());
+        check_write_protection(start_address, start_address + bytes.len() as u32)?;
+        #[cfg(feature = "flash-erase-check")]
+        check_erased(start_address, bytes, family::get_flash_regions())?;
 
-        impl embedded_storage::nor_flash::NorFlash for crate::_generated::flash_regions::$type_name<'_> {
-            const WRITE_SIZE: usize = $write_size;
-            const ERASE_SIZE: usize = $erase_size;
-
-            fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
-                self.blocking_write(offset, bytes)
+        let mut result = Ok(());
+        for word in bytes.chunks_exact(WRITE_SIZE) {
+            super::idle::begin();
+            let _idle = OnDrop::new(super::idle::end);
+            result = critical_section::with(|_| {
+                measure(WRITE_SIZE as u32, 0, || unsafe {
+                    family::blocking_write::<WRITE_SIZE>(self.address, word.try_into().unwrap())
+                })
+            });
+            if result.is_err() {
+                break;
+            }
+            self.address += WRITE_SIZE as u32;
+        }
+        observer::notify(|o| o.on_write(start_address, bytes.len(), result));
+        result
+    }
+}
+
+/// An independently owned part of the flash, see [`Flash::into_parts`].
+///
+/// A part only allows access to its own range. Program and erase operations of all parts are serialized on
+/// the shared flash controller, so parts can be used from different tasks and interrupt priorities at once.
+pub struct FlashPart<'d> {
+    region: FlashRegion,
+    _flash: PhantomData<&'d mut crate::peripherals::FLASH>,
+}
+
+impl FlashPart<'_> {
+    /// The range of the flash covered by this part.
+    pub fn region(&self) -> &FlashRegion {
+        &self.region
+    }
+
+    pub fn blocking_read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        self.region.blocking_read(offset, bytes)
+    }
+
+    /// Returns `len` bytes at `offset` directly from the memory-mapped flash, see [`Flash::map`].
+    pub fn map(&self, offset: u32, len: usize) -> Result<Mapped<'_>, Error> {
+        let bytes = mapped::map(self.region.base, self.region.size, offset, len)?;
+        Ok(Mapped::new(self.region.base + offset, bytes))
+    }
+
+    pub fn blocking_write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        self.region.blocking_write(offset, bytes)
+    }
+
+    pub fn blocking_erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        self.region.blocking_erase(from, to)
+    }
+
+    /// Erases `from..to`, also if the range contains the running program.
+    pub fn blocking_erase_self(&mut self, from: u32, to: u32, allow: AllowSelfErase) -> Result<(), Error> {
+        self.region.blocking_erase_self(from, to, allow)
+    }
+}
+
+/// An independently owned part of a single region, see the `split_at` method of the region handles.
+///
+/// Unlike [`FlashPart`], the part keeps the write and erase size of its region in its type, so the storage traits
+/// report the erase size of the region also on families with sectors of different sizes.
+pub struct RegionPart<'d, const WRITE_SIZE: usize, const ERASE_SIZE: usize> {
+    region: FlashRegion,
+    _flash: PhantomData<&'d mut crate::peripherals::FLASH>,
+}
+
+impl<'d, const WRITE_SIZE: usize, const ERASE_SIZE: usize> RegionPart<'d, WRITE_SIZE, ERASE_SIZE> {
+    /// The range of the flash covered by this part.
+    pub fn region(&self) -> &FlashRegion {
+        &self.region
+    }
+
+    /// Splits the part into the part below `offset` and the part starting at `offset`, see
+    /// [`FlashRegion::split_at`].
+    ///
+    /// # Panics
+    /// Panics if `offset` is not aligned to the erase size, or lies outside the part.
+    pub fn split_at(self, offset: u32) -> (Self, Self) {
+        let (low, high) = self.region.split_at(offset);
+        (
+            Self {
+                region: low,
+                _flash: PhantomData,
+            },
+            Self {
+                region: high,
+                _flash: PhantomData,
+            },
+        )
+    }
+
+    pub fn blocking_read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        blocking_read(self.region.base, self.region.size, offset, bytes)
+    }
+
+    /// Returns `len` bytes at `offset` directly from the memory-mapped flash, see [`Flash::map`].
+    pub fn map(&self, offset: u32, len: usize) -> Result<Mapped<'_>, Error> {
+        let bytes = mapped::map(self.region.base, self.region.size, offset, len)?;
+        Ok(Mapped::new(self.region.base + offset, bytes))
+    }
+
+    pub fn blocking_write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        unsafe { blocking_write::<WRITE_SIZE>(self.region.base, self.region.size, offset, bytes) }
+    }
+
+    pub fn blocking_write_iter<'a>(
+        &mut self,
+        offset: u32,
+        chunks: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<(), Error> {
+        unsafe { blocking_write_iter::<WRITE_SIZE>(self.region.base, self.region.size, offset, chunks) }
+    }
+
+    pub fn blocking_erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        unsafe { blocking_erase(self.region.base, self.region.size, from, to, false) }
+    }
+
+    /// Erases `from..to`, also if the range contains the running program.
+    pub fn blocking_erase_self(&mut self, from: u32, to: u32, _allow: AllowSelfErase) -> Result<(), Error> {
+        unsafe { blocking_erase(self.region.base, self.region.size, from, to, true) }
+    }
+}
+
+/// Carve consecutive parts ending at `ends` (offsets from `base`) out of `regions`.
+fn split_parts<const N: usize>(base: u32, regions: &[&FlashRegion], ends: [u32; N]) -> [FlashRegion; N] {
+    let mut start = base;
+    core::array::from_fn(|i| {
+        let end = base + ends[i];
+        assert!(start < end);
+        let region = regions
+            .iter()
+            .find(|region| start >= region.base && start < region.end())
+            .expect("Flash part outside of the flash");
+        assert!(end <= region.end(), "Flash part spans multiple regions");
+        assert!((start - region.base) % region.erase_size == 0 && (end - region.base) % region.erase_size == 0);
+
+        let part = FlashRegion {
+            base: start,
+            size: end - start,
+            ..**region
+        };
+        start = end;
+        part
+    })
+}
+
+/// The parts covering `ranges` (offsets from `base`) of `regions`, which must not overlap.
+fn partition_regions<const N: usize>(
+    base: u32,
+    regions: &[&FlashRegion],
+    ranges: &[Range<u32>; N],
+) -> Result<[FlashRegion; N], Error> {
+    let absolute = |range: &Range<u32>| -> Result<(u32, u32), Error> {
+        let start = base.checked_add(range.start).ok_or(Error::OutOfBounds)?;
+        let end = base.checked_add(range.end).ok_or(Error::OutOfBounds)?;
+        Ok((start, end))
+    };
+    for (i, range) in ranges.iter().enumerate() {
+        let (start, end) = absolute(range)?;
+        region_in(start, end, regions)?;
+        if overlaps_any(range.start, range.end, &ranges[..i]) {
+            return Err(Error::Overlap);
+        }
+    }
+    Ok(core::array::from_fn(|i| {
+        let (start, end) = absolute(&ranges[i]).unwrap();
+        region_in(start, end, regions).unwrap()
+    }))
+}
+
+/// The part `start..end` of one of `regions`, given as absolute addresses.
+fn region_in(start: u32, end: u32, regions: &[&FlashRegion]) -> Result<FlashRegion, Error> {
+    if start >= end {
+        return Err(Error::Size);
+    }
+    let region = regions
+        .iter()
+        .find(|region| start >= region.base && start < region.end())
+        .ok_or(Error::OutOfBounds)?;
+    if end > region.end() {
+        // The range continues in another region, or past the end of the flash
+        check_spanned_regions(start, end, regions)?;
+        return Err(Error::IncompatibleRegions);
+    }
+    if (start - region.base) % region.erase_size != 0 || (end - region.base) % region.erase_size != 0 {
+        return Err(Error::Unaligned);
+    }
+
+    Ok(FlashRegion {
+        base: start,
+        size: end - start,
+        ..**region
+    })
+}
+
+/// Checks the arguments of [`Flash::overwrite`] and returns the sectors the write touches.
+fn check_overwrite(
+    base: u32,
+    size: u32,
+    offset: u32,
+    len: usize,
+    preserve: &Preserve<'_>,
+    regions: &[&FlashRegion],
+) -> Result<Range<u32>, Error> {
+    check_range(size, offset, len)?;
+    if len == 0 {
+        return Ok(base + offset..base + offset);
+    }
+    let start = base + offset;
+    let end = start + len as u32;
+    let first = find_sector(start, regions).ok_or(Error::OutOfBounds)?;
+    let last = find_sector(end - 1, regions).ok_or(Error::OutOfBounds)?;
+    let sectors = first.start..last.start + last.size;
+
+    let partial_size = [&first, &last]
+        .iter()
+        .filter(|sector| start > sector.start || end < sector.start + sector.size)
+        .map(|sector| sector.size)
+        .max();
+    match (preserve, partial_size) {
+        (Preserve::Nothing, _) => {
+            if offset % WRITE_SIZE as u32 != 0 || len % WRITE_SIZE != 0 {
+                return Err(Error::Unaligned);
+            }
+        }
+        (Preserve::Ram(buf), Some(partial_size)) if buf.len() < partial_size as usize => return Err(Error::Size),
+        (Preserve::Scratch(scratch), Some(partial_size)) => {
+            check_range(size, *scratch, 1)?;
+            let sector = find_sector(base + scratch, regions).ok_or(Error::OutOfBounds)?;
+            if sector.start != base + scratch {
+                return Err(Error::Unaligned);
+            }
+            let scratch_end = sector.start + sector.size;
+            if sector.size < partial_size || (sector.start < sectors.end && sectors.start < scratch_end) {
+                return Err(Error::Size);
+            }
+            check_self_erase(sector.start, scratch_end, false)?;
+        }
+        _ => {}
+    }
+
+    Ok(sectors)
+}
+
+/// Reads `bytes` at `offset` of the `size` bytes at `base`, with volatile reads.
+///
+/// Reading doesn't need the controller, so this works while the flash is locked.
+fn blocking_read(base: u32, size: u32, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+    check_range(size, offset, bytes.len())?;
+
+    let mut address = (base + offset) as *const u8;
+    for byte in bytes.iter_mut() {
+        unsafe {
+            *byte = address.read_volatile();
+            address = address.add(1);
+        }
+    }
+    Ok(())
+}
+
+/// The index of the first byte of `actual` that differs from `expected` of its index.
+fn first_mismatch(actual: &[u8], expected: impl Fn(usize) -> u8) -> Option<usize> {
+    actual.iter().enumerate().position(|(i, &b)| b != expected(i))
+}
+
+/// Check that `len` bytes at `offset` lie within `size` bytes, or fail with [`Error::OutOfBounds`].
+fn check_range(size: u32, offset: u32, len: usize) -> Result<(), Error> {
+    if offset as u64 + len as u64 > size as u64 {
+        return Err(Error::OutOfBounds);
+    }
+    Ok(())
+}
+
+/// Check that `from..to` within `size` bytes at `base` consists of complete sectors of `regions`.
+fn check_erase_range(base: u32, size: u32, from: u32, to: u32, regions: &[&FlashRegion]) -> Result<(), Error> {
+    if from > to || to > size {
+        return Err(Error::OutOfBounds);
+    }
+    check_spanned_regions(base + from, base + to, regions)?;
+
+    let end_address = base as u64 + to as u64;
+    let mut address = base as u64 + from as u64;
+    while address < end_address {
+        let sector = find_sector(address as u32, regions).ok_or(Error::OutOfBounds)?;
+        if sector.start as u64 != address {
+            return Err(Error::Unaligned);
+        }
+        validate_sector(&sector, regions)?;
+        address += sector.size as u64;
+    }
+    if address != end_address {
+        return Err(Error::Unaligned);
+    }
+    Ok(())
+}
+
+/// The regions of `regions` that overlap `start..end`.
+fn overlapping_regions<'a>(
+    start: u32,
+    end: u32,
+    regions: &'a [&'a FlashRegion],
+) -> impl Iterator<Item = &'a FlashRegion> + 'a {
+    regions
+        .iter()
+        .copied()
+        .filter(move |region| region.base < end && start < region.end())
+}
+
+/// Check that the regions covering `start..end` are physically contiguous and compatible.
+///
+/// Regions are compatible if they have the same write size and erase value, so that one operation can be
+/// split at their boundaries. Their erase sizes may differ, since erases are checked sector by sector.
+fn check_spanned_regions(start: u32, end: u32, regions: &[&FlashRegion]) -> Result<(), Error> {
+    let mut previous: Option<&FlashRegion> = None;
+    for region in overlapping_regions(start, end, regions) {
+        match previous {
+            None if region.base > start => return Err(Error::OutOfBounds),
+            Some(previous) if previous.end() != region.base => return Err(Error::OutOfBounds),
+            Some(previous)
+                if previous.write_size != region.write_size || previous.erase_value != region.erase_value =>
+            {
+                return Err(Error::IncompatibleRegions)
+            }
+            _ => {}
+        }
+        previous = Some(region);
+    }
+
+    match previous {
+        Some(last) if last.end() < end => Err(Error::OutOfBounds),
+        None if start < end => Err(Error::OutOfBounds),
+        _ => Ok(()),
+    }
+}
+
+/// Split `start..end` at the boundaries of `regions`.
+fn region_spans<'a>(start: u32, end: u32, regions: &'a [&'a FlashRegion]) -> impl Iterator<Item = Range<u32>> + 'a {
+    overlapping_regions(start, end, regions)
+        .map(move |region| core::cmp::max(start, region.base)..core::cmp::min(end, region.end()))
+}
+
+/// Whether reading `len` bytes at `address` accesses `bank`.
+///
+/// Addresses outside of all regions are treated as part of every bank.
+fn reads_bank(regions: &[&FlashRegion], bank: FlashBank, address: u32, len: usize) -> bool {
+    let end = address as u64 + len as u64;
+    let in_other_banks = regions
+        .iter()
+        .filter(|region| region.bank != bank)
+        .any(|region| address >= region.base && end <= region.end() as u64);
+    len > 0 && !in_other_banks
+}
+
+/// Check whether `len` bytes at `address` lie within one of `areas`.
+fn is_readable(areas: &[Range<u32>], address: u32, len: usize) -> bool {
+    areas
+        .iter()
+        .any(|area| address >= area.start && address as u64 + len as u64 <= area.end as u64)
+}
+
+/// Check whether the flash at `address` holds exactly `data`.
+fn contains(address: u32, data: &[u8]) -> bool {
+    let flash_data = unsafe { core::slice::from_raw_parts(address as *const u8, data.len()) };
+    flash_data == data
+}
+
+/// Check whether `len` bytes at `address` are erased.
+fn is_erased(address: u32, len: usize, regions: &[&FlashRegion]) -> bool {
+    let erase_value = regions
+        .iter()
+        .find(|region| address >= region.base && address < region.end())
+        .map_or(0xFF, |region| region.erase_value);
+    let flash_data = unsafe { core::slice::from_raw_parts(address as *const u8, len) };
+    flash_data.iter().all(|&b| b == erase_value)
+}
+
+/// Copies `src` to `dst` of `flash`, whose offsets start at `base`, see [`Flash::copy_region`].
+///
+/// The ranges are checked by the caller, only the alignment of the destination sectors is checked here.
+fn copy_sectors<F: NorFlash<Error = Error>>(
+    flash: &mut F,
+    base: u32,
+    regions: &[&FlashRegion],
+    src: Range<u32>,
+    dst: Range<u32>,
+    resume_from: usize,
+    mut progress: impl FnMut(usize),
+) -> Result<(), Error> {
+    const CHUNK_SIZE: usize = 64;
+
+    let len = dst.len() as u32;
+    let mut copied = 0;
+    while copied < len {
+        let sector = get_sector(base + dst.start + copied, regions);
+        if sector.start != base + dst.start + copied {
+            return Err(Error::Unaligned);
+        }
+        let sector_len = core::cmp::min(sector.size, len - copied);
+
+        if copied < resume_from as u32 {
+            // A resumed copy must continue at the start of a destination sector
+            if copied + sector_len > resume_from as u32 {
+                return Err(Error::Unaligned);
+            }
+            copied += sector_len;
+            continue;
+        }
+
+        trace!("Copying sector: {:?}", sector);
+        flash.erase(sector.start - base, sector.start - base + sector.size)?;
+
+        let mut buf = [0; CHUNK_SIZE];
+        let mut readback = [0; CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < sector_len {
+            let chunk_len = core::cmp::min(CHUNK_SIZE as u32, sector_len - offset) as usize;
+            let chunk = &mut buf[..chunk_len];
+            flash.read(src.start + copied + offset, chunk)?;
+            flash.write(dst.start + copied + offset, chunk)?;
+
+            let readback = &mut readback[..chunk_len];
+            flash.read(dst.start + copied + offset, readback)?;
+            if readback != chunk {
+                return Err(Error::Prog);
+            }
+
+            offset += chunk_len as u32;
+        }
+
+        copied += sector_len;
+        progress(copied as usize);
+    }
+
+    Ok(())
+}
+
+/// Makes sure `flash`, whose offsets start at `base`, contains `data` at `offset`, see [`Flash::ensure_contains`].
+fn provision<F: NorFlash<Error = Error>>(
+    flash: &mut F,
+    base: u32,
+    regions: &[&FlashRegion],
+    offset: u32,
+    data: &[u8],
+) -> Result<Provisioned, Error> {
+    let end = offset + data.len() as u32;
+    let mut result = Provisioned::Unchanged;
+    let mut pos = offset;
+    while pos < end {
+        let sector = get_sector(base + pos, regions);
+        let chunk_end = core::cmp::min(end, sector.start + sector.size - base);
+        let target = &data[(pos - offset) as usize..(chunk_end - offset) as usize];
+
+        if !reads_as(flash, pos, target.len(), |i| target[i])? {
+            let erase_value = regions
+                .iter()
+                .find(|region| base + pos >= region.base && base + pos < region.end())
+                .map_or(0xFF, |region| region.erase_value);
+            if !reads_as(flash, pos, target.len(), |_| erase_value)? {
+                if sector.start != base + pos || chunk_end - pos != sector.size {
+                    return Err(Error::Unaligned);
+                }
+                trace!("Provisioning sector: {:?}", sector);
+                flash.erase(pos, chunk_end)?;
+            }
+            flash.write(pos, target)?;
+            result = Provisioned::Updated;
+        }
+
+        pos = chunk_end;
+    }
+
+    Ok(result)
+}
+
+/// Whether the `len` bytes at `offset` of `flash` read as `expected`.
+fn reads_as<F: ReadNorFlash<Error = Error>>(
+    flash: &mut F,
+    offset: u32,
+    len: usize,
+    expected: impl Fn(usize) -> u8,
+) -> Result<bool, Error> {
+    let mut buf = [0; 64];
+    let mut done = 0;
+    while done < len {
+        let chunk = &mut buf[..core::cmp::min(64, len - done)];
+        flash.read(offset + done as u32, chunk)?;
+        if first_mismatch(chunk, |i| expected(done + i)).is_some() {
+            return Ok(false);
+        }
+        done += chunk.len();
+    }
+    Ok(true)
+}
+
+/// Writes `bytes` in units of `N` bytes, the write size of the region.
+unsafe fn blocking_write<const N: usize>(base: u32, size: u32, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+    write_chunks::<_, N>(&mut Hardware, family::get_flash_regions(), base, size, offset, bytes)
+}
+
+/// Programs `bytes` at `offset` of the flash at `base` on `backend`, in units of `N` bytes.
+unsafe fn write_chunks<B: FlashBackend, const N: usize>(
+    backend: &mut B,
+    regions: &[&FlashRegion],
+    base: u32,
+    size: u32,
+    offset: u32,
+    bytes: &[u8],
+) -> Result<(), Error> {
+    check_range(size, offset, bytes.len())?;
+    if offset % N as u32 != 0 || bytes.len() % N != 0 {
+        return Err(Error::Unaligned);
+    }
+
+    let start_address = base + offset;
+    let end_address = start_address + bytes.len() as u32;
+    trace!("Writing {} bytes at 0x{:x}", bytes.len(), start_address);
+
+    check_spanned_regions(start_address, end_address, regions)?;
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    let () = WriteUnit::<N>::OK;
+    observer::assert_not_observing();
+    mapped::assert_not_mapped(start_address, bytes.len());
+    backend.check_write(start_address, bytes)?;
+
+    // The controller is unlocked once for the whole write, interrupts are only masked while a unit is programmed
+    critical_section::with(|_| recover());
+    super::idle::begin();
+    let _idle = OnDrop::new(super::idle::end);
+    let mut backend = Unlocked::new(backend);
+
+    // Program each region separately, so that no burst crosses a region boundary
+    for span in region_spans(start_address, end_address, regions) {
+        let mut address = span.start;
+        let mut bytes = &bytes[(span.start - start_address) as usize..(span.end - start_address) as usize];
+        while !bytes.is_empty() {
+            // Aligned half pages are programmed in one burst, the edges word by word
+            #[cfg(flash_l0)]
+            if address % family::HALF_PAGE_SIZE as u32 == 0 && bytes.len() >= family::HALF_PAGE_SIZE {
+                let (half_page, rest) = bytes.split_at(family::HALF_PAGE_SIZE);
+                program_chunk::<_, { family::HALF_PAGE_SIZE }>(
+                    &mut *backend.0,
+                    address,
+                    half_page.try_into().unwrap(),
+                )?;
+                address += family::HALF_PAGE_SIZE as u32;
+                bytes = rest;
+                continue;
+            }
+
+            let (chunk, rest) = bytes.split_at(N);
+            program_chunk::<_, N>(&mut *backend.0, address, chunk.try_into().unwrap())?;
+            address += N as u32;
+            bytes = rest;
+        }
+    }
+    Ok(())
+}
+
+unsafe fn blocking_write_iter<'a, const N: usize>(
+    base: u32,
+    size: u32,
+    offset: u32,
+    chunks: impl IntoIterator<Item = &'a [u8]>,
+) -> Result<(), Error> {
+    if offset > size {
+        return Err(Error::OutOfBounds);
+    }
+    if offset % N as u32 != 0 {
+        return Err(Error::Unaligned);
+    }
+
+    trace!("Writing chunks at 0x{:x}", base + offset);
+
+    let end_address = base + size;
+    stage_chunks::<N>(base + offset, chunks, |address, unit| {
+        if address + N as u32 > end_address {
+            return Err(Error::OutOfBounds);
+        }
+        write_unit(&mut Hardware, address, unit)
+    })
+}
+
+/// Programs exactly one write unit at `address`, with the unlock, fence and status handling of the driver.
+///
+/// [`Flash::program_unit`] is the checked alternative.
+///
+/// # Safety
+/// `address` must be an absolute address in the main flash, aligned to [`WRITE_SIZE`]. The unit must be
+/// programmable over the current contents, which usually means they are erased, and no other program or erase
+/// operation may run at the same time.
+pub unsafe fn program_unit(address: u32, bytes: &[u8; WRITE_SIZE]) -> Result<(), Error> {
+    write_unit(&mut Hardware, address, bytes)
+}
+
+/// Checks that one write unit can be programmed at `offset` of the flash at `base`, returning its address.
+fn check_unit(base: u32, size: u32, offset: u32, regions: &[&FlashRegion]) -> Result<u32, Error> {
+    check_range(size, offset, WRITE_SIZE)?;
+    if offset % WRITE_SIZE as u32 != 0 {
+        return Err(Error::Unaligned);
+    }
+    let address = base + offset;
+    find_sector(address, regions).ok_or(Error::OutOfBounds)?;
+    Ok(address)
+}
+
+/// Programs a unit of `N` bytes on `backend`. `N` must be a multiple of the write size of the family, which is checked
+/// at compile time.
+unsafe fn write_unit<B: FlashBackend, const N: usize>(
+    backend: &mut B,
+    address: u32,
+    unit: &[u8; N],
+) -> Result<(), Error> {
+    let () = WriteUnit::<N>::OK;
+    observer::assert_not_observing();
+    mapped::assert_not_mapped(address, N);
+    backend.check_write(address, unit)?;
+
+    critical_section::with(|_| recover());
+    super::idle::begin();
+    let _idle = OnDrop::new(super::idle::end);
+    let mut backend = Unlocked::new(backend);
+    program_chunk(&mut *backend.0, address, unit)
+}
+
+/// Programs a unit of `N` bytes on the unlocked `backend` in a critical section, and reports it to the observer.
+unsafe fn program_chunk<B: FlashBackend, const N: usize>(
+    backend: &mut B,
+    address: u32,
+    unit: &[u8; N],
+) -> Result<(), Error> {
+    let result = critical_section::with(|_| backend.write_chunk(address, unit));
+    observer::notify(|o| o.on_write(address, N, result));
+    result
+}
+
+/// Check that each word of `unit` can be programmed over the current flash content at `address`.
+fn check_erased(address: u32, unit: &[u8], regions: &[&FlashRegion]) -> Result<(), Error> {
+    let erase_value = regions
+        .iter()
+        .find(|region| address >= region.base && address < region.end())
+        .map_or(0xFF, |region| region.erase_value);
+    let current = unsafe { core::slice::from_raw_parts(address as *const u8, unit.len()) };
+
+    for (i, (current, new)) in current.chunks(WRITE_SIZE).zip(unit.chunks(WRITE_SIZE)).enumerate() {
+        if !super::Rewrite::FAMILY.can_program(current, new, erase_value) {
+            return Err(Error::NotErased {
+                address: address + (i * WRITE_SIZE) as u32,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// An unaligned write split into the write units it covers, see [`pad_unaligned`].
+struct Padded<'a, const N: usize> {
+    /// Offset of the first unit.
+    start: u32,
+    /// The first unit, if the write doesn't start on a unit boundary.
+    head: Option<[u8; N]>,
+    /// The complete units.
+    body: &'a [u8],
+    /// The last unit, if the write doesn't end on a unit boundary.
+    tail: Option<[u8; N]>,
+}
+
+/// Splits a write of `data` at `offset` into units of `N` bytes. The partially covered units are filled with the
+/// bytes of `current` of the unit at the given offset.
+fn pad_unaligned<'a, const N: usize>(offset: u32, data: &'a [u8], current: impl Fn(u32) -> [u8; N]) -> Padded<'a, N> {
+    let start = offset - offset % N as u32;
+    let lead = (offset - start) as usize;
+
+    let (head, data) = if lead > 0 {
+        let mut unit = current(start);
+        let n = core::cmp::min(N - lead, data.len());
+        unit[lead..lead + n].copy_from_slice(&data[..n]);
+        (Some(unit), &data[n..])
+    } else {
+        (None, data)
+    };
+
+    let (body, rest) = data.split_at(data.len() - data.len() % N);
+    let tail = (!rest.is_empty()).then(|| {
+        let unit_offset = start + head.map_or(0, |_| N as u32) + body.len() as u32;
+        let mut unit = current(unit_offset);
+        unit[..rest.len()].copy_from_slice(rest);
+        unit
+    });
+
+    Padded {
+        start,
+        head,
+        body,
+        tail,
+    }
+}
+
+/// Collects `chunks` into units of `N` bytes and passes each complete unit to `program`.
+///
+/// The chunks may have arbitrary lengths, partial units are carried over to the next chunk.
+/// Fails with [`Error::Unaligned`] if the total length is not a multiple of `N`,
+/// in which case the trailing partial unit is not programmed.
+fn stage_chunks<'a, const N: usize>(
+    mut address: u32,
+    chunks: impl IntoIterator<Item = &'a [u8]>,
+    mut program: impl FnMut(u32, &[u8; N]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut staging = [0; N];
+    let mut staged = 0;
+
+    for mut chunk in chunks {
+        if staged > 0 {
+            let n = core::cmp::min(N - staged, chunk.len());
+            staging[staged..staged + n].copy_from_slice(&chunk[..n]);
+            staged += n;
+            chunk = &chunk[n..];
+
+            if staged < N {
+                continue;
+            }
+            program(address, &staging)?;
+            address += N as u32;
+            staged = 0;
+        }
+
+        let mut units = chunk.chunks_exact(N);
+        for unit in &mut units {
+            program(address, unit.try_into().unwrap())?;
+            address += N as u32;
+        }
+
+        let remainder = units.remainder();
+        staging[..remainder.len()].copy_from_slice(remainder);
+        staged = remainder.len();
+    }
+
+    if staged != 0 {
+        return Err(Error::Unaligned);
+    }
+    Ok(())
+}
+
+unsafe fn blocking_erase(base: u32, size: u32, from: u32, to: u32, allow_self: bool) -> Result<(), Error> {
+    blocking_erase_range(base, size, from, to, allow_self).map_err(|e| e.error)
+}
+
+unsafe fn blocking_erase_range(base: u32, size: u32, from: u32, to: u32, allow_self: bool) -> Result<(), EraseError> {
+    erase_range(
+        &mut Hardware,
+        family::get_flash_regions(),
+        base,
+        size,
+        from,
+        to,
+        allow_self,
+    )
+}
+
+/// Erases `from..to` of the flash at `base` on `backend`, see [`Flash::blocking_erase_range`].
+unsafe fn erase_range<B: FlashBackend>(
+    backend: &mut B,
+    regions: &[&FlashRegion],
+    base: u32,
+    size: u32,
+    from: u32,
+    to: u32,
+    allow_self: bool,
+) -> Result<(), EraseError> {
+    let rejected = |error| EraseError { offset: from, error };
+    check_erase_range(base, size, from, to, regions).map_err(rejected)?;
+
+    let start_address = base + from;
+    let end_address = base + to;
+    check_self_erase(start_address, end_address, allow_self).map_err(rejected)?;
+    trace!("Erasing from 0x{:x} to 0x{:x}", start_address, end_address);
+
+    erase_sectors(backend, start_address, end_address, regions).map_err(|(address, error)| EraseError {
+        offset: address - base,
+        error,
+    })
+}
+
+unsafe fn erase_sector(sector: &FlashSector) -> Result<(), Error> {
+    observer::assert_not_observing();
+    mapped::assert_not_mapped(sector.start, sector.size as usize);
+    validate_sector(sector, family::get_flash_regions())?;
+    check_self_erase(sector.start, sector.start + sector.size, false)?;
+    super::check_clocks()?;
+    check_write_protection(sector.start, sector.start + sector.size)?;
+
+    critical_section::with(|_| {
+        recover();
+        super::idle::begin();
+        let _idle = OnDrop::new(super::idle::end);
+        let _guard = WriteGuard::unlock();
+
+        report_erase(sector, || {
+            erase_retrying(&mut Hardware, sector, ERASE_RETRIES.load(Ordering::Relaxed))
+        })?;
+        record_wear(&mut Hardware, sector, family::get_flash_regions())
+    })
+}
+
+/// Erases `sector` of the unlocked `backend`, erasing it again up to `retries` times if it fails.
+///
+/// Without retries, the sector is erased once and not read back. Otherwise only an error the backend considers
+/// retryable or a sector that doesn't read back blank is retried, other errors are returned at once.
+unsafe fn erase_retrying<B: FlashBackend>(backend: &mut B, sector: &FlashSector, retries: u8) -> Result<(), Error> {
+    if retries == 0 {
+        return backend.erase_sector(sector);
+    }
+
+    let attempts = retries.saturating_add(1);
+    for attempt in 1..=attempts {
+        match backend.erase_sector(sector) {
+            Ok(()) if backend.is_blank(sector) => return Ok(()),
+            Ok(()) => trace!("Sector 0x{:x} not blank after erase attempt {}", sector.start, attempt),
+            Err(e) if backend.is_retryable(e) => {
+                trace!("Erase attempt {} of sector 0x{:x} failed", attempt, sector.start)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(Error::Erase { attempts })
+}
+
+/// Counts the erase of `sector` in the area set with [`Flash::track_wear`], on the unlocked `backend`.
+///
+/// Erases of the area itself are not counted.
+#[cfg(feature = "flash-stats")]
+unsafe fn record_wear<B: FlashBackend>(
+    backend: &mut B,
+    sector: &FlashSector,
+    regions: &[&FlashRegion],
+) -> Result<(), Error> {
+    let Some(area) = super::stats::wear_area() else {
+        return Ok(());
+    };
+    record_wear_in::<_, MAX_ERASE_SIZE>(backend, area, sector, regions)
+}
+
+#[cfg(not(feature = "flash-stats"))]
+unsafe fn record_wear<B: FlashBackend>(
+    _backend: &mut B,
+    _sector: &FlashSector,
+    _regions: &[&FlashRegion],
+) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Counts the erase of `sector` in the accounting `area` of `ERASE_SIZE` byte sectors, on the unlocked `backend`.
+#[cfg(feature = "flash-stats")]
+unsafe fn record_wear_in<B: FlashBackend, const ERASE_SIZE: usize>(
+    backend: &mut B,
+    area: Range<u32>,
+    sector: &FlashSector,
+    regions: &[&FlashRegion],
+) -> Result<(), Error> {
+    if area.contains(&sector.start) {
+        return Ok(());
+    }
+    let area = WearArea::<_, ERASE_SIZE> { backend, area, regions };
+    WearLog::new(area, sector_count(regions)).record(sector_number(sector.start, regions))
+}
+
+/// Runs `f` on the counters in the area set with [`Flash::track_wear`], or returns `None` if wear is not tracked.
+#[cfg(feature = "flash-stats")]
+fn with_wear_log<B: FlashBackend, R>(
+    backend: &mut B,
+    regions: &[&FlashRegion],
+    f: impl FnOnce(&mut WearLog<WearArea<'_, B, MAX_ERASE_SIZE>>) -> Result<R, Error>,
+) -> Option<Result<R, Error>> {
+    let area = super::stats::wear_area()?;
+    let area = WearArea::<_, MAX_ERASE_SIZE> { backend, area, regions };
+    Some(f(&mut WearLog::new(area, sector_count(regions))))
+}
+
+/// Checks that an accounting area of `len` bytes can count the erases of all sectors of `regions`.
+#[cfg(feature = "flash-stats")]
+fn check_wear_area(len: u32, regions: &[&FlashRegion]) -> Result<(), Error> {
+    let counters = (sector_count(regions) * 4 + WRITE_SIZE - 1) / WRITE_SIZE * WRITE_SIZE;
+    let header = (8 + WRITE_SIZE - 1) / WRITE_SIZE * WRITE_SIZE;
+    if len % MAX_ERASE_SIZE as u32 != 0 || len < 2 * MAX_ERASE_SIZE as u32 || header + counters >= MAX_ERASE_SIZE {
+        return Err(Error::Size);
+    }
+    Ok(())
+}
+
+/// The number of sectors of all `regions`.
+#[cfg(feature = "flash-stats")]
+fn sector_count(regions: &[&FlashRegion]) -> usize {
+    regions.iter().map(|region| region.sectors() as usize).sum()
+}
+
+/// The number of the sector at `address`, counting the sectors of all `regions` before it.
+#[cfg(feature = "flash-stats")]
+fn sector_number(address: u32, regions: &[&FlashRegion]) -> usize {
+    let mut number = 0;
+    for region in regions {
+        if address >= region.base && address < region.end() {
+            return number + ((address - region.base) / region.erase_size) as usize;
+        }
+        number += region.sectors() as usize;
+    }
+    panic!("No sector at 0x{:x}", address);
+}
+
+/// The accounting area of [`Flash::track_wear`] on an unlocked `backend`, in sectors of `ERASE_SIZE` bytes.
+#[cfg(feature = "flash-stats")]
+struct WearArea<'a, B: FlashBackend, const ERASE_SIZE: usize> {
+    backend: &'a mut B,
+    area: Range<u32>,
+    regions: &'a [&'a FlashRegion],
+}
+
+#[cfg(feature = "flash-stats")]
+impl<B: FlashBackend, const ERASE_SIZE: usize> embedded_storage::nor_flash::ErrorType for WearArea<'_, B, ERASE_SIZE> {
+    type Error = Error;
+}
+
+#[cfg(feature = "flash-stats")]
+impl<B: FlashBackend, const ERASE_SIZE: usize> ReadNorFlash for WearArea<'_, B, ERASE_SIZE> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        check_range(self.area.len() as u32, offset, bytes.len())?;
+        self.backend.read(self.area.start + offset, bytes);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.area.len()
+    }
+}
+
+#[cfg(feature = "flash-stats")]
+impl<B: FlashBackend, const ERASE_SIZE: usize> NorFlash for WearArea<'_, B, ERASE_SIZE> {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        check_range(self.area.len() as u32, offset, bytes.len())?;
+        let mut address = self.area.start + offset;
+        for unit in bytes.chunks_exact(WRITE_SIZE) {
+            unsafe { program_chunk::<_, WRITE_SIZE>(&mut *self.backend, address, unit.try_into().unwrap())? };
+            address += WRITE_SIZE as u32;
+        }
+        // Programming stays enabled after a unit, which the erase that follows must not see
+        unsafe {
+            self.backend.lock();
+            self.backend.unlock();
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        check_range(self.area.len() as u32, from, (to - from) as usize)?;
+        let retries = ERASE_RETRIES.load(Ordering::Relaxed);
+        let mut address = self.area.start + from;
+        while address < self.area.start + to {
+            let backend = &mut *self.backend;
+            let sector = get_sector(address, self.regions);
+            critical_section::with(|_| report_erase(&sector, || unsafe { erase_retrying(backend, &sector, retries) }))?;
+            address += sector.size;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(flash_f0)]
+unsafe fn blocking_erase_all(allow_self: bool) -> Result<(), Error> {
+    let start_address = FLASH_BASE as u32;
+    let end_address = start_address + FLASH_SIZE as u32;
+    check_self_erase(start_address, end_address, allow_self)?;
+    observer::assert_not_observing();
+    mapped::assert_not_mapped(start_address, FLASH_SIZE);
+    super::check_clocks()?;
+    check_write_protection(start_address, end_address)?;
+    let sectors = family::get_flash_regions()
+        .iter()
+        .map(|region| region.sectors() as u32)
+        .sum();
+    trace!("Mass erasing from 0x{:x} to 0x{:x}", start_address, end_address);
+
+    critical_section::with(|_| {
+        recover();
+        super::idle::begin();
+        let _idle = OnDrop::new(super::idle::end);
+        let _guard = WriteGuard::unlock();
+
+        measure(0, sectors, || family::blocking_erase_all())
+    })
+}
+
+/// Erase a single sector with `op`, and report it to the statistics and the observer.
+fn report_erase(sector: &FlashSector, op: impl FnOnce() -> Result<(), Error>) -> Result<(), Error> {
+    observer::notify(|o| o.on_erase_start(sector));
+    let result = measure(0, 1, op);
+    observer::notify(|o| o.on_erase_end(sector, result));
+    result
+}
+
+/// Run a single program or erase operation, and account it in the statistics if they are enabled.
+#[inline(always)]
+fn measure(bytes_written: u32, sectors_erased: u32, op: impl FnOnce() -> Result<(), Error>) -> Result<(), Error> {
+    #[cfg(feature = "flash-stats")]
+    let measurement = super::stats::Measurement::start();
+    let result = op();
+    #[cfg(feature = "flash-stats")]
+    measurement.finish(&result, bytes_written, sectors_erased);
+    #[cfg(not(feature = "flash-stats"))]
+    let _ = (bytes_written, sectors_erased);
+    result
+}
+
+/// Erase the sectors from `start_address` to `end_address`, which must be validated sector boundaries.
+///
+/// The controller is unlocked once for the whole range, and locked again after the last sector or the first failure.
+/// A critical section is only held while each sector is erased. On failure, the address of the sector that failed is
+/// returned with the error.
+unsafe fn erase_sectors<B: FlashBackend>(
+    backend: &mut B,
+    start_address: u32,
+    end_address: u32,
+    regions: &[&FlashRegion],
+) -> Result<(), (u32, Error)> {
+    observer::assert_not_observing();
+    mapped::assert_not_mapped(start_address, (end_address - start_address) as usize);
+    backend
+        .check_erase(start_address, end_address)
+        .map_err(|e| (start_address, e))?;
+
+    critical_section::with(|_| recover());
+    super::idle::begin();
+    let _idle = OnDrop::new(super::idle::end);
+    let mut backend = Unlocked::new(backend);
+    let retries = ERASE_RETRIES.load(Ordering::Relaxed);
+
+    // Interrupts are only masked while a single sector is erased, a large range takes seconds
+    let mut address = start_address;
+    while address < end_address {
+        let sector = get_sector(address, regions);
+        trace!("Erasing sector: {:?}", sector);
+        critical_section::with(|_| report_erase(&sector, || erase_retrying(&mut *backend.0, &sector, retries)))
+            .map_err(|e| (address, e))?;
+        record_wear(&mut *backend.0, &sector, regions).map_err(|e| (address, e))?;
+        address += sector.size;
+    }
+    Ok(())
+}
+
+/// Check that `sector` is a complete sector of one of `regions`, before its start is handed to the hardware.
+///
+/// The erase address register takes any value, and an address that is not a sector start may erase a different
+/// sector than intended.
+fn validate_sector(sector: &FlashSector, regions: &[&FlashRegion]) -> Result<(), Error> {
+    let region = regions
+        .iter()
+        .find(|region| sector.start >= region.base && sector.start < region.end())
+        .ok_or(Error::OutOfBounds)?;
+    if (sector.start - region.base) % region.erase_size != 0 || sector.size != region.erase_size {
+        return Err(Error::Unaligned);
+    }
+    Ok(())
+}
+
+/// Fail with [`Error::WouldEraseSelf`] if `start..end` overlaps the running program, unless `allow_self` is set.
+pub(crate) fn check_self_erase(start: u32, end: u32, allow_self: bool) -> Result<(), Error> {
+    if !allow_self && overlaps_any(start, end, &running_image()) {
+        return Err(Error::WouldEraseSelf);
+    }
+    Ok(())
+}
+
+/// Fail with [`Error::Protected`] before an operation on `start..end` is started, if the write protection of the
+/// loaded option bytes covers any of it. The controller would only report it once the operation was attempted.
+#[cfg(flash_f0)]
+fn check_write_protection(start: u32, end: u32) -> Result<(), Error> {
+    let range = super::WrpMask::covering(start - FLASH_BASE as u32, end - FLASH_BASE as u32);
+    if family::write_protection().0 & range.0 != 0 {
+        return Err(Error::Protected);
+    }
+    Ok(())
+}
+
+/// The other families report write protection when the operation is attempted.
+#[cfg(not(flash_f0))]
+fn check_write_protection(_start: u32, _end: u32) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Whether `start..end` overlaps any of `ranges`.
+fn overlaps_any(start: u32, end: u32, ranges: &[Range<u32>]) -> bool {
+    ranges
+        .iter()
+        .any(|range| range.start < range.end && range.start < end && start < range.end)
+}
+
+/// The parts of the running program, as placed by the cortex-m-rt linker script: the vector table, the code, the
+/// read-only data and the initial values of `.data`. Only the parts in flash can overlap an erase.
+#[cfg(all(feature = "rt", not(test)))]
+fn running_image() -> [Range<u32>; 4] {
+    extern "C" {
+        static __reset_vector: u8;
+        static __stext: u8;
+        static __etext: u8;
+        static __srodata: u8;
+        static __erodata: u8;
+        static __sidata: u8;
+        static __sdata: u8;
+        static __edata: u8;
+    }
+
+    let address = |symbol: &u8| symbol as *const u8 as u32;
+    unsafe {
+        let vector_table = address(&__reset_vector);
+        let data_len = address(&__edata) - address(&__sdata);
+        [
+            vector_table..vector_table + 1,
+            address(&__stext)..address(&__etext),
+            address(&__srodata)..address(&__erodata),
+            address(&__sidata)..address(&__sidata) + data_len,
+        ]
+    }
+}
+
+/// Without the cortex-m-rt linker script the extent of the program is unknown. Only the flash driver itself is
+/// known to be part of it, so at least the sector it executes from is protected.
+#[cfg(all(not(feature = "rt"), not(test)))]
+fn running_image() -> [Range<u32>; 4] {
+    let driver = running_image as usize as u32;
+    [driver..driver + 1, 0..0, 0..0, 0..0]
+}
+
+/// The host tests don't run from flash.
+#[cfg(test)]
+fn running_image() -> [Range<u32>; 4] {
+    [0..0, 0..0, 0..0, 0..0]
+}
+
+/// The flash controller, unlocked for a program or erase operation.
+///
+/// Dropping the guard clears the program and erase bits and locks the controller again, so the controller is never
+/// left unlocked by an early return or a panic between unlocking and locking. The family functions it wraps remain
+/// the low-level layer for the sequences that can't be scoped, e.g. [`Flash::try_start_erase`].
+pub(crate) struct WriteGuard(());
+
+impl WriteGuard {
+    /// Clear the error flags and unlock the controller.
+    pub(crate) unsafe fn unlock() -> Self {
+        Hardware.unlock();
+        Self(())
+    }
+
+    /// Unlock the controller and enable programming.
+    pub(crate) unsafe fn begin_write() -> Self {
+        let guard = Self::unlock();
+        family::begin_write();
+        fence(Ordering::SeqCst);
+        guard
+    }
+}
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        unsafe { Hardware.lock() }
+    }
+}
+
+/// The primitives of a flash controller that the write and erase sequences of the driver are built on.
+///
+/// [`Hardware`] is the controller of the family. The tests run the sequences on a
+/// [`MockFlash`](super::mem_flash::MockFlash) instead, so that they can be tested on the host.
+pub(crate) trait FlashBackend {
+    /// Checks that `unit` can be programmed at `address`, before the controller is unlocked.
+    fn check_write(&self, address: u32, unit: &[u8]) -> Result<(), Error>;
+
+    /// Checks that `start..end` can be erased, before the controller is unlocked.
+    fn check_erase(&self, start: u32, end: u32) -> Result<(), Error>;
+
+    /// Clears the error flags and unlocks the controller.
+    unsafe fn unlock(&mut self);
+
+    /// Clears the program and erase bits, and locks the controller.
+    unsafe fn lock(&mut self);
+
+    /// Programs a unit of `N` bytes at `address` of the unlocked controller, and waits for it to complete.
+    unsafe fn write_chunk<const N: usize>(&mut self, address: u32, chunk: &[u8; N]) -> Result<(), Error>;
+
+    /// Erases `sector` of the unlocked controller, and waits for it to complete.
+    unsafe fn erase_sector(&mut self, sector: &FlashSector) -> Result<(), Error>;
+
+    /// Whether all of `sector` reads back erased.
+    fn is_blank(&self, sector: &FlashSector) -> bool;
+
+    /// Reads `bytes` at `address`, which works while the controller is locked.
+    fn read(&self, address: u32, bytes: &mut [u8]);
+
+    /// Whether a failed erase with `error` may succeed if it is repeated.
+    fn is_retryable(&self, error: Error) -> bool;
+}
+
+/// The flash controller of the family.
+pub(crate) struct Hardware;
+
+impl FlashBackend for Hardware {
+    fn check_write(&self, address: u32, unit: &[u8]) -> Result<(), Error> {
+        super::check_clocks()?;
+        check_write_protection(address, address + unit.len() as u32)?;
+        #[cfg(feature = "flash-erase-check")]
+        check_erased(address, unit, family::get_flash_regions())?;
+        Ok(())
+    }
+
+    fn check_erase(&self, start: u32, end: u32) -> Result<(), Error> {
+        super::check_clocks()?;
+        check_write_protection(start, end)
+    }
+
+    unsafe fn unlock(&mut self) {
+        family::clear_all_err();
+        fence(Ordering::SeqCst);
+        family::unlock();
+        fence(Ordering::SeqCst);
+    }
+
+    unsafe fn lock(&mut self) {
+        // `end_write` clears the program and the erase bits
+        family::end_write();
+        fence(Ordering::SeqCst);
+        family::lock();
+    }
+
+    unsafe fn write_chunk<const N: usize>(&mut self, address: u32, chunk: &[u8; N]) -> Result<(), Error> {
+        family::begin_write();
+        fence(Ordering::SeqCst);
+
+        #[cfg(flash_l0)]
+        if N == family::HALF_PAGE_SIZE {
+            return measure(N as u32, 0, || {
+                family::blocking_write_half_page(address, chunk[..].try_into().unwrap())
+            });
+        }
+
+        measure(N as u32, 0, || family::blocking_write(address, chunk))
+    }
+
+    unsafe fn erase_sector(&mut self, sector: &FlashSector) -> Result<(), Error> {
+        family::clear_all_err();
+        family::blocking_erase_sector(sector)
+    }
+
+    fn is_blank(&self, sector: &FlashSector) -> bool {
+        is_erased(sector.start, sector.size as usize, family::get_flash_regions())
+    }
+
+    fn read(&self, address: u32, bytes: &mut [u8]) {
+        blocking_read(address, bytes.len() as u32, 0, bytes).unwrap()
+    }
+
+    fn is_retryable(&self, error: Error) -> bool {
+        // Only F0 and F3 report a missing end of operation flag as `Prog`, elsewhere it's a real programming error
+        cfg!(any(flash_f0, flash_f3)) && error == Error::Prog
+    }
+}
+
+/// A [`FlashBackend`] that is unlocked until the guard is dropped.
+struct Unlocked<'a, B: FlashBackend>(&'a mut B);
+
+impl<'a, B: FlashBackend> Unlocked<'a, B> {
+    unsafe fn new(backend: &'a mut B) -> Self {
+        backend.unlock();
+        Self(backend)
+    }
+}
+
+impl<B: FlashBackend> Drop for Unlocked<'_, B> {
+    fn drop(&mut self) {
+        unsafe { self.0.lock() }
+    }
+}
+
+/// Set when a started program or erase operation was abandoned before it completed.
+static POISONED: AtomicBool = AtomicBool::new(false);
+
+/// Guard for a program or erase operation that completes asynchronously.
+///
+/// The hardware can't abort an operation once it is started, so dropping the guard before
+/// [`PendingOperation::complete`] (e.g. because the future driving the operation was dropped) does not wait.
+/// Instead, the driver is marked as poisoned, and the next operation first waits for the abandoned one to
+/// finish and restores the idle state of the controller. Cancelling is therefore always cheap, and the
+/// driver stays usable afterwards; the abandoned operation may or may not have completed.
+pub(crate) struct PendingOperation(());
+
+impl PendingOperation {
+    pub(crate) fn start() -> Self {
+        super::idle::begin();
+        Self(())
+    }
+
+    pub(crate) fn complete(self) {
+        core::mem::forget(self);
+        super::idle::end();
+    }
+}
+
+impl Drop for PendingOperation {
+    fn drop(&mut self) {
+        POISONED.store(true, Ordering::SeqCst);
+        super::idle::abandon();
+    }
+}
+
+fn take_poisoned() -> bool {
+    POISONED.swap(false, Ordering::SeqCst)
+}
+
+/// Wait for an abandoned operation to finish, and restore the idle state of the controller.
+pub(crate) unsafe fn recover() {
+    if take_poisoned() {
+        trace!("Recovering from an abandoned flash operation");
+        family::wait_idle();
+        family::clear_all_err();
+        family::end_write();
+        fence(Ordering::SeqCst);
+        family::lock();
+        super::idle::finish_abandoned();
+    }
+}
+
+pub(crate) fn get_sector(address: u32, regions: &[&FlashRegion]) -> FlashSector {
+    find_sector(address, regions).expect("Flash sector not found")
+}
+
+/// The sector of `regions` that contains `address`, if any.
+fn find_sector(address: u32, regions: &[&FlashRegion]) -> Option<FlashSector> {
+    let mut current_bank = FlashBank::Bank1;
+    let mut bank_offset = 0;
+    for region in regions {
+        if region.bank != current_bank {
+            current_bank = region.bank;
+            bank_offset = 0;
+        }
+
+        if address >= region.base && address < region.end() {
+            let index_in_region = (address - region.base) / region.erase_size;
+            return Some(FlashSector {
+                bank: region.bank,
+                index_in_bank: bank_offset + index_in_region as u8,
+                start: region.base + index_in_region * region.erase_size,
+                size: region.erase_size,
+            });
+        }
+
+        bank_offset += region.sectors();
+    }
+
+    None
+}
+
+/// Builds a [`FlashRegion`] from the start and end symbols of a flash area defined in the linker script.
+///
+/// This keeps the linker script as the only definition of the area. The symbols are checked at runtime with
+/// [`FlashRegion::from_range`], so the macro evaluates to a `Result<FlashRegion, Error>`.
+///
+/// # Example
+///
+/// The storage area is cut from the end of the flash in `memory.x`:
+///
+/// ```text
+/// MEMORY
+/// {
+///   FLASH   : ORIGIN = 0x08000000, LENGTH = 448K
+///   STORAGE : ORIGIN = 0x08070000, LENGTH = 64K
+///   RAM     : ORIGIN = 0x20000000, LENGTH = 128K
+/// }
+///
+/// __storage_start = ORIGIN(STORAGE);
+/// __storage_end = ORIGIN(STORAGE) + LENGTH(STORAGE);
+/// ```
+///
+/// ```rust,ignore
+/// use embassy_stm32::region_from_linker;
+///
+/// let mut storage = region_from_linker!(__storage_start, __storage_end).unwrap();
+/// storage.blocking_erase(0, storage.size).unwrap();
+/// storage.blocking_write(0, &[0x12, 0x34, 0x56, 0x78]).unwrap();
+/// ```
+#[macro_export]
+macro_rules! region_from_linker {
+    ($start:ident, $end:ident) => {{
+        extern "C" {
+            static $start: u8;
+            static $end: u8;
+        }
+        // Only the addresses of the symbols are used, they are never read
+        let start = unsafe { ::core::ptr::addr_of!($start) } as u32;
+        let end = unsafe { ::core::ptr::addr_of!($end) } as u32;
+        $crate::flash::FlashRegion::from_range(start, end)
+    }};
+}
+
+impl FlashRegion {
+    /// The part `start..end` of the flash, given as absolute addresses, see [`region_from_linker!`].
+    ///
+    /// The range must be aligned to the erase size and lie within a single region of the flash. A range that
+    /// leaves the flash returns [`Error::OutOfBounds`], one that continues into another region returns
+    /// [`Error::IncompatibleRegions`].
+    ///
+    /// The returned region accesses the flash without owning the peripheral. It must not overlap other regions or
+    /// parts in use.
+    ///
+    /// [`region_from_linker!`]: crate::region_from_linker
+    pub fn from_range(start: u32, end: u32) -> Result<FlashRegion, Error> {
+        region_in(start, end, family::get_flash_regions())
+    }
+
+    /// Reads `bytes` at `offset` from the start of the region.
+    ///
+    /// A read that doesn't lie completely within the region fails with [`Error::OutOfBounds`]. Reads don't use
+    /// the controller, so they work while the flash is locked.
+    pub fn read(&self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        blocking_read(self.base, self.size, offset, bytes)
+    }
+
+    /// Reads `bytes` at `offset`, see [`FlashRegion::read`].
+    pub fn blocking_read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        self.read(offset, bytes)
+    }
+
+    pub fn blocking_write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        unsafe { blocking_write::<WRITE_SIZE>(self.base, self.size, offset, bytes) }
+    }
+
+    pub fn blocking_write_iter<'a>(
+        &mut self,
+        offset: u32,
+        chunks: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<(), Error> {
+        unsafe { blocking_write_iter::<WRITE_SIZE>(self.base, self.size, offset, chunks) }
+    }
+
+    pub fn blocking_erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        unsafe { blocking_erase(self.base, self.size, from, to, false) }
+    }
+
+    /// Erases `from..to`, also if the range contains the running program.
+    pub fn blocking_erase_self(&mut self, from: u32, to: u32, _allow: AllowSelfErase) -> Result<(), Error> {
+        unsafe { blocking_erase(self.base, self.size, from, to, true) }
+    }
+}
+
+impl embedded_storage::nor_flash::ErrorType for Flash<'_> {
+    type Error = Error;
+}
+
+impl embedded_storage::nor_flash::ReadNorFlash for Flash<'_> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.blocking_read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        FLASH_SIZE
+    }
+}
+
+#[cfg(any(flash_f4, flash_f7))]
+impl embedded_storage::nor_flash::MultiwriteNorFlash for Flash<'_> {}
+
+impl embedded_storage::nor_flash::NorFlash for Flash<'_> {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = MAX_ERASE_SIZE;
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.blocking_write(offset, bytes)
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.blocking_erase(from, to)
+    }
+}
+
+#[cfg(all(flash_f0, feature = "nightly"))]
+impl embedded_storage_async::nor_flash::ReadNorFlash for Flash<'_> {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.blocking_read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        FLASH_SIZE
+    }
+}
+
+#[cfg(all(flash_f0, feature = "nightly"))]
+impl embedded_storage_async::nor_flash::NorFlash for Flash<'_> {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = MAX_ERASE_SIZE;
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        Flash::write(self, offset, bytes).await
+    }
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.erase_range(from, to, |_, _| {}).await
+    }
+}
+
+// The runtime sized regions and parts only know their erase size at runtime. They implement the storage traits on
+// the families where all regions have the same erase size, elsewhere the typed `RegionPart` does.
+#[cfg(flash_uniform_erase_size)]
+impl embedded_storage::nor_flash::ErrorType for FlashRegion {
+    type Error = Error;
+}
+
+#[cfg(flash_uniform_erase_size)]
+impl embedded_storage::nor_flash::ReadNorFlash for FlashRegion {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.blocking_read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+
+#[cfg(all(flash_uniform_erase_size, any(flash_f4, flash_f7)))]
+impl embedded_storage::nor_flash::MultiwriteNorFlash for FlashRegion {}
+
+#[cfg(flash_uniform_erase_size)]
+impl embedded_storage::nor_flash::NorFlash for FlashRegion {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = MAX_ERASE_SIZE;
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.blocking_write(offset, bytes)
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.blocking_erase(from, to)
+    }
+}
+
+#[cfg(flash_uniform_erase_size)]
+impl embedded_storage::nor_flash::ErrorType for FlashPart<'_> {
+    type Error = Error;
+}
+
+#[cfg(flash_uniform_erase_size)]
+impl embedded_storage::nor_flash::ReadNorFlash for FlashPart<'_> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.blocking_read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.region.size as usize
+    }
+}
+
+#[cfg(all(flash_uniform_erase_size, any(flash_f4, flash_f7)))]
+impl embedded_storage::nor_flash::MultiwriteNorFlash for FlashPart<'_> {}
+
+#[cfg(flash_uniform_erase_size)]
+impl embedded_storage::nor_flash::NorFlash for FlashPart<'_> {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = MAX_ERASE_SIZE;
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.blocking_write(offset, bytes)
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.blocking_erase(from, to)
+    }
+}
+
+impl<const WRITE_SIZE: usize, const ERASE_SIZE: usize> embedded_storage::nor_flash::ErrorType
+    for RegionPart<'_, WRITE_SIZE, ERASE_SIZE>
+{
+    type Error = Error;
+}
+
+impl<const WRITE_SIZE: usize, const ERASE_SIZE: usize> embedded_storage::nor_flash::ReadNorFlash
+    for RegionPart<'_, WRITE_SIZE, ERASE_SIZE>
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.blocking_read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.region.size as usize
+    }
+}
+
+#[cfg(any(flash_f4, flash_f7))]
+impl<const WRITE_SIZE: usize, const ERASE_SIZE: usize> embedded_storage::nor_flash::MultiwriteNorFlash
+    for RegionPart<'_, WRITE_SIZE, ERASE_SIZE>
+{
+}
+
+impl<const WRITE_SIZE: usize, const ERASE_SIZE: usize> embedded_storage::nor_flash::NorFlash
+    for RegionPart<'_, WRITE_SIZE, ERASE_SIZE>
+{
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.blocking_write(offset, bytes)
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.blocking_erase(from, to)
+    }
+}
+
+foreach_flash_region! {
+    ($type_name:ident, $write_size:literal, $erase_size:literal) => {
+        impl<'d> crate::_generated::flash_regions::$type_name<'d> {
+            /// Splits the region into two independently owned parts at `offset`, see [`FlashRegion::split_at`].
+            ///
+            /// # Panics
+            /// Panics if `offset` is not aligned to the erase size, or lies outside the region.
+            pub fn split_at(
+                self,
+                offset: u32,
+            ) -> (RegionPart<'d, $write_size, $erase_size>, RegionPart<'d, $write_size, $erase_size>) {
+                let part = RegionPart {
+                    region: FlashRegion { ..*self.0 },
+                    _flash: PhantomData,
+                };
+                part.split_at(offset)
+            }
+
+            pub fn blocking_read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+                blocking_read(self.0.base, self.0.size, offset, bytes)
+            }
+
+            /// Returns `len` bytes at `offset` directly from the memory-mapped flash, see [`Flash::map`].
+            pub fn map(&self, offset: u32, len: usize) -> Result<Mapped<'_>, Error> {
+                let bytes = mapped::map(self.0.base, self.0.size, offset, len)?;
+                Ok(Mapped::new(self.0.base + offset, bytes))
+            }
+
+            pub fn blocking_write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+                unsafe { blocking_write::<$write_size>(self.0.base, self.0.size, offset, bytes) }
+            }
+
+            pub fn blocking_write_iter<'a>(
+                &mut self,
+                offset: u32,
+                chunks: impl IntoIterator<Item = &'a [u8]>,
+            ) -> Result<(), Error> {
+                unsafe { blocking_write_iter::<$write_size>(self.0.base, self.0.size, offset, chunks) }
+            }
+
+            pub fn blocking_erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+                unsafe { blocking_erase(self.0.base, self.0.size, from, to, false) }
+            }
+
+            /// Erases `from..to`, also if the range contains the running program.
+            pub fn blocking_erase_self(&mut self, from: u32, to: u32, _allow: AllowSelfErase) -> Result<(), Error> {
+                unsafe { blocking_erase(self.0.base, self.0.size, from, to, true) }
+            }
+        }
+
+        impl embedded_storage::nor_flash::ErrorType for crate::_generated::flash_regions::$type_name<'_> {
+            type Error = Error;
+        }
+
+        impl embedded_storage::nor_flash::ReadNorFlash for crate::_generated::flash_regions::$type_name<'_> {
+            const READ_SIZE: usize = 1;
+
+            fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+                self.blocking_read(offset, bytes)
+            }
+
+            fn capacity(&self) -> usize {
+                self.0.size as usize
+            }
+        }
+
+        #[cfg(any(flash_f4, flash_f7))]
+        impl embedded_storage::nor_flash::MultiwriteNorFlash for crate::_generated::flash_regions::$type_name<'_> {}
+
+        impl embedded_storage::nor_flash::NorFlash for crate::_generated::flash_regions::$type_name<'_> {
+            const WRITE_SIZE: usize = $write_size;
+            const ERASE_SIZE: usize = $erase_size;
+
+            fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+                self.blocking_write(offset, bytes)
             }
 
             fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
@@ -237,3 +2828,882 @@ foreach_flash_region! {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::NorFlash;
+
+    use super::*;
+    use crate::flash::mem_flash::{MemFlash, MockFlash};
+
+    /// Small xorshift generator so the chunkings are random but reproducible.
+    struct XorShift(u32);
+
+    impl XorShift {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+    }
+
+    #[test]
+    fn can_stage_random_chunks() {
+        const SIZE: usize = 1024;
+
+        let mut rng = XorShift(0x1234_5678);
+        for _ in 0..200 {
+            let len = (rng.next() as usize % (SIZE / WRITE_SIZE)) * WRITE_SIZE;
+            let start = (rng.next() as usize % ((SIZE - len) / WRITE_SIZE + 1)) * WRITE_SIZE;
+            let mut data = [0; SIZE];
+            for byte in data[..len].iter_mut() {
+                *byte = rng.next() as u8;
+            }
+
+            // Split the data at random points, including empty chunks
+            let mut chunks = [&data[..0]; 32];
+            let mut rest = &data[..len];
+            for chunk in chunks.iter_mut().take(31) {
+                let n = core::cmp::min(rng.next() as usize % 48, rest.len());
+                (*chunk, rest) = rest.split_at(n);
+            }
+            chunks[31] = rest;
+
+            let mut flash = MemFlash::<SIZE, 128, WRITE_SIZE>::default();
+            stage_chunks::<WRITE_SIZE>(start as u32, chunks, |address, unit| flash.write(address, unit)).unwrap();
+
+            assert_eq!(&data[..len], &flash.mem[start..start + len]);
+            assert!(flash.mem[..start].iter().all(|&b| b == 0xFF));
+            assert!(flash.mem[start + len..].iter().all(|&b| b == 0xFF));
+        }
+    }
+
+    #[test]
+    fn can_stage_units_of_any_write_size() {
+        let data: [u8; 48] = core::array::from_fn(|i| i as u8);
+        let chunks = [&data[..3], &data[3..21], &data[21..]];
+
+        let mut small = MemFlash::<64, 64, 2>::default();
+        stage_chunks::<2>(0, chunks, |address, unit| small.write(address, unit)).unwrap();
+        assert_eq!(data, small.mem[..48]);
+
+        let mut large = MemFlash::<64, 64, 16>::default();
+        stage_chunks::<16>(16, chunks, |address, unit| large.write(address, unit)).unwrap();
+        assert_eq!(data, large.mem[16..]);
+    }
+
+    #[test]
+    fn can_pad_unaligned_writes() {
+        let mem: [u8; 16] = core::array::from_fn(|i| 0xF0 + i as u8);
+        let current = |offset: u32| -> [u8; 4] { mem[offset as usize..offset as usize + 4].try_into().unwrap() };
+        let data: [u8; 9] = core::array::from_fn(|i| i as u8);
+
+        // Aligned, no padding
+        let padded = pad_unaligned::<4>(4, &data[..8], current);
+        assert_eq!(
+            (4, None, &data[..8], None),
+            (padded.start, padded.head, padded.body, padded.tail)
+        );
+
+        // Odd start and end
+        let padded = pad_unaligned::<4>(3, &data, current);
+        assert_eq!(0, padded.start);
+        assert_eq!(Some([0xF0, 0xF1, 0xF2, 0]), padded.head);
+        assert_eq!(&data[1..9], padded.body);
+        assert_eq!(None, padded.tail);
+
+        let padded = pad_unaligned::<4>(2, &data[..5], current);
+        assert_eq!(0, padded.start);
+        assert_eq!(Some([0xF0, 0xF1, 0, 1]), padded.head);
+        assert_eq!(&[] as &[u8], padded.body);
+        assert_eq!(Some([2, 3, 4, 0xF7]), padded.tail);
+
+        // Within a single unit
+        let padded = pad_unaligned::<4>(9, &data[..2], current);
+        assert_eq!(8, padded.start);
+        assert_eq!(Some([0xF8, 0, 1, 0xFB]), padded.head);
+        assert_eq!((&[] as &[u8], None), (padded.body, padded.tail));
+
+        // Aligned start, odd length
+        let padded = pad_unaligned::<2>(4, &data[..3], |offset| [mem[offset as usize], mem[offset as usize + 1]]);
+        assert_eq!((4, None, &data[..2]), (padded.start, padded.head, padded.body));
+        assert_eq!(Some([2, 0xF7]), padded.tail);
+    }
+
+    #[test]
+    fn can_check_overwrite_rules() {
+        use crate::flash::Rewrite;
+
+        assert!(Rewrite::Never.can_program(&[0xFF, 0xFF], &[0x12, 0x34], 0xFF));
+        assert!(Rewrite::Never.can_program(&[0x00, 0x00], &[0x12, 0x34], 0x00));
+        assert!(!Rewrite::Zeros.can_program(&[0xFF, 0x00], &[0x12, 0x34], 0xFF));
+        assert!(Rewrite::Zeros.can_program(&[0x12, 0x34], &[0x00, 0x00], 0xFF));
+        assert!(!Rewrite::Never.can_program(&[0x12, 0x34], &[0x00, 0x00], 0xFF));
+        assert!(!Rewrite::Zeros.can_program(&[0x12, 0x34], &[0x12, 0x34], 0xFF));
+
+        // Clearing bits works on families without ECC, setting them doesn't
+        assert!(Rewrite::ClearBits.can_program(&[0x13, 0x34], &[0x12, 0x30], 0xFF));
+        assert!(Rewrite::ClearBits.can_program(&[0x12, 0x34], &[0x00, 0x00], 0xFF));
+        assert!(!Rewrite::ClearBits.can_program(&[0x12, 0x34], &[0x13, 0x34], 0xFF));
+    }
+
+    /// Builds a random layout of up to four contiguous regions with power of two erase sizes.
+    fn random_layout(rng: &mut XorShift) -> ([FlashRegion; 4], usize) {
+        let count = 1 + rng.next() as usize % 4;
+        let mut base = 0x0800_0000;
+        let regions = core::array::from_fn(|i| {
+            let erase_size = 256 << (rng.next() % 6);
+            let region = FlashRegion {
+                bank: if i >= 2 { FlashBank::Bank2 } else { FlashBank::Bank1 },
+                base,
+                size: erase_size * (1 + rng.next() % 8),
+                erase_size,
+                write_size: 4,
+                erase_value: 0xFF,
+            };
+            base += region.size;
+            region
+        });
+        (regions, count)
+    }
+
+    #[test]
+    fn can_check_ranges() {
+        let mut rng = XorShift(0x0bad_cafe);
+        for _ in 0..1000 {
+            let size = rng.next() % 0x1_0000;
+            let offset = rng.next() % 0x1_2000;
+            let len = rng.next() as usize % 0x1000;
+            assert_eq!(
+                offset as usize + len <= size as usize,
+                check_range(size, offset, len).is_ok()
+            );
+        }
+
+        // Degenerate cases
+        assert!(check_range(0x800, 0x800, 0).is_ok());
+        assert!(check_range(0x800, 0, 0x800).is_ok());
+        assert!(check_range(0x800, 0x7FF, 1).is_ok());
+        assert!(check_range(0x800, 0x800, 1).is_err());
+        assert!(check_range(0x800, u32::MAX, 2).is_err());
+        assert_eq!(Err(Error::OutOfBounds), check_range(0x800, 0x7FF, 2));
+    }
+
+    #[test]
+    fn can_find_mismatches() {
+        let data = [0x12, 0x34, 0x56, 0x78];
+        assert_eq!(None, first_mismatch(&data, |i| data[i]));
+        assert_eq!(Some(2), first_mismatch(&[0x12, 0x34, 0x00, 0x78], |i| data[i]));
+        assert_eq!(Some(0), first_mismatch(&data, |_| 0xFF));
+        assert_eq!(None, first_mismatch(&[0xFF; 4], |_| 0xFF));
+        assert_eq!(None, first_mismatch(&[], |_| 0xFF));
+    }
+
+    #[test]
+    fn can_check_erase_ranges() {
+        let mut rng = XorShift(0x1357_9bdf);
+        for _ in 0..500 {
+            let (storage, count) = random_layout(&mut rng);
+            let layout = &storage[..count];
+            let regions: [&FlashRegion; 4] = core::array::from_fn(|i| &storage[i]);
+            let regions = &regions[..count];
+            let base = layout[0].base;
+            let size = layout[count - 1].end() - base;
+
+            let is_boundary = |offset: u32| {
+                offset == size
+                    || layout.iter().any(|region| {
+                        let address = base + offset;
+                        address >= region.base
+                            && address < region.end()
+                            && (address - region.base) % region.erase_size == 0
+                    })
+            };
+
+            for _ in 0..20 {
+                let from = rng.next() % (size + 0x400);
+                let to = rng.next() % (size + 0x400);
+                // Empty ranges are accepted anywhere within the flash
+                let expected = from <= to && to <= size && (from == to || (is_boundary(from) && is_boundary(to)));
+                assert_eq!(expected, check_erase_range(base, size, from, to, regions).is_ok());
+            }
+
+            // The complete flash and empty ranges at the end
+            assert!(check_erase_range(base, size, 0, size, regions).is_ok());
+            assert!(check_erase_range(base, size, size, size, regions).is_ok());
+
+            // Every sector contains its address and agrees with the sector count of its region
+            for _ in 0..20 {
+                let address = base + rng.next() % size;
+                let sector = get_sector(address, regions);
+                assert!(sector.start <= address && address < sector.start + sector.size);
+                let region = layout.iter().find(|r| r.base <= address && address < r.end()).unwrap();
+                assert_eq!(region.erase_size, sector.size);
+                assert_eq!(0, (sector.start - region.base) % region.erase_size);
+
+                let first_in_bank = layout.iter().position(|r| r.bank == region.bank).unwrap();
+                let before: u32 = layout
+                    .iter()
+                    .skip(first_in_bank)
+                    .take_while(|r| r.base < region.base)
+                    .map(|r| r.sectors() as u32)
+                    .sum();
+                let index = before + (sector.start - region.base) / region.erase_size;
+                assert_eq!(index, sector.index_in_bank as u32);
+            }
+            assert_eq!(None, find_sector(base + size, regions));
+            assert_eq!(None, find_sector(base - 1, regions));
+        }
+    }
+
+    #[test]
+    fn can_poison_on_abandoned_operation() {
+        PendingOperation::start().complete();
+        assert!(!take_poisoned());
+
+        drop(PendingOperation::start());
+        assert!(take_poisoned());
+        assert!(!take_poisoned());
+    }
+
+    #[test]
+    fn can_reject_unaligned_total_length() {
+        let mut flash = MemFlash::<64, 64, WRITE_SIZE>::default();
+        let data = [0x55; WRITE_SIZE + 1];
+        let chunks = [&data[..1], &data[1..]];
+
+        assert_eq!(
+            Err(Error::Unaligned),
+            stage_chunks::<WRITE_SIZE>(0, chunks, |address, unit| flash.write(address, unit))
+        );
+        assert_eq!(&data[..WRITE_SIZE], &flash.mem[..WRITE_SIZE]);
+        assert_eq!(0xFF, flash.mem[WRITE_SIZE]);
+    }
+
+    #[test]
+    fn can_split_into_parts() {
+        const REGION: FlashRegion = FlashRegion {
+            bank: FlashBank::Bank1,
+            base: 0x0800_0000,
+            size: 0x4000,
+            erase_size: 0x400,
+            write_size: 2,
+            erase_value: 0xFF,
+        };
+
+        let [config, log] = split_parts(0x0800_0000, &[&REGION], [0x800, 0x4000]);
+        assert_eq!((0x0800_0000, 0x800), (config.base, config.size));
+        assert_eq!((0x0800_0800, 0x3800), (log.base, log.size));
+        assert_eq!(REGION.erase_size, log.erase_size);
+
+        fn assert_send<T: Send>() {}
+        assert_send::<FlashPart<'static>>();
+    }
+
+    #[test]
+    #[should_panic]
+    fn can_reject_parts_outside_flash() {
+        const REGION: FlashRegion = FlashRegion {
+            bank: FlashBank::Bank1,
+            base: 0x0800_0000,
+            size: 0x4000,
+            erase_size: 0x400,
+            write_size: 2,
+            erase_value: 0xFF,
+        };
+
+        split_parts(0x0800_0000, &[&REGION], [0x800, 0x4400]);
+    }
+
+    #[test]
+    fn can_detect_same_bank_reads() {
+        const BANK1: FlashRegion = FlashRegion {
+            bank: FlashBank::Bank1,
+            base: 0x0800_0000,
+            size: 0x1000,
+            erase_size: 0x800,
+            write_size: 8,
+            erase_value: 0xFF,
+        };
+        const BANK2: FlashRegion = FlashRegion {
+            bank: FlashBank::Bank2,
+            base: 0x0800_1000,
+            ..BANK1
+        };
+        let regions = [&BANK1, &BANK2];
+
+        assert!(reads_bank(&regions, FlashBank::Bank1, 0x0800_0FFC, 4));
+        assert!(!reads_bank(&regions, FlashBank::Bank1, 0x0800_1000, 0x1000));
+        assert!(reads_bank(&regions, FlashBank::Bank2, 0x0800_1FFC, 4));
+        // Crossing into the busy bank
+        assert!(reads_bank(&regions, FlashBank::Bank1, 0x0800_0FFC, 8));
+        assert!(reads_bank(&regions, FlashBank::Bank2, 0x0800_0FFC, 8));
+        // A single-bank device
+        assert!(reads_bank(&[&BANK1], FlashBank::Bank1, 0x0800_0000, 4));
+        assert!(!reads_bank(&regions, FlashBank::Bank1, 0x0800_1000, 0));
+    }
+
+    #[test]
+    fn can_check_read_only_areas() {
+        let areas = [0x1FFF_0000..0x1FFF_7000, 0x1FFF_7800..0x1FFF_7810];
+
+        assert!(is_readable(&areas, 0x1FFF_0000, 0x7000));
+        assert!(is_readable(&areas, 0x1FFF_780C, 4));
+        assert!(!is_readable(&areas, 0x1FFF_6FFC, 8));
+        assert!(!is_readable(&areas, 0x1FFF_7400, 4));
+        assert!(!is_readable(&areas, 0xFFFF_FFFC, 8));
+    }
+
+    #[test]
+    fn can_detect_erases_of_running_image() {
+        let image = [
+            0x0800_0000..0x0800_0001,
+            0x0800_0400..0x0800_3000,
+            0x0800_3000..0x0800_3800,
+            0x2000_0000..0x2000_0000,
+        ];
+        assert!(overlaps_any(0x0800_0000, 0x0800_0800, &image));
+        assert!(overlaps_any(0x0800_2800, 0x0800_3000, &image));
+        assert!(overlaps_any(0x0800_3000, 0x0800_4000, &image));
+        assert!(!overlaps_any(0x0800_3800, 0x0800_4000, &image));
+        // Empty parts of the image, like a missing .data section, never overlap
+        assert!(!overlaps_any(0x1FFF_F000, 0x2000_1000, &image));
+        assert!(!overlaps_any(0x0800_1000, 0x0800_1000, &image));
+
+        assert_eq!(Ok(()), check_self_erase(0x0800_0000, 0x0800_0800, true));
+    }
+
+    #[test]
+    fn can_span_contiguous_regions() {
+        const FIRST: FlashRegion = FlashRegion {
+            bank: FlashBank::Bank1,
+            base: 0x0800_0000,
+            size: 0x2_0000,
+            erase_size: 0x800,
+            write_size: 8,
+            erase_value: 0xFF,
+        };
+        const SECOND: FlashRegion = FlashRegion {
+            base: 0x0802_0000,
+            bank: FlashBank::Bank2,
+            ..FIRST
+        };
+        const OTP: FlashRegion = FlashRegion {
+            bank: FlashBank::Otp,
+            base: 0x0804_0000,
+            size: 0x400,
+            erase_size: 0x400,
+            write_size: 4,
+            erase_value: 0xFF,
+        };
+        const DETACHED: FlashRegion = FlashRegion {
+            base: 0x0805_0000,
+            ..FIRST
+        };
+        let regions = [&FIRST, &SECOND, &OTP, &DETACHED];
+
+        // An image that straddles the boundary is split
+        assert_eq!(Ok(()), check_spanned_regions(0x0801_FF00, 0x0802_0100, &regions));
+        assert!(
+            region_spans(0x0801_FF00, 0x0802_0100, &regions).eq([0x0801_FF00..0x0802_0000, 0x0802_0000..0x0802_0100])
+        );
+        assert_eq!(
+            Ok(()),
+            check_erase_range(0x0800_0000, 0x4_0000, 0x1_F800, 0x2_0800, &regions)
+        );
+        // Ranges ending right at a boundary don't touch the next region
+        assert_eq!(Ok(()), check_spanned_regions(0x0803_FF00, 0x0804_0000, &regions));
+        assert_eq!(1, region_spans(0x0803_FF00, 0x0804_0000, &regions).count());
+        assert_eq!(Ok(()), check_spanned_regions(0x0804_0000, 0x0804_0000, &regions));
+
+        // Program flash and OTP are adjacent, but have different write sizes
+        assert_eq!(
+            Err(Error::IncompatibleRegions),
+            check_spanned_regions(0x0803_FF00, 0x0804_0100, &regions)
+        );
+        // Gaps between regions are not flash
+        assert_eq!(
+            Err(Error::OutOfBounds),
+            check_spanned_regions(0x0804_0300, 0x0805_0100, &regions)
+        );
+        assert_eq!(
+            Err(Error::OutOfBounds),
+            check_spanned_regions(0x0804_0400, 0x0804_0404, &regions)
+        );
+    }
+
+    #[test]
+    fn can_carve_regions_from_ranges() {
+        let regions = [&SMALL, &LARGE];
+
+        let region = region_in(0x0800_1000, 0x0800_3000, &regions).unwrap();
+        assert_eq!(
+            (0x0800_1000, 0x2000, 0x800),
+            (region.base, region.size, region.erase_size)
+        );
+        let region = region_in(0x0800_0400, 0x0800_0800, &regions).unwrap();
+        assert_eq!(
+            (0x0800_0400, 0x400, 0x400),
+            (region.base, region.size, region.erase_size)
+        );
+
+        assert_eq!(
+            Err(Error::Size),
+            region_in(0x0800_0400, 0x0800_0400, &regions).map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::Unaligned),
+            region_in(0x0800_0200, 0x0800_0800, &regions).map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::Unaligned),
+            region_in(0x0800_1000, 0x0800_1400, &regions).map(|_| ())
+        );
+        // The erase sizes of the two regions differ
+        assert_eq!(
+            Err(Error::IncompatibleRegions),
+            region_in(0x0800_0C00, 0x0800_1800, &regions).map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::OutOfBounds),
+            region_in(0x0800_2000, 0x0800_4000, &regions).map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::OutOfBounds),
+            region_in(0x2000_0000, 0x2000_0400, &regions).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn can_partition_regions() {
+        let regions = [&SMALL, &LARGE];
+
+        // Out of order and with a gap
+        let parts = partition_regions(0x0800_0000, &regions, &[0x1000..0x3000, 0..0x400, 0xC00..0x1000]);
+        let parts = parts.unwrap().map(|part| (part.base, part.size));
+        assert_eq!(
+            [(0x0800_1000, 0x2000), (0x0800_0000, 0x400), (0x0800_0C00, 0x400)],
+            parts
+        );
+
+        assert_eq!(
+            Err(Error::Overlap),
+            partition_regions(0x0800_0000, &regions, &[0..0x800, 0x400..0xC00]).map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::Overlap),
+            partition_regions(0x0800_0000, &regions, &[0x400..0x800, 0..0x1000]).map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::Unaligned),
+            partition_regions(0x0800_0000, &regions, &[0..0x200]).map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::Size),
+            partition_regions(0x0800_0000, &regions, &[0..0x400, 0x800..0x800]).map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::OutOfBounds),
+            partition_regions(0x0800_0000, &regions, &[0..0x400, 0x400..u32::MAX]).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn can_check_overwrites() {
+        let regions = [&SMALL, &LARGE];
+        let check =
+            |offset, len, preserve: &Preserve| check_overwrite(0x0800_0000, 0x3000, offset, len, preserve, &regions);
+
+        // Only the touched sectors are erased
+        assert_eq!(Ok(0x0800_0400..0x0800_0800), check(0x400, 0x400, &Preserve::Nothing));
+        assert_eq!(Ok(0x0800_0C00..0x0800_1800), check(0xC10, 0x800, &Preserve::Nothing));
+        assert_eq!(Ok(0x0800_0000..0x0800_0000), check(0, 0, &Preserve::Nothing));
+        assert_eq!(Err(Error::OutOfBounds), check(0x2FF0, 0x20, &Preserve::Nothing));
+        // Without preservation, the data is written as is
+        if WRITE_SIZE > 1 {
+            assert_eq!(Err(Error::Unaligned), check(0x401, WRITE_SIZE, &Preserve::Nothing));
+        }
+
+        // The buffer must hold the largest partially covered sector, fully covered sectors don't need it
+        let mut buf = [0; 0x400];
+        assert_eq!(Ok(0x0800_0400..0x0800_0800), check(0x402, 3, &Preserve::Ram(&mut buf)));
+        assert_eq!(Err(Error::Size), check(0xC00, 0x410, &Preserve::Ram(&mut buf)));
+        assert_eq!(
+            Ok(0x0800_1000..0x0800_1800),
+            check(0x1000, 0x800, &Preserve::Ram(&mut []))
+        );
+
+        // The scratch sector must be a large enough sector outside of the touched ones
+        assert_eq!(
+            Ok(0x0800_0400..0x0800_0800),
+            check(0x402, 3, &Preserve::Scratch(0x2800))
+        );
+        assert_eq!(
+            Ok(0x0800_1000..0x0800_1800),
+            check(0x1010, 4, &Preserve::Scratch(0x2800))
+        );
+        assert_eq!(Err(Error::Size), check(0x1010, 4, &Preserve::Scratch(0xC00)));
+        assert_eq!(Err(Error::Size), check(0x1010, 4, &Preserve::Scratch(0x1000)));
+        assert_eq!(Err(Error::Unaligned), check(0x402, 3, &Preserve::Scratch(0x2900)));
+        assert_eq!(Err(Error::OutOfBounds), check(0x402, 3, &Preserve::Scratch(0x3000)));
+    }
+
+    #[test]
+    fn can_check_program_units() {
+        const FIRST: FlashRegion = FlashRegion {
+            bank: FlashBank::Bank1,
+            base: 0x0800_0000,
+            size: 0x1000,
+            erase_size: 0x400,
+            write_size: 4,
+            erase_value: 0xFF,
+        };
+        const DETACHED: FlashRegion = FlashRegion {
+            base: 0x0800_2000,
+            ..FIRST
+        };
+        let regions = [&FIRST, &DETACHED];
+        let check = |offset| check_unit(0x0800_0000, 0x3000, offset, &regions);
+
+        assert_eq!(Ok(0x0800_0000), check(0));
+        assert_eq!(Ok(0x0800_1000 - WRITE_SIZE as u32), check(0x1000 - WRITE_SIZE as u32));
+        assert_eq!(Ok(0x0800_2000), check(0x2000));
+        // Between the regions and past the end of the flash
+        assert_eq!(Err(Error::OutOfBounds), check(0x1000));
+        assert_eq!(Err(Error::OutOfBounds), check(0x3000));
+        assert_eq!(Err(Error::OutOfBounds), check(u32::MAX - 1));
+        if WRITE_SIZE > 1 {
+            assert_eq!(Err(Error::Unaligned), check(1));
+        }
+    }
+
+    #[test]
+    fn can_validate_sectors() {
+        let regions = [&SMALL, &LARGE];
+        let sector = |start, size| FlashSector {
+            bank: FlashBank::Bank1,
+            index_in_bank: 0,
+            start,
+            size,
+        };
+
+        assert_eq!(Ok(()), validate_sector(&sector(0x0800_0000, 0x400), &regions));
+        assert_eq!(Ok(()), validate_sector(&sector(0x0800_0C00, 0x400), &regions));
+        // The last page of the flash
+        assert_eq!(Ok(()), validate_sector(&sector(0x0800_2800, 0x800), &regions));
+        assert_eq!(get_sector(0x0800_2FFF, &regions), sector(0x0800_2800, 0x800));
+
+        assert_eq!(
+            Err(Error::Unaligned),
+            validate_sector(&sector(0x0800_0200, 0x400), &regions)
+        );
+        assert_eq!(
+            Err(Error::Unaligned),
+            validate_sector(&sector(0x0800_1400, 0x400), &regions)
+        );
+        assert_eq!(
+            Err(Error::OutOfBounds),
+            validate_sector(&sector(0x0800_3000, 0x800), &regions)
+        );
+        assert_eq!(
+            Err(Error::OutOfBounds),
+            validate_sector(&sector(0x0700_0000, 0x400), &regions)
+        );
+    }
+
+    /// Two adjacent regions with different erase sizes, like the sectors of the F4 and F7.
+    const SMALL: FlashRegion = FlashRegion {
+        bank: FlashBank::Bank1,
+        base: 0x0800_0000,
+        size: 0x1000,
+        erase_size: 0x400,
+        write_size: 4,
+        erase_value: 0xFF,
+    };
+    const LARGE: FlashRegion = FlashRegion {
+        base: 0x0800_1000,
+        size: 0x2000,
+        erase_size: 0x800,
+        ..SMALL
+    };
+
+    const MOCK_REGION: FlashRegion = FlashRegion {
+        bank: FlashBank::Bank1,
+        base: 0x0800_0000,
+        size: 0x1000,
+        erase_size: 0x400,
+        write_size: WRITE_SIZE as u32,
+        erase_value: 0xFF,
+    };
+
+    fn mock_write(flash: &mut MockFlash<0x1000>, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        let (base, size) = (MOCK_REGION.base, MOCK_REGION.size);
+        unsafe { write_chunks::<_, WRITE_SIZE>(flash, &[&MOCK_REGION], base, size, offset, bytes) }
+    }
+
+    fn mock_erase(flash: &mut MockFlash<0x1000>, from: u32, to: u32) -> Result<(), EraseError> {
+        let (base, size) = (MOCK_REGION.base, MOCK_REGION.size);
+        unsafe { erase_range(flash, &[&MOCK_REGION], base, size, from, to, false) }
+    }
+
+    #[test]
+    fn can_write_chunks_on_mock() {
+        let mut flash = MockFlash::<0x1000>::new(MOCK_REGION.base, 0xFF);
+        let data: [u8; 64] = core::array::from_fn(|i| i as u8);
+        // Off the half pages of the L0, so that all units are single words
+        let offset = 0x100 + WRITE_SIZE;
+
+        // The controller is unlocked once for the whole write
+        mock_write(&mut flash, offset as u32, &data).unwrap();
+        assert_eq!(data, flash.mem[offset..offset + 64]);
+        assert_eq!(1, flash.unlocks);
+        assert!(!flash.unlocked);
+
+        // Nothing is programmed past the end
+        let end = 0x1000 - WRITE_SIZE as u32;
+        assert_eq!(
+            Err(Error::OutOfBounds),
+            mock_write(&mut flash, end, &data[..2 * WRITE_SIZE])
+        );
+        assert_eq!(1, flash.unlocks);
+
+        // Programming can't set bits again
+        assert_eq!(
+            Err(Error::Prog),
+            mock_write(&mut flash, offset as u32, &[0xFF; WRITE_SIZE])
+        );
+        assert!(!flash.unlocked);
+
+        // A failure stops the write after the units before it, and locks the controller
+        let offset = 0x200 + WRITE_SIZE;
+        let failed = offset + 2 * WRITE_SIZE;
+        flash.fail_at = Some((MOCK_REGION.base + failed as u32, Error::Protected));
+        assert_eq!(Err(Error::Protected), mock_write(&mut flash, offset as u32, &data));
+        assert_eq!(data[..2 * WRITE_SIZE], flash.mem[offset..failed]);
+        assert!(flash.mem[failed..offset + 64].iter().all(|&b| b == 0xFF));
+        assert!(!flash.unlocked);
+    }
+
+    #[test]
+    fn can_lock_after_failed_unit() {
+        let mut flash = MockFlash::<0x1000>::new(MOCK_REGION.base, 0xFF);
+        let address = MOCK_REGION.base + 0x100;
+
+        // The failure happens after unlocking, the guard locks the controller again
+        flash.fail_at = Some((address, Error::Seq));
+        flash.fail_count = Some(1);
+        assert_eq!(Err(Error::Seq), unsafe {
+            write_unit(&mut flash, address, &[0; WRITE_SIZE])
+        });
+        assert_eq!(1, flash.unlocks);
+        assert!(!flash.unlocked);
+
+        // So the next unit can be programmed
+        assert_eq!(Ok(()), unsafe { write_unit(&mut flash, address, &[0; WRITE_SIZE]) });
+        assert_eq!([0; WRITE_SIZE], flash.mem[0x100..0x100 + WRITE_SIZE]);
+        assert!(!flash.unlocked);
+    }
+
+    #[test]
+    fn can_erase_range_on_mock() {
+        let mut flash = MockFlash::<0x1000>::new(MOCK_REGION.base, 0x00);
+
+        // The range is erased with a single unlock
+        mock_erase(&mut flash, 0x400, 0xC00).unwrap();
+        assert!(flash.mem[..0x400].iter().all(|&b| b == 0x00));
+        assert!(flash.mem[0x400..0xC00].iter().all(|&b| b == 0xFF));
+        assert!(flash.mem[0xC00..].iter().all(|&b| b == 0x00));
+        assert_eq!(1, flash.unlocks);
+        assert!(!flash.unlocked);
+
+        // Invalid ranges are rejected at their start, without unlocking
+        let rejected = |offset, error| Err(EraseError { offset, error });
+        assert_eq!(rejected(0x200, Error::Unaligned), mock_erase(&mut flash, 0x200, 0x400));
+        assert_eq!(rejected(0, Error::OutOfBounds), mock_erase(&mut flash, 0, 0x2000));
+        assert_eq!(1, flash.unlocks);
+
+        // A failure reports the failed sector, the sectors before it stay erased
+        flash.mem.fill(0x00);
+        flash.fail_at = Some((0x0800_0804, Error::Prog));
+        assert_eq!(rejected(0x800, Error::Prog), mock_erase(&mut flash, 0x400, 0x1000));
+        assert!(flash.mem[0x400..0x800].iter().all(|&b| b == 0xFF));
+        assert!(flash.mem[0x800..].iter().all(|&b| b == 0x00));
+        assert!(!flash.unlocked);
+    }
+
+    #[test]
+    fn can_retry_failed_erase() {
+        let mut flash = MockFlash::<0x1000>::new(MOCK_REGION.base, 0x00);
+        let sector = get_sector(0x0800_0400, &[&MOCK_REGION]);
+        let erase = |flash: &mut MockFlash<0x1000>, retries| unsafe {
+            let mut backend = Unlocked::new(flash);
+            erase_retrying(&mut *backend.0, &sector, retries)
+        };
+
+        // Without retries, the error of the controller is returned
+        flash.fail_at = Some((0x0800_0400, Error::Prog));
+        flash.fail_count = Some(1);
+        assert_eq!(Err(Error::Prog), erase(&mut flash, 0));
+
+        // A single spurious failure is retried
+        flash.fail_count = Some(1);
+        assert_eq!(Ok(()), erase(&mut flash, 1));
+        assert!(flash.is_blank(&sector));
+
+        // The error reports all attempts
+        flash.fail_count = None;
+        assert_eq!(Err(Error::Erase { attempts: 3 }), erase(&mut flash, 2));
+
+        // Other errors are not retried
+        flash.fail_at = Some((0x0800_0400, Error::Protected));
+        flash.fail_count = Some(1);
+        assert_eq!(Err(Error::Protected), erase(&mut flash, 2));
+        assert_eq!(Some(0), flash.fail_count);
+    }
+
+    #[test]
+    #[cfg(feature = "flash-stats")]
+    fn can_record_wear_on_mock() {
+        let mut flash = MockFlash::<0x1000>::new(MOCK_REGION.base, 0xFF);
+        let regions = [&MOCK_REGION];
+        let area = 0x0800_0800..0x0800_1000;
+        let record = |flash: &mut MockFlash<0x1000>, address| unsafe {
+            let mut backend = Unlocked::new(flash);
+            let sector = get_sector(address, &regions);
+            record_wear_in::<_, 0x400>(&mut *backend.0, area.clone(), &sector, &regions)
+        };
+
+        // Enough erases to fill the log of an accounting sector and continue in the other one
+        for _ in 0..300 {
+            record(&mut flash, 0x0800_0000).unwrap();
+        }
+        record(&mut flash, 0x0800_0400).unwrap();
+        // The erases of the area itself are not counted
+        record(&mut flash, 0x0800_0800).unwrap();
+        assert!(!flash.unlocked);
+
+        let area = WearArea::<_, 0x400> {
+            backend: &mut flash,
+            area,
+            regions: &regions,
+        };
+        let mut log = WearLog::new(area, sector_count(&regions));
+        assert_eq!(300, log.wear(0).unwrap());
+        assert_eq!(1, log.wear(1).unwrap());
+        assert_eq!(0, log.wear(2).unwrap());
+        assert_eq!((0, 300), log.most_worn().unwrap());
+    }
+
+    #[test]
+    fn can_copy_sectors_and_resume() {
+        let mut flash = MemFlash::<0x1000, 0x400, WRITE_SIZE>::default();
+        let regions = [&MOCK_REGION];
+        let base = MOCK_REGION.base;
+        for (i, byte) in flash.mem[..0x800].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        let mut reported = [0; 2];
+        let mut calls = 0;
+        let progress = |copied| {
+            reported[calls] = copied;
+            calls += 1;
+        };
+        copy_sectors(&mut flash, base, &regions, 0..0x800, 0x800..0x1000, 0, progress).unwrap();
+        assert_eq!([0x400, 0x800], reported);
+        assert_eq!(flash.mem[..0x800], flash.mem[0x800..]);
+
+        // A resumed copy leaves the completed sector alone
+        flash.mem[..0x800].fill(0x5A);
+        let mut calls = 0;
+        copy_sectors(&mut flash, base, &regions, 0..0x800, 0x800..0x1000, 0x400, |_| {
+            calls += 1
+        })
+        .unwrap();
+        assert_eq!(1, calls);
+        assert!(flash.mem[0x800..0xC00].iter().enumerate().all(|(i, &b)| b == i as u8));
+        assert!(flash.mem[0xC00..].iter().all(|&b| b == 0x5A));
+
+        // It can only continue at the start of a destination sector
+        assert_eq!(
+            Err(Error::Unaligned),
+            copy_sectors(&mut flash, base, &regions, 0..0x800, 0x800..0x1000, 0x200, |_| {})
+        );
+        assert_eq!(
+            Err(Error::Unaligned),
+            copy_sectors(&mut flash, base, &regions, 0..0x400, 0x200..0x600, 0, |_| {})
+        );
+    }
+
+    #[test]
+    fn can_stop_copy_on_failed_sector() {
+        let mut flash = MemFlash::<0x1000, 0x400, WRITE_SIZE>::default();
+        flash.mem[..0x800].fill(0x00);
+
+        // The first sector takes 0x400 / 64 writes, the second one fails
+        flash.pending_write_successes = Some(0x400 / 64);
+        let mut copied = 0;
+        assert_eq!(
+            Err(Error::Prog),
+            copy_sectors(
+                &mut flash,
+                MOCK_REGION.base,
+                &[&MOCK_REGION],
+                0..0x800,
+                0x800..0x1000,
+                0,
+                |n| copied = n
+            )
+        );
+        assert_eq!(0x400, copied);
+        assert!(flash.mem[0x800..0xC00].iter().all(|&b| b == 0x00));
+    }
+
+    #[test]
+    fn can_provision_only_differing_sectors() {
+        let mut flash = MemFlash::<0x1000, 0x400, WRITE_SIZE>::default();
+        let regions = [&MOCK_REGION];
+        let base = MOCK_REGION.base;
+        let mut data = [0x5A; 0x800];
+
+        assert_eq!(
+            Ok(Provisioned::Updated),
+            provision(&mut flash, base, &regions, 0x400, &data)
+        );
+        assert_eq!(data, flash.mem[0x400..0xC00]);
+
+        // Nothing is written when the data is there already
+        flash.pending_write_successes = Some(0);
+        assert_eq!(
+            Ok(Provisioned::Unchanged),
+            provision(&mut flash, base, &regions, 0x400, &data)
+        );
+
+        // Only the differing sector is erased and programmed, with a single write, the erase sets the bits again
+        flash.pending_write_successes = Some(1);
+        data[0x400..].fill(0xA5);
+        assert_eq!(
+            Ok(Provisioned::Updated),
+            provision(&mut flash, base, &regions, 0x400, &data)
+        );
+        assert_eq!(data, flash.mem[0x400..0xC00]);
+
+        // Erased bytes of a partially covered sector are programmed, the rest of the sector is kept
+        flash.pending_write_successes = None;
+        flash.mem[0xC00..0xD00].fill(0x11);
+        assert_eq!(
+            Ok(Provisioned::Updated),
+            provision(&mut flash, base, &regions, 0xD00, &data[..0x100])
+        );
+        assert!(flash.mem[0xC00..0xD00].iter().all(|&b| b == 0x11));
+        assert_eq!(data[..0x100], flash.mem[0xD00..0xE00]);
+
+        // A partially covered sector with other data isn't erased
+        assert_eq!(
+            Err(Error::Unaligned),
+            provision(&mut flash, base, &regions, 0xC00, &data[0x400..0x500])
+        );
+        assert!(flash.mem[0xC00..0xD00].iter().all(|&b| b == 0x11));
+    }
+}
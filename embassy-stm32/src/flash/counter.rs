@@ -0,0 +1,208 @@
+use embedded_storage::nor_flash::NorFlash;
+
+use super::MAX_WRITE_SIZE;
+
+/// Size of the `base` + `!base` sector header, before rounding up to the write size.
+const HEADER_LEN: usize = 8;
+
+/// Monotonic counter that survives power loss, stored in a dedicated flash area.
+///
+/// The area is split into its erase sectors. Each sector starts with a header holding the
+/// counter value at the time the sector was started, followed by one write unit per increment.
+/// An increment clears the next unit, so it is a single program operation. Only when a sector is
+/// full, the next sector is erased and started with the current value in its header.
+///
+/// The value is the highest `header + cleared units` of all sectors with a valid header, so an
+/// interrupted increment or sector switch can never make the counter go backwards.
+///
+/// The area must consist of at least two sectors, and must read as `0xFF` when erased.
+pub struct FlashCounter<F> {
+    flash: F,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct State {
+    sector: usize,
+    value: u32,
+    used: usize,
+}
+
+impl<F: NorFlash> FlashCounter<F> {
+    /// Create a counter that uses all of `flash`.
+    ///
+    /// # Panics
+    /// Panics if `flash` has less than two sectors or its write size is not supported.
+    pub fn new(flash: F) -> Self {
+        assert!(flash.capacity() / F::ERASE_SIZE >= 2);
+        assert!(F::WRITE_SIZE <= MAX_WRITE_SIZE);
+        Self { flash }
+    }
+
+    /// Release the underlying flash.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Read the current counter value.
+    pub fn read(&mut self) -> Result<u32, F::Error> {
+        Ok(self.scan()?.map_or(0, |state| state.value))
+    }
+
+    /// Increment the counter, returning the new value.
+    pub fn increment(&mut self) -> Result<u32, F::Error> {
+        let state = match self.scan()? {
+            None => self.start_sector(0, 0)?,
+            Some(state) if state.used == self.units_per_sector() => {
+                let next = (state.sector + 1) % self.sectors();
+                self.start_sector(next, state.value)?
+            }
+            Some(state) => state,
+        };
+
+        let zeros = [0; MAX_WRITE_SIZE];
+        let offset = self.unit_offset(state.sector, state.used);
+        self.flash.write(offset, &zeros[..F::WRITE_SIZE])?;
+        Ok(state.value + 1)
+    }
+
+    fn sectors(&self) -> usize {
+        self.flash.capacity() / F::ERASE_SIZE
+    }
+
+    fn header_size(&self) -> usize {
+        (HEADER_LEN + F::WRITE_SIZE - 1) / F::WRITE_SIZE * F::WRITE_SIZE
+    }
+
+    fn units_per_sector(&self) -> usize {
+        (F::ERASE_SIZE - self.header_size()) / F::WRITE_SIZE
+    }
+
+    fn unit_offset(&self, sector: usize, unit: usize) -> u32 {
+        (sector * F::ERASE_SIZE + self.header_size() + unit * F::WRITE_SIZE) as u32
+    }
+
+    /// Erase `sector` and mark it as starting at `base`.
+    fn start_sector(&mut self, sector: usize, base: u32) -> Result<State, F::Error> {
+        let start = (sector * F::ERASE_SIZE) as u32;
+        self.flash.erase(start, start + F::ERASE_SIZE as u32)?;
+
+        let mut header = [0xFF; HEADER_LEN + MAX_WRITE_SIZE];
+        header[..4].copy_from_slice(&base.to_le_bytes());
+        header[4..8].copy_from_slice(&(!base).to_le_bytes());
+        self.flash.write(start, &header[..self.header_size()])?;
+
+        Ok(State {
+            sector,
+            value: base,
+            used: 0,
+        })
+    }
+
+    /// Find the sector holding the highest value.
+    fn scan(&mut self) -> Result<Option<State>, F::Error> {
+        let mut best: Option<State> = None;
+
+        for sector in 0..self.sectors() {
+            let mut header = [0; HEADER_LEN];
+            self.flash.read((sector * F::ERASE_SIZE) as u32, &mut header)?;
+            let base = u32::from_le_bytes(header[..4].try_into().unwrap());
+            let check = u32::from_le_bytes(header[4..].try_into().unwrap());
+            if base != !check {
+                continue;
+            }
+
+            let mut used = 0;
+            let mut unit = [0; MAX_WRITE_SIZE];
+            while used < self.units_per_sector() {
+                self.flash
+                    .read(self.unit_offset(sector, used), &mut unit[..F::WRITE_SIZE])?;
+                if unit[..F::WRITE_SIZE].iter().all(|&b| b == 0xFF) {
+                    break;
+                }
+                used += 1;
+            }
+
+            let state = State {
+                sector,
+                value: base + used as u32,
+                used,
+            };
+            // On a tie, continue in the sector with free space
+            best = match best {
+                Some(b) if b.value > state.value || (b.value == state.value && b.used <= state.used) => Some(b),
+                _ => Some(state),
+            };
+        }
+
+        Ok(best)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mem_flash::{MemFlash, Operation, Recorder};
+
+    type Flash = MemFlash<256, 64, 4>;
+
+    #[test]
+    fn can_count_across_sectors() {
+        let mut counter = FlashCounter::new(Flash::default());
+        assert_eq!(0, counter.read().unwrap());
+
+        // 14 units per sector, so this wraps around all four sectors a few times
+        for i in 1..=200 {
+            assert_eq!(i, counter.increment().unwrap());
+        }
+
+        let mut counter = FlashCounter::new(counter.into_inner());
+        assert_eq!(200, counter.read().unwrap());
+    }
+
+    #[test]
+    fn erases_only_on_sector_switch() {
+        static RECORDER: Recorder = Recorder::new();
+        let mut flash = Flash::default();
+        flash.observer = Some(&RECORDER);
+        let mut counter = FlashCounter::new(flash);
+
+        counter.increment().unwrap();
+        RECORDER.assert_and_clear(&[Operation::Erase(0), Operation::Write(0, 8), Operation::Write(8, 4)]);
+
+        // Each further increment in the sector is a single program operation
+        for unit in 1..14 {
+            counter.increment().unwrap();
+            RECORDER.assert_and_clear(&[Operation::Write(8 + unit * 4, 4)]);
+        }
+
+        // The next sector is erased and started with the current value before its first unit
+        counter.increment().unwrap();
+        RECORDER.assert_and_clear(&[Operation::Erase(64), Operation::Write(64, 8), Operation::Write(72, 4)]);
+        assert_eq!(15, counter.read().unwrap());
+        RECORDER.assert_and_clear(&[]);
+    }
+
+    #[test]
+    fn never_goes_backwards_on_power_loss() {
+        let mut flash = Flash::default();
+        let mut acknowledged = 0;
+
+        for successes in (0..6).cycle().take(150) {
+            // Cut the power after `successes` writes
+            flash.pending_write_successes = Some(successes);
+            let mut counter = FlashCounter::new(flash);
+            while let Ok(value) = counter.increment() {
+                acknowledged = value;
+            }
+            flash = counter.into_inner();
+            flash.pending_write_successes = None;
+
+            let mut counter = FlashCounter::new(flash);
+            let value = counter.read().unwrap();
+            assert!(value >= acknowledged);
+            assert!(value <= acknowledged + 1);
+            acknowledged = value;
+            flash = counter.into_inner();
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use embassy_hal_common::into_ref;
+
+use super::{Error, Flash, FLASH_BASE, FLASH_SIZE};
+use crate::dma::{Channel, Transfer, TransferOptions};
+use crate::Peripheral;
+
+/// Alignment of the part of the buffer that is written by the DMA.
+///
+/// On the Cortex-M7 parts, cache lines that are only partly covered by the buffer are copied by the CPU instead,
+/// so that invalidating them after the transfer can't discard other data.
+#[cfg(any(stm32f7, stm32h7))]
+const ALIGN: usize = 32;
+#[cfg(not(any(stm32f7, stm32h7)))]
+const ALIGN: usize = 4;
+
+/// The largest number of items of a single transfer.
+const MAX_TRANSFER: usize = 0xFFFF;
+
+/// Reads shorter than this are done by the CPU, which is faster than setting up and awaiting a transfer.
+const MIN_DMA_LEN: usize = 64;
+
+impl Flash<'_> {
+    /// Reads from the flash at `offset` with memory to memory DMA transfers on `channel`.
+    ///
+    /// This frees the CPU while large amounts of data are read, e.g. to stream them to a display. The edges of
+    /// `bytes` that are not aligned are read by the CPU. On the F2, F4 and F7 parts, only the channels of DMA2 can
+    /// read the flash.
+    ///
+    /// The read is done by the CPU like [`Flash::blocking_read`] if `bytes` is shorter than 64 bytes, the range is
+    /// not in the main flash, or an erase started with [`Flash::try_start_erase`] is pending. The bounds are checked
+    /// like in [`Flash::blocking_read`] in all cases. Without a free channel, use [`Flash::blocking_read`] instead.
+    pub async fn read_dma(
+        &mut self,
+        channel: impl Peripheral<P = impl Channel>,
+        offset: u32,
+        bytes: &mut [u8],
+    ) -> Result<(), Error> {
+        if bytes.len() < MIN_DMA_LEN || offset as u64 + bytes.len() as u64 > FLASH_SIZE as u64 || self.has_pending() {
+            return self.blocking_read(offset, bytes);
+        }
+        into_ref!(channel);
+
+        let len = bytes.len();
+        let head = core::cmp::min(len, bytes.as_ptr().align_offset(ALIGN));
+        let tail = (len - head) % ALIGN;
+        let body = head..len - tail;
+        self.blocking_read(offset, &mut bytes[..head])?;
+        self.blocking_read(offset + body.end as u32, &mut bytes[body.end..])?;
+        if body.is_empty() {
+            return Ok(());
+        }
+
+        #[cfg(any(stm32f7, stm32h7))]
+        let mut scb = unsafe { cortex_m::Peripherals::steal() }.SCB;
+        // Write back and drop the cached lines of the buffer, so that they can't be evicted over the copied data
+        #[cfg(any(stm32f7, stm32h7))]
+        unsafe {
+            scb.clean_invalidate_dcache_by_address(bytes[body.clone()].as_ptr() as usize, body.len())
+        };
+
+        let src = FLASH_BASE as usize + offset as usize + body.start;
+        let dst = bytes[body.clone()].as_mut_ptr();
+        // Words are only copied if the source is aligned like the buffer
+        if src % 4 == 0 {
+            let words = body.len() / 4;
+            for start in (0..words).step_by(MAX_TRANSFER) {
+                let count = core::cmp::min(MAX_TRANSFER, words - start);
+                let src = core::ptr::slice_from_raw_parts((src as *const u32).wrapping_add(start), count);
+                let dst = core::ptr::slice_from_raw_parts_mut((dst as *mut u32).wrapping_add(start), count);
+                unsafe { Transfer::new_copy_raw(channel.reborrow(), src, dst, TransferOptions::default()) }.await;
+            }
+        } else {
+            for start in (0..body.len()).step_by(MAX_TRANSFER) {
+                let count = core::cmp::min(MAX_TRANSFER, body.len() - start);
+                let src = core::ptr::slice_from_raw_parts((src as *const u8).wrapping_add(start), count);
+                let dst = core::ptr::slice_from_raw_parts_mut(dst.wrapping_add(start), count);
+                unsafe { Transfer::new_copy_raw(channel.reborrow(), src, dst, TransferOptions::default()) }.await;
+            }
+        }
+
+        // Drop lines that were speculatively fetched during the transfer
+        #[cfg(any(stm32f7, stm32h7))]
+        unsafe {
+            scb.invalidate_dcache_by_address(bytes[body.clone()].as_ptr() as usize, body.len())
+        };
+
+        Ok(())
+    }
+}
@@ -1,9 +1,10 @@
-use core::convert::TryInto;
-use core::ptr::write_volatile;
+use core::future::poll_fn;
+use core::task::Poll;
 
 use atomic_polyfill::{fence, Ordering};
+use embassy_sync::waitqueue::AtomicWaker;
 
-use super::{FlashRegion, FlashSector, FLASH_REGIONS, WRITE_SIZE};
+use super::{FlashRegion, FlashSector, ProtectionCause, WriteUnit, FLASH_BASE, FLASH_REGIONS, WRITE_SIZE};
 use crate::flash::Error;
 use crate::pac;
 
@@ -11,6 +12,13 @@ pub const fn get_flash_regions() -> &'static [&'static FlashRegion] {
     &FLASH_REGIONS
 }
 
+/// Readable areas outside of the main flash: the end of the system memory (common to all F0 devices) with the
+/// calibration values, and the option bytes.
+pub(crate) const READ_ONLY_AREAS: &[core::ops::Range<u32>] = &[0x1FFF_EC00..0x1FFF_F800, 0x1FFF_F800..0x1FFF_F810];
+
+/// Woken by the FLASH interrupt when an operation ends.
+static WAKER: AtomicWaker = AtomicWaker::new();
+
 pub(crate) unsafe fn lock() {
     pac::FLASH.cr().modify(|w| w.set_lock(true));
 }
@@ -30,20 +38,43 @@ pub(crate) unsafe fn end_write() {
     pac::FLASH.cr().write(|w| w.set_pg(false));
 }
 
-pub(crate) unsafe fn blocking_write(start_address: u32, buf: &[u8; WRITE_SIZE]) -> Result<(), Error> {
-    let mut address = start_address;
-    for chunk in buf.chunks(2) {
-        write_volatile(address as *mut u16, u16::from_le_bytes(chunk.try_into().unwrap()));
-        address += chunk.len() as u32;
+pub(crate) unsafe fn blocking_write<const N: usize>(start_address: u32, buf: &[u8; N]) -> Result<(), Error> {
+    let () = WriteUnit::<N>::OK;
+    // The common layer only passes write size aligned addresses, so this is halfword aligned
+    let mut address = start_address as *mut u16;
+    for halfword in buf.chunks_exact(2) {
+        write_halfword(address, u16::from_le_bytes([halfword[0], halfword[1]]));
+        blocking_wait_ready()?;
+        address = address.add(1);
+    }
+    Ok(())
+}
 
-        // prevents parallelism errors
-        fence(Ordering::SeqCst);
+/// Starts programming `buf` without waiting for it, see [`wait_ready`].
+pub(crate) unsafe fn start_write(start_address: u32, buf: &[u8; WRITE_SIZE]) {
+    // The common layer only passes write size aligned addresses, so this is halfword aligned
+    let mut address = start_address as *mut u16;
+    for chunk in buf.chunks_exact(2) {
+        write_halfword(address, u16::from_le_bytes([chunk[0], chunk[1]]));
+        address = address.add(1);
     }
+}
 
-    blocking_wait_ready()
+/// Starts programming `halfword` at `address`.
+#[inline(always)]
+unsafe fn write_halfword(address: *mut u16, halfword: u16) {
+    address.write_volatile(halfword);
+
+    // The write must have reached the flash interface before BSY is checked
+    fence(Ordering::SeqCst);
 }
 
 pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), Error> {
+    start_erase_sector(sector);
+    finish_erase_sector(sector)
+}
+
+pub(crate) unsafe fn start_erase_sector(sector: &FlashSector) {
     pac::FLASH.cr().modify(|w| {
         w.set_per(true);
     });
@@ -53,8 +84,13 @@ pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), E
     pac::FLASH.cr().modify(|w| {
         w.set_strt(true);
     });
+}
 
+pub(crate) unsafe fn finish_erase_sector(_sector: &FlashSector) -> Result<(), Error> {
     let mut ret: Result<(), Error> = blocking_wait_ready();
+    if ret == Err(Error::Timeout) {
+        return ret;
+    }
 
     if !pac::FLASH.sr().read().eop() {
         trace!("FLASH: EOP not set");
@@ -72,6 +108,37 @@ pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), E
     Ok(())
 }
 
+/// Erases the complete main flash. A write protected page makes the controller refuse the erase with WRPRTERR.
+pub(crate) unsafe fn blocking_erase_all() -> Result<(), Error> {
+    pac::FLASH.cr().modify(|w| w.set_mer(true));
+    pac::FLASH.cr().modify(|w| w.set_strt(true));
+
+    let mut ret: Result<(), Error> = blocking_wait_ready();
+    if ret == Err(Error::Timeout) {
+        return ret;
+    }
+
+    if ret.is_ok() && !pac::FLASH.sr().read().eop() {
+        trace!("FLASH: EOP not set");
+        ret = Err(Error::Prog);
+    } else {
+        pac::FLASH.sr().write(|w| w.set_eop(true));
+    }
+
+    pac::FLASH.cr().modify(|w| w.set_mer(false));
+
+    clear_all_err();
+    ret
+}
+
+pub(crate) fn is_busy() -> bool {
+    unsafe { pac::FLASH.sr().read().bsy() }
+}
+
+pub(crate) unsafe fn wait_idle() {
+    while is_busy() {}
+}
+
 pub(crate) unsafe fn clear_all_err() {
     pac::FLASH.sr().modify(|w| {
         if w.pgerr() {
@@ -86,20 +153,621 @@ pub(crate) unsafe fn clear_all_err() {
     });
 }
 
+/// Size of the flash area covered by a single WRP bit.
+const WRP_GRANULE_SIZE: u32 = 4 * 1024;
+
+/// Write protection of the main flash, with a bit for each 4 KB granule that is set if the granule is protected.
+///
+/// Bit `n` covers the flash offsets `n * 4096..(n + 1) * 4096`. On devices with more than 128 KB, the last bit
+/// covers the rest of the flash.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WrpMask(pub u32);
+
+impl WrpMask {
+    /// Nothing is protected.
+    pub const NONE: Self = Self(0);
+
+    /// The granules covering the flash offsets `from..to`, e.g. the pages of a bootloader.
+    pub const fn covering(from: u32, to: u32) -> Self {
+        if from >= to {
+            return Self::NONE;
+        }
+        let first = granule(from);
+        let last = granule(to - 1);
+        Self((u32::MAX >> (31 - last)) & (u32::MAX << first))
+    }
+
+    /// The granules protected by either mask.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Whether the granule containing the flash offset `offset` is protected.
+    pub const fn is_protected(self, offset: u32) -> bool {
+        self.0 & (1 << granule(offset)) != 0
+    }
+}
+
+/// The WRP bit covering the flash offset `offset`.
+const fn granule(offset: u32) -> u32 {
+    let granule = offset / WRP_GRANULE_SIZE;
+    if granule > 31 {
+        31
+    } else {
+        granule
+    }
+}
+
+/// The write protection of the loaded option bytes.
+pub(crate) fn write_protection() -> WrpMask {
+    WrpMask(!unsafe { pac::FLASH.wrpr().read().0 })
+}
+
+pub(crate) fn protection_cause(address: u32) -> Option<ProtectionCause> {
+    let (obr, wrpr) = unsafe { (pac::FLASH.obr().read().0, pac::FLASH.wrpr().read().0) };
+    decode_protection(obr, wrpr, address - FLASH_BASE as u32)
+}
+
+fn decode_protection(obr: u32, wrpr: u32, offset: u32) -> Option<ProtectionCause> {
+    // A cleared WRP bit means the granule is protected, the last bit covers the rest of the flash.
+    let granule = core::cmp::min(offset / WRP_GRANULE_SIZE, 31);
+    if wrpr & (1 << granule) == 0 {
+        return Some(ProtectionCause::WriteProtection);
+    }
+
+    // OBR.RDPRT: 0b00 is level 0, anything else is level 1 or 2.
+    if (obr >> 1) & 0b11 != 0 {
+        return Some(ProtectionCause::ReadoutProtection);
+    }
+
+    None
+}
+
+/// The number of polls of the status register after which [`blocking_wait_ready`] gives up.
+///
+/// A page or mass erase takes at most 40 ms. A poll takes at least a few cycles, so at the maximum core clock of
+/// 48 MHz this bound is still more than 100 ms.
+const MAX_POLLS: u32 = 4_000_000;
+
 unsafe fn blocking_wait_ready() -> Result<(), Error> {
-    loop {
+    for _ in 0..MAX_POLLS {
+        super::count_poll();
+
         let sr = pac::FLASH.sr().read();
 
         if !sr.bsy() {
-            if sr.wrprt() {
+            return status(sr);
+        }
+    }
+
+    trace!("FLASH: timeout, SR = 0x{:x}", pac::FLASH.sr().read().0);
+    abort();
+    Err(Error::Timeout)
+}
+
+/// Leave the controller in a sane state after an operation that didn't end: no operation selected, and locked.
+unsafe fn abort() {
+    pac::FLASH.cr().modify(|w| {
+        w.set_pg(false);
+        w.set_per(false);
+        w.set_mer(false);
+    });
+    lock();
+}
+
+/// The result of the last operation, once BSY is cleared.
+fn status(sr: pac::flash::regs::Sr) -> Result<(), Error> {
+    if sr.wrprt() {
+        return Err(Error::Protected);
+    }
+
+    if sr.pgerr() {
+        return Err(Error::Seq);
+    }
+
+    Ok(())
+}
+
+/// Waits for the operation in progress to end.
+///
+/// With `irq`, the task is woken by the EOP or error interrupt, which must be handled by [`on_interrupt`]. Otherwise
+/// it is woken again on every poll.
+pub(crate) async fn wait_ready(irq: bool) -> Result<(), Error> {
+    poll_fn(|cx| unsafe {
+        super::count_poll();
+
+        if irq {
+            WAKER.register(cx.waker());
+            // Enabled before BSY is checked, so an operation that ends in between still interrupts
+            pac::FLASH.cr().modify(|w| {
+                w.set_eopie(true);
+                w.set_errie(true);
+            });
+        }
+
+        let sr = pac::FLASH.sr().read();
+        if sr.bsy() {
+            if !irq {
+                cx.waker().wake_by_ref();
+            }
+            return Poll::Pending;
+        }
+
+        disable_interrupts();
+        Poll::Ready(status(sr))
+    })
+    .await
+}
+
+/// Handles the FLASH interrupt by waking the task in [`wait_ready`], which clears the flags.
+pub(crate) unsafe fn on_interrupt() {
+    disable_interrupts();
+    WAKER.wake();
+}
+
+unsafe fn disable_interrupts() {
+    pac::FLASH.cr().modify(|w| {
+        w.set_eopie(false);
+        w.set_errie(false);
+    });
+}
+
+/// Ends an operation waited for with [`wait_ready`], also if the waiting future was dropped: disables the
+/// interrupts and clears PG and PER.
+pub(crate) unsafe fn end_async() {
+    pac::FLASH.cr().modify(|w| {
+        w.set_eopie(false);
+        w.set_errie(false);
+        w.set_pg(false);
+        w.set_per(false);
+    });
+}
+
+mod option_bytes {
+    use super::{blocking_wait_ready, lock, unlock, write_protection, WrpMask};
+    use crate::flash::{
+        check_self_erase, verify_option_bytes, ConfirmMassErase, Error, Flash, NeedsReload, RdpLevel, FLASH_BASE,
+        FLASH_SIZE,
+    };
+    use crate::pac;
+
+    /// Address of the option bytes. Each byte is stored in a halfword, with its complement in the upper byte.
+    const OPTION_BYTES: u32 = 0x1FFF_F800;
+    /// The option bytes in order: RDP, USER, DATA0, DATA1 and WRP0 to WRP3.
+    const OPTION_COUNT: usize = 8;
+    const RDP: usize = 0;
+    const USER: usize = 1;
+    const WRP: usize = 4;
+
+    const RDP_LEVEL0: u8 = 0xAA;
+    const RDP_LEVEL1: u8 = 0xBB;
+    const RDP_LEVEL2: u8 = 0xCC;
+
+    /// nBOOT0, nBOOT1 and BOOT_SEL, which select the boot mode and are kept as they are.
+    const BOOT_MASK: u8 = 0b1001_1000;
+
+    impl RdpLevel {
+        /// Decode the RDPRT bits of OBR.
+        fn from_rdprt(rdprt: u32) -> Self {
+            match rdprt & 0b11 {
+                0b00 => Self::Level0,
+                0b01 => Self::Level1,
+                _ => Self::Level2,
+            }
+        }
+    }
+
+    /// User options, as selected by the USER option byte.
+    ///
+    /// Read them with [`Flash::read_user_options`], change the fields and program them with
+    /// [`Flash::write_user_options`]. The boot configuration bits are kept as they were read.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub struct UserOptions {
+        /// The independent watchdog is started by software (WDG_SW), instead of by hardware at reset.
+        pub software_watchdog: bool,
+        /// Entering Stop mode resets the device (nRST_STOP cleared).
+        pub reset_on_stop: bool,
+        /// Entering Standby mode resets the device (nRST_STDBY cleared).
+        pub reset_on_standby: bool,
+        /// The analog supply voltage is monitored (VDDA_MONITOR).
+        pub vdda_monitor: bool,
+        /// The RAM parity check is enabled (RAM_PARITY_CHECK cleared).
+        pub ram_parity_check: bool,
+        boot: u8,
+    }
+
+    impl UserOptions {
+        fn from_bits(bits: u8) -> Self {
+            Self {
+                software_watchdog: bits & 1 != 0,
+                reset_on_stop: bits & (1 << 1) == 0,
+                reset_on_standby: bits & (1 << 2) == 0,
+                vdda_monitor: bits & (1 << 5) != 0,
+                ram_parity_check: bits & (1 << 6) == 0,
+                boot: bits & BOOT_MASK,
+            }
+        }
+
+        fn to_bits(self) -> u8 {
+            let mut bits = self.boot & BOOT_MASK;
+            bits |= self.software_watchdog as u8;
+            bits |= (!self.reset_on_stop as u8) << 1;
+            bits |= (!self.reset_on_standby as u8) << 2;
+            bits |= (self.vdda_monitor as u8) << 5;
+            bits |= (!self.ram_parity_check as u8) << 6;
+            bits
+        }
+    }
+
+    impl<'d> Flash<'d> {
+        /// The user options of the loaded option bytes.
+        pub fn read_user_options(&self) -> UserOptions {
+            let obr = unsafe { pac::FLASH.obr().read().0 };
+            UserOptions::from_bits((obr >> 8) as u8)
+        }
+
+        /// Programs the user options into the option bytes, and verifies the programmed option bytes.
+        ///
+        /// The option bytes can only be changed together, so they are erased and all of them are programmed
+        /// again. The readout protection, the write protection and the data bytes keep their programmed values.
+        ///
+        /// **The new options only become active after the option bytes are reloaded**, by a power-on reset
+        /// or [`Flash::launch_option_bytes`]. Until then, [`Flash::read_user_options`] returns the loaded ones.
+        ///
+        /// The readout protection level is never changed; the RDP byte is programmed back first. Nothing is erased
+        /// and [`Error::Protected`] is returned at level 1 and 2, or if the programmed RDP byte would change the
+        /// level on the next reload. At level 1, erasing the option bytes would mass erase the flash, so the level
+        /// must be regressed with [`Flash::regress_rdp`] first.
+        pub fn write_user_options(&mut self, options: UserOptions) -> Result<(), Error> {
+            let current = read_option_halfwords();
+            let mut bytes = current.map(|halfword| halfword as u8);
+            bytes[RDP] = rdp_to_keep(self.rdp_level(), current[RDP])?;
+            bytes[USER] = options.to_bits();
+            write_option_bytes(&current, &bytes)
+        }
+
+        /// The write protection of the loaded option bytes.
+        pub fn write_protection(&self) -> WrpMask {
+            write_protection()
+        }
+
+        /// Programs the write protection into the option bytes, and verifies the programmed option bytes. The
+        /// granules set in `mask` are protected, all others are unprotected. The other option bytes keep their
+        /// programmed values.
+        ///
+        /// The new protection only becomes active after the option bytes are reloaded, by a power-on reset
+        /// or [`Flash::launch_option_bytes`]. Programming and erasing a protected granule then fails with
+        /// [`Error::Protected`], before the operation is started.
+        ///
+        /// Like [`Flash::write_user_options`], this returns [`Error::Protected`] at level 1 and 2 without erasing
+        /// anything.
+        pub fn set_write_protection(&mut self, mask: WrpMask) -> Result<(), Error> {
+            let current = read_option_halfwords();
+            let mut bytes = current.map(|halfword| halfword as u8);
+            bytes[RDP] = rdp_to_keep(self.rdp_level(), current[RDP])?;
+            bytes[WRP..].copy_from_slice(&(!mask.0).to_le_bytes());
+            write_option_bytes(&current, &bytes)
+        }
+
+        /// The readout protection level of the loaded option bytes.
+        pub fn rdp_level(&self) -> RdpLevel {
+            let obr = unsafe { pac::FLASH.obr().read().0 };
+            RdpLevel::from_rdprt(obr >> 1)
+        }
+
+        /// Sets the readout protection to level 0 or 1, and verifies the programmed option bytes. The other option
+        /// bytes keep their programmed values.
+        ///
+        /// The new level only becomes active after the option bytes are reloaded, by a power-on reset
+        /// or [`Flash::launch_option_bytes`].
+        ///
+        /// The level can only be raised. At level 1, setting level 1 again is a no-op, and setting level 0 returns
+        /// [`Error::Protected`] as it would mass erase the flash; that is only done by [`Flash::regress_rdp`].
+        ///
+        /// Level 2 is permanent and can only be set with [`Flash::set_rdp_level2_permanent`]; passing it here
+        /// returns [`Error::Protected`]. Nothing can be changed anymore at level 2, which also returns
+        /// [`Error::Protected`].
+        pub fn set_rdp_level(&mut self, level: RdpLevel) -> Result<(), Error> {
+            let byte = match (self.rdp_level(), level) {
+                (RdpLevel::Level2, _) | (_, RdpLevel::Level2) => return Err(Error::Protected),
+                (RdpLevel::Level1, RdpLevel::Level1) => return Ok(()),
+                (RdpLevel::Level1, RdpLevel::Level0) => return Err(Error::Protected),
+                (RdpLevel::Level0, RdpLevel::Level0) => RDP_LEVEL0,
+                (RdpLevel::Level0, RdpLevel::Level1) => RDP_LEVEL1,
+            };
+            program_rdp(byte)
+        }
+
+        /// Sets the readout protection to level 2, and verifies the programmed option bytes.
+        ///
+        /// **This can't be undone.** Once the option bytes are reloaded, the debug interface is disabled for good,
+        /// the option bytes can't be changed anymore and the device can never be reprogrammed from outside;
+        /// only the running firmware can still program and erase the main flash.
+        ///
+        /// Only possible from level 0, at level 1 the flash would be mass erased and [`Error::Protected`] is
+        /// returned.
+        pub fn set_rdp_level2_permanent(&mut self) -> Result<(), Error> {
+            if self.rdp_level() != RdpLevel::Level0 {
                 return Err(Error::Protected);
             }
+            warn!("Setting readout protection level 2, which is permanent once the option bytes are reloaded");
+            program_rdp(RDP_LEVEL2)
+        }
+
+        /// Regresses the readout protection from level 1 to level 0.
+        ///
+        /// **All flash contents are destroyed** by a mass erase, which starts as soon as the option bytes are
+        /// erased. The running program must not be in the flash, otherwise [`Error::WouldEraseSelf`] is returned
+        /// before anything is changed. The flash is consumed, as nothing of it can be used after the regression;
+        /// the level 0 only becomes active once the option bytes are reloaded with the returned [`NeedsReload`].
+        ///
+        /// Returns [`Error::Protected`] at level 2, which can't be regressed. At level 0, nothing is programmed.
+        pub fn regress_rdp(self, _confirm: ConfirmMassErase) -> Result<NeedsReload<'d>, Error> {
+            match self.rdp_level() {
+                RdpLevel::Level0 => return Ok(NeedsReload { flash: self }),
+                RdpLevel::Level2 => return Err(Error::Protected),
+                RdpLevel::Level1 => {}
+            }
+            check_self_erase(FLASH_BASE as u32, FLASH_BASE as u32 + FLASH_SIZE as u32, false)?;
+            warn!("Regressing readout protection, the flash is mass erased");
+            program_rdp(RDP_LEVEL0)?;
+            Ok(NeedsReload { flash: self })
+        }
+
+        /// Reloads the option bytes, which resets the device.
+        ///
+        /// # Panics
+        /// Panics if the option bytes can't be unlocked, see [`Error::Seq`].
+        pub fn launch_option_bytes(&mut self) -> ! {
+            unsafe {
+                unlock();
+                unwrap!(unlock_option_bytes());
+                // OBL_LAUNCH in the reference manual
+                pac::FLASH.cr().modify(|w| w.set_force_optload(true));
+            }
+            // The reset is not immediate
+            loop {
+                cortex_m::asm::nop();
+            }
+        }
+    }
+
+    /// Programs the RDP byte `byte`, keeping the other option bytes.
+    fn program_rdp(byte: u8) -> Result<(), Error> {
+        let current = read_option_halfwords();
+        let mut bytes = current.map(|halfword| halfword as u8);
+        bytes[RDP] = byte;
+        write_option_bytes(&current, &bytes)
+    }
+
+    fn read_option_halfwords() -> [u16; OPTION_COUNT] {
+        core::array::from_fn(|i| unsafe { core::ptr::read_volatile((OPTION_BYTES + 2 * i as u32) as *const u16) })
+    }
+
+    /// The option halfword for `byte`, with the complement in the upper byte.
+    fn option_halfword(byte: u8) -> u16 {
+        ((!byte as u16) << 8) | byte as u16
+    }
+
+    /// The RDP byte to program back after erasing the option bytes, given the loaded level and the programmed
+    /// RDP halfword.
+    ///
+    /// An RDP byte without a valid complement is loaded as level 1, so it is replaced by level 1.
+    fn rdp_to_keep(loaded: RdpLevel, programmed: u16) -> Result<u8, Error> {
+        // The option bytes can't be changed at level 2, and erasing them at level 1 mass erases the flash
+        if loaded != RdpLevel::Level0 {
+            return Err(Error::Protected);
+        }
+        let byte = if programmed == option_halfword(programmed as u8) {
+            programmed as u8
+        } else {
+            RDP_LEVEL1
+        };
+        match byte {
+            // Programming level 2 is permanent
+            RDP_LEVEL2 => Err(Error::Protected),
+            byte => Ok(byte),
+        }
+    }
+
+    fn write_option_bytes(current: &[u16; OPTION_COUNT], bytes: &[u8; OPTION_COUNT]) -> Result<(), Error> {
+        critical_section::with(|_| unsafe {
+            unlock();
+            let result = unlock_option_bytes().and_then(|_| program_option_bytes(current, bytes));
+            if let Err(Error::OptionByteVerify { .. }) = result {
+                // Program the previous option bytes back, so an incomplete write doesn't stay in effect. They are
+                // only loaded on the next reload, so the loaded RDP level is the same as before.
+                let _ = program_option_bytes(current, &current.map(|halfword| halfword as u8));
+            }
+            lock_option_bytes();
+            lock();
+            result
+        })
+    }
+
+    unsafe fn unlock_option_bytes() -> Result<(), Error> {
+        pac::FLASH.optkeyr().write(|w| w.set_optkeyr(0x4567_0123));
+        pac::FLASH.optkeyr().write(|w| w.set_optkeyr(0xCDEF_89AB));
+        if !pac::FLASH.cr().read().optwre() {
+            return Err(Error::Seq);
+        }
+        Ok(())
+    }
 
-            if sr.pgerr() {
-                return Err(Error::Seq);
+    unsafe fn lock_option_bytes() {
+        pac::FLASH.cr().modify(|w| w.set_optwre(false));
+    }
+
+    /// Erase the option bytes and program `bytes`, then verify all option halfwords against
+    /// [`expected_option_halfwords`], including the erased ones.
+    unsafe fn program_option_bytes(current: &[u16; OPTION_COUNT], bytes: &[u8; OPTION_COUNT]) -> Result<(), Error> {
+        blocking_wait_ready()?;
+
+        pac::FLASH.cr().modify(|w| w.set_opter(true));
+        pac::FLASH.cr().modify(|w| w.set_strt(true));
+        let result = blocking_wait_ready();
+        pac::FLASH.cr().modify(|w| w.set_opter(false));
+        result?;
+
+        let expected = expected_option_halfwords(current, bytes);
+
+        // RDP comes first, so the protection level is restored before anything else can fail
+        pac::FLASH.cr().modify(|w| w.set_optpg(true));
+        let result = (0..OPTION_COUNT).filter(|&i| expected[i] != 0xFFFF).try_for_each(|i| {
+            let address = (OPTION_BYTES + 2 * i as u32) as *mut u16;
+            address.write_volatile(expected[i]);
+            blocking_wait_ready()
+        });
+        pac::FLASH.cr().modify(|w| w.set_optpg(false));
+        result?;
+
+        // The complement is in the upper byte, so comparing the complete halfwords verifies both
+        let actual = read_option_halfwords();
+        (0..OPTION_COUNT).try_for_each(|i| verify_option_bytes(expected[i] as u32, actual[i] as u32, 0xFFFF, true))
+    }
+
+    /// The option halfwords after programming `bytes`. Option bytes that were erased (`current`) and stay 0xFF are
+    /// left erased, except for RDP and USER.
+    fn expected_option_halfwords(current: &[u16; OPTION_COUNT], bytes: &[u8; OPTION_COUNT]) -> [u16; OPTION_COUNT] {
+        core::array::from_fn(|i| {
+            if i <= USER || current[i] != 0xFFFF || bytes[i] != 0xFF {
+                option_halfword(bytes[i])
+            } else {
+                0xFFFF
             }
+        })
+    }
 
-            return Ok(());
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn can_round_trip_user_options() {
+            for bits in 0..=u8::MAX {
+                assert_eq!(bits, UserOptions::from_bits(bits).to_bits());
+            }
+
+            let options = UserOptions::from_bits(0xFF);
+            assert!(options.software_watchdog && !options.reset_on_stop && !options.reset_on_standby);
+            assert!(options.vdda_monitor && !options.ram_parity_check);
+
+            let options = UserOptions {
+                reset_on_stop: true,
+                ..options
+            };
+            assert_eq!(0xFD, options.to_bits());
+        }
+
+        #[test]
+        fn can_decode_rdp_levels() {
+            assert_eq!(RdpLevel::Level0, RdpLevel::from_rdprt(0b00));
+            assert_eq!(RdpLevel::Level1, RdpLevel::from_rdprt(0b01));
+            assert_eq!(RdpLevel::Level2, RdpLevel::from_rdprt(0b11));
+            // Only the RDPRT bits are decoded
+            assert_eq!(RdpLevel::Level1, RdpLevel::from_rdprt(0b101));
         }
+
+        #[test]
+        fn can_expect_option_halfwords() {
+            let mut current = [0xFFFF; OPTION_COUNT];
+            current[RDP] = option_halfword(RDP_LEVEL0);
+            current[USER] = option_halfword(0xFF);
+            current[WRP + 1] = option_halfword(0xFE);
+            let mut bytes = current.map(|halfword| halfword as u8);
+            bytes[WRP] = 0xFD;
+            bytes[WRP + 1] = 0xFF;
+
+            let expected = expected_option_halfwords(&current, &bytes);
+            assert_eq!([0x55AA, 0x00FF, 0xFFFF, 0xFFFF], expected[..WRP]);
+            // A changed byte and a programmed byte that becomes 0xFF are programmed, the other erased bytes not
+            assert_eq!([0x02FD, 0x00FF, 0xFFFF, 0xFFFF], expected[WRP..]);
+        }
+
+        #[test]
+        fn can_keep_rdp() {
+            assert_eq!(0x55AA, option_halfword(RDP_LEVEL0));
+            assert_eq!(Ok(RDP_LEVEL0), rdp_to_keep(RdpLevel::Level0, 0x55AA));
+            // A raise to level 1 that is not loaded yet is kept
+            assert_eq!(Ok(0x00), rdp_to_keep(RdpLevel::Level0, 0xFF00));
+            // An invalid complement is loaded as level 1
+            assert_eq!(Ok(RDP_LEVEL1), rdp_to_keep(RdpLevel::Level0, 0xFFFF));
+
+            assert_eq!(Err(Error::Protected), rdp_to_keep(RdpLevel::Level2, 0x33CC));
+            assert_eq!(Err(Error::Protected), rdp_to_keep(RdpLevel::Level0, 0x33CC));
+            // Erasing the option bytes at level 1 would mass erase the flash
+            assert_eq!(Err(Error::Protected), rdp_to_keep(RdpLevel::Level1, 0xFF00));
+            assert_eq!(Err(Error::Protected), rdp_to_keep(RdpLevel::Level1, 0x55AA));
+        }
+    }
+}
+
+pub use option_bytes::UserOptions;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_cover_ranges() {
+        assert_eq!(WrpMask::NONE, WrpMask::covering(0, 0));
+        assert_eq!(WrpMask(0b1), WrpMask::covering(0, 1));
+        assert_eq!(WrpMask(0b1), WrpMask::covering(0, 0x1000));
+        assert_eq!(WrpMask(0b11), WrpMask::covering(0, 0x1001));
+        assert_eq!(WrpMask(0b110), WrpMask::covering(0x1FFF, 0x2001));
+        // The last bit covers the rest of the flash
+        assert_eq!(WrpMask(1 << 31), WrpMask::covering(0x3_0000, 0x4_0000));
+        assert_eq!(WrpMask(0xC000_0000), WrpMask::covering(0x1_E000, 0x4_0000));
+        assert_eq!(WrpMask(u32::MAX), WrpMask::covering(0, 0x4_0000));
+
+        let mask = WrpMask::covering(0, 0x2000).union(WrpMask::covering(0x8000, 0x9000));
+        assert_eq!(WrpMask(0b1_0000_0011), mask);
+        assert!(mask.is_protected(0x1FFF));
+        assert!(!mask.is_protected(0x2000));
+        assert!(mask.is_protected(0x8000));
+    }
+
+    #[test]
+    fn can_decode_protection() {
+        const UNPROTECTED: u32 = 0xFFFF_FFFF;
+
+        assert_eq!(None, decode_protection(0, UNPROTECTED, 0));
+        assert_eq!(None, decode_protection(0, UNPROTECTED, 0x3FFFF));
+
+        // Granule 1 (0x1000..0x2000) is write protected
+        let wrpr = !(1 << 1);
+        assert_eq!(None, decode_protection(0, wrpr, 0x0FFF));
+        assert_eq!(
+            Some(ProtectionCause::WriteProtection),
+            decode_protection(0, wrpr, 0x1000)
+        );
+        assert_eq!(
+            Some(ProtectionCause::WriteProtection),
+            decode_protection(0, wrpr, 0x1FFF)
+        );
+        assert_eq!(None, decode_protection(0, wrpr, 0x2000));
+
+        // The last bit covers everything above granule 31
+        let wrpr = !(1 << 31);
+        assert_eq!(
+            Some(ProtectionCause::WriteProtection),
+            decode_protection(0, wrpr, 0x1F000)
+        );
+        assert_eq!(
+            Some(ProtectionCause::WriteProtection),
+            decode_protection(0, wrpr, 0x3F000)
+        );
+
+        // RDP level 1 and 2
+        assert_eq!(
+            Some(ProtectionCause::ReadoutProtection),
+            decode_protection(0b010, UNPROTECTED, 0)
+        );
+        assert_eq!(
+            Some(ProtectionCause::ReadoutProtection),
+            decode_protection(0b110, UNPROTECTED, 0)
+        );
     }
 }
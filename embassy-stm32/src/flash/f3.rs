@@ -3,7 +3,7 @@ use core::ptr::write_volatile;
 
 use atomic_polyfill::{fence, Ordering};
 
-use super::{FlashRegion, FlashSector, FLASH_REGIONS, WRITE_SIZE};
+use super::{FlashRegion, FlashSector, ProtectionCause, WriteUnit, FLASH_BASE, FLASH_REGIONS, WRITE_SIZE};
 use crate::flash::Error;
 use crate::pac;
 
@@ -11,6 +11,9 @@ pub const fn get_flash_regions() -> &'static [&'static FlashRegion] {
     &FLASH_REGIONS
 }
 
+/// Readable areas outside of the main flash: the system memory with the calibration values, and the option bytes.
+pub(crate) const READ_ONLY_AREAS: &[core::ops::Range<u32>] = &[0x1FFF_D800..0x1FFF_F800, 0x1FFF_F800..0x1FFF_F810];
+
 pub(crate) unsafe fn lock() {
     pac::FLASH.cr().modify(|w| w.set_lock(true));
 }
@@ -30,7 +33,8 @@ pub(crate) unsafe fn end_write() {
     pac::FLASH.cr().write(|w| w.set_pg(false));
 }
 
-pub(crate) unsafe fn blocking_write(start_address: u32, buf: &[u8; WRITE_SIZE]) -> Result<(), Error> {
+pub(crate) unsafe fn blocking_write<const N: usize>(start_address: u32, buf: &[u8; N]) -> Result<(), Error> {
+    let () = WriteUnit::<N>::OK;
     let mut address = start_address;
     for chunk in buf.chunks(2) {
         write_volatile(address as *mut u16, u16::from_le_bytes(chunk.try_into().unwrap()));
@@ -38,12 +42,19 @@ pub(crate) unsafe fn blocking_write(start_address: u32, buf: &[u8; WRITE_SIZE])
 
         // prevents parallelism errors
         fence(Ordering::SeqCst);
-    }
 
-    blocking_wait_ready()
+        // Each halfword is programmed on its own
+        blocking_wait_ready()?;
+    }
+    Ok(())
 }
 
 pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), Error> {
+    start_erase_sector(sector);
+    finish_erase_sector(sector)
+}
+
+pub(crate) unsafe fn start_erase_sector(sector: &FlashSector) {
     pac::FLASH.cr().modify(|w| {
         w.set_per(true);
     });
@@ -53,7 +64,9 @@ pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), E
     pac::FLASH.cr().modify(|w| {
         w.set_strt(true);
     });
+}
 
+pub(crate) unsafe fn finish_erase_sector(_sector: &FlashSector) -> Result<(), Error> {
     let mut ret: Result<(), Error> = blocking_wait_ready();
 
     if !pac::FLASH.sr().read().eop() {
@@ -72,6 +85,14 @@ pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), E
     Ok(())
 }
 
+pub(crate) fn is_busy() -> bool {
+    unsafe { pac::FLASH.sr().read().bsy() }
+}
+
+pub(crate) unsafe fn wait_idle() {
+    while is_busy() {}
+}
+
 pub(crate) unsafe fn clear_all_err() {
     pac::FLASH.sr().modify(|w| {
         if w.pgerr() {
@@ -86,8 +107,33 @@ pub(crate) unsafe fn clear_all_err() {
     });
 }
 
+/// Size of the flash area covered by a single WRP bit.
+const WRP_GRANULE_SIZE: u32 = 4 * 1024;
+
+pub(crate) fn protection_cause(address: u32) -> Option<ProtectionCause> {
+    let (obr, wrpr) = unsafe { (pac::FLASH.obr().read().0, pac::FLASH.wrpr().read().0) };
+    decode_protection(obr, wrpr, address - FLASH_BASE as u32)
+}
+
+fn decode_protection(obr: u32, wrpr: u32, offset: u32) -> Option<ProtectionCause> {
+    // A cleared WRP bit means the granule is protected, the last bit covers the rest of the flash.
+    let granule = core::cmp::min(offset / WRP_GRANULE_SIZE, 31);
+    if wrpr & (1 << granule) == 0 {
+        return Some(ProtectionCause::WriteProtection);
+    }
+
+    // OBR.RDPRT: 0b00 is level 0, anything else is level 1 or 2.
+    if (obr >> 1) & 0b11 != 0 {
+        return Some(ProtectionCause::ReadoutProtection);
+    }
+
+    None
+}
+
 unsafe fn blocking_wait_ready() -> Result<(), Error> {
     loop {
+        super::count_poll();
+
         let sr = pac::FLASH.sr().read();
 
         if !sr.bsy() {
@@ -103,3 +149,50 @@ unsafe fn blocking_wait_ready() -> Result<(), Error> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_decode_protection() {
+        const UNPROTECTED: u32 = 0xFFFF_FFFF;
+
+        assert_eq!(None, decode_protection(0, UNPROTECTED, 0));
+        assert_eq!(None, decode_protection(0, UNPROTECTED, 0x3_F800));
+
+        // Granule 0 covers the first two 2 KB pages, e.g. a bootloader
+        let wrpr = !1;
+        assert_eq!(
+            Some(ProtectionCause::WriteProtection),
+            decode_protection(0, wrpr, 0x0800)
+        );
+        assert_eq!(None, decode_protection(0, wrpr, 0x1000));
+
+        // On 256 KB parts, the last bit covers the upper half of the flash
+        let wrpr = !(1 << 31);
+        assert_eq!(None, decode_protection(0, wrpr, 0x1_EFFF));
+        assert_eq!(
+            Some(ProtectionCause::WriteProtection),
+            decode_protection(0, wrpr, 0x1_F000)
+        );
+        assert_eq!(
+            Some(ProtectionCause::WriteProtection),
+            decode_protection(0, wrpr, 0x3_F800)
+        );
+
+        // The write protection is reported before the readout protection
+        assert_eq!(
+            Some(ProtectionCause::WriteProtection),
+            decode_protection(0b010, wrpr, 0x3_F800)
+        );
+        assert_eq!(
+            Some(ProtectionCause::ReadoutProtection),
+            decode_protection(0b010, UNPROTECTED, 0)
+        );
+        assert_eq!(
+            Some(ProtectionCause::ReadoutProtection),
+            decode_protection(0b100, UNPROTECTED, 0)
+        );
+    }
+}
@@ -2,8 +2,8 @@ use core::convert::TryInto;
 use core::ptr::write_volatile;
 use core::sync::atomic::{fence, Ordering};
 
-use super::{FlashRegion, FlashSector, FLASH_REGIONS, WRITE_SIZE};
-use crate::flash::Error;
+use super::{FlashRegion, FlashSector, ProtectionCause, WriteUnit, FLASH_REGIONS, WRITE_SIZE};
+use crate::flash::{get_sector, Error, FlashBank};
 use crate::pac;
 
 #[cfg(any(stm32f427, stm32f429, stm32f437, stm32f439, stm32f469, stm32f479))]
@@ -45,6 +45,9 @@ mod alt_regions {
         &ALT_BANK2_REGION3,
     ];
 
+    // The dual bank mode only exists on the 1 MB parts, on the larger parts the layout only covers part of the flash
+    const _: () = assert!(crate::flash::validate_regions(&ALT_FLASH_REGIONS) <= FLASH_SIZE as u32);
+
     pub type AltBank1Region1<'d> = Bank1Region1<'d>;
     pub type AltBank1Region2<'d> = Bank1Region2<'d>;
     pub struct AltBank1Region3<'d>(pub &'static FlashRegion, PeripheralRef<'d, FLASH>);
@@ -106,6 +109,17 @@ pub const fn get_flash_regions() -> &'static [&'static FlashRegion] {
     &FLASH_REGIONS
 }
 
+/// Readable areas outside of the main flash: the system memory, the OTP area, the unique ID and calibration values,
+/// and the option bytes.
+pub(crate) const READ_ONLY_AREAS: &[core::ops::Range<u32>] = &[
+    0x1FFF_0000..0x1FFF_7800,
+    0x1FFF_7800..0x1FFF_7A10,
+    0x1FFF_7A10..0x1FFF_7A30,
+    #[cfg(any(stm32f427, stm32f429, stm32f437, stm32f439, stm32f469, stm32f479))]
+    0x1FFE_C000..0x1FFE_C010,
+    0x1FFF_C000..0x1FFF_C010,
+];
+
 pub(crate) unsafe fn lock() {
     pac::FLASH.cr().modify(|w| w.set_lock(true));
 }
@@ -128,20 +142,29 @@ pub(crate) unsafe fn end_write() {
     pac::FLASH.cr().write(|w| w.set_pg(false));
 }
 
-pub(crate) unsafe fn blocking_write(start_address: u32, buf: &[u8; WRITE_SIZE]) -> Result<(), Error> {
+pub(crate) unsafe fn blocking_write<const N: usize>(start_address: u32, buf: &[u8; N]) -> Result<(), Error> {
+    let () = WriteUnit::<N>::OK;
     let mut address = start_address;
-    for val in buf.chunks(4) {
-        write_volatile(address as *mut u32, u32::from_le_bytes(val.try_into().unwrap()));
-        address += val.len() as u32;
+    for unit in buf.chunks_exact(WRITE_SIZE) {
+        for val in unit.chunks(4) {
+            write_volatile(address as *mut u32, u32::from_le_bytes(val.try_into().unwrap()));
+            address += val.len() as u32;
 
-        // prevents parallelism errors
-        fence(Ordering::SeqCst);
-    }
+            // prevents parallelism errors
+            fence(Ordering::SeqCst);
+        }
 
-    blocking_wait_ready()
+        blocking_wait_ready()?;
+    }
+    Ok(())
 }
 
 pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), Error> {
+    start_erase_sector(sector);
+    finish_erase_sector(sector)
+}
+
+pub(crate) unsafe fn start_erase_sector(sector: &FlashSector) {
     let snb = ((sector.bank as u8) << 4) + sector.index_in_bank;
 
     pac::FLASH.cr().modify(|w| {
@@ -152,7 +175,9 @@ pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), E
     pac::FLASH.cr().modify(|w| {
         w.set_strt(true);
     });
+}
 
+pub(crate) unsafe fn finish_erase_sector(_sector: &FlashSector) -> Result<(), Error> {
     let ret: Result<(), Error> = blocking_wait_ready();
 
     clear_all_err();
@@ -160,6 +185,14 @@ pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), E
     ret
 }
 
+pub(crate) fn is_busy() -> bool {
+    unsafe { pac::FLASH.sr().read().bsy() }
+}
+
+pub(crate) unsafe fn wait_idle() {
+    while is_busy() {}
+}
+
 pub(crate) unsafe fn clear_all_err() {
     pac::FLASH.sr().write(|w| {
         w.set_pgserr(true);
@@ -170,8 +203,44 @@ pub(crate) unsafe fn clear_all_err() {
     });
 }
 
+pub(crate) fn protection_cause(address: u32) -> Option<ProtectionCause> {
+    let sector = get_sector(address, get_flash_regions());
+    let optcr = unsafe { pac::FLASH.optcr().read().0 };
+    #[cfg(any(stm32f427, stm32f429, stm32f437, stm32f439, stm32f469, stm32f479))]
+    let optcr1 = unsafe { pac::FLASH.optcr1().read().0 };
+    #[cfg(not(any(stm32f427, stm32f429, stm32f437, stm32f439, stm32f469, stm32f479)))]
+    let optcr1 = 0xFFFF_FFFF;
+
+    decode_protection(optcr, optcr1, &sector)
+}
+
+fn decode_protection(optcr: u32, optcr1: u32, sector: &FlashSector) -> Option<ProtectionCause> {
+    let nwrp = match sector.bank {
+        FlashBank::Bank1 => (optcr >> 16) & 0xFFF,
+        _ => (optcr1 >> 16) & 0xFFF,
+    };
+    let bit = nwrp & (1 << sector.index_in_bank) != 0;
+
+    // With SPRMOD set the nWRP bits select PCROP protected sectors instead (active high).
+    let sprmod = optcr & (1 << 31) != 0;
+    match (sprmod, bit) {
+        (false, false) => return Some(ProtectionCause::WriteProtection),
+        (true, true) => return Some(ProtectionCause::ProprietaryCodeProtection),
+        _ => {}
+    }
+
+    // OPTCR.RDP: 0xAA is level 0, anything else is level 1 or 2.
+    if (optcr >> 8) & 0xFF != 0xAA {
+        return Some(ProtectionCause::ReadoutProtection);
+    }
+
+    None
+}
+
 unsafe fn blocking_wait_ready() -> Result<(), Error> {
     loop {
+        super::count_poll();
+
         let sr = pac::FLASH.sr().read();
 
         if !sr.bsy() {
@@ -199,7 +268,6 @@ unsafe fn blocking_wait_ready() -> Result<(), Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::flash::{get_sector, FlashBank};
 
     #[test]
     #[cfg(stm32f429)]
@@ -271,4 +339,65 @@ mod tests {
         assert_sector(FlashBank::Bank2, 7, 0x080E_0000, LARGE_SECTOR_SIZE, 0x080E_0000);
         assert_sector(FlashBank::Bank2, 7, 0x080E_0000, LARGE_SECTOR_SIZE, 0x080F_FFFF);
     }
+
+    #[test]
+    fn can_decode_protection() {
+        const UNPROTECTED: u32 = 0x0FFF_AAED;
+
+        let sector = |bank: FlashBank, index_in_bank: u8| FlashSector {
+            bank,
+            index_in_bank,
+            start: 0,
+            size: 0,
+        };
+
+        assert_eq!(
+            None,
+            decode_protection(UNPROTECTED, UNPROTECTED, &sector(FlashBank::Bank1, 0))
+        );
+        assert_eq!(
+            None,
+            decode_protection(UNPROTECTED, UNPROTECTED, &sector(FlashBank::Bank2, 11))
+        );
+
+        // Sector 3 of bank 1 is write protected
+        let optcr = UNPROTECTED & !(1 << (16 + 3));
+        assert_eq!(
+            None,
+            decode_protection(optcr, UNPROTECTED, &sector(FlashBank::Bank1, 2))
+        );
+        assert_eq!(
+            Some(ProtectionCause::WriteProtection),
+            decode_protection(optcr, UNPROTECTED, &sector(FlashBank::Bank1, 3))
+        );
+        assert_eq!(
+            None,
+            decode_protection(optcr, UNPROTECTED, &sector(FlashBank::Bank2, 3))
+        );
+
+        // Sector 5 of bank 2 is write protected
+        let optcr1 = UNPROTECTED & !(1 << (16 + 5));
+        assert_eq!(
+            Some(ProtectionCause::WriteProtection),
+            decode_protection(UNPROTECTED, optcr1, &sector(FlashBank::Bank2, 5))
+        );
+
+        // Sector 0 is PCROP protected
+        let optcr = (1 << 31) | (1 << 16) | (UNPROTECTED & 0xFFFF);
+        assert_eq!(
+            Some(ProtectionCause::ProprietaryCodeProtection),
+            decode_protection(optcr, UNPROTECTED, &sector(FlashBank::Bank1, 0))
+        );
+        assert_eq!(
+            None,
+            decode_protection(optcr, UNPROTECTED, &sector(FlashBank::Bank1, 1))
+        );
+
+        // RDP level 1
+        let optcr = (UNPROTECTED & !0xFF00) | (0x55 << 8);
+        assert_eq!(
+            Some(ProtectionCause::ReadoutProtection),
+            decode_protection(optcr, UNPROTECTED, &sector(FlashBank::Bank1, 0))
+        );
+    }
 }
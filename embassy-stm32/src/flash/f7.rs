@@ -2,14 +2,18 @@ use core::convert::TryInto;
 use core::ptr::write_volatile;
 use core::sync::atomic::{fence, Ordering};
 
-use super::{FlashRegion, FlashSector, FLASH_REGIONS, WRITE_SIZE};
-use crate::flash::Error;
+use super::{FlashRegion, FlashSector, ProtectionCause, WriteUnit, FLASH_REGIONS, WRITE_SIZE};
+use crate::flash::{get_sector, Error};
 use crate::pac;
 
 pub const fn get_flash_regions() -> &'static [&'static FlashRegion] {
     &FLASH_REGIONS
 }
 
+/// Readable areas outside of the main flash: the option bytes. The location of the system memory and OTP area differs
+/// between the F7 lines, so they are not included.
+pub(crate) const READ_ONLY_AREAS: &[core::ops::Range<u32>] = &[0x1FFF_0000..0x1FFF_0020];
+
 pub(crate) unsafe fn lock() {
     pac::FLASH.cr().modify(|w| w.set_lock(true));
 }
@@ -32,20 +36,29 @@ pub(crate) unsafe fn end_write() {
     pac::FLASH.cr().write(|w| w.set_pg(false));
 }
 
-pub(crate) unsafe fn blocking_write(start_address: u32, buf: &[u8; WRITE_SIZE]) -> Result<(), Error> {
+pub(crate) unsafe fn blocking_write<const N: usize>(start_address: u32, buf: &[u8; N]) -> Result<(), Error> {
+    let () = WriteUnit::<N>::OK;
     let mut address = start_address;
-    for val in buf.chunks(4) {
-        write_volatile(address as *mut u32, u32::from_le_bytes(val.try_into().unwrap()));
-        address += val.len() as u32;
+    for unit in buf.chunks_exact(WRITE_SIZE) {
+        for val in unit.chunks(4) {
+            write_volatile(address as *mut u32, u32::from_le_bytes(val.try_into().unwrap()));
+            address += val.len() as u32;
 
-        // prevents parallelism errors
-        fence(Ordering::SeqCst);
-    }
+            // prevents parallelism errors
+            fence(Ordering::SeqCst);
+        }
 
-    blocking_wait_ready()
+        blocking_wait_ready()?;
+    }
+    Ok(())
 }
 
 pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), Error> {
+    start_erase_sector(sector);
+    finish_erase_sector(sector)
+}
+
+pub(crate) unsafe fn start_erase_sector(sector: &FlashSector) {
     pac::FLASH.cr().modify(|w| {
         w.set_ser(true);
         w.set_snb(sector.index_in_bank)
@@ -54,7 +67,9 @@ pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), E
     pac::FLASH.cr().modify(|w| {
         w.set_strt(true);
     });
+}
 
+pub(crate) unsafe fn finish_erase_sector(_sector: &FlashSector) -> Result<(), Error> {
     let ret: Result<(), Error> = blocking_wait_ready();
 
     pac::FLASH.cr().modify(|w| w.set_ser(false));
@@ -64,6 +79,14 @@ pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), E
     ret
 }
 
+pub(crate) fn is_busy() -> bool {
+    unsafe { pac::FLASH.sr().read().bsy() }
+}
+
+pub(crate) unsafe fn wait_idle() {
+    while is_busy() {}
+}
+
 pub(crate) unsafe fn clear_all_err() {
     pac::FLASH.sr().modify(|w| {
         if w.erserr() {
@@ -84,8 +107,27 @@ pub(crate) unsafe fn clear_all_err() {
     });
 }
 
+pub(crate) fn protection_cause(address: u32) -> Option<ProtectionCause> {
+    let sector = get_sector(address, get_flash_regions());
+    let optcr = unsafe { pac::FLASH.optcr().read().0 };
+
+    // OPTCR.nWRP: a cleared bit means the sector is write protected.
+    if (optcr >> 16) & (1 << sector.index_in_bank) == 0 {
+        return Some(ProtectionCause::WriteProtection);
+    }
+
+    // OPTCR.RDP: 0xAA is level 0, anything else is level 1 or 2.
+    if (optcr >> 8) & 0xFF != 0xAA {
+        return Some(ProtectionCause::ReadoutProtection);
+    }
+
+    None
+}
+
 unsafe fn blocking_wait_ready() -> Result<(), Error> {
     loop {
+        super::count_poll();
+
         let sr = pac::FLASH.sr().read();
 
         if !sr.bsy() {
@@ -113,7 +155,7 @@ unsafe fn blocking_wait_ready() -> Result<(), Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::flash::{get_sector, FlashBank};
+    use crate::flash::FlashBank;
 
     #[test]
     #[cfg(stm32f732)]
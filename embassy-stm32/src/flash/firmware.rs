@@ -0,0 +1,369 @@
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use super::pages::round_up;
+use super::{Error, MAX_WRITE_SIZE};
+
+const MAGIC: u32 = 0x4e49_5746;
+#[cfg(feature = "flash-sha256")]
+const DIGEST_MAGIC: u32 = 0x3635_3253;
+
+/// The largest block that is read or written, the [`ImageDigest`] block.
+const MAX_BLOCK_SIZE: usize = 40;
+
+/// Error returned when reading a [`FirmwareInfo`] block.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InvalidMetadata {
+    /// Reading the flash failed.
+    Flash(Error),
+    /// The block does not start with the expected magic, e.g. because it was never written.
+    Magic,
+    /// The block checksum does not match its contents.
+    Crc,
+}
+
+impl From<Error> for InvalidMetadata {
+    fn from(e: Error) -> Self {
+        Self::Flash(e)
+    }
+}
+
+/// The digest that [`FirmwareInfo::check_image`] compares with the image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImageCheck {
+    /// The CRC-32 in [`FirmwareInfo::crc`].
+    Crc32,
+    /// The SHA-256 digest of the [`ImageDigest`] block at [`FirmwareInfo::digest_offset`].
+    #[cfg(feature = "flash-sha256")]
+    Sha256,
+}
+
+/// Error returned by [`FirmwareInfo::check_image`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImageError {
+    /// Reading the flash failed.
+    Flash(Error),
+    /// There is no valid block holding the digest to compare against.
+    MissingDigest,
+    /// The length of the image is zero or exceeds the space for it.
+    InvalidLength,
+    /// The image doesn't match the digest, e.g. because it is corrupt or truncated.
+    DigestMismatch,
+}
+
+impl From<Error> for ImageError {
+    fn from(e: Error) -> Self {
+        Self::Flash(e)
+    }
+}
+
+impl From<InvalidMetadata> for ImageError {
+    fn from(e: InvalidMetadata) -> Self {
+        match e {
+            InvalidMetadata::Flash(e) => Self::Flash(e),
+            InvalidMetadata::Magic | InvalidMetadata::Crc => Self::MissingDigest,
+        }
+    }
+}
+
+/// Firmware metadata block, shared between bootloader and application.
+///
+/// The block is stored little-endian as: magic, `version`, `length`, `crc`, `build_id` and a CRC-32
+/// over all preceding bytes, for a total of [`FirmwareInfo::SIZE`] bytes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FirmwareInfo {
+    /// Firmware version.
+    pub version: u32,
+    /// Length of the image in bytes.
+    pub length: u32,
+    /// CRC-32 of the image.
+    pub crc: u32,
+    /// Build identifier, e.g. a truncated commit hash.
+    pub build_id: [u8; 16],
+}
+
+impl FirmwareInfo {
+    /// The serialized size of the block.
+    pub const SIZE: usize = 36;
+
+    /// Read and validate the block at `offset` of `flash`.
+    pub fn read_from<F: ReadNorFlash<Error = Error>>(flash: &mut F, offset: u32) -> Result<Self, InvalidMetadata> {
+        let buf: [u8; Self::SIZE] = read_block(flash, offset, MAGIC)?;
+        let word = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        Ok(Self {
+            version: word(4),
+            length: word(8),
+            crc: word(12),
+            build_id: buf[16..32].try_into().unwrap(),
+        })
+    }
+
+    /// Write the block to `offset` of `flash`.
+    ///
+    /// `offset` must be aligned to the write size, and the area must have been erased beforehand.
+    /// The block is padded with `0xFF` up to the next write unit.
+    pub fn write_to<F: NorFlash<Error = Error>>(&self, flash: &mut F, offset: u32) -> Result<(), Error> {
+        let mut fields = [0; Self::SIZE - 8];
+        fields[..4].copy_from_slice(&self.version.to_le_bytes());
+        fields[4..8].copy_from_slice(&self.length.to_le_bytes());
+        fields[8..12].copy_from_slice(&self.crc.to_le_bytes());
+        fields[12..28].copy_from_slice(&self.build_id);
+        write_block(flash, offset, MAGIC, &fields)
+    }
+
+    /// The offset of the [`ImageDigest`] block that follows the block at `offset`, at the next write unit.
+    pub fn digest_offset<F: NorFlash>(offset: u32) -> u32 {
+        offset + round_up::<F>(Self::SIZE) as u32
+    }
+
+    /// Compute the CRC-32 of the `length` bytes at `offset` of `flash`.
+    pub fn image_crc<F: ReadNorFlash<Error = Error>>(flash: &mut F, offset: u32, length: u32) -> Result<u32, Error> {
+        let mut crc = !0;
+        let mut buf = [0; 64];
+        let mut done = 0;
+        while done < length {
+            let n = core::cmp::min(buf.len() as u32, length - done);
+            flash.read(offset + done, &mut buf[..n as usize])?;
+            crc = crc32(crc, &buf[..n as usize]);
+            done += n;
+        }
+        Ok(crc ^ !0)
+    }
+
+    /// Check whether the image at `offset` of `flash` matches `length` and `crc` of this block.
+    pub fn verify_image<F: ReadNorFlash<Error = Error>>(&self, flash: &mut F, offset: u32) -> Result<bool, Error> {
+        Ok(Self::image_crc(flash, offset, self.length)? == self.crc)
+    }
+
+    /// Read the block at `offset` of `flash`, and check the image at `image_offset` against it with `check`.
+    ///
+    /// This is meant for a bootloader, to refuse an update before swapping it in. The image must not be longer than
+    /// `max_length`, the space reserved for it, so that a length that is read from a corrupt block is not trusted.
+    pub fn check_image<F: NorFlash<Error = Error>>(
+        flash: &mut F,
+        offset: u32,
+        image_offset: u32,
+        max_length: u32,
+        check: ImageCheck,
+    ) -> Result<Self, ImageError> {
+        let info = Self::read_from(flash, offset)?;
+        if info.length == 0 || info.length > max_length {
+            return Err(ImageError::InvalidLength);
+        }
+
+        let matches = match check {
+            ImageCheck::Crc32 => info.verify_image(flash, image_offset)?,
+            #[cfg(feature = "flash-sha256")]
+            ImageCheck::Sha256 => {
+                let digest = ImageDigest::read_from(flash, Self::digest_offset::<F>(offset))?;
+                digest.verify_image(flash, image_offset, info.length)?
+            }
+        };
+        if !matches {
+            return Err(ImageError::DigestMismatch);
+        }
+        Ok(info)
+    }
+}
+
+/// SHA-256 digest of the image described by a [`FirmwareInfo`] block, stored at [`FirmwareInfo::digest_offset`].
+///
+/// The block is stored as: magic, `sha256` and a CRC-32 over the preceding bytes, for a total of
+/// [`ImageDigest::SIZE`] bytes.
+#[cfg(feature = "flash-sha256")]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ImageDigest {
+    /// SHA-256 of the image.
+    pub sha256: [u8; 32],
+}
+
+#[cfg(feature = "flash-sha256")]
+impl ImageDigest {
+    /// The serialized size of the block.
+    pub const SIZE: usize = 40;
+
+    /// Read and validate the block at `offset` of `flash`.
+    pub fn read_from<F: ReadNorFlash<Error = Error>>(flash: &mut F, offset: u32) -> Result<Self, InvalidMetadata> {
+        let buf: [u8; Self::SIZE] = read_block(flash, offset, DIGEST_MAGIC)?;
+        Ok(Self {
+            sha256: buf[4..36].try_into().unwrap(),
+        })
+    }
+
+    /// Write the block to `offset` of `flash`, like [`FirmwareInfo::write_to`].
+    pub fn write_to<F: NorFlash<Error = Error>>(&self, flash: &mut F, offset: u32) -> Result<(), Error> {
+        write_block(flash, offset, DIGEST_MAGIC, &self.sha256)
+    }
+
+    /// Compute the digest of the `length` bytes at `offset` of `flash`.
+    pub fn image_digest<F: ReadNorFlash<Error = Error>>(
+        flash: &mut F,
+        offset: u32,
+        length: u32,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            sha256: super::sha256::sha256(flash, offset..offset + length)?,
+        })
+    }
+
+    /// Check whether the `length` bytes at `offset` of `flash` match this digest.
+    pub fn verify_image<F: ReadNorFlash<Error = Error>>(
+        &self,
+        flash: &mut F,
+        offset: u32,
+        length: u32,
+    ) -> Result<bool, Error> {
+        Ok(Self::image_digest(flash, offset, length)? == *self)
+    }
+}
+
+/// Read a block of `N` bytes at `offset` of `flash`, which starts with `magic` and ends with a CRC-32 over the
+/// preceding bytes.
+fn read_block<F: ReadNorFlash<Error = Error>, const N: usize>(
+    flash: &mut F,
+    offset: u32,
+    magic: u32,
+) -> Result<[u8; N], InvalidMetadata> {
+    let mut buf = [0; N];
+    flash.read(offset, &mut buf)?;
+
+    if buf[..4] != magic.to_le_bytes() {
+        return Err(InvalidMetadata::Magic);
+    }
+    if buf[N - 4..] != (crc32(!0, &buf[..N - 4]) ^ !0).to_le_bytes() {
+        return Err(InvalidMetadata::Crc);
+    }
+    Ok(buf)
+}
+
+/// Write `magic`, `fields` and a CRC-32 over both as a block at `offset` of `flash`, padded with `0xFF` up to the
+/// next write unit.
+fn write_block<F: NorFlash<Error = Error>>(flash: &mut F, offset: u32, magic: u32, fields: &[u8]) -> Result<(), Error> {
+    let len = fields.len() + 8;
+    let mut buf = [0xFF; MAX_BLOCK_SIZE + MAX_WRITE_SIZE];
+    buf[..4].copy_from_slice(&magic.to_le_bytes());
+    buf[4..len - 4].copy_from_slice(fields);
+    let crc = crc32(!0, &buf[..len - 4]) ^ !0;
+    buf[len - 4..len].copy_from_slice(&crc.to_le_bytes());
+    flash.write(offset, &buf[..round_up::<F>(len)])
+}
+
+/// Update a CRC-32 (IEEE 802.3) with `data`, without the initial and final inversion.
+pub(super) fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mem_flash::MemFlash;
+
+    #[test]
+    fn can_compute_crc32() {
+        assert_eq!(0xCBF4_3926, crc32(!0, b"123456789") ^ !0);
+    }
+
+    #[test]
+    fn can_write_and_read_back() {
+        let mut flash = MemFlash::<256, 64, 8>::default();
+        flash.mem[128..228].copy_from_slice(&[0xA5; 100]);
+
+        let info = FirmwareInfo {
+            version: 3,
+            length: 100,
+            crc: FirmwareInfo::image_crc(&mut flash, 128, 100).unwrap(),
+            build_id: *b"0123456789abcdef",
+        };
+        assert_eq!(Err(InvalidMetadata::Magic), FirmwareInfo::read_from(&mut flash, 0));
+        info.write_to(&mut flash, 0).unwrap();
+
+        let read = FirmwareInfo::read_from(&mut flash, 0).unwrap();
+        assert_eq!(info, read);
+        assert_eq!(Ok(true), read.verify_image(&mut flash, 128));
+
+        flash.mem[200] = 0;
+        assert_eq!(Ok(false), read.verify_image(&mut flash, 128));
+        flash.mem[20] = 0;
+        assert_eq!(Err(InvalidMetadata::Crc), FirmwareInfo::read_from(&mut flash, 0));
+    }
+
+    const IMAGE: u32 = 512;
+
+    /// A flash with an image of 300 bytes at [`IMAGE`] and its info block at 0.
+    fn flash_with_image() -> (MemFlash<1024, 256, 8>, FirmwareInfo) {
+        let mut flash = MemFlash::<1024, 256, 8>::default();
+        for (i, byte) in flash.mem[IMAGE as usize..IMAGE as usize + 300].iter_mut().enumerate() {
+            *byte = (i as u8).wrapping_mul(13);
+        }
+        let info = FirmwareInfo {
+            version: 2,
+            length: 300,
+            crc: FirmwareInfo::image_crc(&mut flash, IMAGE, 300).unwrap(),
+            build_id: *b"0123456789abcdef",
+        };
+        (flash, info)
+    }
+
+    fn check(flash: &mut MemFlash<1024, 256, 8>, check: ImageCheck) -> Result<FirmwareInfo, ImageError> {
+        FirmwareInfo::check_image(flash, 0, IMAGE, 512, check)
+    }
+
+    #[test]
+    fn can_check_image_crc() {
+        let (mut flash, info) = flash_with_image();
+        assert_eq!(Err(ImageError::MissingDigest), check(&mut flash, ImageCheck::Crc32));
+
+        info.write_to(&mut flash, 0).unwrap();
+        assert_eq!(Ok(info), check(&mut flash, ImageCheck::Crc32));
+
+        // A corrupt image
+        flash.mem[IMAGE as usize + 123] ^= 0x01;
+        assert_eq!(Err(ImageError::DigestMismatch), check(&mut flash, ImageCheck::Crc32));
+        flash.mem[IMAGE as usize + 123] ^= 0x01;
+
+        // A truncated download, whose end is still erased
+        flash.mem[IMAGE as usize + 200..].fill(0xFF);
+        assert_eq!(Err(ImageError::DigestMismatch), check(&mut flash, ImageCheck::Crc32));
+
+        // Lengths that can't be checked
+        for length in [0, 513] {
+            flash.erase(0, 256).unwrap();
+            FirmwareInfo { length, ..info }.write_to(&mut flash, 0).unwrap();
+            assert_eq!(Err(ImageError::InvalidLength), check(&mut flash, ImageCheck::Crc32));
+        }
+    }
+
+    #[cfg(feature = "flash-sha256")]
+    #[test]
+    fn can_check_image_sha256() {
+        let (mut flash, info) = flash_with_image();
+        info.write_to(&mut flash, 0).unwrap();
+        assert_eq!(Err(ImageError::MissingDigest), check(&mut flash, ImageCheck::Sha256));
+
+        let digest = ImageDigest::image_digest(&mut flash, IMAGE, 300).unwrap();
+        let offset = FirmwareInfo::digest_offset::<MemFlash<1024, 256, 8>>(0);
+        assert_eq!(40, offset);
+        digest.write_to(&mut flash, offset).unwrap();
+        assert_eq!(Ok(digest), ImageDigest::read_from(&mut flash, offset));
+        assert_eq!(Ok(info), check(&mut flash, ImageCheck::Sha256));
+
+        // A corrupt image
+        flash.mem[IMAGE as usize + 7] ^= 0x01;
+        assert_eq!(Err(ImageError::DigestMismatch), check(&mut flash, ImageCheck::Sha256));
+        flash.mem[IMAGE as usize + 7] ^= 0x01;
+
+        // A corrupt digest block holds no digest
+        flash.mem[offset as usize + 10] ^= 0x01;
+        assert_eq!(Err(ImageError::MissingDigest), check(&mut flash, ImageCheck::Sha256));
+    }
+}
@@ -0,0 +1,217 @@
+use embedded_storage::nor_flash::{MultiwriteNorFlash, NorFlash};
+
+use super::MAX_WRITE_SIZE;
+
+const MAGIC: u32 = 0x4653_5546;
+
+/// Version of the fuse area layout, stored in the area header.
+pub const FUSE_LAYOUT_VERSION: u16 = 1;
+
+/// Size of the area header, before rounding up to the write size.
+const HEADER_LEN: usize = 8;
+
+/// Error returned by [`FuseFlags`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FuseError<E> {
+    /// The underlying flash failed.
+    Flash(E),
+    /// The flag index does not fit into the area.
+    InvalidIndex,
+    /// The area was written with a different layout version or packing.
+    LayoutMismatch,
+}
+
+impl<E> From<E> for FuseError<E> {
+    fn from(e: E) -> Self {
+        Self::Flash(e)
+    }
+}
+
+/// One-time flags stored in a small reserved flash area.
+///
+/// A flag is set by programming it from the erased state to zero, which needs no erase.
+/// There is no way to clear a single flag; [`FuseFlags::factory_reset`] erases the complete area.
+///
+/// By default every flag occupies a full write unit. Flash that supports writing a unit multiple times
+/// ([`MultiwriteNorFlash`]) can use [`FuseFlags::new_packed`] to store one flag per bit instead.
+///
+/// The area starts with a header recording the layout version and packing, which is written on first use.
+pub struct FuseFlags<F> {
+    flash: F,
+    packed: bool,
+}
+
+impl<F: NorFlash> FuseFlags<F> {
+    /// Create fuse flags using one write unit per flag.
+    pub fn new(flash: F) -> Self {
+        assert!(F::WRITE_SIZE <= MAX_WRITE_SIZE);
+        Self { flash, packed: false }
+    }
+
+    /// Release the underlying flash.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// The number of flags that fit into the area.
+    pub fn capacity(&self) -> usize {
+        let units = (self.flash.capacity() - self.header_size()) / F::WRITE_SIZE;
+        if self.packed {
+            units * F::WRITE_SIZE * 8
+        } else {
+            units
+        }
+    }
+
+    /// Set flag `index`. Setting an already set flag does nothing.
+    pub fn set(&mut self, index: usize) -> Result<(), FuseError<F::Error>> {
+        self.check_index(index)?;
+        if self.check_header()? {
+            self.write_header()?;
+        } else if self.is_set(index)? {
+            return Ok(());
+        }
+
+        let (offset, mut unit) = self.locate(index);
+        if self.packed {
+            unit[(index / 8) % F::WRITE_SIZE] &= !(1 << (index % 8));
+        } else {
+            unit.fill(0);
+        }
+        self.flash.write(offset, &unit[..F::WRITE_SIZE])?;
+        Ok(())
+    }
+
+    /// Check whether flag `index` is set.
+    pub fn is_set(&mut self, index: usize) -> Result<bool, FuseError<F::Error>> {
+        self.check_index(index)?;
+        if self.check_header()? {
+            return Ok(false);
+        }
+
+        let (offset, mut unit) = self.locate(index);
+        self.flash.read(offset, &mut unit[..F::WRITE_SIZE])?;
+        if self.packed {
+            Ok(unit[(index / 8) % F::WRITE_SIZE] & (1 << (index % 8)) == 0)
+        } else {
+            Ok(unit[..F::WRITE_SIZE].iter().any(|&b| b != 0xFF))
+        }
+    }
+
+    /// Clear all flags by erasing the complete area.
+    ///
+    /// This is intended for factory or RMA flows only.
+    pub fn factory_reset(&mut self) -> Result<(), FuseError<F::Error>> {
+        let len = self.flash.capacity() / F::ERASE_SIZE * F::ERASE_SIZE;
+        self.flash.erase(0, len as u32)?;
+        self.write_header()?;
+        Ok(())
+    }
+
+    fn header_size(&self) -> usize {
+        (HEADER_LEN + F::WRITE_SIZE - 1) / F::WRITE_SIZE * F::WRITE_SIZE
+    }
+
+    fn check_index(&self, index: usize) -> Result<(), FuseError<F::Error>> {
+        if index >= self.capacity() {
+            return Err(FuseError::InvalidIndex);
+        }
+        Ok(())
+    }
+
+    /// Offset of the unit holding flag `index`, and an erased unit buffer.
+    fn locate(&self, index: usize) -> (u32, [u8; MAX_WRITE_SIZE]) {
+        let unit = if self.packed {
+            index / (F::WRITE_SIZE * 8)
+        } else {
+            index
+        };
+        (
+            (self.header_size() + unit * F::WRITE_SIZE) as u32,
+            [0xFF; MAX_WRITE_SIZE],
+        )
+    }
+
+    fn header(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&FUSE_LAYOUT_VERSION.to_le_bytes());
+        header[6..].copy_from_slice(&(self.packed as u16).to_le_bytes());
+        header
+    }
+
+    /// Validate the area header, returning `true` if the area is still blank.
+    fn check_header(&mut self) -> Result<bool, FuseError<F::Error>> {
+        let mut header = [0; HEADER_LEN];
+        self.flash.read(0, &mut header)?;
+        if header.iter().all(|&b| b == 0xFF) {
+            Ok(true)
+        } else if header == self.header() {
+            Ok(false)
+        } else {
+            Err(FuseError::LayoutMismatch)
+        }
+    }
+
+    fn write_header(&mut self) -> Result<(), F::Error> {
+        let mut buf = [0xFF; HEADER_LEN + MAX_WRITE_SIZE];
+        buf[..HEADER_LEN].copy_from_slice(&self.header());
+        self.flash.write(0, &buf[..self.header_size()])
+    }
+}
+
+impl<F: MultiwriteNorFlash> FuseFlags<F> {
+    /// Create fuse flags storing one flag per bit.
+    pub fn new_packed(flash: F) -> Self {
+        assert!(F::WRITE_SIZE <= MAX_WRITE_SIZE);
+        Self { flash, packed: true }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mem_flash::MemFlash;
+
+    type Flash = MemFlash<64, 64, 2>;
+
+    #[test]
+    fn can_set_flags() {
+        let mut fuses = FuseFlags::new(Flash::default());
+        assert_eq!(28, fuses.capacity());
+        assert_eq!(Ok(false), fuses.is_set(3));
+
+        fuses.set(3).unwrap();
+        fuses.set(3).unwrap();
+        fuses.set(27).unwrap();
+
+        let mut fuses = FuseFlags::new(fuses.into_inner());
+        assert_eq!(Ok(true), fuses.is_set(3));
+        assert_eq!(Ok(false), fuses.is_set(4));
+        assert_eq!(Ok(true), fuses.is_set(27));
+        assert_eq!(Err(FuseError::InvalidIndex), fuses.set(28));
+
+        fuses.factory_reset().unwrap();
+        assert_eq!(Ok(false), fuses.is_set(3));
+    }
+
+    #[test]
+    fn can_set_packed_flags() {
+        let mut fuses = FuseFlags::new_packed(Flash::default());
+        assert_eq!(28 * 16, fuses.capacity());
+
+        for i in [0, 1, 7, 8, 15, 16, 447] {
+            fuses.set(i).unwrap();
+        }
+
+        let mut fuses = FuseFlags::new_packed(fuses.into_inner());
+        for i in 0..fuses.capacity() {
+            assert_eq!(Ok([0, 1, 7, 8, 15, 16, 447].contains(&i)), fuses.is_set(i));
+        }
+
+        // The layout is recorded, so the area can't be reinterpreted
+        let mut fuses = FuseFlags::new(fuses.into_inner());
+        assert_eq!(Err(FuseError::LayoutMismatch), fuses.is_set(0));
+    }
+}
@@ -3,7 +3,7 @@ use core::ptr::write_volatile;
 
 use atomic_polyfill::{fence, Ordering};
 
-use super::{FlashRegion, FlashSector, BANK1_REGION, FLASH_REGIONS, WRITE_SIZE};
+use super::{FlashRegion, FlashSector, ProtectionCause, WriteUnit, BANK1_REGION, FLASH_REGIONS, WRITE_SIZE};
 use crate::flash::Error;
 use crate::pac;
 
@@ -15,6 +15,9 @@ pub fn get_flash_regions() -> &'static [&'static FlashRegion] {
     &FLASH_REGIONS
 }
 
+/// Readable areas outside of the main flash: the system memory of bank 1, including the unique ID.
+pub(crate) const READ_ONLY_AREAS: &[core::ops::Range<u32>] = &[0x1FF0_0000..0x1FF2_0000];
+
 pub(crate) unsafe fn lock() {
     pac::FLASH.bank(0).cr().modify(|w| w.set_lock(true));
     if is_dual_bank() {
@@ -35,9 +38,22 @@ pub(crate) unsafe fn begin_write() {
     assert_eq!(0, WRITE_SIZE % 4);
 }
 
-pub(crate) unsafe fn end_write() {}
+/// Clears PG and SER, also after an interrupted write or erase.
+pub(crate) unsafe fn end_write() {
+    let end = |bank: pac::flash::Bank| {
+        bank.cr().modify(|w| {
+            w.set_pg(false);
+            w.set_ser(false);
+        })
+    };
+    end(pac::FLASH.bank(0));
+    if is_dual_bank() {
+        end(pac::FLASH.bank(1));
+    }
+}
 
-pub(crate) unsafe fn blocking_write(start_address: u32, buf: &[u8; WRITE_SIZE]) -> Result<(), Error> {
+pub(crate) unsafe fn blocking_write<const N: usize>(start_address: u32, buf: &[u8; N]) -> Result<(), Error> {
+    let () = WriteUnit::<N>::OK;
     // We cannot have the write setup sequence in begin_write as it depends on the address
     let bank = if start_address < BANK1_REGION.end() {
         pac::FLASH.bank(0)
@@ -79,6 +95,11 @@ pub(crate) unsafe fn blocking_write(start_address: u32, buf: &[u8; WRITE_SIZE])
 }
 
 pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), Error> {
+    start_erase_sector(sector);
+    finish_erase_sector(sector)
+}
+
+pub(crate) unsafe fn start_erase_sector(sector: &FlashSector) {
     let bank = pac::FLASH.bank(sector.bank as usize);
     bank.cr().modify(|w| {
         w.set_ser(true);
@@ -88,7 +109,10 @@ pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), E
     bank.cr().modify(|w| {
         w.set_start(true);
     });
+}
 
+pub(crate) unsafe fn finish_erase_sector(sector: &FlashSector) -> Result<(), Error> {
+    let bank = pac::FLASH.bank(sector.bank as usize);
     let ret: Result<(), Error> = blocking_wait_ready(bank);
 
     bank.cr().modify(|w| w.set_ser(false));
@@ -98,6 +122,18 @@ pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), E
     ret
 }
 
+pub(crate) fn is_busy() -> bool {
+    let busy = |bank: pac::flash::Bank| {
+        let sr = unsafe { bank.sr().read() };
+        sr.bsy() || sr.qw()
+    };
+    busy(pac::FLASH.bank(0)) || (is_dual_bank() && busy(pac::FLASH.bank(1)))
+}
+
+pub(crate) unsafe fn wait_idle() {
+    while is_busy() {}
+}
+
 pub(crate) unsafe fn clear_all_err() {
     bank_clear_all_err(pac::FLASH.bank(0));
     bank_clear_all_err(pac::FLASH.bank(1));
@@ -139,8 +175,32 @@ unsafe fn bank_clear_all_err(bank: pac::flash::Bank) {
     });
 }
 
+pub(crate) fn protection_cause(address: u32) -> Option<ProtectionCause> {
+    let bank = if address < BANK1_REGION.end() {
+        pac::FLASH.bank(0)
+    } else {
+        pac::FLASH.bank(1)
+    };
+    // The error flags of the rejected operation are kept until the next operation clears them.
+    let sr = unsafe { bank.sr().read() };
+
+    if sr.wrperr() {
+        return Some(ProtectionCause::WriteProtection);
+    }
+    if sr.rdperr() {
+        return Some(ProtectionCause::ProprietaryCodeProtection);
+    }
+    if sr.rdserr() {
+        return Some(ProtectionCause::SecureArea);
+    }
+
+    None
+}
+
 unsafe fn blocking_wait_ready(bank: pac::flash::Bank) -> Result<(), Error> {
     loop {
+        super::count_poll();
+
         let sr = bank.sr().read();
 
         if !sr.bsy() && !sr.qw() {
@@ -0,0 +1,205 @@
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::MultiWakerRegistration;
+
+use super::{family, Flash};
+
+/// Number of tasks that can wait at the same time. Further waiters wake the registered ones, which register again.
+const WAKERS: usize = 4;
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State::new()));
+
+struct State {
+    /// A program or erase operation is in progress, and its completion will be reported.
+    active: bool,
+    /// The last operation was abandoned before it completed, so its completion is only noticed by polling.
+    abandoned: bool,
+    /// Number of completed operations, wrapping.
+    completions: u32,
+    wakers: MultiWakerRegistration<WAKERS>,
+}
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            active: false,
+            abandoned: false,
+            completions: 0,
+            wakers: MultiWakerRegistration::new(),
+        }
+    }
+
+    fn begin(&mut self) {
+        self.active = true;
+        self.abandoned = false;
+    }
+
+    fn end(&mut self) {
+        self.active = false;
+        self.abandoned = false;
+        self.completions = self.completions.wrapping_add(1);
+        self.wakers.wake();
+    }
+
+    fn abandon(&mut self) {
+        self.active = false;
+        self.abandoned = true;
+        // The waiters poll from now on
+        self.wakers.wake();
+    }
+
+    /// Poll for `ready`, with `busy` the state of the controller.
+    fn poll(&mut self, busy: bool, waker: &Waker, ready: impl FnOnce(&Self, bool) -> bool) -> Poll<()> {
+        if self.abandoned && !busy {
+            self.end();
+        }
+        if ready(self, busy) {
+            return Poll::Ready(());
+        }
+
+        if !self.active && busy {
+            // Nothing reports the end of an abandoned operation, or of one that was not started by this driver
+            waker.wake_by_ref();
+        } else if self.wakers.register(waker).is_err() {
+            self.wakers.wake();
+            unwrap!(self.wakers.register(waker).ok());
+        }
+        Poll::Pending
+    }
+}
+
+/// Mark the start of a program or erase operation.
+pub(crate) fn begin() {
+    STATE.lock(|state| state.borrow_mut().begin());
+}
+
+/// Mark the completion of the operation, and wake the waiting tasks.
+pub(crate) fn end() {
+    STATE.lock(|state| state.borrow_mut().end());
+}
+
+/// Mark the operation as abandoned before it completed.
+pub(crate) fn abandon() {
+    STATE.lock(|state| state.borrow_mut().abandon());
+}
+
+/// Report the completion of an abandoned operation, once the driver waited for it, if no waiter noticed it yet.
+pub(crate) fn finish_abandoned() {
+    STATE.lock(|state| {
+        let mut state = state.borrow_mut();
+        if state.abandoned {
+            state.end();
+        }
+    });
+}
+
+async fn wait_until(mut ready: impl FnMut(&State, bool) -> bool) {
+    poll_fn(|cx| STATE.lock(|state| state.borrow_mut().poll(family::is_busy(), cx.waker(), &mut ready))).await
+}
+
+/// Subscription to the completions of flash operations, created with [`Flash::subscribe_idle`].
+pub struct IdleSubscription {
+    seen: u32,
+}
+
+impl IdleSubscription {
+    /// Wait until a program or erase operation completes that was not yet reported to this subscription.
+    ///
+    /// Completions are counted, not queued: if multiple operations completed since the last call, this returns
+    /// once for all of them.
+    pub async fn next(&mut self) {
+        let seen = self.seen;
+        wait_until(|state, _| state.completions != seen).await;
+        self.seen = STATE.lock(|state| state.borrow().completions);
+    }
+}
+
+impl Flash<'_> {
+    /// Wait until the flash controller is idle.
+    ///
+    /// This returns immediately if no operation is in progress. It only observes the controller: it doesn't
+    /// reserve it, so another operation can start as soon as this returns. Operations of this driver wake the
+    /// waiting tasks when they complete, whether they are blocking or started with [`Flash::try_start_erase`].
+    /// An operation that was abandoned, or that was not started by this driver, is polled for instead.
+    pub async fn wait_idle() {
+        wait_until(|state, busy| !state.active && !busy).await
+    }
+
+    /// Subscribe to the completions of program and erase operations, starting with the ones that complete after
+    /// this call.
+    pub fn subscribe_idle() -> IdleSubscription {
+        IdleSubscription {
+            seen: STATE.lock(|state| state.borrow().completions),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::task::Context;
+
+    use futures::task::noop_waker_ref;
+
+    use super::*;
+
+    fn poll(state: &mut State, busy: bool, ready: impl FnOnce(&State, bool) -> bool) -> bool {
+        let cx = Context::from_waker(noop_waker_ref());
+        state.poll(busy, cx.waker(), ready).is_ready()
+    }
+
+    fn idle(state: &State, busy: bool) -> bool {
+        !state.active && !busy
+    }
+
+    #[test]
+    fn can_wait_for_completion() {
+        let mut state = State::new();
+        assert!(poll(&mut state, false, idle));
+
+        state.begin();
+        assert!(!poll(&mut state, true, idle));
+        assert!(!poll(&mut state, false, idle));
+        state.end();
+        assert!(poll(&mut state, false, idle));
+
+        // A completion is reported once for every subscription
+        let seen = state.completions;
+        assert!(!poll(&mut state, false, |s, _| s.completions != seen));
+        state.begin();
+        state.end();
+        assert!(poll(&mut state, false, |s, _| s.completions != seen));
+        assert_eq!(seen.wrapping_add(1), state.completions);
+    }
+
+    #[test]
+    fn can_notice_abandoned_completion() {
+        let mut state = State::new();
+        let seen = state.completions;
+        state.begin();
+        state.abandon();
+
+        assert!(!poll(&mut state, true, idle));
+        assert!(!poll(&mut state, true, |s, _| s.completions != seen));
+        assert!(poll(&mut state, false, idle));
+        assert!(poll(&mut state, false, |s, _| s.completions != seen));
+
+        // Recovering afterwards doesn't report it again
+        assert!(!state.abandoned);
+        assert_eq!(seen.wrapping_add(1), state.completions);
+    }
+
+    #[test]
+    fn can_wait_with_more_tasks_than_wakers() {
+        let mut state = State::new();
+        state.begin();
+        for _ in 0..2 * WAKERS {
+            assert!(!poll(&mut state, true, idle));
+        }
+        state.end();
+        assert!(poll(&mut state, false, idle));
+    }
+}
@@ -0,0 +1,329 @@
+use embedded_io::SeekFrom;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use embedded_storage_async::nor_flash::{NorFlash as AsyncNorFlash, ReadNorFlash as AsyncReadNorFlash};
+
+use super::{Error, MAX_WRITE_SIZE};
+
+impl embedded_io::Error for Error {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        embedded_io::ErrorKind::Other
+    }
+}
+
+/// [`embedded_io`] writer that streams data into a flash region.
+///
+/// Written bytes are collected until a complete write unit of the flash's `WRITE_SIZE` is available, which is then
+/// programmed. Call `flush()` to program the final partial unit, padded with `0xFF`.
+/// The target area must have been erased beforehand.
+/// Writing past the end of the region fails with [`Error::OutOfBounds`].
+pub struct FlashWriter<F> {
+    flash: F,
+    start: u32,
+    offset: u32,
+    buf: [u8; MAX_WRITE_SIZE],
+    buffered: usize,
+}
+
+impl<F> FlashWriter<F> {
+    /// Create a writer that starts writing at `start_offset` of `flash`.
+    ///
+    /// Writing fails with [`Error::Unaligned`] if `start_offset` is not aligned to the write size of `flash`.
+    pub fn new(flash: F, start_offset: u32) -> Self {
+        Self {
+            flash,
+            start: start_offset,
+            offset: start_offset,
+            buf: [0; MAX_WRITE_SIZE],
+            buffered: 0,
+        }
+    }
+
+    /// The number of bytes accepted since the start (or the last rewind).
+    pub fn position(&self) -> u32 {
+        self.offset + self.buffered as u32 - self.start
+    }
+
+    /// Restart writing at the start offset, discarding any buffered bytes.
+    ///
+    /// The already programmed area must be erased again before it can be rewritten.
+    pub fn rewind(&mut self) {
+        self.offset = self.start;
+        self.buffered = 0;
+    }
+
+    /// Release the underlying flash. Any buffered bytes are discarded.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Limit `len` to what fits into a region of `capacity` bytes, written in units of `unit` bytes.
+    ///
+    /// # Panics
+    /// Panics if the write size is not supported.
+    fn accepted_len(&self, unit: usize, capacity: usize, len: usize) -> Result<usize, Error> {
+        assert!(unit <= MAX_WRITE_SIZE);
+        if self.start % unit as u32 != 0 {
+            return Err(Error::Unaligned);
+        }
+        let remaining = capacity.saturating_sub(self.offset as usize + self.buffered);
+        if remaining == 0 && len > 0 {
+            return Err(Error::OutOfBounds);
+        }
+        Ok(core::cmp::min(len, remaining))
+    }
+
+    /// Copy as much of `data` into the staging buffer as fits into a unit, returning the amount of bytes taken.
+    fn stage(&mut self, unit: usize, data: &[u8]) -> usize {
+        let n = core::cmp::min(unit - self.buffered, data.len());
+        self.buf[self.buffered..self.buffered + n].copy_from_slice(&data[..n]);
+        self.buffered += n;
+        n
+    }
+
+    fn pad(&mut self, unit: usize) {
+        self.buf[self.buffered..unit].fill(0xFF);
+        self.buffered = unit;
+    }
+}
+
+impl<F> embedded_io::Io for FlashWriter<F> {
+    type Error = Error;
+}
+
+impl<F: NorFlash<Error = Error>> embedded_io::blocking::Write for FlashWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let unit = F::WRITE_SIZE;
+        let len = self.accepted_len(unit, self.flash.capacity(), buf.len())?;
+        let mut data = &buf[..len];
+
+        if self.buffered > 0 {
+            data = &data[self.stage(unit, data)..];
+            if self.buffered < unit {
+                return Ok(len);
+            }
+            self.flash.write(self.offset, &self.buf[..unit])?;
+            self.offset += unit as u32;
+            self.buffered = 0;
+        }
+
+        // Program all complete units directly from the caller's buffer
+        let aligned = data.len() - data.len() % unit;
+        if aligned > 0 {
+            self.flash.write(self.offset, &data[..aligned])?;
+            self.offset += aligned as u32;
+        }
+        self.stage(unit, &data[aligned..]);
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        let unit = F::WRITE_SIZE;
+        if self.buffered > 0 {
+            self.pad(unit);
+            self.flash.write(self.offset, &self.buf[..unit])?;
+            self.offset += unit as u32;
+            self.buffered = 0;
+        }
+        Ok(())
+    }
+}
+
+impl<F: AsyncNorFlash<Error = Error>> embedded_io::asynch::Write for FlashWriter<F> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let unit = F::WRITE_SIZE;
+        let len = self.accepted_len(unit, self.flash.capacity(), buf.len())?;
+        let mut data = &buf[..len];
+
+        if self.buffered > 0 {
+            data = &data[self.stage(unit, data)..];
+            if self.buffered < unit {
+                return Ok(len);
+            }
+            self.flash.write(self.offset, &self.buf[..unit]).await?;
+            self.offset += unit as u32;
+            self.buffered = 0;
+        }
+
+        // Program all complete units directly from the caller's buffer
+        let aligned = data.len() - data.len() % unit;
+        if aligned > 0 {
+            self.flash.write(self.offset, &data[..aligned]).await?;
+            self.offset += aligned as u32;
+        }
+        self.stage(unit, &data[aligned..]);
+
+        Ok(len)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        let unit = F::WRITE_SIZE;
+        if self.buffered > 0 {
+            self.pad(unit);
+            self.flash.write(self.offset, &self.buf[..unit]).await?;
+            self.offset += unit as u32;
+            self.buffered = 0;
+        }
+        Ok(())
+    }
+}
+
+/// [`embedded_io`] reader that reads from a flash region.
+///
+/// Reads never go past the end of the region, a read at the end returns 0 bytes.
+/// Seeking before the start or past the end of the region fails with [`Error::OutOfBounds`].
+pub struct FlashReader<F> {
+    flash: F,
+    offset: u32,
+}
+
+impl<F> FlashReader<F> {
+    /// Create a reader positioned at the start of `flash`.
+    pub fn new(flash: F) -> Self {
+        Self { flash, offset: 0 }
+    }
+
+    /// Release the underlying flash.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    fn readable_len(&self, capacity: usize, len: usize) -> usize {
+        core::cmp::min(len, capacity.saturating_sub(self.offset as usize))
+    }
+
+    fn new_offset(&self, capacity: usize, pos: SeekFrom) -> Result<u32, Error> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => i64::try_from(offset).map_err(|_| Error::OutOfBounds)?,
+            SeekFrom::End(delta) => capacity as i64 + delta,
+            SeekFrom::Current(delta) => self.offset as i64 + delta,
+        };
+        if offset < 0 || offset > capacity as i64 {
+            return Err(Error::OutOfBounds);
+        }
+        Ok(offset as u32)
+    }
+}
+
+impl<F> embedded_io::Io for FlashReader<F> {
+    type Error = Error;
+}
+
+impl<F: ReadNorFlash<Error = Error>> embedded_io::blocking::Read for FlashReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = self.readable_len(self.flash.capacity(), buf.len());
+        self.flash.read(self.offset, &mut buf[..len])?;
+        self.offset += len as u32;
+        Ok(len)
+    }
+}
+
+impl<F: ReadNorFlash<Error = Error>> embedded_io::blocking::Seek for FlashReader<F> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        self.offset = self.new_offset(self.flash.capacity(), pos)?;
+        Ok(self.offset as u64)
+    }
+}
+
+impl<F: AsyncReadNorFlash<Error = Error>> embedded_io::asynch::Read for FlashReader<F> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = self.readable_len(self.flash.capacity(), buf.len());
+        self.flash.read(self.offset, &mut buf[..len]).await?;
+        self.offset += len as u32;
+        Ok(len)
+    }
+}
+
+impl<F: AsyncReadNorFlash<Error = Error>> embedded_io::asynch::Seek for FlashReader<F> {
+    async fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        self.offset = self.new_offset(self.flash.capacity(), pos)?;
+        Ok(self.offset as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_io::blocking::{Read, Seek, Write};
+
+    use super::*;
+    use crate::flash::mem_flash::MemFlash;
+    use crate::flash::WRITE_SIZE;
+
+    #[test]
+    fn can_write_unaligned_pieces() {
+        let mut writer = FlashWriter::new(MemFlash::<256, 64, WRITE_SIZE>::default(), 0);
+        let data: [u8; 100] = core::array::from_fn(|i| i as u8);
+
+        let mut written = 0;
+        for len in [1, 3, 7, 0, 13, 76] {
+            written += writer.write(&data[written..written + len]).unwrap();
+        }
+        assert_eq!(100, writer.position());
+        writer.flush().unwrap();
+
+        let flash = writer.into_inner();
+        assert_eq!(&data[..], &flash.mem[..100]);
+        assert!(flash.mem[100..].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn can_write_units_of_the_flash_write_size() {
+        let data: [u8; 21] = core::array::from_fn(|i| i as u8);
+
+        // Units smaller and larger than the write size of the family
+        let mut writer = FlashWriter::new(MemFlash::<64, 64, 2>::default(), 2);
+        assert_eq!(Ok(21), writer.write(&data));
+        writer.flush().unwrap();
+        let flash = writer.into_inner();
+        assert_eq!(data, flash.mem[2..23]);
+        assert_eq!(0xFF, flash.mem[23]);
+
+        let mut writer = FlashWriter::new(MemFlash::<64, 64, 32>::default(), 32);
+        for chunk in data.chunks(5) {
+            writer.write(chunk).unwrap();
+        }
+        writer.flush().unwrap();
+        let flash = writer.into_inner();
+        assert_eq!(data, flash.mem[32..53]);
+        assert!(flash.mem[53..].iter().all(|&b| b == 0xFF));
+
+        // The start must be aligned to the write size of the flash
+        let mut writer = FlashWriter::new(MemFlash::<64, 64, 32>::default(), 16);
+        assert_eq!(Err(Error::Unaligned), writer.write(&data));
+    }
+
+    #[test]
+    fn can_reject_write_past_end() {
+        let mut writer = FlashWriter::new(MemFlash::<64, 64, WRITE_SIZE>::default(), 32);
+
+        assert_eq!(Ok(32), writer.write(&[0; 40]));
+        assert_eq!(Err(Error::OutOfBounds), writer.write(&[0; 1]));
+        assert_eq!(Ok(0), writer.write(&[]));
+
+        writer.rewind();
+        assert_eq!(0, writer.position());
+        assert_eq!(Ok(1), writer.write(&[0; 1]));
+    }
+
+    #[test]
+    fn can_read_and_seek() {
+        let mut flash = MemFlash::<64, 64, WRITE_SIZE>::default();
+        flash.mem = core::array::from_fn(|i| i as u8);
+        let mut reader = FlashReader::new(flash);
+
+        let mut buf = [0; 8];
+        assert_eq!(Ok(8), reader.read(&mut buf));
+        assert_eq!([0, 1, 2, 3, 4, 5, 6, 7], buf);
+
+        assert_eq!(Ok(60), reader.seek(SeekFrom::End(-4)));
+        assert_eq!(Ok(4), reader.read(&mut buf));
+        assert_eq!([60, 61, 62, 63], buf[..4]);
+        assert_eq!(Ok(0), reader.read(&mut buf));
+
+        assert_eq!(Ok(62), reader.seek(SeekFrom::Current(-2)));
+        assert_eq!(Err(Error::OutOfBounds), reader.seek(SeekFrom::Start(65)));
+        assert_eq!(Err(Error::OutOfBounds), reader.seek(SeekFrom::Current(-63)));
+        assert_eq!(Ok(64), reader.seek(SeekFrom::Start(64)));
+        assert_eq!(Ok(0), reader.read(&mut buf));
+    }
+}
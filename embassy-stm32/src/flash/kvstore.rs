@@ -0,0 +1,536 @@
+use embedded_storage::nor_flash::NorFlash;
+
+use super::pages::{round_up, Newest, PageHeader, Pages};
+use super::MAX_WRITE_SIZE;
+
+/// The magic of the page header, whose check word is the complement of the sequence number.
+const MAGIC: u32 = 0x4B56_5354;
+
+/// Size of the record header (key, value length and CRC-32).
+const RECORD_HEADER_LEN: usize = 8;
+
+/// The key of an erased record header, which can't be stored.
+const ERASED_KEY: u16 = 0xFFFF;
+
+/// Error returned by [`KvStore`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KvError<E> {
+    /// The underlying flash failed.
+    Flash(E),
+    /// The key `0xFFFF` is reserved.
+    InvalidKey,
+    /// The value doesn't fit into a page, together with the latest values of all other keys.
+    Full,
+    /// The buffer passed to [`KvStore::fetch`] is smaller than the value, which is `len` bytes long.
+    BufferTooSmall { len: usize },
+}
+
+impl<E> From<E> for KvError<E> {
+    fn from(e: E) -> Self {
+        Self::Flash(e)
+    }
+}
+
+/// Key-value store for small settings, alternating between two pages to level the wear.
+///
+/// The active page starts with a header holding a sequence number, followed by a log of records. Each record
+/// holds a key, the length of the value, a CRC-32 and the value, and is padded to the write size. Storing a
+/// value appends a record, and fetching a key returns the value of its last valid record.
+///
+/// When the active page is full, the latest value of every key is copied into the other page, which is erased
+/// first. Its header, with the next sequence number, is written last, and only then the full page is erased. A
+/// compaction that is interrupted, e.g. by a power loss, leaves the other page without a valid header, so the
+/// full page stays active. If only the final erase was interrupted, both pages are valid and the one with the
+/// newer sequence number is used. The sequence numbers are compared with wrapping arithmetic.
+///
+/// Records whose CRC doesn't match, e.g. because their write was interrupted, are skipped. If the end of the log
+/// can't be found, the page is treated as full and is compacted by the next store.
+///
+/// Both pages must read as `0xFF` when erased. Two sectors of one region can be used with
+/// [`RegionPart::split_at`](super::RegionPart::split_at).
+pub struct KvStore<F> {
+    pages: Pages<F>,
+}
+
+/// The active page.
+type Active = Newest<()>;
+
+#[derive(Debug, Clone, Copy)]
+enum Entry {
+    /// A valid record of `key` with a value of `len` bytes.
+    Record { key: u16, len: usize, size: usize },
+    /// A record whose CRC doesn't match, of `size` bytes.
+    Invalid { size: usize },
+    /// The end of the log.
+    End,
+}
+
+impl<F: NorFlash> KvStore<F> {
+    /// Create a store in the pages `a` and `b`.
+    ///
+    /// # Panics
+    /// Panics if the pages differ in size or can't hold a record, or the write size is not supported.
+    pub fn new(a: F, b: F) -> Self {
+        assert_eq!(a.capacity(), b.capacity());
+        let this = Self {
+            pages: Pages::new(a, b),
+        };
+        assert!(this.capacity() >= this.header_size() + round_up::<F>(RECORD_HEADER_LEN));
+        this
+    }
+
+    /// Release the underlying pages.
+    pub fn into_inner(self) -> (F, F) {
+        self.pages.into_inner()
+    }
+
+    /// Copy the latest value of `key` into `buf`, and return its length, or `None` if the key was never stored.
+    pub fn fetch(&mut self, key: u16, buf: &mut [u8]) -> Result<Option<usize>, KvError<F::Error>> {
+        let Some(active) = self.active()? else {
+            return Ok(None);
+        };
+        let Some((offset, len)) = self.find(active.page, key, self.header_size())? else {
+            return Ok(None);
+        };
+        if len > buf.len() {
+            return Err(KvError::BufferTooSmall { len });
+        }
+        self.pages[active.page].read((offset + RECORD_HEADER_LEN) as u32, &mut buf[..len])?;
+        Ok(Some(len))
+    }
+
+    /// Store `value` as the latest value of `key`.
+    ///
+    /// The record is appended to the active page, which is compacted first if the record doesn't fit.
+    pub fn store(&mut self, key: u16, value: &[u8]) -> Result<(), KvError<F::Error>> {
+        if key == ERASED_KEY {
+            return Err(KvError::InvalidKey);
+        }
+        let size = round_up::<F>(RECORD_HEADER_LEN + value.len());
+        if value.len() > u16::MAX as usize || self.header_size() + size > self.capacity() {
+            return Err(KvError::Full);
+        }
+
+        let active = match self.active()? {
+            Some(active) => active,
+            None => {
+                // Nothing was stored yet, or the only page written was never completed
+                let capacity = self.capacity() as u32;
+                self.pages[0].erase(0, capacity)?;
+                self.write_header(0, 0)?;
+                Active {
+                    page: 0,
+                    seq: 0,
+                    value: (),
+                }
+            }
+        };
+
+        let end = self.end(active.page)?;
+        if end + size <= self.capacity() {
+            self.write_record(active.page, end, key, value)?;
+            return Ok(());
+        }
+        self.compact(active, key, value)
+    }
+
+    fn capacity(&self) -> usize {
+        self.pages[0].capacity()
+    }
+
+    fn header_size(&self) -> usize {
+        self.pages.header_size()
+    }
+
+    /// Find the page with a valid header and the newest sequence number.
+    fn active(&mut self) -> Result<Option<Active>, F::Error> {
+        self.pages
+            .newest(MAGIC, |_, header| Ok((header.check == !header.seq).then_some(())))
+    }
+
+    /// Read the log entry at `offset` of `page`.
+    fn entry(&mut self, page: usize, offset: usize) -> Result<Entry, F::Error> {
+        if offset + RECORD_HEADER_LEN > self.capacity() {
+            return Ok(Entry::End);
+        }
+        let mut header = [0; RECORD_HEADER_LEN];
+        self.pages[page].read(offset as u32, &mut header)?;
+        if header.iter().all(|&b| b == 0xFF) {
+            return Ok(Entry::End);
+        }
+
+        let key = u16::from_le_bytes([header[0], header[1]]);
+        let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let size = round_up::<F>(RECORD_HEADER_LEN + len);
+        if offset + size > self.capacity() {
+            // The length is corrupt, the following records can't be found
+            return Ok(Entry::End);
+        }
+
+        let mut crc = super::firmware::crc32(!0, &header[..4]);
+        let mut chunk = [0; MAX_WRITE_SIZE];
+        let mut read = 0;
+        while read < len {
+            let n = core::cmp::min(MAX_WRITE_SIZE, len - read);
+            self.pages[page].read((offset + RECORD_HEADER_LEN + read) as u32, &mut chunk[..n])?;
+            crc = super::firmware::crc32(crc, &chunk[..n]);
+            read += n;
+        }
+
+        if crc ^ !0 == u32::from_le_bytes(header[4..].try_into().unwrap()) && key != ERASED_KEY {
+            Ok(Entry::Record { key, len, size })
+        } else {
+            Ok(Entry::Invalid { size })
+        }
+    }
+
+    /// Find the last valid record of `key` in `page` from `offset` on, and return its offset and value length.
+    fn find(&mut self, page: usize, key: u16, mut offset: usize) -> Result<Option<(usize, usize)>, F::Error> {
+        let mut found = None;
+        loop {
+            match self.entry(page, offset)? {
+                Entry::Record { key: k, len, size } => {
+                    if k == key {
+                        found = Some((offset, len));
+                    }
+                    offset += size;
+                }
+                Entry::Invalid { size } => offset += size,
+                Entry::End => return Ok(found),
+            }
+        }
+    }
+
+    /// The offset at which the next record of `page` is appended.
+    ///
+    /// If the rest of the page after the last record is not erased, e.g. because a record write was interrupted
+    /// before its header was programmed, nothing can be appended and the capacity is returned.
+    fn end(&mut self, page: usize) -> Result<usize, F::Error> {
+        let mut offset = self.header_size();
+        loop {
+            match self.entry(page, offset)? {
+                Entry::Record { size, .. } | Entry::Invalid { size } => offset += size,
+                Entry::End => break,
+            }
+        }
+
+        let mut chunk = [0; MAX_WRITE_SIZE];
+        let mut address = offset;
+        while address < self.capacity() {
+            let n = core::cmp::min(MAX_WRITE_SIZE, self.capacity() - address);
+            self.pages[page].read(address as u32, &mut chunk[..n])?;
+            if chunk[..n].iter().any(|&b| b != 0xFF) {
+                return Ok(self.capacity());
+            }
+            address += n;
+        }
+        Ok(offset)
+    }
+
+    /// Copy the latest value of every key other than `key` from the `active` page into the other one, append
+    /// `value`, and make the other page active.
+    fn compact(&mut self, active: Active, key: u16, value: &[u8]) -> Result<(), KvError<F::Error>> {
+        let target = 1 - active.page;
+        let capacity = self.capacity() as u32;
+        self.pages[target].erase(0, capacity)?;
+
+        let mut end = self.header_size();
+        let mut offset = self.header_size();
+        loop {
+            match self.entry(active.page, offset)? {
+                Entry::Record { key: k, size, .. } => {
+                    // Only the last record of a key is copied
+                    let superseded = self.find(active.page, k, offset + size)?.is_some();
+                    if k != key && !superseded {
+                        if end + size > self.capacity() {
+                            return Err(KvError::Full);
+                        }
+                        self.copy_record(active.page, offset, target, end, size)?;
+                        end += size;
+                    }
+                    offset += size;
+                }
+                Entry::Invalid { size } => offset += size,
+                Entry::End => break,
+            }
+        }
+
+        if end + round_up::<F>(RECORD_HEADER_LEN + value.len()) > self.capacity() {
+            return Err(KvError::Full);
+        }
+        self.write_record(target, end, key, value)?;
+        self.write_header(target, active.seq.wrapping_add(1))?;
+        self.pages[active.page].erase(0, capacity)?;
+        Ok(())
+    }
+
+    fn write_header(&mut self, page: usize, seq: u32) -> Result<(), F::Error> {
+        self.pages.write_header(page, MAGIC, PageHeader { seq, check: !seq })
+    }
+
+    /// Write a record of `key` with `value` at `offset` of `page`, padded to the write size.
+    fn write_record(&mut self, page: usize, offset: usize, key: u16, value: &[u8]) -> Result<(), F::Error> {
+        let mut header = [0; RECORD_HEADER_LEN];
+        header[..2].copy_from_slice(&key.to_le_bytes());
+        header[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        let crc = super::firmware::crc32(super::firmware::crc32(!0, &header[..4]), value) ^ !0;
+        header[4..].copy_from_slice(&crc.to_le_bytes());
+
+        let mut chunk = [0xFF; MAX_WRITE_SIZE];
+        let mut filled = 0;
+        let mut address = offset;
+        for &byte in header.iter().chain(value) {
+            chunk[filled] = byte;
+            filled += 1;
+            if filled == MAX_WRITE_SIZE {
+                self.pages[page].write(address as u32, &chunk)?;
+                address += MAX_WRITE_SIZE;
+                chunk = [0xFF; MAX_WRITE_SIZE];
+                filled = 0;
+            }
+        }
+        if filled > 0 {
+            self.pages[page].write(address as u32, &chunk[..round_up::<F>(filled)])?;
+        }
+        Ok(())
+    }
+
+    /// Copy the `size` bytes of the record at `from` of page `src` to `to` of page `dst`.
+    fn copy_record(&mut self, src: usize, from: usize, dst: usize, to: usize, size: usize) -> Result<(), F::Error> {
+        let mut chunk = [0; MAX_WRITE_SIZE];
+        let mut copied = 0;
+        while copied < size {
+            let n = core::cmp::min(MAX_WRITE_SIZE, size - copied);
+            self.pages[src].read((from + copied) as u32, &mut chunk[..n])?;
+            self.pages[dst].write((to + copied) as u32, &chunk[..n])?;
+            copied += n;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mem_flash::{MemFlash, Operation, Recorder};
+    use crate::flash::Error;
+
+    type Page = MemFlash<256, 64, 8>;
+
+    fn store() -> KvStore<Page> {
+        KvStore::new(Page::default(), Page::default())
+    }
+
+    fn fetch(store: &mut KvStore<Page>, key: u16) -> Option<[u8; 4]> {
+        let mut buf = [0; 4];
+        let len = store.fetch(key, &mut buf).unwrap()?;
+        assert_eq!(4, len);
+        Some(buf)
+    }
+
+    #[test]
+    fn can_store_and_fetch() {
+        let mut store = store();
+        assert_eq!(None, fetch(&mut store, 1));
+
+        store.store(1, &[1, 2, 3, 4]).unwrap();
+        store.store(2, &[5, 6, 7, 8]).unwrap();
+        store.store(1, &[9, 9, 9, 9]).unwrap();
+        assert_eq!(Some([9, 9, 9, 9]), fetch(&mut store, 1));
+        assert_eq!(Some([5, 6, 7, 8]), fetch(&mut store, 2));
+        assert_eq!(None, fetch(&mut store, 3));
+
+        // Values of any length, also empty ones
+        store.store(3, &[]).unwrap();
+        store.store(4, &[0xAB; 37]).unwrap();
+        let mut buf = [0; 40];
+        assert_eq!(Some(0), store.fetch(3, &mut buf).unwrap());
+        assert_eq!(Some(37), store.fetch(4, &mut buf).unwrap());
+        assert_eq!([0xAB; 37][..], buf[..37]);
+        assert_eq!(Err(KvError::BufferTooSmall { len: 37 }), store.fetch(4, &mut buf[..4]));
+
+        assert_eq!(Err(KvError::InvalidKey), store.store(0xFFFF, &[0]));
+        assert_eq!(Err(KvError::Full), store.store(5, &[0; 256]));
+    }
+
+    #[test]
+    fn can_compact_full_page() {
+        let mut store = store();
+        // A record takes 16 bytes, so a page holds 15 of them after the header
+        for value in 0..100u8 {
+            store.store(value as u16 % 3, &[value; 4]).unwrap();
+            store.store(7, &[0x77; 4]).unwrap();
+            assert_eq!(Some([value; 4]), fetch(&mut store, value as u16 % 3));
+        }
+        assert_eq!(Some([97; 4]), fetch(&mut store, 1));
+        assert_eq!(Some([98; 4]), fetch(&mut store, 2));
+        assert_eq!(Some([99; 4]), fetch(&mut store, 0));
+        assert_eq!(Some([0x77; 4]), fetch(&mut store, 7));
+
+        // Exactly one page is active, the other one is erased
+        let (a, b) = store.into_inner();
+        let erased = |page: &Page| page.mem.iter().all(|&byte| byte == 0xFF);
+        assert!(erased(&a) != erased(&b));
+
+        // The latest values of all other keys don't leave room for the new one
+        let mut store = KvStore::new(Page::default(), Page::default());
+        for key in 0..15 {
+            store.store(key, &[key as u8; 4]).unwrap();
+        }
+        assert_eq!(Err(KvError::Full), store.store(15, &[15; 4]));
+        assert_eq!(Some([3; 4]), fetch(&mut store, 3));
+        store.store(3, &[33; 4]).unwrap();
+        assert_eq!(Some([33; 4]), fetch(&mut store, 3));
+    }
+
+    #[test]
+    fn writes_header_after_copied_records() {
+        static RECORDER_A: Recorder = Recorder::new();
+        static RECORDER_B: Recorder = Recorder::new();
+        let (mut a, mut b) = (Page::default(), Page::default());
+        a.observer = Some(&RECORDER_A);
+        b.observer = Some(&RECORDER_B);
+        let mut store = KvStore::new(a, b);
+
+        // The first record starts the page, later ones are appended with a single write
+        store.store(1, &[1; 4]).unwrap();
+        RECORDER_A.assert_and_clear(&[
+            Operation::Erase(0),
+            Operation::Erase(64),
+            Operation::Erase(128),
+            Operation::Erase(192),
+            Operation::Write(0, 16),
+            Operation::Write(16, 16),
+        ]);
+        store.store(2, &[2; 4]).unwrap();
+        RECORDER_A.assert_and_clear(&[Operation::Write(32, 16)]);
+        for value in 3..16 {
+            store.store(1, &[value; 4]).unwrap();
+        }
+        let appended: [_; 13] = core::array::from_fn(|i| Operation::Write(48 + 16 * i as u32, 16));
+        RECORDER_A.assert_and_clear(&appended);
+        RECORDER_B.assert_and_clear(&[]);
+
+        // The full page is compacted into the other one, whose header is written only after the records, and
+        // the old page is erased last
+        store.store(3, &[3; 4]).unwrap();
+        RECORDER_B.assert_and_clear(&[
+            Operation::Erase(0),
+            Operation::Erase(64),
+            Operation::Erase(128),
+            Operation::Erase(192),
+            Operation::Write(16, 16),
+            Operation::Write(32, 16),
+            Operation::Write(48, 16),
+            Operation::Write(0, 16),
+        ]);
+        RECORDER_A.assert_and_clear(&[
+            Operation::Erase(0),
+            Operation::Erase(64),
+            Operation::Erase(128),
+            Operation::Erase(192),
+        ]);
+    }
+
+    fn store_and_compact<F: NorFlash + Default>() {
+        let mut store = KvStore::new(F::default(), F::default());
+        let mut buf = [0; 5];
+        for value in 0..50u8 {
+            store.store(value as u16 % 3, &[value; 5]).unwrap();
+            assert_eq!(Some(5), store.fetch(value as u16 % 3, &mut buf).unwrap());
+            assert_eq!([value; 5], buf);
+        }
+    }
+
+    #[test]
+    fn can_use_any_write_size() {
+        store_and_compact::<MemFlash<256, 64, 1>>();
+        store_and_compact::<MemFlash<256, 64, 2>>();
+        store_and_compact::<MemFlash<256, 64, 4>>();
+        store_and_compact::<MemFlash<256, 64, 32>>();
+    }
+
+    #[test]
+    fn skips_corrupt_records() {
+        let mut store = store();
+        store.store(1, &[1; 4]).unwrap();
+        store.store(1, &[2; 4]).unwrap();
+        store.store(2, &[3; 4]).unwrap();
+
+        // A flipped bit in the value of the second record
+        let (mut a, b) = store.into_inner();
+        a.mem[16 + 16 + 9] ^= 0x04;
+        let mut store = KvStore::new(a, b);
+        assert_eq!(Some([1; 4]), fetch(&mut store, 1));
+        assert_eq!(Some([3; 4]), fetch(&mut store, 2));
+
+        store.store(1, &[4; 4]).unwrap();
+        assert_eq!(Some([4; 4]), fetch(&mut store, 1));
+
+        // A record that is not erased but has no header can't be appended to, so the page is compacted
+        let (mut a, b) = store.into_inner();
+        a.mem[16 + 4 * 16 + 12] = 0;
+        let mut store = KvStore::new(a, b);
+        store.store(2, &[5; 4]).unwrap();
+        assert_eq!(Some([4; 4]), fetch(&mut store, 1));
+        assert_eq!(Some([5; 4]), fetch(&mut store, 2));
+        let (a, _) = store.into_inner();
+        assert!(a.mem.iter().all(|&byte| byte == 0xFF));
+    }
+
+    #[test]
+    fn recovers_from_power_loss() {
+        let mut store = store();
+        let mut acknowledged = [None; 3];
+
+        for (value, successes) in (0..8).cycle().take(200).enumerate() {
+            let key = value % 3;
+            let (mut a, mut b) = store.into_inner();
+            // Cut the power after `successes` writes
+            a.pending_write_successes = Some(successes);
+            b.pending_write_successes = Some(successes);
+
+            let mut interrupted = KvStore::new(a, b);
+            match interrupted.store(key as u16, &[value as u8; 4]) {
+                Ok(()) => acknowledged[key] = Some(value as u8),
+                Err(e) => assert_eq!(KvError::Flash(Error::Prog), e),
+            }
+
+            let (mut a, mut b) = interrupted.into_inner();
+            a.pending_write_successes = None;
+            b.pending_write_successes = None;
+            store = KvStore::new(a, b);
+
+            // The acknowledged values survive, also if a compaction was interrupted
+            for key in 0..3 {
+                let fetched = fetch(&mut store, key as u16).map(|value| value[0]);
+                assert_eq!(acknowledged[key], fetched);
+            }
+        }
+        assert!(acknowledged.iter().all(|value| value.is_some()));
+    }
+
+    #[test]
+    fn keeps_newest_page_if_erase_was_interrupted() {
+        let mut store = store();
+        store.store(1, &[1; 4]).unwrap();
+        let (a, b) = store.into_inner();
+        let stale = a.mem;
+
+        // The 16th record doesn't fit, so the page is compacted into the other one and erased
+        let mut store = KvStore::new(a, b);
+        for value in 2..17 {
+            store.store(1, &[value; 4]).unwrap();
+        }
+        let (mut a, b) = store.into_inner();
+        assert!(a.mem.iter().all(|&byte| byte == 0xFF));
+
+        // Both pages are valid if the final erase was cut, the newer one is used
+        a.mem = stale;
+        let mut store = KvStore::new(a, b);
+        assert_eq!(Some([16; 4]), fetch(&mut store, 1));
+        store.store(1, &[17; 4]).unwrap();
+        assert_eq!(Some([17; 4]), fetch(&mut store, 1));
+    }
+}
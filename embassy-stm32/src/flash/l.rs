@@ -2,12 +2,488 @@ use core::ptr::write_volatile;
 
 use atomic_polyfill::{fence, Ordering};
 
-use super::{FlashRegion, FlashSector, FLASH_REGIONS, WRITE_SIZE};
+use super::{FlashRegion, FlashSector, ProtectionCause, WriteUnit, WRITE_SIZE};
 use crate::flash::Error;
+#[cfg(flash_l4)]
+use crate::flash::FlashBank;
 use crate::pac;
 
+#[cfg(any(stm32l4rx, stm32l4sx, stm32l49x, stm32l4ax))]
+mod bank_modes {
+    use stm32_metapac::{FLASH_BASE, FLASH_SIZE};
+
+    use crate::_generated::flash_regions::{BANK1_REGION, SINGLE_BANK_ERASE_SIZE};
+    use crate::flash::{Flash, FlashBank, FlashRegion};
+
+    /// Bank configuration of the flash, as selected by the DBANK/DUALBANK option bit.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub enum BankMode {
+        /// One bank, with double sized pages on the L4R/L4S. The L49x/L4Ax keep their 2 K pages.
+        Single,
+        /// Two banks, split at half of the flash size.
+        Dual,
+    }
+
+    #[cfg(any(stm32l4rx, stm32l4sx))]
+    const OPTR_DUAL_BANK: u32 = 1 << 22;
+    #[cfg(any(stm32l49x, stm32l4ax))]
+    const OPTR_DUAL_BANK: u32 = 1 << 21;
+
+    pub const SINGLE_BANK_REGION: FlashRegion = FlashRegion {
+        bank: FlashBank::Bank1,
+        base: FLASH_BASE as u32,
+        size: FLASH_SIZE as u32,
+        erase_size: SINGLE_BANK_ERASE_SIZE,
+        write_size: 8,
+        erase_value: 0xFF,
+    };
+    pub const DUAL_BANK1_REGION: FlashRegion = FlashRegion {
+        size: FLASH_SIZE as u32 / 2,
+        erase_size: BANK1_REGION.erase_size,
+        ..SINGLE_BANK_REGION
+    };
+    pub const DUAL_BANK2_REGION: FlashRegion = FlashRegion {
+        bank: FlashBank::Bank2,
+        base: FLASH_BASE as u32 + FLASH_SIZE as u32 / 2,
+        ..DUAL_BANK1_REGION
+    };
+
+    pub const SINGLE_BANK_REGIONS: [&FlashRegion; 1] = [&SINGLE_BANK_REGION];
+    pub const DUAL_BANK_REGIONS: [&FlashRegion; 2] = [&DUAL_BANK1_REGION, &DUAL_BANK2_REGION];
+
+    const _: () = assert!(crate::flash::validate_regions(&SINGLE_BANK_REGIONS) == FLASH_SIZE as u32);
+    const _: () = assert!(crate::flash::validate_regions(&DUAL_BANK_REGIONS) == FLASH_SIZE as u32);
+
+    /// Read the bank configuration from the loaded option bytes.
+    pub fn bank_mode() -> BankMode {
+        if unsafe { crate::pac::FLASH.optr().read().0 } & OPTR_DUAL_BANK != 0 {
+            BankMode::Dual
+        } else {
+            BankMode::Single
+        }
+    }
+
+    impl<'d> Flash<'d> {
+        /// The bank configuration that is currently active.
+        ///
+        /// All sector based operations use the page size and bank split of this configuration.
+        pub fn bank_mode(&self) -> BankMode {
+            bank_mode()
+        }
+    }
+}
+
+#[cfg(any(stm32l4rx, stm32l4sx, stm32l49x, stm32l4ax))]
+pub use bank_modes::{BankMode, DUAL_BANK_REGIONS, SINGLE_BANK_REGIONS};
+
+#[cfg(any(flash_l0, flash_l4))]
+mod bor_level {
+    use super::{blocking_wait_ready, lock, unlock};
+    use crate::flash::{verify_option_bytes, Error, Flash};
+    use crate::pac;
+
+    /// Brown-out reset threshold, as selected by the BOR_LEV option bits.
+    ///
+    /// The voltages are the typical falling thresholds, see the datasheet for the exact values.
+    #[cfg(flash_l4)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(u8)]
+    pub enum BorLevel {
+        /// Around 1.7 V.
+        Level0 = 0,
+        /// Around 2.0 V.
+        Level1 = 1,
+        /// Around 2.2 V.
+        Level2 = 2,
+        /// Around 2.5 V.
+        Level3 = 3,
+        /// Around 2.8 V.
+        Level4 = 4,
+    }
+
+    /// Brown-out reset threshold, as selected by the BOR_LEV option bits.
+    ///
+    /// The voltages are the typical falling thresholds, see the datasheet for the exact values.
+    #[cfg(flash_l0)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(u8)]
+    pub enum BorLevel {
+        /// Brown-out reset disabled, only the power-down reset is active.
+        Off = 0x0,
+        /// Around 1.7 V.
+        Level1 = 0x8,
+        /// Around 1.9 V.
+        Level2 = 0x9,
+        /// Around 2.3 V.
+        Level3 = 0xA,
+        /// Around 2.55 V.
+        Level4 = 0xB,
+        /// Around 2.8 V.
+        Level5 = 0xC,
+    }
+
+    impl BorLevel {
+        fn from_bits(bits: u8) -> Option<Self> {
+            #[cfg(flash_l4)]
+            let level = match bits {
+                0 => Self::Level0,
+                1 => Self::Level1,
+                2 => Self::Level2,
+                3 => Self::Level3,
+                4 => Self::Level4,
+                _ => return None,
+            };
+            #[cfg(flash_l0)]
+            let level = match bits {
+                0x0..=0x7 => Self::Off,
+                0x8 => Self::Level1,
+                0x9 => Self::Level2,
+                0xA => Self::Level3,
+                0xB => Self::Level4,
+                0xC => Self::Level5,
+                _ => return None,
+            };
+            Some(level)
+        }
+    }
+
+    #[cfg(flash_l4)]
+    const BOR_LEV_SHIFT: u32 = 8;
+    #[cfg(flash_l4)]
+    const BOR_LEV_MASK: u32 = 0b111;
+
+    #[cfg(flash_l0)]
+    const BOR_LEV_SHIFT: u32 = 16;
+    #[cfg(flash_l0)]
+    const BOR_LEV_MASK: u32 = 0b1111;
+
+    /// Address of the user option word, with BOR_LEV in its lower half and the complement in the upper half.
+    #[cfg(flash_l0)]
+    const USER_OPTION_ADDRESS: u32 = 0x1FF8_0004;
+
+    impl<'d> Flash<'d> {
+        /// The brown-out reset level of the loaded option bytes.
+        ///
+        /// Returns `None` if the option bits hold a reserved value.
+        pub fn read_bor_level(&self) -> Option<BorLevel> {
+            let optr = unsafe { pac::FLASH.optr().read().0 };
+            BorLevel::from_bits(((optr >> BOR_LEV_SHIFT) & BOR_LEV_MASK) as u8)
+        }
+
+        /// Programs the brown-out reset level into the option bytes, and verifies the programmed option bytes.
+        ///
+        /// All user options are read back, not only the BOR level. Returns [`Error::OptionByteVerify`] if any of
+        /// them differ, after trying to restore the previous values.
+        ///
+        /// The new level only becomes active after the option bytes are reloaded, by a power-on reset
+        /// or [`Flash::launch_option_bytes`].
+        pub fn set_bor_level(&mut self, level: BorLevel) -> Result<(), Error> {
+            critical_section::with(|_| unsafe {
+                unlock();
+                let result = unlock_option_bytes().and_then(|_| program_bor_level(level));
+                lock();
+                result
+            })
+        }
+
+        /// Reloads the option bytes, which resets the device.
+        ///
+        /// # Panics
+        /// Panics if the option bytes can't be unlocked, see [`Error::Seq`].
+        pub fn launch_option_bytes(&mut self) -> ! {
+            unsafe {
+                unlock();
+                unwrap!(unlock_option_bytes());
+                #[cfg(flash_l4)]
+                pac::FLASH.cr().modify(|w| w.set_obl_launch(true));
+                #[cfg(flash_l0)]
+                pac::FLASH.pecr().modify(|w| w.set_obl_launch(true));
+            }
+            // The reset is not immediate
+            loop {
+                cortex_m::asm::nop();
+            }
+        }
+    }
+
+    /// Unlocks the option bytes, after the flash was unlocked.
+    ///
+    /// Fails with [`Error::Seq`] if they stay locked, which happens after a wrong key until the next reset.
+    pub(super) unsafe fn unlock_option_bytes() -> Result<(), Error> {
+        #[cfg(flash_l4)]
+        {
+            pac::FLASH.optkeyr().write(|w| w.set_optkeyr(0x0819_2A3B));
+            pac::FLASH.optkeyr().write(|w| w.set_optkeyr(0x4C5D_6E7F));
+            if pac::FLASH.cr().read().optlock() {
+                return Err(Error::Seq);
+            }
+        }
+
+        #[cfg(flash_l0)]
+        {
+            pac::FLASH.optkeyr().write(|w| w.set_optkeyr(0xFBEA_D9C8));
+            pac::FLASH.optkeyr().write(|w| w.set_optkeyr(0x2425_2627));
+            if pac::FLASH.pecr().read().optlock() {
+                return Err(Error::Seq);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Address of the user option bytes, followed by their complement.
+    #[cfg(flash_l4)]
+    pub(super) const USER_OPTION_ADDRESS: u32 = 0x1FFF_7800;
+
+    #[cfg(flash_l4)]
+    unsafe fn program_bor_level(level: BorLevel) -> Result<(), Error> {
+        let previous = pac::FLASH.optr().read().0;
+        let expected = (previous & !(BOR_LEV_MASK << BOR_LEV_SHIFT)) | ((level as u32) << BOR_LEV_SHIFT);
+        program_verified(previous, expected)
+    }
+
+    /// Programs the user option word `expected`, and verifies all of its fields, so that an option that was not
+    /// meant to change can't silently take another value.
+    ///
+    /// If the verification fails, the `previous` word is programmed back, so an incomplete write doesn't stay in
+    /// effect. The option bytes are only loaded on the next reload, so this never changes the active options.
+    #[cfg(flash_l4)]
+    pub(super) unsafe fn program_verified(previous: u32, expected: u32) -> Result<(), Error> {
+        program_user_options(expected)?;
+
+        let actual = core::ptr::read_volatile(USER_OPTION_ADDRESS as *const u32);
+        let complement = core::ptr::read_volatile((USER_OPTION_ADDRESS + 4) as *const u32);
+        let result = verify_option_bytes(expected, actual, u32::MAX, complement == !actual);
+        if result.is_err() {
+            let _ = program_user_options(previous);
+        }
+        result
+    }
+
+    #[cfg(flash_l4)]
+    pub(super) unsafe fn program_user_options(optr: u32) -> Result<(), Error> {
+        blocking_wait_ready()?;
+        pac::FLASH.optr().write(|w| w.0 = optr);
+        pac::FLASH.cr().modify(|w| w.set_optstrt(true));
+        blocking_wait_ready()
+    }
+
+    #[cfg(flash_l0)]
+    unsafe fn program_bor_level(level: BorLevel) -> Result<(), Error> {
+        let user = (pac::FLASH.optr().read().0 >> 16) as u16;
+        let previous = core::ptr::read_volatile(USER_OPTION_ADDRESS as *const u32);
+        let expected = user_option_word(user, level);
+        program_user_options(expected)?;
+
+        let actual = core::ptr::read_volatile(USER_OPTION_ADDRESS as *const u32);
+        let result = verify_option_bytes(expected, actual, 0xFFFF, (actual >> 16) as u16 == !(actual as u16));
+        if result.is_err() {
+            // Try to restore the previous options, so an incomplete write doesn't stay in effect
+            let _ = program_user_options(previous);
+        }
+        result
+    }
+
+    #[cfg(flash_l0)]
+    unsafe fn program_user_options(word: u32) -> Result<(), Error> {
+        blocking_wait_ready()?;
+        core::ptr::write_volatile(USER_OPTION_ADDRESS as *mut u32, word);
+        blocking_wait_ready()
+    }
+
+    /// Build the user option word with `level`, keeping the other user options.
+    #[cfg(flash_l0)]
+    fn user_option_word(user: u16, level: BorLevel) -> u32 {
+        let user = (user & !(BOR_LEV_MASK as u16)) | level as u16;
+        ((!user as u32) << 16) | user as u32
+    }
+
+    #[cfg(all(test, flash_l0))]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn can_build_user_option_word() {
+            assert_eq!(0x8F07_70F8, user_option_word(0x70F0, BorLevel::Level1));
+            assert_eq!(0x8F03_70FC, user_option_word(0x70F0, BorLevel::Level5));
+            assert_eq!(Some(BorLevel::Off), BorLevel::from_bits(0x3));
+            assert_eq!(None, BorLevel::from_bits(0xD));
+        }
+    }
+}
+
+#[cfg(any(flash_l0, flash_l4))]
+pub use bor_level::BorLevel;
+
+#[cfg(flash_l4)]
+mod rdp {
+    use super::bor_level::{program_verified, unlock_option_bytes};
+    use super::{lock, unlock};
+    use crate::flash::{ConfirmMassErase, Error, Flash, NeedsReload, RdpLevel};
+    use crate::pac;
+
+    const RDP_MASK: u32 = 0xFF;
+    const RDP_LEVEL0: u8 = 0xAA;
+    const RDP_LEVEL1: u8 = 0xBB;
+    const RDP_LEVEL2: u8 = 0xCC;
+
+    impl RdpLevel {
+        fn from_bits(bits: u8) -> Self {
+            match bits {
+                RDP_LEVEL0 => Self::Level0,
+                RDP_LEVEL2 => Self::Level2,
+                _ => Self::Level1,
+            }
+        }
+    }
+
+    impl<'d> Flash<'d> {
+        /// The readout protection level of the loaded option bytes.
+        pub fn rdp_level(&self) -> RdpLevel {
+            let optr = unsafe { pac::FLASH.optr().read().0 };
+            RdpLevel::from_bits((optr & RDP_MASK) as u8)
+        }
+
+        /// Sets the readout protection to level 0 or 1, and verifies the programmed option bytes.
+        ///
+        /// The level can only be raised. At level 1, setting level 0 returns [`Error::Protected`] as it would mass
+        /// erase the flash; that is only done by [`Flash::regress_rdp`].
+        ///
+        /// Level 2 is permanent and can only be set with [`Flash::set_rdp_level2_permanent`]; passing it here
+        /// returns [`Error::Protected`]. Nothing can be changed anymore at level 2, which also returns
+        /// [`Error::Protected`].
+        ///
+        /// All user options are read back. Returns [`Error::OptionByteVerify`] if any of them differ, after trying
+        /// to restore the previous values.
+        ///
+        /// The new level only becomes active after the option bytes are reloaded, by a power-on reset
+        /// or [`Flash::launch_option_bytes`].
+        pub fn set_rdp_level(&mut self, level: RdpLevel) -> Result<(), Error> {
+            let byte = match (self.rdp_level(), level) {
+                (RdpLevel::Level2, _) | (_, RdpLevel::Level2) => return Err(Error::Protected),
+                (RdpLevel::Level1, RdpLevel::Level0) => return Err(Error::Protected),
+                (RdpLevel::Level0, RdpLevel::Level0) => RDP_LEVEL0,
+                (_, RdpLevel::Level1) => RDP_LEVEL1,
+            };
+            program_rdp_unlocked(byte)
+        }
+
+        /// Sets the readout protection to level 2, and verifies the programmed option bytes.
+        ///
+        /// **This can't be undone.** Once the option bytes are reloaded, the debug interface is disabled for good,
+        /// the option bytes can't be changed anymore and the device can never be reprogrammed from outside;
+        /// only the running firmware can still program and erase the main flash.
+        ///
+        /// Possible from level 0 and 1, nothing is erased. Returns [`Error::Protected`] at level 2.
+        pub fn set_rdp_level2_permanent(&mut self) -> Result<(), Error> {
+            if self.rdp_level() == RdpLevel::Level2 {
+                return Err(Error::Protected);
+            }
+            warn!("Setting readout protection level 2, which is permanent once the option bytes are reloaded");
+            program_rdp_unlocked(RDP_LEVEL2)
+        }
+
+        /// Regresses the readout protection from level 1 to level 0.
+        ///
+        /// **All flash contents are destroyed** by a mass erase when the option bytes are reloaded with the
+        /// returned [`NeedsReload`]. The flash is consumed, as nothing of it can be used after the regression.
+        ///
+        /// Returns [`Error::Protected`] at level 2, which can't be regressed. At level 0, nothing is programmed
+        /// and reloading the option bytes doesn't erase the flash.
+        pub fn regress_rdp(self, _confirm: ConfirmMassErase) -> Result<NeedsReload<'d>, Error> {
+            match self.rdp_level() {
+                RdpLevel::Level0 => return Ok(NeedsReload { flash: self }),
+                RdpLevel::Level2 => return Err(Error::Protected),
+                RdpLevel::Level1 => {}
+            }
+            warn!("Regressing readout protection, the flash is mass erased on the next option byte reload");
+            program_rdp_unlocked(RDP_LEVEL0)?;
+            Ok(NeedsReload { flash: self })
+        }
+    }
+
+    /// Unlocks the option bytes and programs the RDP byte `level` with [`program_rdp`].
+    fn program_rdp_unlocked(level: u8) -> Result<(), Error> {
+        critical_section::with(|_| unsafe {
+            unlock();
+            let result = unlock_option_bytes().and_then(|_| program_rdp(level));
+            lock();
+            result
+        })
+    }
+
+    /// Programs the RDP byte `level`, keeping the other user options.
+    ///
+    /// Restoring the previous word after a failed verification is safe at any level: the new level is only
+    /// loaded on the next reload, so the flash isn't mass erased before it.
+    unsafe fn program_rdp(level: u8) -> Result<(), Error> {
+        let previous = pac::FLASH.optr().read().0;
+        program_verified(previous, rdp_option_word(previous, level))
+    }
+
+    /// Build the user option word with the RDP byte `level`, keeping the other user options.
+    fn rdp_option_word(optr: u32, level: u8) -> u32 {
+        (optr & !RDP_MASK) | level as u32
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn can_decode_rdp_levels() {
+            assert_eq!(RdpLevel::Level0, RdpLevel::from_bits(0xAA));
+            assert_eq!(RdpLevel::Level2, RdpLevel::from_bits(0xCC));
+            // Every other value is level 1
+            assert_eq!(RdpLevel::Level1, RdpLevel::from_bits(0xBB));
+            assert_eq!(RdpLevel::Level1, RdpLevel::from_bits(0x00));
+            assert_eq!(RdpLevel::Level1, RdpLevel::from_bits(0xFF));
+
+            assert_eq!(0xFFEF_F8AA, rdp_option_word(0xFFEF_F8BB, RDP_LEVEL0));
+            assert_eq!(0x0000_01BB, rdp_option_word(0x0000_01AA, RDP_LEVEL1));
+        }
+    }
+}
+
+/// Readable areas outside of the main flash: the system memory, the OTP area, the unique ID and calibration values,
+/// and the option bytes.
+#[cfg(any(flash_l4, flash_wb, flash_wl))]
+pub(crate) const READ_ONLY_AREAS: &[core::ops::Range<u32>] = &[
+    0x1FFF_0000..0x1FFF_7000,
+    0x1FFF_7000..0x1FFF_7400,
+    #[cfg(flash_l4)]
+    0x1FFF_7500..0x1FFF_7600,
+    0x1FFF_7800..0x1FFF_7810,
+];
+
+/// Readable areas outside of the main flash: the system memory, the option bytes, and the unique ID and calibration
+/// values.
+#[cfg(flash_l0)]
+pub(crate) const READ_ONLY_AREAS: &[core::ops::Range<u32>] = &[
+    0x1FF0_0000..0x1FF0_2000,
+    0x1FF8_0000..0x1FF8_0020,
+    0x1FF8_0050..0x1FF8_0080,
+];
+
+/// Readable areas outside of the main flash: the option bytes.
+#[cfg(flash_l1)]
+pub(crate) const READ_ONLY_AREAS: &[core::ops::Range<u32>] = &[0x1FF8_0000..0x1FF8_0020];
+
+/// The flash geometry, selected at runtime from the option bytes as they change the page size and bank split.
+#[cfg(any(stm32l4rx, stm32l4sx, stm32l49x, stm32l4ax))]
+pub fn get_flash_regions() -> &'static [&'static FlashRegion] {
+    match bank_modes::bank_mode() {
+        BankMode::Single => &SINGLE_BANK_REGIONS,
+        BankMode::Dual => &DUAL_BANK_REGIONS,
+    }
+}
+
+#[cfg(not(any(stm32l4rx, stm32l4sx, stm32l49x, stm32l4ax)))]
 pub const fn get_flash_regions() -> &'static [&'static FlashRegion] {
-    &FLASH_REGIONS
+    &super::FLASH_REGIONS
 }
 
 pub(crate) unsafe fn lock() {
@@ -49,22 +525,93 @@ pub(crate) unsafe fn begin_write() {
 pub(crate) unsafe fn end_write() {
     #[cfg(any(flash_wl, flash_wb, flash_l4))]
     pac::FLASH.cr().write(|w| w.set_pg(false));
+
+    // The programming and erase bits are only cleared at the end of an operation, also clear them after an
+    // interrupted one
+    #[cfg(any(flash_l0, flash_l1))]
+    pac::FLASH.pecr().modify(|w| {
+        w.set_erase(false);
+        w.set_prog(false);
+        w.set_fprg(false);
+    });
 }
 
-pub(crate) unsafe fn blocking_write(start_address: u32, buf: &[u8; WRITE_SIZE]) -> Result<(), Error> {
+pub(crate) unsafe fn blocking_write<const N: usize>(start_address: u32, buf: &[u8; N]) -> Result<(), Error> {
+    let () = WriteUnit::<N>::OK;
     let mut address = start_address;
-    for val in buf.chunks(4) {
-        write_volatile(address as *mut u32, u32::from_le_bytes(val.try_into().unwrap()));
-        address += val.len() as u32;
+    for unit in buf.chunks_exact(WRITE_SIZE) {
+        for val in unit.chunks(4) {
+            write_volatile(address as *mut u32, u32::from_le_bytes(val.try_into().unwrap()));
+            address += val.len() as u32;
+
+            // prevents parallelism errors
+            fence(Ordering::SeqCst);
+        }
 
-        // prevents parallelism errors
-        fence(Ordering::SeqCst);
+        blocking_wait_ready()?;
     }
+    Ok(())
+}
 
-    blocking_wait_ready()
+/// Size of a half page, the unit of the fast programming mode.
+#[cfg(flash_l0)]
+pub(crate) const HALF_PAGE_SIZE: usize = 64;
+
+/// Programs a half page in one burst with the fast programming mode.
+///
+/// A half page takes about as long to program as a single word (3.2 ms typical according to the datasheets), so
+/// this gives up to 16 times the throughput of word programming. From these timings, 1 KB takes about 51 ms
+/// (20 KB/s) in half pages and about 820 ms (1.25 KB/s) word by word; `flash_bench` in the L0 examples measures
+/// both on the board.
+///
+/// The 16 words must be written without any flash access in between, so the burst runs from RAM. Interrupt handlers
+/// that execute from flash must be masked during the burst; the common layer calls this within a critical section.
+#[cfg(flash_l0)]
+pub(crate) unsafe fn blocking_write_half_page(start_address: u32, buf: &[u8; HALF_PAGE_SIZE]) -> Result<(), Error> {
+    let mut words = [0; HALF_PAGE_SIZE / 4];
+    for (word, bytes) in words.iter_mut().zip(buf.chunks_exact(4)) {
+        *word = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+
+    pac::FLASH.pecr().modify(|w| {
+        w.set_fprg(true);
+        w.set_prog(true);
+    });
+    fence(Ordering::SeqCst);
+
+    write_half_page(start_address as *mut u32, &words, pac::FLASH.sr().ptr() as *const u32);
+
+    let result = blocking_wait_ready();
+    pac::FLASH.pecr().modify(|w| {
+        w.set_fprg(false);
+        w.set_prog(false);
+    });
+    result
+}
+
+/// Writes the half page and waits until it is programmed, without touching the flash otherwise.
+///
+/// Only plain volatile accesses are used, so that nothing is called from flash.
+#[cfg(flash_l0)]
+#[inline(never)]
+#[link_section = ".data.flash_write_half_page"]
+unsafe fn write_half_page(address: *mut u32, words: &[u32; HALF_PAGE_SIZE / 4], sr: *const u32) {
+    const BSY: u32 = 1 << 0;
+
+    let mut i = 0;
+    while i < words.len() {
+        address.add(i).write_volatile(words[i]);
+        i += 1;
+    }
+    while sr.read_volatile() & BSY != 0 {}
 }
 
 pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), Error> {
+    start_erase_sector(sector);
+    finish_erase_sector(sector)
+}
+
+pub(crate) unsafe fn start_erase_sector(sector: &FlashSector) {
     #[cfg(any(flash_l0, flash_l1))]
     {
         pac::FLASH.pecr().modify(|w| {
@@ -77,10 +624,11 @@ pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), E
 
     #[cfg(any(flash_wl, flash_wb, flash_l4))]
     {
+        #[cfg(any(flash_wl, flash_wb))]
         let idx = (sector.start - super::FLASH_BASE as u32) / super::BANK1_REGION.erase_size as u32;
 
         #[cfg(flash_l4)]
-        let (idx, bank) = if idx > 255 { (idx - 256, true) } else { (idx, false) };
+        let (idx, bank) = page_location(get_flash_regions(), sector.start);
 
         pac::FLASH.cr().modify(|w| {
             w.set_per(true);
@@ -93,7 +641,9 @@ pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), E
             w.set_bker(bank);
         });
     }
+}
 
+pub(crate) unsafe fn finish_erase_sector(_sector: &FlashSector) -> Result<(), Error> {
     let ret: Result<(), Error> = blocking_wait_ready();
 
     #[cfg(any(flash_wl, flash_wb, flash_l4))]
@@ -110,6 +660,36 @@ pub(crate) unsafe fn blocking_erase_sector(sector: &FlashSector) -> Result<(), E
     ret
 }
 
+/// Translate `address` into the page number within its bank, and whether that bank is bank 2.
+#[cfg(flash_l4)]
+fn page_location(regions: &[&FlashRegion], address: u32) -> (u32, bool) {
+    let mut current_bank = FlashBank::Bank1;
+    let mut bank_offset = 0;
+    for region in regions {
+        if region.bank != current_bank {
+            current_bank = region.bank;
+            bank_offset = 0;
+        }
+
+        if address >= region.base && address < region.end() {
+            let page = bank_offset + (address - region.base) / region.erase_size;
+            return (page, region.bank == FlashBank::Bank2);
+        }
+
+        bank_offset += region.size / region.erase_size;
+    }
+
+    panic!("Flash page not found");
+}
+
+pub(crate) fn is_busy() -> bool {
+    unsafe { pac::FLASH.sr().read().bsy() }
+}
+
+pub(crate) unsafe fn wait_idle() {
+    while is_busy() {}
+}
+
 pub(crate) unsafe fn clear_all_err() {
     pac::FLASH.sr().modify(|w| {
         #[cfg(any(flash_wl, flash_wb, flash_l4, flash_l0))]
@@ -148,8 +728,26 @@ pub(crate) unsafe fn clear_all_err() {
     });
 }
 
+pub(crate) fn protection_cause(_address: u32) -> Option<ProtectionCause> {
+    // The error flags of the rejected operation are kept until the next operation clears them.
+    let sr = unsafe { pac::FLASH.sr().read() };
+
+    #[cfg(any(flash_wl, flash_wb, flash_l4, flash_l0))]
+    if sr.rderr() {
+        return Some(ProtectionCause::ProprietaryCodeProtection);
+    }
+
+    if sr.wrperr() {
+        return Some(ProtectionCause::WriteProtection);
+    }
+
+    None
+}
+
 unsafe fn blocking_wait_ready() -> Result<(), Error> {
     loop {
+        super::count_poll();
+
         let sr = pac::FLASH.sr().read();
 
         if !sr.bsy() {
@@ -184,3 +782,50 @@ unsafe fn blocking_wait_ready() -> Result<(), Error> {
         }
     }
 }
+
+#[cfg(all(test, flash_l4))]
+mod tests {
+    use super::*;
+
+    const fn region(bank: FlashBank, base: u32, size: u32, erase_size: u32) -> FlashRegion {
+        FlashRegion {
+            bank,
+            base,
+            size,
+            erase_size,
+            write_size: 8,
+            erase_value: 0xFF,
+        }
+    }
+
+    #[test]
+    fn can_locate_pages_around_bank_split() {
+        const BANK1: FlashRegion = region(FlashBank::Bank1, 0x0800_0000, 0x10_0000, 0x1000);
+        const BANK2: FlashRegion = region(FlashBank::Bank2, 0x0810_0000, 0x10_0000, 0x1000);
+        let dual = [&BANK1, &BANK2];
+
+        assert_eq!((0, false), page_location(&dual, 0x0800_0000));
+        assert_eq!((255, false), page_location(&dual, 0x080F_F000));
+        assert_eq!((255, false), page_location(&dual, 0x080F_FFFF));
+        assert_eq!((0, true), page_location(&dual, 0x0810_0000));
+        assert_eq!((1, true), page_location(&dual, 0x0810_1000));
+        assert_eq!((255, true), page_location(&dual, 0x081F_F000));
+
+        const SINGLE: FlashRegion = region(FlashBank::Bank1, 0x0800_0000, 0x20_0000, 0x2000);
+        let single = [&SINGLE];
+
+        assert_eq!((0, false), page_location(&single, 0x0800_0000));
+        assert_eq!((127, false), page_location(&single, 0x080F_E000));
+        assert_eq!((128, false), page_location(&single, 0x0810_0000));
+        assert_eq!((128, false), page_location(&single, 0x0810_1000));
+        assert_eq!((255, false), page_location(&single, 0x081F_E000));
+
+        // A 512 K L49x/L4Ax keeps its 2 K pages in single-bank mode
+        const SINGLE_2K: FlashRegion = region(FlashBank::Bank1, 0x0800_0000, 0x8_0000, 0x800);
+        let single_2k = [&SINGLE_2K];
+
+        assert_eq!((127, false), page_location(&single_2k, 0x0803_F800));
+        assert_eq!((128, false), page_location(&single_2k, 0x0804_0000));
+        assert_eq!((255, false), page_location(&single_2k, 0x0807_F800));
+    }
+}
@@ -0,0 +1,167 @@
+use core::ops::Deref;
+
+use super::{Error, FlashRegion};
+
+/// Flash contents borrowed directly from the memory-mapped flash, without copying them to RAM.
+///
+/// The guard borrows the flash or region it was created from, so the compiler rejects writes and erases through
+/// that handle while it is alive. In debug builds, writes and erases through any other handle panic if they touch
+/// the range of an outstanding guard.
+pub struct Mapped<'a> {
+    bytes: &'a [u8],
+    #[cfg(debug_assertions)]
+    slot: Option<usize>,
+}
+
+impl<'a> Mapped<'a> {
+    pub(crate) fn new(address: u32, bytes: &'a [u8]) -> Self {
+        #[cfg(not(debug_assertions))]
+        let _ = address;
+        Self {
+            bytes,
+            #[cfg(debug_assertions)]
+            slot: tracking::track(address, bytes.len()),
+        }
+    }
+}
+
+impl Deref for Mapped<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.bytes
+    }
+}
+
+impl Drop for Mapped<'_> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        if let Some(slot) = self.slot {
+            tracking::untrack(slot);
+        }
+    }
+}
+
+/// Debug check that `len` bytes at `address` are not covered by an outstanding [`Mapped`] guard.
+#[inline]
+pub(crate) fn assert_not_mapped(address: u32, len: usize) {
+    #[cfg(debug_assertions)]
+    assert!(
+        !tracking::is_mapped(address, len),
+        "Flash range is modified while it is mapped"
+    );
+    #[cfg(not(debug_assertions))]
+    let _ = (address, len);
+}
+
+/// The `len` bytes at `address` of the memory-mapped flash, if they are within `size` bytes at `base`.
+pub(crate) fn map(base: u32, size: u32, offset: u32, len: usize) -> Result<&'static [u8], Error> {
+    if offset as u64 + len as u64 > size as u64 {
+        return Err(Error::OutOfBounds);
+    }
+    // The flash is mapped at its address for the whole lifetime of the program
+    Ok(unsafe { core::slice::from_raw_parts((base + offset) as *const u8, len) })
+}
+
+impl FlashRegion {
+    /// Returns `len` bytes at `offset` of the region directly from the memory-mapped flash, without copying.
+    ///
+    /// # Safety
+    /// The slice is not protected against changes of the flash: it must not be held across an erase or write of
+    /// the same range, which change the data behind it. Prefer the `map` method of [`Flash`](super::Flash) or the
+    /// region handles, which returns a [`Mapped`] guard that prevents this.
+    pub unsafe fn mapped(&self, offset: u32, len: usize) -> Result<&'static [u8], Error> {
+        map(self.base, self.size, offset, len)
+    }
+}
+
+#[cfg(debug_assertions)]
+mod tracking {
+    use core::cell::Cell;
+
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+    use embassy_sync::blocking_mutex::Mutex;
+
+    /// Number of guards tracked at the same time. Further guards are not checked.
+    const SLOTS: usize = 8;
+
+    static MAPPED: Mutex<CriticalSectionRawMutex, Cell<[Option<(u32, u32)>; SLOTS]>> =
+        Mutex::new(Cell::new([None; SLOTS]));
+
+    pub(super) fn track(address: u32, len: usize) -> Option<usize> {
+        if len == 0 {
+            return None;
+        }
+        MAPPED.lock(|mapped| {
+            let mut ranges = mapped.get();
+            let slot = ranges.iter().position(Option::is_none)?;
+            ranges[slot] = Some((address, address + len as u32));
+            mapped.set(ranges);
+            Some(slot)
+        })
+    }
+
+    pub(super) fn untrack(slot: usize) {
+        MAPPED.lock(|mapped| {
+            let mut ranges = mapped.get();
+            ranges[slot] = None;
+            mapped.set(ranges);
+        })
+    }
+
+    pub(super) fn is_mapped(address: u32, len: usize) -> bool {
+        let end = address as u64 + len as u64;
+        MAPPED.lock(|mapped| {
+            mapped
+                .get()
+                .iter()
+                .flatten()
+                .any(|&(start, stop)| (start as u64) < end && address < stop)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_track_mapped_ranges() {
+        let data = [0u8; 16];
+        {
+            let guard = Mapped::new(0x0800_1000, &data);
+            assert_eq!(16, guard.len());
+            assert!(tracking::is_mapped(0x0800_100F, 1));
+            assert!(tracking::is_mapped(0x0800_0000, 0x1001));
+            assert!(!tracking::is_mapped(0x0800_1010, 16));
+            assert!(!tracking::is_mapped(0x0800_0FF0, 16));
+            assert_not_mapped(0x0800_2000, 0x800);
+        }
+        // Dropping the guard releases the range
+        assert!(!tracking::is_mapped(0x0800_1000, 16));
+
+        // Guards beyond the tracked ones work, but are not checked
+        let guards: [_; 9] = core::array::from_fn(|i| Mapped::new(0x0800_0000 + i as u32 * 0x100, &data));
+        assert!(tracking::is_mapped(0x0800_0700, 1));
+        assert!(!tracking::is_mapped(0x0800_0800, 1));
+        drop(guards);
+        assert!(!tracking::is_mapped(0x0800_0000, 0x1000));
+    }
+
+    #[test]
+    fn can_check_mapped_bounds() {
+        let region = FlashRegion {
+            bank: super::super::FlashBank::Bank1,
+            base: 0x0800_0000,
+            size: 0x1000,
+            erase_size: 0x400,
+            write_size: 4,
+            erase_value: 0xFF,
+        };
+        assert_eq!(Err(Error::OutOfBounds), unsafe { region.mapped(0xFFF, 2) }.map(|_| ()));
+        assert_eq!(
+            Err(Error::OutOfBounds),
+            unsafe { region.mapped(u32::MAX, 2) }.map(|_| ())
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use embedded_storage::nor_flash::{ErrorType, MultiwriteNorFlash, NorFlash, ReadNorFlash};
+
+use super::observer::FlashObserver;
+#[cfg(flash)]
+use super::FlashBackend;
+use super::{Error, FlashBank, FlashSector, Rewrite};
+
+/// In-memory flash with NOR semantics, used to test the flash helpers on the host.
+///
+/// Programming can only clear bits, like on real flash. How programmed words can be programmed again is set
+/// with `rewrite`, to follow the rules of a family.
+pub struct MemFlash<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> {
+    pub mem: [u8; SIZE],
+    /// The number of writes that succeed before all following writes fail with [`Error::Prog`].
+    pub pending_write_successes: Option<usize>,
+    /// Receives all writes and erases, like the observer of the flash driver.
+    pub observer: Option<&'static dyn FlashObserver>,
+    /// Writes over programmed words that these rules don't allow fail like on the family, with [`Error::Seq`] for
+    /// [`Rewrite::Zeros`] (PGERR on F0) and [`Error::Prog`] for [`Rewrite::Never`]. The default
+    /// [`Rewrite::ClearBits`] never fails.
+    pub rewrite: Rewrite,
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> MemFlash<SIZE, ERASE_SIZE, WRITE_SIZE> {
+    pub const fn new(fill: u8) -> Self {
+        Self {
+            mem: [fill; SIZE],
+            pending_write_successes: None,
+            observer: None,
+            rewrite: Rewrite::ClearBits,
+        }
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> Default
+    for MemFlash<SIZE, ERASE_SIZE, WRITE_SIZE>
+{
+    fn default() -> Self {
+        Self::new(0xFF)
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> ErrorType
+    for MemFlash<SIZE, ERASE_SIZE, WRITE_SIZE>
+{
+    type Error = Error;
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> ReadNorFlash
+    for MemFlash<SIZE, ERASE_SIZE, WRITE_SIZE>
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        if offset + bytes.len() > SIZE {
+            return Err(Error::OutOfBounds);
+        }
+        bytes.copy_from_slice(&self.mem[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> NorFlash
+    for MemFlash<SIZE, ERASE_SIZE, WRITE_SIZE>
+{
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let (from, to) = (from as usize, to as usize);
+        if from > to || to > SIZE {
+            return Err(Error::OutOfBounds);
+        }
+        if from % ERASE_SIZE != 0 || to % ERASE_SIZE != 0 {
+            return Err(Error::Unaligned);
+        }
+        for start in (from..to).step_by(ERASE_SIZE) {
+            let sector = FlashSector {
+                bank: FlashBank::Bank1,
+                index_in_bank: (start / ERASE_SIZE) as u8,
+                start: start as u32,
+                size: ERASE_SIZE as u32,
+            };
+            if let Some(observer) = self.observer {
+                observer.on_erase_start(&sector);
+            }
+            self.mem[start..start + ERASE_SIZE].fill(0xFF);
+            if let Some(observer) = self.observer {
+                observer.on_erase_end(&sector, Ok(()));
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        if offset + bytes.len() > SIZE {
+            return Err(Error::OutOfBounds);
+        }
+        if offset % WRITE_SIZE != 0 || bytes.len() % WRITE_SIZE != 0 {
+            return Err(Error::Unaligned);
+        }
+
+        // Clearing bits always works, setting them has no effect
+        let rewrite = self.rewrite;
+        let rejected = rewrite != Rewrite::ClearBits
+            && self.mem[offset..offset + bytes.len()]
+                .chunks(WRITE_SIZE)
+                .zip(bytes.chunks(WRITE_SIZE))
+                .any(|(current, new)| !rewrite.can_program(current, new, 0xFF));
+
+        let result = match self.pending_write_successes {
+            Some(0) => Err(Error::Prog),
+            _ if rejected && rewrite == Rewrite::Zeros => Err(Error::Seq),
+            _ if rejected => Err(Error::Prog),
+            pending_successes => {
+                self.pending_write_successes = pending_successes.map(|n| n - 1);
+                for (mem_byte, new_byte) in self.mem[offset..offset + bytes.len()].iter_mut().zip(bytes) {
+                    *mem_byte &= *new_byte;
+                }
+                Ok(())
+            }
+        };
+        if let Some(observer) = self.observer {
+            observer.on_write(offset as u32, bytes.len(), result);
+        }
+        result
+    }
+}
+
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> MultiwriteNorFlash
+    for MemFlash<SIZE, ERASE_SIZE, WRITE_SIZE>
+{
+}
+
+#[cfg(feature = "nightly")]
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize>
+    embedded_storage_async::nor_flash::ReadNorFlash for MemFlash<SIZE, ERASE_SIZE, WRITE_SIZE>
+{
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        <Self as ReadNorFlash>::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+#[cfg(feature = "nightly")]
+impl<const SIZE: usize, const ERASE_SIZE: usize, const WRITE_SIZE: usize> embedded_storage_async::nor_flash::NorFlash
+    for MemFlash<SIZE, ERASE_SIZE, WRITE_SIZE>
+{
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        <Self as NorFlash>::erase(self, from, to)
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        <Self as NorFlash>::write(self, offset, bytes)
+    }
+}
+
+/// In-memory flash controller, used to test the write and erase sequences of the flash driver on the host.
+///
+/// The memory starts at the absolute address `base`. Erasing sets all bytes of a sector to `0xFF`, and programming
+/// fails with [`Error::Prog`] if it would set a cleared bit.
+#[cfg(flash)]
+pub struct MockFlash<const SIZE: usize> {
+    pub base: u32,
+    pub mem: [u8; SIZE],
+    /// Program and erase operations that include this address fail with the error, e.g. [`Error::Prog`] or
+    /// [`Error::Protected`], and leave the memory unchanged.
+    pub fail_at: Option<(u32, Error)>,
+    /// How many operations fail at [`MockFlash::fail_at`] before it works again, or `None` to always fail.
+    pub fail_count: Option<usize>,
+    /// Whether the controller is unlocked.
+    pub unlocked: bool,
+    /// The number of times the controller was unlocked.
+    pub unlocks: usize,
+}
+
+#[cfg(flash)]
+impl<const SIZE: usize> MockFlash<SIZE> {
+    pub const fn new(base: u32, fill: u8) -> Self {
+        Self {
+            base,
+            mem: [fill; SIZE],
+            fail_at: None,
+            fail_count: None,
+            unlocked: false,
+            unlocks: 0,
+        }
+    }
+
+    /// The memory of `len` bytes at `address`, or the injected error if it includes [`MockFlash::fail_at`].
+    fn operate(&mut self, address: u32, len: usize) -> Result<&mut [u8], Error> {
+        assert!(self.unlocked, "Flash operation on a locked controller");
+        if let Some((at, error)) = self.fail_at {
+            if at >= address && at < address + len as u32 && self.fail_count != Some(0) {
+                self.fail_count = self.fail_count.map(|n| n - 1);
+                return Err(error);
+            }
+        }
+        let start = (address - self.base) as usize;
+        Ok(&mut self.mem[start..start + len])
+    }
+}
+
+#[cfg(flash)]
+impl<const SIZE: usize> FlashBackend for MockFlash<SIZE> {
+    fn check_write(&self, _address: u32, _unit: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn check_erase(&self, _start: u32, _end: u32) -> Result<(), Error> {
+        Ok(())
+    }
+
+    unsafe fn unlock(&mut self) {
+        assert!(!self.unlocked, "Controller unlocked twice");
+        self.unlocked = true;
+        self.unlocks += 1;
+    }
+
+    unsafe fn lock(&mut self) {
+        self.unlocked = false;
+    }
+
+    unsafe fn write_chunk<const N: usize>(&mut self, address: u32, chunk: &[u8; N]) -> Result<(), Error> {
+        let mem = self.operate(address, N)?;
+        if mem.iter().zip(chunk).any(|(current, new)| new & !current != 0) {
+            return Err(Error::Prog);
+        }
+        mem.copy_from_slice(chunk);
+        Ok(())
+    }
+
+    unsafe fn erase_sector(&mut self, sector: &FlashSector) -> Result<(), Error> {
+        self.operate(sector.start, sector.size as usize)?.fill(0xFF);
+        Ok(())
+    }
+
+    fn is_blank(&self, sector: &FlashSector) -> bool {
+        let start = (sector.start - self.base) as usize;
+        self.mem[start..start + sector.size as usize].iter().all(|&b| b == 0xFF)
+    }
+
+    fn read(&self, address: u32, bytes: &mut [u8]) {
+        let start = (address - self.base) as usize;
+        bytes.copy_from_slice(&self.mem[start..start + bytes.len()]);
+    }
+
+    fn is_retryable(&self, error: Error) -> bool {
+        error == Error::Prog
+    }
+}
+
+/// An operation seen by the [`Recorder`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Operation {
+    Erase(u32),
+    Write(u32, usize),
+    Failed,
+}
+
+/// Observer that records the sequence of operations, to assert how the flash helpers use the flash.
+pub struct Recorder {
+    operations: embassy_sync::blocking_mutex::CriticalSectionMutex<core::cell::RefCell<([Operation; 64], usize)>>,
+}
+
+impl Recorder {
+    pub const fn new() -> Self {
+        Self {
+            operations: embassy_sync::blocking_mutex::CriticalSectionMutex::new(core::cell::RefCell::new((
+                [Operation::Failed; 64],
+                0,
+            ))),
+        }
+    }
+
+    /// Compare the recorded operations with `expected`, and forget them.
+    pub fn assert_and_clear(&self, expected: &[Operation]) {
+        self.operations.lock(|operations| {
+            let (operations, len) = &mut *operations.borrow_mut();
+            assert_eq!(expected, &operations[..*len]);
+            *len = 0;
+        });
+    }
+
+    fn push(&self, operation: Operation) {
+        self.operations.lock(|operations| {
+            let (operations, len) = &mut *operations.borrow_mut();
+            operations[*len] = operation;
+            *len += 1;
+        });
+    }
+}
+
+impl FlashObserver for Recorder {
+    fn on_erase_end(&self, sector: &FlashSector, result: Result<(), Error>) {
+        self.push(match result {
+            Ok(()) => Operation::Erase(sector.start),
+            Err(_) => Operation::Failed,
+        });
+    }
+
+    fn on_write(&self, address: u32, len: usize, result: Result<(), Error>) {
+        self.push(match result {
+            Ok(()) => Operation::Write(address, len),
+            Err(_) => Operation::Failed,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::{NorFlashError, NorFlashErrorKind};
+
+    use super::*;
+
+    /// Append an item with a length header, like log-structured storage crates such as `sequential-storage` do.
+    fn push<F: NorFlash>(flash: &mut F, offset: &mut u32, data: &[u8]) -> Result<u32, F::Error> {
+        let start = *offset;
+        let mut header = [0xFF; 8];
+        header[..2].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        flash.write(start, &header[..F::WRITE_SIZE])?;
+
+        let mut padded = [0xFF; 32];
+        let len = (data.len() + F::WRITE_SIZE - 1) / F::WRITE_SIZE * F::WRITE_SIZE;
+        padded[..data.len()].copy_from_slice(data);
+        flash.write(start + F::WRITE_SIZE as u32, &padded[..len])?;
+        *offset = start + (F::WRITE_SIZE + len) as u32;
+        Ok(start)
+    }
+
+    /// Mark the item at `start` as consumed by programming its header to zeros.
+    fn consume<F: NorFlash>(flash: &mut F, start: u32) -> Result<(), F::Error> {
+        flash.write(start, &[0; 8][..F::WRITE_SIZE])
+    }
+
+    #[test]
+    fn can_consume_items_with_halfword_writes() {
+        let mut flash = MemFlash::<1024, 256, 2>::default();
+        flash.rewrite = Rewrite::Zeros;
+
+        let mut offset = 0;
+        let first = push(&mut flash, &mut offset, &[1, 2, 3]).unwrap();
+        let second = push(&mut flash, &mut offset, &[4]).unwrap();
+        assert_eq!(6, second);
+        assert_eq!(10, offset);
+
+        consume(&mut flash, first).unwrap();
+        assert_eq!([0, 0, 1, 2, 3, 0xFF], flash.mem[..6]);
+        assert_eq!([1, 0, 4, 0xFF], flash.mem[6..10]);
+
+        // Only zeros can be programmed over an item
+        let error = flash.write(second, &[0x01, 0x00]).unwrap_err();
+        assert_eq!(Error::Seq, error);
+        assert_eq!(NorFlashErrorKind::Other, error.kind());
+        assert_eq!([1, 0], flash.mem[6..8]);
+    }
+
+    #[test]
+    fn can_reject_consuming_items_with_ecc() {
+        let mut flash = MemFlash::<1024, 256, 8>::default();
+        flash.rewrite = Rewrite::Never;
+
+        let mut offset = 0;
+        let first = push(&mut flash, &mut offset, &[1, 2, 3]).unwrap();
+        assert_eq!(16, offset);
+
+        // With flash ECC, consumed items can only be dropped by erasing the page
+        assert_eq!(Err(Error::Prog), consume(&mut flash, first));
+        flash.erase(0, 256).unwrap();
+        push(&mut flash, &mut offset, &[4]).unwrap();
+    }
+
+    #[test]
+    fn can_report_error_kinds() {
+        let mut flash = MemFlash::<1024, 256, 2>::default();
+        assert_eq!(1024, flash.capacity());
+        assert_eq!(
+            NorFlashErrorKind::OutOfBounds,
+            flash.read(1023, &mut [0; 2]).unwrap_err().kind()
+        );
+        assert_eq!(NorFlashErrorKind::OutOfBounds, flash.erase(0, 1280).unwrap_err().kind());
+        assert_eq!(
+            NorFlashErrorKind::NotAligned,
+            flash.write(1, &[0; 2]).unwrap_err().kind()
+        );
+        assert_eq!(NorFlashErrorKind::NotAligned, flash.erase(128, 256).unwrap_err().kind());
+    }
+}
@@ -1,15 +1,85 @@
 use embedded_storage::nor_flash::{NorFlashError, NorFlashErrorKind};
 
+#[cfg(crc)]
+mod checksum;
 #[cfg(flash)]
 mod common;
 
+#[cfg(crc)]
+pub use checksum::*;
 #[cfg(flash)]
 pub use common::*;
 
+mod counter;
+#[cfg(all(flash, any(dma, bdma), not(gpdma)))]
+mod dma;
+mod firmware;
+mod fuse;
+#[cfg(flash)]
+mod idle;
+#[cfg(feature = "nightly")]
+mod io;
+mod kvstore;
+mod mapped;
+#[cfg(test)]
+mod mem_flash;
+mod observer;
+mod pages;
+#[cfg(feature = "panic-store")]
+mod panic_store;
+#[cfg(feature = "sequential-storage")]
+mod seq_storage;
+#[cfg(feature = "nightly")]
+mod service;
+mod settings;
+#[cfg(any(feature = "flash-sha256", hash))]
+mod sha256;
+#[cfg(feature = "flash-stats")]
+mod stats;
+mod storage;
+mod wear;
+
+pub use counter::*;
+pub use firmware::*;
+pub use fuse::*;
+#[cfg(flash)]
+pub use idle::IdleSubscription;
+#[cfg(feature = "nightly")]
+pub use io::*;
+pub use kvstore::{KvError, KvStore};
+pub use mapped::Mapped;
+pub use observer::FlashObserver;
+#[cfg(feature = "panic-store")]
+pub use panic_store::*;
+#[cfg(feature = "sequential-storage")]
+pub use seq_storage::ZeroRewrite;
+#[cfg(feature = "nightly")]
+pub use service::*;
+pub use settings::{AbSettings, SettingsBlob};
+#[cfg(any(feature = "flash-sha256", hash))]
+pub use sha256::*;
+#[cfg(feature = "flash-stats")]
+pub use stats::FlashStats;
+pub use storage::*;
+pub use wear::*;
+
 pub use crate::_generated::flash_regions::*;
 pub use crate::_generated::MAX_ERASE_SIZE;
 pub use crate::pac::{FLASH_BASE, FLASH_SIZE, WRITE_SIZE};
 
+/// The largest write size supported by the generic flash helpers.
+pub(crate) const MAX_WRITE_SIZE: usize = 32;
+
+/// Checks at compile time that a write unit of `N` bytes is made of whole [`WRITE_SIZE`] words.
+pub(crate) struct WriteUnit<const N: usize>;
+
+impl<const N: usize> WriteUnit<N> {
+    pub(crate) const OK: () = assert!(
+        N > 0 && N % WRITE_SIZE == 0,
+        "The write unit must be a multiple of WRITE_SIZE."
+    );
+}
+
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct FlashRegion {
@@ -46,6 +116,57 @@ impl FlashRegion {
     pub const fn sectors(&self) -> u8 {
         (self.size / self.erase_size) as u8
     }
+
+    /// Splits the region into the part below `offset` and the part starting at `offset`.
+    ///
+    /// Each part only allows access to its own range. The region is consumed, so it can't be used
+    /// to access both parts at once anymore.
+    ///
+    /// # Panics
+    /// Panics if `offset` is not aligned to the erase size, or lies outside the region.
+    pub const fn split_at(self, offset: u32) -> (FlashRegion, FlashRegion) {
+        assert!(offset % self.erase_size == 0);
+        assert!(offset <= self.size);
+
+        let low = FlashRegion { size: offset, ..self };
+        let high = FlashRegion {
+            base: self.base + offset,
+            size: self.size - offset,
+            ..self
+        };
+        (low, high)
+    }
+}
+
+/// Checks that `regions` are sorted, don't overlap and consist of whole, aligned sectors, and returns their total
+/// size. Used to validate the hand-written region tables of the family backends at compile time.
+///
+/// # Panics
+/// Panics if any of the checks fails.
+#[allow(unused)]
+pub(crate) const fn validate_regions(regions: &[&FlashRegion]) -> u32 {
+    let mut total = 0;
+    let mut i = 0;
+    while i < regions.len() {
+        let region = regions[i];
+        assert!(
+            region.size > 0 && region.size % region.erase_size == 0,
+            "Flash region is not a whole number of sectors"
+        );
+        assert!(
+            region.base % region.erase_size == 0,
+            "Flash region is not aligned to its erase size"
+        );
+        if i > 0 {
+            assert!(
+                regions[i - 1].end() <= region.base,
+                "Flash regions overlap or are not sorted"
+            );
+        }
+        total += region.size;
+        i += 1;
+    }
+    total
 }
 
 #[cfg_attr(any(flash_l0, flash_l1, flash_l4, flash_wl, flash_wb), path = "l.rs")]
@@ -65,6 +186,13 @@ mod family;
 #[allow(unused_imports)]
 pub use family::*;
 
+/// Count one poll of the status register for the operation statistics.
+#[inline(always)]
+pub(crate) fn count_poll() {
+    #[cfg(feature = "flash-stats")]
+    stats::count_poll();
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Error {
@@ -75,14 +203,242 @@ pub enum Error {
     Protected,
     Unaligned,
     Parallelism,
+    OutOfBounds,
+    /// The flash is busy with an operation on the same bank, or with an erase started with `Flash::try_start_erase`.
+    Busy,
+    /// The word at `address` is not erased, and the family can't program the new value over it.
+    NotErased {
+        address: u32,
+    },
+    /// The option bytes read back after programming don't match the programmed value.
+    OptionByteVerify {
+        expected: u32,
+        actual: u32,
+    },
+    /// The operation spans adjacent regions with different write sizes or erase values, e.g. program flash
+    /// and OTP.
+    IncompatibleRegions,
+    /// The erase would erase the running program. Pass [`AllowSelfErase`] to erase it anyway.
+    WouldEraseSelf,
+    /// The flash controller can't program or erase, because `clock` is not running.
+    ClockNotReady {
+        clock: FlashClock,
+    },
+    /// The data read back after programming differs from the written data, first at `offset` from the flash base.
+    Verify {
+        offset: u32,
+    },
+    /// The controller stayed busy for much longer than the slowest operation takes. The operation was abandoned and
+    /// the controller locked.
+    Timeout,
+    /// The partitions passed to [`Flash::into_partitions`] overlap.
+    Overlap,
+    /// A sector failed to erase in all `attempts`, because the end of operation flag was not set, or the sector
+    /// didn't read back blank. See [`Flash::set_erase_options`] to retry more often, and
+    /// [`Flash::blocking_erase_range`] for the offset of the sector.
+    Erase {
+        attempts: u8,
+    },
+}
+
+/// A clock the flash controller needs to program and erase, see [`Error::ClockNotReady`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FlashClock {
+    /// The high speed internal oscillator, HSI16 on the L0 family.
+    Hsi,
+}
+
+/// Check the clocks the flash controller of the family needs to program and erase.
+#[allow(unused)]
+pub(crate) fn check_clocks() -> Result<(), Error> {
+    #[cfg(any(rcc_f0, rcc_f3, rcc_l0, rcc_l1))]
+    if !crate::rcc::hsi_ready() {
+        return Err(Error::ClockNotReady { clock: FlashClock::Hsi });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ProtectionCause {
+    /// The sector is covered by the write protection (WRP) option bytes.
+    WriteProtection,
+    /// The sector is covered by proprietary code readout protection (PCROP).
+    ProprietaryCodeProtection,
+    /// The device is readout protected (RDP level 1 or 2).
+    ReadoutProtection,
+    /// The sector is in the secure-only area, which only secure code can access.
+    SecureArea,
 }
 
 impl NorFlashError for Error {
     fn kind(&self) -> NorFlashErrorKind {
         match self {
-            Self::Size => NorFlashErrorKind::OutOfBounds,
+            Self::Size | Self::OutOfBounds => NorFlashErrorKind::OutOfBounds,
             Self::Unaligned => NorFlashErrorKind::NotAligned,
             _ => NorFlashErrorKind::Other,
         }
     }
 }
+
+/// How a programmed word can be programmed again without erasing it first.
+#[allow(unused)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Rewrite {
+    /// Any bits can be cleared, as required by [`MultiwriteNorFlash`](embedded_storage::nor_flash::MultiwriteNorFlash).
+    ClearBits,
+    /// Only all zeros can be programmed over a programmed word.
+    Zeros,
+    /// Programmed words can't be programmed again, e.g. because of the flash ECC.
+    Never,
+}
+
+#[allow(unused)]
+impl Rewrite {
+    /// The rules of the family. F4 and F7 have no flash ECC and program by clearing bits, F0 and F3 reject
+    /// everything but zeros over a programmed halfword.
+    pub(crate) const FAMILY: Self = if cfg!(any(flash_f4, flash_f7)) {
+        Self::ClearBits
+    } else if cfg!(any(flash_f0, flash_f3)) {
+        Self::Zeros
+    } else {
+        Self::Never
+    };
+
+    /// Whether a word holding `current` can be programmed with `new`.
+    pub(crate) fn can_program(self, current: &[u8], new: &[u8], erase_value: u8) -> bool {
+        if current.iter().all(|&b| b == erase_value) {
+            return true;
+        }
+        match self {
+            Self::ClearBits => current.iter().zip(new).all(|(&current, &new)| new & !current == 0),
+            Self::Zeros => new.iter().all(|&b| b == 0),
+            Self::Never => false,
+        }
+    }
+}
+
+/// Check the option bytes read back after programming `expected`.
+///
+/// Only the bits in `mask` are compared. `complement_valid` tells whether the complement copy, on families that store
+/// the option bytes twice, matches `actual`.
+#[allow(unused)]
+pub(crate) fn verify_option_bytes(expected: u32, actual: u32, mask: u32, complement_valid: bool) -> Result<(), Error> {
+    if actual & mask != expected & mask || !complement_valid {
+        return Err(Error::OptionByteVerify { expected, actual });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_verify_option_bytes() {
+        assert_eq!(Ok(()), verify_option_bytes(0x0000_0700, 0xFFFF_F7FF, 0x0000_0700, true));
+        assert_eq!(
+            Err(Error::OptionByteVerify {
+                expected: 0x0000_0700,
+                actual: 0x0000_0300
+            }),
+            verify_option_bytes(0x0000_0700, 0x0000_0300, 0x0000_0700, true)
+        );
+        assert!(verify_option_bytes(0x0000_0700, 0x0000_0700, 0x0000_0700, false).is_err());
+    }
+
+    #[test]
+    fn can_split_region() {
+        let region = FlashRegion {
+            bank: FlashBank::Bank1,
+            base: 0x0800_0000,
+            size: 0x1_0000,
+            erase_size: 0x400,
+            write_size: 2,
+            erase_value: 0xFF,
+        };
+
+        let (app, data) = region.split_at(0xC000);
+        assert_eq!((0x0800_0000, 0xC000), (app.base, app.size));
+        assert_eq!((0x0800_C000, 0x4000), (data.base, data.size));
+        assert_eq!(app.end(), data.base);
+        assert_eq!((0x400, 2, 0xFF), (data.erase_size, data.write_size, data.erase_value));
+    }
+
+    #[test]
+    fn can_validate_regions() {
+        const SMALL: FlashRegion = FlashRegion {
+            bank: FlashBank::Bank1,
+            base: 0x0800_0000,
+            size: 0x1_0000,
+            erase_size: 0x4000,
+            write_size: 4,
+            erase_value: 0xFF,
+        };
+        const LARGE: FlashRegion = FlashRegion {
+            base: 0x0801_0000,
+            size: 0x2_0000,
+            erase_size: 0x1_0000,
+            ..SMALL
+        };
+        const DISTANT: FlashRegion = FlashRegion {
+            bank: FlashBank::Bank2,
+            base: 0x0810_0000,
+            ..SMALL
+        };
+        const _: () = assert!(validate_regions(&[&SMALL, &LARGE, &DISTANT]) == 0x4_0000);
+
+        assert_eq!(0x3_0000, validate_regions(&[&SMALL, &LARGE]));
+        assert_eq!(0, validate_regions(&[]));
+    }
+
+    #[test]
+    #[should_panic]
+    fn can_reject_overlapping_regions() {
+        let region = FlashRegion {
+            bank: FlashBank::Bank1,
+            base: 0x0800_0000,
+            size: 0x1_0000,
+            erase_size: 0x400,
+            write_size: 2,
+            erase_value: 0xFF,
+        };
+        let next = FlashRegion {
+            base: 0x0800_FC00,
+            ..region
+        };
+
+        validate_regions(&[&region, &next]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn can_reject_partial_sectors() {
+        let region = FlashRegion {
+            bank: FlashBank::Bank1,
+            base: 0x0800_0000,
+            size: 0x1_0200,
+            erase_size: 0x400,
+            write_size: 2,
+            erase_value: 0xFF,
+        };
+
+        validate_regions(&[&region]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn can_reject_unaligned_split() {
+        let region = FlashRegion {
+            bank: FlashBank::Bank1,
+            base: 0x0800_0000,
+            size: 0x1_0000,
+            erase_size: 0x400,
+            write_size: 2,
+            erase_value: 0xFF,
+        };
+
+        region.split_at(0x200);
+    }
+}
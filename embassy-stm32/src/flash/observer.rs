@@ -0,0 +1,50 @@
+use core::cell::Cell;
+
+use atomic_polyfill::{AtomicBool, Ordering};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use super::{Error, FlashSector};
+
+/// Callbacks for every program and erase operation of the flash driver, e.g. to keep an audit log.
+///
+/// The callbacks are invoked by [`Flash`](super::Flash) and all regions created from it, from within the critical
+/// section of the operation, so they should return quickly. They must not use the flash driver; doing so panics.
+pub trait FlashObserver: Sync {
+    /// Called before `sector` is erased.
+    fn on_erase_start(&self, _sector: &FlashSector) {}
+
+    /// Called after erasing `sector` finished with `result`.
+    fn on_erase_end(&self, _sector: &FlashSector, _result: Result<(), Error>) {}
+
+    /// Called after programming `len` bytes at `address` finished with `result`.
+    fn on_write(&self, _address: u32, _len: usize, _result: Result<(), Error>) {}
+}
+
+static OBSERVER: Mutex<CriticalSectionRawMutex, Cell<Option<&'static dyn FlashObserver>>> = Mutex::new(Cell::new(None));
+
+/// Set while a callback runs, to catch observers that use the driver.
+static IN_OBSERVER: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_observer(observer: Option<&'static dyn FlashObserver>) {
+    OBSERVER.lock(|o| o.set(observer));
+}
+
+/// Invoke `f` with the observer, if one is set.
+#[inline]
+pub(crate) fn notify(f: impl FnOnce(&dyn FlashObserver)) {
+    if let Some(observer) = OBSERVER.lock(|o| o.get()) {
+        IN_OBSERVER.store(true, Ordering::SeqCst);
+        f(observer);
+        IN_OBSERVER.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Panic if the driver is used from within an observer callback.
+#[inline]
+pub(crate) fn assert_not_observing() {
+    assert!(
+        !IN_OBSERVER.load(Ordering::SeqCst),
+        "Flash observers must not use the flash driver"
+    );
+}
@@ -1,11 +1,13 @@
 #![allow(unused)]
 
-use super::{Error, FlashRegion, FlashSector, FLASH_REGIONS, WRITE_SIZE};
+use super::{Error, FlashRegion, FlashSector, ProtectionCause, FLASH_REGIONS};
 
 pub const fn get_flash_regions() -> &'static [&'static FlashRegion] {
     &FLASH_REGIONS
 }
 
+pub(crate) const READ_ONLY_AREAS: &[core::ops::Range<u32>] = &[];
+
 pub(crate) unsafe fn lock() {
     unimplemented!();
 }
@@ -18,12 +20,27 @@ pub(crate) unsafe fn begin_write() {
 pub(crate) unsafe fn end_write() {
     unimplemented!();
 }
-pub(crate) unsafe fn blocking_write(_start_address: u32, _buf: &[u8; WRITE_SIZE]) -> Result<(), Error> {
+pub(crate) unsafe fn blocking_write<const N: usize>(_start_address: u32, _buf: &[u8; N]) -> Result<(), Error> {
     unimplemented!();
 }
 pub(crate) unsafe fn blocking_erase_sector(_sector: &FlashSector) -> Result<(), Error> {
     unimplemented!();
 }
+pub(crate) unsafe fn start_erase_sector(_sector: &FlashSector) {
+    unimplemented!();
+}
+pub(crate) unsafe fn finish_erase_sector(_sector: &FlashSector) -> Result<(), Error> {
+    unimplemented!();
+}
+pub(crate) fn is_busy() -> bool {
+    unimplemented!();
+}
+pub(crate) unsafe fn wait_idle() {
+    unimplemented!();
+}
 pub(crate) unsafe fn clear_all_err() {
     unimplemented!();
 }
+pub(crate) fn protection_cause(_address: u32) -> Option<ProtectionCause> {
+    unimplemented!();
+}
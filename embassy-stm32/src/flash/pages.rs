@@ -0,0 +1,108 @@
+use core::ops::{Index, IndexMut};
+
+use embedded_storage::nor_flash::NorFlash;
+
+use super::MAX_WRITE_SIZE;
+
+/// Size of the page header (magic, sequence number and check word), before rounding up to the write size.
+const HEADER_LEN: usize = 12;
+
+/// The header at the start of a page, written after the rest of the page so that it marks the page as complete.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct PageHeader {
+    pub seq: u32,
+    /// A word that lets each store validate the page, e.g. a CRC over its contents.
+    pub check: u32,
+}
+
+/// The page with a valid header and the newest sequence number, and the value that validated it.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Newest<V> {
+    pub page: usize,
+    pub seq: u32,
+    pub value: V,
+}
+
+/// Two pages that are written alternately, each starting with a [`PageHeader`] once it is complete.
+pub(super) struct Pages<F> {
+    pages: [F; 2],
+}
+
+impl<F: NorFlash> Pages<F> {
+    /// # Panics
+    /// Panics if the write size is not supported.
+    pub fn new(a: F, b: F) -> Self {
+        assert!(F::WRITE_SIZE <= MAX_WRITE_SIZE);
+        Self { pages: [a, b] }
+    }
+
+    pub fn into_inner(self) -> (F, F) {
+        let [a, b] = self.pages;
+        (a, b)
+    }
+
+    /// The size of the header, after which the contents of a page start.
+    pub fn header_size(&self) -> usize {
+        round_up::<F>(HEADER_LEN)
+    }
+
+    /// Find the newest page whose header holds `magic`, and for which `validate` returns a value.
+    ///
+    /// The sequence numbers are compared with wrapping arithmetic, so that they can wrap around.
+    pub fn newest<V>(
+        &mut self,
+        magic: u32,
+        mut validate: impl FnMut(&mut F, PageHeader) -> Result<Option<V>, F::Error>,
+    ) -> Result<Option<Newest<V>>, F::Error> {
+        let mut newest: Option<Newest<V>> = None;
+
+        for (page, flash) in self.pages.iter_mut().enumerate() {
+            let mut header = [0; HEADER_LEN];
+            flash.read(0, &mut header)?;
+            let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+            if word(0) != magic {
+                continue;
+            }
+            let seq = word(4);
+            let Some(value) = validate(flash, PageHeader { seq, check: word(8) })? else {
+                continue;
+            };
+
+            newest = match newest {
+                Some(n) if (seq.wrapping_sub(n.seq) as i32) <= 0 => Some(n),
+                _ => Some(Newest { page, seq, value }),
+            };
+        }
+
+        Ok(newest)
+    }
+
+    /// Write the header of `page`, which must be erased.
+    pub fn write_header(&mut self, page: usize, magic: u32, header: PageHeader) -> Result<(), F::Error> {
+        let mut buf = [0xFF; HEADER_LEN + MAX_WRITE_SIZE];
+        buf[..4].copy_from_slice(&magic.to_le_bytes());
+        buf[4..8].copy_from_slice(&header.seq.to_le_bytes());
+        buf[8..12].copy_from_slice(&header.check.to_le_bytes());
+        let header_size = self.header_size();
+        self.pages[page].write(0, &buf[..header_size])
+    }
+}
+
+impl<F> Index<usize> for Pages<F> {
+    type Output = F;
+
+    fn index(&self, page: usize) -> &F {
+        &self.pages[page]
+    }
+}
+
+impl<F> IndexMut<usize> for Pages<F> {
+    fn index_mut(&mut self, page: usize) -> &mut F {
+        &mut self.pages[page]
+    }
+}
+
+/// Round `len` up to the write size of `F`.
+pub(super) fn round_up<F: NorFlash>(len: usize) -> usize {
+    (len + F::WRITE_SIZE - 1) / F::WRITE_SIZE * F::WRITE_SIZE
+}
@@ -0,0 +1,315 @@
+//! Persist panic messages in flash, so they survive power loss.
+//!
+//! Call [`PanicStore::store_panic`] from the panic handler, and [`PanicStore::read_last_panic`] and
+//! [`PanicStore::clear`] on the next boot. The store needs an area of its own, e.g. the last sector of the bank,
+//! which the linker script must keep free of the program:
+//!
+//! ```ignore
+//! #[panic_handler]
+//! fn panic(info: &core::panic::PanicInfo) -> ! {
+//!     let flash = unsafe { embassy_stm32::peripherals::FLASH::steal() };
+//!     let bank1 = Flash::new(flash).into_regions().bank1_region;
+//!     let (_, last_sector) = bank1.split_at(bank1.0.size - bank1.0.erase_size);
+//!     if let Ok(mut store) = PanicStore::in_region(last_sector) {
+//!         let _ = store.store_panic(info, embassy_time::Instant::now().as_ticks());
+//!     }
+//!     cortex_m::peripheral::SCB::sys_reset();
+//! }
+//! ```
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+
+use embedded_storage::nor_flash::NorFlash;
+
+use super::MAX_WRITE_SIZE;
+#[cfg(flash)]
+use super::{check_self_erase, Error, RegionPart};
+
+const MAGIC: u32 = 0x4349_4e50;
+
+/// Size of a single slot in the store.
+pub const PANIC_SLOT_SIZE: usize = 256;
+
+/// Size of the slot header: magic, sequence number, timestamp and message length, padded to the largest write size.
+const HEADER_LEN: usize = 32;
+
+/// The maximum length of a stored message, longer messages are truncated.
+pub const MAX_PANIC_MESSAGE_LEN: usize = PANIC_SLOT_SIZE - HEADER_LEN;
+
+/// A panic message read back from flash.
+#[derive(Debug, Clone)]
+pub struct PanicRecord {
+    /// The timestamp passed when storing the panic.
+    pub timestamp: u64,
+    len: usize,
+    message: [u8; MAX_PANIC_MESSAGE_LEN],
+}
+
+impl PanicRecord {
+    /// The (possibly truncated) panic message.
+    pub fn message(&self) -> &str {
+        match core::str::from_utf8(&self.message[..self.len]) {
+            Ok(message) => message,
+            // Truncation may have split a character
+            Err(e) => unsafe { core::str::from_utf8_unchecked(&self.message[..e.valid_up_to()]) },
+        }
+    }
+}
+
+/// Panic message store in a reserved flash area.
+///
+/// The area is split into slots of [`PANIC_SLOT_SIZE`] bytes. Every stored panic takes the next
+/// erased slot, so storing never erases and only uses the blocking write of the flash. Once all slots
+/// but the last are used, further panics are dropped until [`PanicStore::clear`] is called. The last slot
+/// counts them instead, one write unit per dropped panic, see [`PanicStore::dropped`].
+///
+/// Nothing is allocated, and a failed store is just reported, so it is safe to use from a panic handler.
+pub struct PanicStore<F> {
+    flash: F,
+}
+
+/// Truncating formatter into a fixed buffer.
+struct Buffer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Buffer<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = core::cmp::min(s.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[cfg(flash)]
+impl<'d, const WRITE_SIZE: usize, const ERASE_SIZE: usize> PanicStore<RegionPart<'d, WRITE_SIZE, ERASE_SIZE>> {
+    /// Create a store that uses all of `region`.
+    ///
+    /// Fails with [`Error::WouldEraseSelf`] if the region contains the running program, which
+    /// [`PanicStore::clear`] would erase.
+    ///
+    /// # Panics
+    /// Panics if `region` can't hold at least two slots.
+    pub fn in_region(region: RegionPart<'d, WRITE_SIZE, ERASE_SIZE>) -> Result<Self, Error> {
+        check_self_erase(region.region().base, region.region().end(), false)?;
+        Ok(Self::new(region))
+    }
+}
+
+impl<F: NorFlash> PanicStore<F> {
+    /// Create a store that uses all of `flash`.
+    ///
+    /// # Panics
+    /// Panics if the erasable part of `flash` can't hold at least two slots, one for a panic and one to count the
+    /// dropped panics, or the write size doesn't divide the slot size.
+    pub fn new(flash: F) -> Self {
+        assert_eq!(0, PANIC_SLOT_SIZE % F::WRITE_SIZE);
+        assert_eq!(0, HEADER_LEN % F::WRITE_SIZE);
+        let this = Self { flash };
+        assert!(this.slots() >= 2, "The panic store needs at least two slots");
+        this
+    }
+
+    /// Release the underlying flash.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Store the message of `info` together with `timestamp`.
+    pub fn store_panic(&mut self, info: &PanicInfo, timestamp: u64) -> Result<(), F::Error> {
+        self.store(format_args!("{}", info), timestamp)
+    }
+
+    /// Store a formatted message together with `timestamp`.
+    ///
+    /// If all slots are used, the message is dropped and only counted, see [`PanicStore::dropped`].
+    pub fn store(&mut self, args: core::fmt::Arguments, timestamp: u64) -> Result<(), F::Error> {
+        let (slot, seq) = match self.scan()? {
+            (Some(free), last) => (free, last.map_or(0, |(_, seq)| seq + 1)),
+            (None, _) => return self.count_dropped(),
+        };
+
+        let mut slot_buf = [0xFF; PANIC_SLOT_SIZE];
+        let (header, body) = slot_buf.split_at_mut(HEADER_LEN);
+        let mut message = Buffer { buf: body, len: 0 };
+        let _ = message.write_fmt(args);
+        let len = message.len;
+
+        header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&seq.to_le_bytes());
+        header[8..16].copy_from_slice(&timestamp.to_le_bytes());
+        header[16..20].copy_from_slice(&(len as u32).to_le_bytes());
+
+        // Write the message before the header, so an interrupted store leaves no valid record
+        let offset = (slot * PANIC_SLOT_SIZE) as u32;
+        let body_len = (len + F::WRITE_SIZE - 1) / F::WRITE_SIZE * F::WRITE_SIZE;
+        self.flash
+            .write(offset + HEADER_LEN as u32, &slot_buf[HEADER_LEN..HEADER_LEN + body_len])?;
+        self.flash.write(offset, &slot_buf[..HEADER_LEN])
+    }
+
+    /// Read the most recently stored panic, if any.
+    pub fn read_last_panic(&mut self) -> Result<Option<PanicRecord>, F::Error> {
+        let Some((slot, _)) = self.scan()?.1 else {
+            return Ok(None);
+        };
+
+        let offset = (slot * PANIC_SLOT_SIZE) as u32;
+        let mut header = [0; HEADER_LEN];
+        self.flash.read(offset, &mut header)?;
+        let len = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+        let len = core::cmp::min(len, MAX_PANIC_MESSAGE_LEN);
+
+        let mut record = PanicRecord {
+            timestamp: u64::from_le_bytes(header[8..16].try_into().unwrap()),
+            len,
+            message: [0; MAX_PANIC_MESSAGE_LEN],
+        };
+        self.flash
+            .read(offset + HEADER_LEN as u32, &mut record.message[..len])?;
+        Ok(Some(record))
+    }
+
+    /// The number of panics that were dropped because all slots were used, since the last [`PanicStore::clear`].
+    ///
+    /// The count saturates at [`PANIC_SLOT_SIZE`] / `WRITE_SIZE`.
+    pub fn dropped(&mut self) -> Result<usize, F::Error> {
+        let offset = self.overflow_slot() * PANIC_SLOT_SIZE;
+        let mut unit = [0; MAX_WRITE_SIZE];
+        for count in 0..PANIC_SLOT_SIZE / F::WRITE_SIZE {
+            self.flash
+                .read((offset + count * F::WRITE_SIZE) as u32, &mut unit[..F::WRITE_SIZE])?;
+            if unit[..F::WRITE_SIZE].iter().all(|&b| b == 0xFF) {
+                return Ok(count);
+            }
+        }
+        Ok(PANIC_SLOT_SIZE / F::WRITE_SIZE)
+    }
+
+    /// Erase all stored panics and the count of dropped panics.
+    pub fn clear(&mut self) -> Result<(), F::Error> {
+        let len = self.flash.capacity() / F::ERASE_SIZE * F::ERASE_SIZE;
+        self.flash.erase(0, len as u32)
+    }
+
+    fn slots(&self) -> usize {
+        // Only use sectors that `clear` erases
+        self.flash.capacity() / F::ERASE_SIZE * F::ERASE_SIZE / PANIC_SLOT_SIZE
+    }
+
+    /// The last slot, which counts the dropped panics instead of holding one.
+    fn overflow_slot(&self) -> usize {
+        self.slots() - 1
+    }
+
+    /// Count a dropped panic by programming the next erased unit of the overflow slot.
+    fn count_dropped(&mut self) -> Result<(), F::Error> {
+        let count = self.dropped()?;
+        if count == PANIC_SLOT_SIZE / F::WRITE_SIZE {
+            return Ok(());
+        }
+        let offset = self.overflow_slot() * PANIC_SLOT_SIZE + count * F::WRITE_SIZE;
+        self.flash.write(offset as u32, &[0; MAX_WRITE_SIZE][..F::WRITE_SIZE])
+    }
+
+    /// Find the first erased slot, and the used slot with the highest sequence number.
+    fn scan(&mut self) -> Result<(Option<usize>, Option<(usize, u32)>), F::Error> {
+        let mut free = None;
+        let mut last: Option<(usize, u32)> = None;
+
+        for slot in 0..self.overflow_slot() {
+            let mut header = [0; HEADER_LEN];
+            self.flash.read((slot * PANIC_SLOT_SIZE) as u32, &mut header)?;
+
+            if u32::from_le_bytes(header[..4].try_into().unwrap()) == MAGIC {
+                let seq = u32::from_le_bytes(header[4..8].try_into().unwrap());
+                if last.map_or(true, |(_, last_seq)| seq > last_seq) {
+                    last = Some((slot, seq));
+                }
+            } else if free.is_none() && header.iter().all(|&b| b == 0xFF) {
+                // The body may have been partially written by an interrupted store
+                let mut body = [0; MAX_PANIC_MESSAGE_LEN];
+                self.flash
+                    .read((slot * PANIC_SLOT_SIZE + HEADER_LEN) as u32, &mut body)?;
+                if body.iter().all(|&b| b == 0xFF) {
+                    free = Some(slot);
+                }
+            }
+        }
+
+        Ok((free, last))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mem_flash::MemFlash;
+
+    #[test]
+    fn can_rotate_through_slots() {
+        let mut store = PanicStore::new(MemFlash::<1024, 512, 2>::default());
+        assert!(store.read_last_panic().unwrap().is_none());
+
+        let expected = ["panicked at 0", "panicked at 1", "panicked at 2"];
+        for i in 0..3u64 {
+            store.store(format_args!("panicked at {}", i), 100 + i).unwrap();
+            let record = store.read_last_panic().unwrap().unwrap();
+            assert_eq!(100 + i, record.timestamp);
+            assert_eq!(expected[i as usize], record.message());
+        }
+        assert_eq!(0, store.dropped().unwrap());
+
+        store.clear().unwrap();
+        assert!(store.read_last_panic().unwrap().is_none());
+        store.store(format_args!("again"), 7).unwrap();
+        assert_eq!("again", store.read_last_panic().unwrap().unwrap().message());
+    }
+
+    #[test]
+    fn counts_dropped_panics_when_full() {
+        let mut store = PanicStore::new(MemFlash::<512, 512, 2>::default());
+        store.store(format_args!("first"), 1).unwrap();
+
+        // The only panic slot is used, so the following panics are dropped and counted
+        for i in 0..3 {
+            store.store(format_args!("dropped"), 2).unwrap();
+            assert_eq!(i + 1, store.dropped().unwrap());
+        }
+        let record = store.read_last_panic().unwrap().unwrap();
+        assert_eq!((1, "first"), (record.timestamp, record.message()));
+
+        // The count persists, and saturates once the overflow slot is fully written
+        let mut store = PanicStore::new(store.into_inner());
+        assert_eq!(3, store.dropped().unwrap());
+        for _ in 0..PANIC_SLOT_SIZE / 2 {
+            store.store(format_args!("dropped"), 2).unwrap();
+        }
+        assert_eq!(PANIC_SLOT_SIZE / 2, store.dropped().unwrap());
+
+        store.clear().unwrap();
+        assert_eq!(0, store.dropped().unwrap());
+    }
+
+    #[test]
+    #[should_panic]
+    fn needs_two_slots() {
+        PanicStore::new(MemFlash::<256, 256, 2>::default());
+    }
+
+    #[test]
+    fn can_truncate_long_messages() {
+        let mut store = PanicStore::new(MemFlash::<512, 512, 2>::default());
+        // The two byte character crosses the end of the slot
+        store
+            .store(format_args!("{:a<1$}é", "", MAX_PANIC_MESSAGE_LEN - 1), 0)
+            .unwrap();
+
+        let record = store.read_last_panic().unwrap().unwrap();
+        assert_eq!(MAX_PANIC_MESSAGE_LEN - 1, record.message().len());
+        assert!(record.message().bytes().all(|b| b == b'a'));
+    }
+}
@@ -0,0 +1,204 @@
+use embedded_storage::nor_flash::{ErrorType, MultiwriteNorFlash, NorFlash, ReadNorFlash};
+
+use super::{Error, Rewrite, MAX_WRITE_SIZE};
+
+/// Makes a flash with halfword writes usable for the `sequential-storage` queue, which needs [`MultiwriteNorFlash`].
+///
+/// F0 and F3 can program a written halfword again, but only with zeros. The queue marks popped items only that way,
+/// so these families can implement [`MultiwriteNorFlash`] through this adapter. Any other write over programmed bits
+/// fails with [`Error::Seq`] before the flash is programmed, like PGERR on the hardware.
+///
+/// The `sequential-storage` map never programs a word twice and uses the flash directly, also on the families with
+/// flash ECC. Their queue can't be used, since its items can't be marked as popped without erasing the page.
+pub struct ZeroRewrite<F>(pub F);
+
+impl<F: NorFlash<Error = Error>> ZeroRewrite<F> {
+    /// Check that each write unit of `bytes` at `offset` is erased, or is only programmed with zeros.
+    fn check_rewrite(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        assert!(F::WRITE_SIZE <= MAX_WRITE_SIZE);
+
+        let mut buf = [0; MAX_WRITE_SIZE];
+        for (i, new) in bytes.chunks(F::WRITE_SIZE).enumerate() {
+            let current = &mut buf[..new.len()];
+            self.0.read(offset + (i * F::WRITE_SIZE) as u32, current)?;
+            if !Rewrite::Zeros.can_program(current, new, 0xFF) {
+                return Err(Error::Seq);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<F: ErrorType> ErrorType for ZeroRewrite<F> {
+    type Error = F::Error;
+}
+
+impl<F: NorFlash<Error = Error>> ReadNorFlash for ZeroRewrite<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+}
+
+impl<F: NorFlash<Error = Error>> NorFlash for ZeroRewrite<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.0.erase(from, to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        // The bounds and the alignment are checked by the flash, before the current contents are read
+        if offset as usize % F::WRITE_SIZE == 0 && offset as usize + bytes.len() <= self.0.capacity() {
+            self.check_rewrite(offset, bytes)?;
+        }
+        self.0.write(offset, bytes)
+    }
+}
+
+impl<F: NorFlash<Error = Error>> MultiwriteNorFlash for ZeroRewrite<F> {}
+
+#[cfg(test)]
+mod tests {
+    use sequential_storage::map::{fetch_item, store_item, StorageItem};
+    use sequential_storage::queue::{peek, pop, push};
+
+    use super::*;
+    use crate::flash::mem_flash::{MemFlash, Operation, Recorder};
+
+    /// A map item like in the tests of `sequential-storage`, a key and a value of a varying length.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Item {
+        key: u8,
+        len: usize,
+        value: [u8; 16],
+    }
+
+    impl Item {
+        fn new(key: u8, value: &[u8]) -> Self {
+            let mut item = Self {
+                key,
+                len: value.len(),
+                value: [0; 16],
+            };
+            item.value[..value.len()].copy_from_slice(value);
+            item
+        }
+    }
+
+    impl StorageItem for Item {
+        type Key = u8;
+        type Error = ();
+
+        fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            if buffer.len() < 1 + self.len {
+                return Err(());
+            }
+            buffer[0] = self.key;
+            buffer[1..1 + self.len].copy_from_slice(&self.value[..self.len]);
+            Ok(1 + self.len)
+        }
+
+        fn deserialize_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+            match buffer.split_first() {
+                Some((&key, value)) if value.len() <= 16 => Ok(Self::new(key, value)),
+                _ => Err(()),
+            }
+        }
+
+        fn key(&self) -> Self::Key {
+            self.key
+        }
+    }
+
+    /// Store items of all lengths, so that the last word of an item is both complete and partial, and overwrite
+    /// them until the pages wrap around.
+    fn store_and_fetch<F: NorFlash>(flash: &mut F) {
+        let range = 0..flash.capacity() as u32;
+        for round in 0..20u8 {
+            for key in 0..4u8 {
+                let value = [round; 16];
+                let item = Item::new(key, &value[..(round as usize + key as usize) % 16]);
+                store_item::<Item, _>(flash, range.clone(), item.clone()).unwrap();
+                assert_eq!(Some(item), fetch_item::<Item, _>(flash, range.clone(), key).unwrap());
+            }
+        }
+        assert_eq!(None, fetch_item::<Item, _>(flash, range, 4).unwrap());
+    }
+
+    #[test]
+    fn can_store_map_items_with_halfword_writes() {
+        let mut flash = MemFlash::<4096, 1024, 2>::default();
+        flash.rewrite = Rewrite::Zeros;
+        store_and_fetch(&mut flash);
+    }
+
+    #[test]
+    fn can_store_map_items_with_ecc() {
+        // 64-bit writes that can't be programmed twice, like on L4, G0 and G4
+        let mut flash = MemFlash::<8192, 2048, 8>::default();
+        flash.rewrite = Rewrite::Never;
+        store_and_fetch(&mut flash);
+    }
+
+    #[test]
+    fn can_push_and_pop_with_halfword_writes() {
+        let mut flash = MemFlash::<4096, 1024, 2>::default();
+        flash.rewrite = Rewrite::Zeros;
+        let mut flash = ZeroRewrite(flash);
+        let range = 0..4096;
+        let mut buf = [0; 32];
+
+        // Items of an odd length don't fill their last halfword
+        for len in 1..=17u8 {
+            let data = [len; 17];
+            push(&mut flash, range.clone(), &data[..len as usize], false).unwrap();
+        }
+        for len in 1..=17u8 {
+            let data = [len; 17];
+            assert_eq!(
+                Some(&data[..len as usize]),
+                peek(&mut flash, range.clone(), &mut buf).unwrap().as_deref()
+            );
+            assert_eq!(
+                Some(&data[..len as usize]),
+                pop(&mut flash, range.clone(), &mut buf).unwrap().as_deref()
+            );
+        }
+        assert_eq!(None, pop(&mut flash, range.clone(), &mut buf).unwrap());
+
+        // The popped pages are erased again once the queue wraps around
+        for i in 0..1000u32 {
+            push(&mut flash, range.clone(), &i.to_le_bytes(), false).unwrap();
+            assert_eq!(
+                Some(&i.to_le_bytes()[..]),
+                pop(&mut flash, range.clone(), &mut buf).unwrap().as_deref()
+            );
+        }
+    }
+
+    #[test]
+    fn can_reject_rewriting_other_than_zeros() {
+        static RECORDER: Recorder = Recorder::new();
+        let mut flash = ZeroRewrite(MemFlash::<1024, 256, 2>::default());
+        flash.0.observer = Some(&RECORDER);
+        flash.write(0, &[0x12, 0x34, 0x12, 0x34]).unwrap();
+
+        assert_eq!(Ok(()), flash.write(0, &[0, 0]));
+        // Clearing bits would work on the memory, but not on the hardware
+        assert_eq!(Err(Error::Seq), flash.write(2, &[0x02, 0x00]));
+        assert_eq!([0, 0, 0x12, 0x34], flash.0.mem[..4]);
+        // The rejected write never reaches the flash
+        RECORDER.assert_and_clear(&[Operation::Write(0, 4), Operation::Write(0, 2)]);
+
+        // The flash reports the bounds and the alignment as before
+        assert_eq!(Err(Error::OutOfBounds), flash.write(1024, &[0, 0]));
+        assert_eq!(Err(Error::Unaligned), flash.write(1, &[0, 0]));
+    }
+}
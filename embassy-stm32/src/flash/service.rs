@@ -0,0 +1,300 @@
+use core::ops::Range;
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+
+use super::Error;
+
+/// The maximum number of adjacent writes that are combined into one program operation.
+const MAX_COALESCED_WRITES: usize = 8;
+
+/// Data of a single read or write request, at most `N` bytes.
+#[derive(Debug, Clone)]
+pub struct Payload<const N: usize> {
+    len: usize,
+    data: [u8; N],
+}
+
+impl<const N: usize> Payload<N> {
+    /// An empty payload.
+    pub const fn new() -> Self {
+        Self { len: 0, data: [0; N] }
+    }
+
+    /// Copy `data` into a payload.
+    ///
+    /// # Panics
+    /// Panics if `data` is longer than `N`.
+    pub fn from_slice(data: &[u8]) -> Self {
+        let mut payload = Self::new();
+        payload.data[..data.len()].copy_from_slice(data);
+        payload.len = data.len();
+        payload
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl<const N: usize> Default for Payload<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Signal on which the [`FlashService`] replies to a request. Writes and erases reply with an empty payload.
+pub type FlashReply<M, const N: usize> = Signal<M, Result<Payload<N>, Error>>;
+
+/// A request to the [`FlashService`].
+pub enum FlashRequest<'a, M: RawMutex, const N: usize> {
+    /// Write `data` at `offset`.
+    Write {
+        offset: u32,
+        data: Payload<N>,
+        reply: &'a FlashReply<M, N>,
+    },
+    /// Erase `range`.
+    Erase {
+        range: Range<u32>,
+        reply: &'a FlashReply<M, N>,
+    },
+    /// Read `len` bytes at `offset`, `len` must be at most `N`.
+    Read {
+        offset: u32,
+        len: usize,
+        reply: &'a FlashReply<M, N>,
+    },
+    /// Stop the service. Requests that are queued after this one are left in the channel.
+    Shutdown,
+}
+
+/// Channel over which [`FlashRequest`]s are sent to the [`FlashService`].
+pub type FlashRequests<'a, M, const N: usize, const CAP: usize> = Channel<M, FlashRequest<'a, M, N>, CAP>;
+
+/// Worker that owns a flash driver and executes the requests of multiple tasks in order.
+///
+/// The request channel is bounded, so senders wait while `CAP` requests are queued.
+/// Writes that directly follow each other in the queue and target adjacent addresses are programmed
+/// in one operation, as long as they fit into `N` bytes together.
+///
+/// Blocking drivers can be used by wrapping them in [`embassy_embedded_hal::adapter::BlockingAsync`].
+pub struct FlashService<'a, M: RawMutex, F, const N: usize, const CAP: usize> {
+    flash: F,
+    requests: &'a FlashRequests<'a, M, N, CAP>,
+}
+
+impl<'a, M: RawMutex, F: AsyncNorFlash<Error = Error>, const N: usize, const CAP: usize>
+    FlashService<'a, M, F, N, CAP>
+{
+    pub fn new(flash: F, requests: &'a FlashRequests<'a, M, N, CAP>) -> Self {
+        Self { flash, requests }
+    }
+
+    /// Serve requests until a [`FlashRequest::Shutdown`] is received, then release the flash.
+    pub async fn run(mut self) -> F {
+        let mut next = None;
+        loop {
+            let request = match next.take() {
+                Some(request) => request,
+                None => self.requests.recv().await,
+            };
+
+            match request {
+                FlashRequest::Write { offset, data, reply } => {
+                    next = self.write_coalesced(offset, data, reply).await;
+                }
+                FlashRequest::Erase { range, reply } => {
+                    let result = self.flash.erase(range.start, range.end).await;
+                    reply.signal(result.map(|_| Payload::new()));
+                }
+                FlashRequest::Read { offset, len, reply } => {
+                    let mut payload = Payload::new();
+                    let result = if len <= N {
+                        self.flash.read(offset, &mut payload.data[..len]).await
+                    } else {
+                        Err(Error::Size)
+                    };
+                    payload.len = len;
+                    reply.signal(result.map(|_| payload));
+                }
+                FlashRequest::Shutdown => return self.flash,
+            }
+        }
+    }
+
+    /// Write `data` together with the adjacent writes queued directly after it.
+    ///
+    /// Returns the first queued request that could not be combined.
+    async fn write_coalesced(
+        &mut self,
+        offset: u32,
+        data: Payload<N>,
+        reply: &'a FlashReply<M, N>,
+    ) -> Option<FlashRequest<'a, M, N>> {
+        let mut combined = data;
+        let mut replies = [None; MAX_COALESCED_WRITES];
+        replies[0] = Some(reply);
+        let mut count = 1;
+        let mut next = None;
+
+        while count < MAX_COALESCED_WRITES {
+            let Ok(request) = self.requests.try_recv() else {
+                break;
+            };
+            match request {
+                FlashRequest::Write { offset: o, data, reply }
+                    if o == offset + combined.len as u32 && combined.len + data.len <= N =>
+                {
+                    combined.data[combined.len..combined.len + data.len].copy_from_slice(data.as_slice());
+                    combined.len += data.len;
+                    replies[count] = Some(reply);
+                    count += 1;
+                }
+                request => {
+                    next = Some(request);
+                    break;
+                }
+            }
+        }
+
+        let result = self.flash.write(offset, combined.as_slice()).await;
+        for reply in replies.iter().flatten() {
+            reply.signal(result.map(|_| Payload::new()));
+        }
+        next
+    }
+}
+
+/// Handle for a task to send requests to a [`FlashService`], waiting for each reply.
+///
+/// Every client needs its own reply signal.
+pub struct FlashClient<'a, M: RawMutex, const N: usize, const CAP: usize> {
+    requests: &'a FlashRequests<'a, M, N, CAP>,
+    reply: &'a FlashReply<M, N>,
+}
+
+impl<'a, M: RawMutex, const N: usize, const CAP: usize> FlashClient<'a, M, N, CAP> {
+    pub fn new(requests: &'a FlashRequests<'a, M, N, CAP>, reply: &'a FlashReply<M, N>) -> Self {
+        Self { requests, reply }
+    }
+
+    /// Write `data` at `offset`, split into requests of at most `N` bytes.
+    ///
+    /// `N` must be a multiple of the write size of the flash, so that every request stays aligned.
+    pub async fn write(&self, mut offset: u32, data: &[u8]) -> Result<(), Error> {
+        for chunk in data.chunks(N) {
+            self.requests
+                .send(FlashRequest::Write {
+                    offset,
+                    data: Payload::from_slice(chunk),
+                    reply: self.reply,
+                })
+                .await;
+            self.reply.wait().await?;
+            offset += chunk.len() as u32;
+        }
+        Ok(())
+    }
+
+    /// Erase `range`.
+    pub async fn erase(&self, range: Range<u32>) -> Result<(), Error> {
+        self.requests
+            .send(FlashRequest::Erase {
+                range,
+                reply: self.reply,
+            })
+            .await;
+        self.reply.wait().await.map(|_| ())
+    }
+
+    /// Read into `buf` from `offset`, split into requests of at most `N` bytes.
+    pub async fn read(&self, mut offset: u32, buf: &mut [u8]) -> Result<(), Error> {
+        for chunk in buf.chunks_mut(N) {
+            self.requests
+                .send(FlashRequest::Read {
+                    offset,
+                    len: chunk.len(),
+                    reply: self.reply,
+                })
+                .await;
+            chunk.copy_from_slice(self.reply.wait().await?.as_slice());
+            offset += chunk.len() as u32;
+        }
+        Ok(())
+    }
+
+    /// Stop the service once all previously sent requests are done.
+    pub async fn shutdown(&self) {
+        self.requests.send(FlashRequest::Shutdown).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_futures::join::join;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+    use crate::flash::mem_flash::MemFlash;
+
+    type Flash = MemFlash<256, 64, 4>;
+
+    #[test]
+    fn can_serve_clients() {
+        let requests = FlashRequests::<NoopRawMutex, 16, 2>::new();
+        let (reply_a, reply_b) = (FlashReply::new(), FlashReply::new());
+        let a = FlashClient::new(&requests, &reply_a);
+        let b = FlashClient::new(&requests, &reply_b);
+        let service = FlashService::new(Flash::default(), &requests);
+
+        let data: [u8; 40] = core::array::from_fn(|i| i as u8);
+        let (_, (write, erase)) = block_on(join(service.run(), async {
+            let write = join(a.write(0, &data), b.write(64, &data)).await;
+            let erase = a.erase(128..192).await;
+
+            let mut buf = [0; 40];
+            a.read(0, &mut buf).await.unwrap();
+            assert_eq!(data, buf);
+            b.read(64, &mut buf).await.unwrap();
+            assert_eq!(data, buf);
+            b.read(128, &mut buf[..4]).await.unwrap();
+            assert_eq!([0xFF; 4], buf[..4]);
+
+            a.shutdown().await;
+            (write, erase)
+        }));
+
+        assert_eq!((Ok(()), Ok(())), write);
+        assert_eq!(Ok(()), erase);
+    }
+
+    #[test]
+    fn can_coalesce_adjacent_writes() {
+        let requests = FlashRequests::<NoopRawMutex, 16, 4>::new();
+        let replies = [FlashReply::new(), FlashReply::new(), FlashReply::new()];
+        for (i, reply) in replies.iter().enumerate() {
+            let data = [i as u8; 4];
+            let request = FlashRequest::Write {
+                offset: 4 * i as u32,
+                data: Payload::from_slice(&data),
+                reply,
+            };
+            requests.try_send(request).ok().unwrap();
+        }
+        requests.try_send(FlashRequest::Shutdown).ok().unwrap();
+
+        // Only a single program operation succeeds
+        let mut flash = Flash::default();
+        flash.pending_write_successes = Some(1);
+        let flash = block_on(FlashService::new(flash, &requests).run());
+
+        for reply in replies.iter() {
+            assert!(block_on(reply.wait()).is_ok());
+        }
+        assert_eq!([0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2], flash.mem[..12]);
+    }
+}
@@ -0,0 +1,318 @@
+use core::marker::PhantomData;
+
+use embedded_storage::nor_flash::NorFlash;
+
+use super::pages::{round_up, Newest, PageHeader, Pages};
+use super::MAX_WRITE_SIZE;
+
+/// The magic of the page header, whose check word is the CRC-32 over the generation and the blob.
+const MAGIC: u32 = 0x5354_4241;
+
+/// A value that [`AbSettings`] stores as a blob of `N` bytes.
+pub trait SettingsBlob<const N: usize>: Sized {
+    /// Encode the value into a blob.
+    fn to_blob(&self) -> [u8; N];
+
+    /// Decode a blob whose CRC matched, or return `None` if it doesn't hold a valid value, e.g. after a change of
+    /// the layout. The copy is then treated like a corrupt one.
+    fn from_blob(blob: &[u8; N]) -> Option<Self>;
+}
+
+impl<const N: usize> SettingsBlob<N> for [u8; N] {
+    fn to_blob(&self) -> [u8; N] {
+        *self
+    }
+
+    fn from_blob(blob: &[u8; N]) -> Option<Self> {
+        Some(*blob)
+    }
+}
+
+/// Settings of type `T`, stored as a blob of `N` bytes alternately in two pages so that a save never destroys the
+/// last one.
+///
+/// Each page holds one copy of the blob, preceded by a header with a generation counter and a CRC-32 over the
+/// generation and the blob. [`AbSettings::save`] erases the page with the older copy, writes the blob and only
+/// then the header, with the generation of the newer copy plus one. [`AbSettings::load`] returns the copy with
+/// the newest generation among the pages whose CRC matches.
+///
+/// A save that is interrupted, e.g. by a power loss, leaves the page without a valid header, so the previous copy
+/// is loaded. Once a save returned, its copy is loaded until the next save. The generations are compared with
+/// wrapping arithmetic, so the counter can wrap around.
+///
+/// The pages are set up like the ones of a [`KvStore`](super::KvStore), but can't be shared with one.
+pub struct AbSettings<F, T, const N: usize> {
+    pages: Pages<F>,
+    _value: PhantomData<T>,
+}
+
+impl<F: NorFlash, T: SettingsBlob<N>, const N: usize> AbSettings<F, T, N> {
+    /// Create settings stored in the pages `a` and `b`.
+    ///
+    /// # Panics
+    /// Panics if a page can't hold the header and `N` bytes, or the write size is not supported.
+    pub fn new(a: F, b: F) -> Self {
+        let this = Self {
+            pages: Pages::new(a, b),
+            _value: PhantomData,
+        };
+        assert!(this.pages[0].capacity() >= this.used_size());
+        assert!(this.pages[1].capacity() >= this.used_size());
+        this
+    }
+
+    /// Release the underlying pages.
+    pub fn into_inner(self) -> (F, F) {
+        self.pages.into_inner()
+    }
+
+    /// Load the newest valid copy of the settings, or `None` if no page holds one, e.g. on first boot.
+    pub fn load(&mut self) -> Result<Option<T>, F::Error> {
+        Ok(self.newest()?.map(|newest| newest.value))
+    }
+
+    /// Save `value` to the page with the older copy.
+    pub fn save(&mut self, value: &T) -> Result<(), F::Error> {
+        let (page, generation) = match self.newest()? {
+            Some(newest) => (1 - newest.page, newest.seq.wrapping_add(1)),
+            None => (0, 0),
+        };
+        self.write_page(page, generation, &value.to_blob())
+    }
+
+    fn used_size(&self) -> usize {
+        self.pages.header_size() + round_up::<F>(N)
+    }
+
+    /// Erase `page` and write a copy of `data` with `generation`, the header last.
+    fn write_page(&mut self, page: usize, generation: u32, data: &[u8; N]) -> Result<(), F::Error> {
+        let erase_size = (self.used_size() + F::ERASE_SIZE - 1) / F::ERASE_SIZE * F::ERASE_SIZE;
+        let header_size = self.pages.header_size();
+        let flash = &mut self.pages[page];
+        flash.erase(0, erase_size as u32)?;
+
+        let aligned = N / F::WRITE_SIZE * F::WRITE_SIZE;
+        if aligned > 0 {
+            flash.write(header_size as u32, &data[..aligned])?;
+        }
+        if aligned < N {
+            let mut unit = [0xFF; MAX_WRITE_SIZE];
+            unit[..N - aligned].copy_from_slice(&data[aligned..]);
+            flash.write((header_size + aligned) as u32, &unit[..F::WRITE_SIZE])?;
+        }
+
+        let header = PageHeader {
+            seq: generation,
+            check: checksum(generation, data),
+        };
+        self.pages.write_header(page, MAGIC, header)
+    }
+
+    /// Find the valid copy with the newest generation, and decode it.
+    fn newest(&mut self) -> Result<Option<Newest<T>>, F::Error> {
+        let header_size = self.pages.header_size() as u32;
+        self.pages.newest(MAGIC, |flash, header| {
+            let mut data = [0; N];
+            flash.read(header_size, &mut data)?;
+            if checksum(header.seq, &data) != header.check {
+                return Ok(None);
+            }
+            Ok(T::from_blob(&data))
+        })
+    }
+}
+
+/// CRC-32 over the little-endian `generation` followed by `data`.
+fn checksum(generation: u32, data: &[u8]) -> u32 {
+    let crc = super::firmware::crc32(!0, &generation.to_le_bytes());
+    super::firmware::crc32(crc, data) ^ !0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mem_flash::{MemFlash, Operation, Recorder};
+
+    type Page = MemFlash<128, 64, 8>;
+
+    fn settings() -> AbSettings<Page, [u8; 21], 21> {
+        AbSettings::new(Page::default(), Page::default())
+    }
+
+    fn blob(value: u32) -> [u8; 21] {
+        core::array::from_fn(|i| (value as u8).wrapping_add(i as u8))
+    }
+
+    #[test]
+    fn can_load_newest_copy() {
+        let mut settings = settings();
+        assert_eq!(None, settings.load().unwrap());
+
+        for value in 0..5 {
+            settings.save(&blob(value)).unwrap();
+            assert_eq!(Some(blob(value)), settings.load().unwrap());
+        }
+
+        // Both pages hold a copy, the older one is not returned
+        let (a, b) = settings.into_inner();
+        let mut settings = AbSettings::<_, [u8; 21], 21>::new(a, b);
+        assert_eq!(Some(blob(4)), settings.load().unwrap());
+    }
+
+    #[test]
+    fn erases_only_older_page() {
+        static RECORDER_A: Recorder = Recorder::new();
+        static RECORDER_B: Recorder = Recorder::new();
+        let (mut a, mut b) = (Page::default(), Page::default());
+        a.observer = Some(&RECORDER_A);
+        b.observer = Some(&RECORDER_B);
+        let mut settings = AbSettings::<_, [u8; 21], 21>::new(a, b);
+
+        // Each save erases the page with the older copy, then writes the blob and finally the header
+        let save = [
+            Operation::Erase(0),
+            Operation::Write(16, 16),
+            Operation::Write(32, 8),
+            Operation::Write(0, 16),
+        ];
+        settings.save(&blob(1)).unwrap();
+        RECORDER_A.assert_and_clear(&save);
+        RECORDER_B.assert_and_clear(&[]);
+        settings.save(&blob(2)).unwrap();
+        RECORDER_A.assert_and_clear(&[]);
+        RECORDER_B.assert_and_clear(&save);
+        settings.save(&blob(3)).unwrap();
+        RECORDER_A.assert_and_clear(&save);
+        RECORDER_B.assert_and_clear(&[]);
+
+        assert_eq!(Some(blob(3)), settings.load().unwrap());
+        RECORDER_A.assert_and_clear(&[]);
+        RECORDER_B.assert_and_clear(&[]);
+    }
+
+    /// Settings with a version, whose older layouts are not loaded.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Config {
+        version: u8,
+        interval: u16,
+        enabled: bool,
+    }
+
+    impl SettingsBlob<4> for Config {
+        fn to_blob(&self) -> [u8; 4] {
+            let [lo, hi] = self.interval.to_le_bytes();
+            [self.version, lo, hi, self.enabled as u8]
+        }
+
+        fn from_blob(blob: &[u8; 4]) -> Option<Self> {
+            match blob {
+                [2, lo, hi, enabled @ (0 | 1)] => Some(Self {
+                    version: 2,
+                    interval: u16::from_le_bytes([*lo, *hi]),
+                    enabled: *enabled == 1,
+                }),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn can_load_typed_settings() {
+        let mut settings = AbSettings::<_, Config, 4>::new(Page::default(), Page::default());
+        let config = Config {
+            version: 2,
+            interval: 500,
+            enabled: true,
+        };
+        settings.save(&config).unwrap();
+        assert_eq!(Some(config), settings.load().unwrap());
+
+        // A newer copy that doesn't decode is skipped like a corrupt one
+        settings.save(&Config { version: 1, ..config }).unwrap();
+        assert_eq!(Some(config), settings.load().unwrap());
+    }
+
+    fn save_and_load<F: NorFlash + Default>() {
+        let mut settings = AbSettings::<F, [u8; 21], 21>::new(F::default(), F::default());
+        for value in 0..5 {
+            settings.save(&blob(value)).unwrap();
+            assert_eq!(Some(blob(value)), settings.load().unwrap());
+        }
+    }
+
+    #[test]
+    fn can_use_any_write_size() {
+        save_and_load::<MemFlash<128, 64, 1>>();
+        save_and_load::<MemFlash<128, 64, 2>>();
+        save_and_load::<MemFlash<128, 64, 4>>();
+        save_and_load::<MemFlash<128, 64, 32>>();
+    }
+
+    #[test]
+    fn can_wrap_generation() {
+        let mut settings = settings();
+        settings.write_page(1, u32::MAX - 1, &blob(1)).unwrap();
+        settings.save(&blob(2)).unwrap();
+        assert_eq!(Some(blob(2)), settings.load().unwrap());
+
+        // The generation wraps to zero, which is newer than u32::MAX
+        settings.save(&blob(3)).unwrap();
+        assert_eq!(Some(blob(3)), settings.load().unwrap());
+        settings.save(&blob(4)).unwrap();
+        assert_eq!(Some(blob(4)), settings.load().unwrap());
+    }
+
+    #[test]
+    fn can_fall_back_on_corruption() {
+        let mut settings = settings();
+        settings.save(&blob(1)).unwrap();
+        settings.save(&blob(2)).unwrap();
+
+        // A flipped bit in the newer copy
+        let (a, mut b) = settings.into_inner();
+        b.mem[20] ^= 0x04;
+        let mut settings = AbSettings::<_, [u8; 21], 21>::new(a, b);
+        assert_eq!(Some(blob(1)), settings.load().unwrap());
+
+        // The next save replaces the corrupt copy
+        settings.save(&blob(3)).unwrap();
+        assert_eq!(Some(blob(3)), settings.load().unwrap());
+        let (a, b) = settings.into_inner();
+        assert_eq!(blob(1)[..], a.mem[16..37]);
+        assert_eq!(blob(3)[..], b.mem[16..37]);
+    }
+
+    #[test]
+    fn never_loads_older_data_on_power_loss() {
+        let mut settings = settings();
+        let mut acknowledged = None;
+
+        // A save takes three writes: the aligned part of the blob, its padded tail and the header
+        for (value, successes) in (0..4).cycle().take(60).enumerate() {
+            let value = value as u32;
+            let (mut a, mut b) = settings.into_inner();
+            // Cut the power after `successes` writes
+            a.pending_write_successes = Some(successes);
+            b.pending_write_successes = Some(successes);
+
+            let mut interrupted = AbSettings::<_, [u8; 21], 21>::new(a, b);
+            if interrupted.save(&blob(value)).is_ok() {
+                acknowledged = Some(value);
+            }
+
+            let (mut a, mut b) = interrupted.into_inner();
+            a.pending_write_successes = None;
+            b.pending_write_successes = None;
+            settings = AbSettings::new(a, b);
+
+            let loaded = settings.load().unwrap();
+            match acknowledged {
+                Some(acknowledged) if acknowledged == value => assert_eq!(Some(blob(value)), loaded),
+                // The torn copy is never returned, the last acknowledged one is
+                Some(acknowledged) => assert_eq!(Some(blob(acknowledged)), loaded),
+                None => assert_eq!(None, loaded),
+            }
+        }
+        assert!(acknowledged.is_some());
+    }
+}
@@ -0,0 +1,322 @@
+#[cfg(any(feature = "flash-sha256", hash_v2, hash_v3, hash_v4))]
+use core::ops::Range;
+
+#[cfg(any(hash_v2, hash_v3, hash_v4))]
+use embassy_hal_common::{into_ref, PeripheralRef};
+#[cfg(any(feature = "flash-sha256", hash_v2, hash_v3, hash_v4))]
+use embedded_storage::nor_flash::ReadNorFlash;
+#[cfg(feature = "flash-sha256")]
+use sha2::{Digest, Sha256};
+
+#[cfg(any(hash_v2, hash_v3, hash_v4))]
+use crate::dma::{Transfer, TransferOptions};
+#[cfg(hash)]
+use crate::peripherals;
+#[cfg(any(hash_v2, hash_v3, hash_v4))]
+use crate::{pac, Peripheral};
+
+/// Incremental SHA-256 over data fed in chunks, e.g. while a firmware image is downloaded.
+#[cfg(feature = "flash-sha256")]
+#[derive(Clone, Default)]
+pub struct Sha256Context(Sha256);
+
+#[cfg(feature = "flash-sha256")]
+impl Sha256Context {
+    pub fn new() -> Self {
+        Self(Sha256::new())
+    }
+
+    /// Hash the next chunk of data.
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    /// Hash `range` of `flash`, reading it through the bounds-checked read of the flash.
+    pub fn update_from_flash<F: ReadNorFlash>(&mut self, flash: &mut F, range: Range<u32>) -> Result<(), F::Error> {
+        read_chunks(flash, range, |data| self.update(data))
+    }
+
+    /// The hash of all data fed so far.
+    pub fn finalize(self) -> [u8; 32] {
+        self.0.finalize().into()
+    }
+}
+
+/// The SHA-256 of `range` of `flash`.
+#[cfg(feature = "flash-sha256")]
+pub fn sha256<F: ReadNorFlash>(flash: &mut F, range: Range<u32>) -> Result<[u8; 32], F::Error> {
+    let mut context = Sha256Context::new();
+    context.update_from_flash(flash, range)?;
+    Ok(context.finalize())
+}
+
+/// Feeds `range` of `flash` to `update` in small chunks, read through the bounds-checked read of the flash.
+#[cfg(any(feature = "flash-sha256", hash_v2, hash_v3, hash_v4))]
+fn read_chunks<F: ReadNorFlash>(
+    flash: &mut F,
+    range: Range<u32>,
+    mut update: impl FnMut(&[u8]),
+) -> Result<(), F::Error> {
+    const CHUNK_SIZE: usize = 64;
+
+    let mut buf = [0; CHUNK_SIZE];
+    let mut offset = range.start;
+    while offset < range.end {
+        let len = core::cmp::min(CHUNK_SIZE as u32, range.end - offset) as usize;
+        flash.read(offset, &mut buf[..len])?;
+        update(&buf[..len]);
+        offset += len as u32;
+    }
+    Ok(())
+}
+
+/// The HASH peripheral, whose input can be fed by a [`HashDma`] channel.
+#[cfg(hash)]
+pub trait HashInstance: 'static {}
+
+#[cfg(hash)]
+impl HashInstance for peripherals::HASH {}
+
+#[cfg(hash)]
+dma_trait!(HashDma, HashInstance);
+
+/// A DMA channel that feeds the input FIFO of the HASH, with its type erased so the flash driver can keep it.
+#[cfg(any(hash_v2, hash_v3, hash_v4))]
+pub(super) trait FeedHash {
+    /// Transfers `words` to the input FIFO, and waits until they were written.
+    fn feed(&mut self, words: *const [u32]);
+}
+
+#[cfg(any(hash_v2, hash_v3, hash_v4))]
+impl<D: HashDma<peripherals::HASH>> FeedHash for D {
+    fn feed(&mut self, words: *const [u32]) {
+        let request = self.request();
+        let din = pac::HASH.din().ptr() as *mut u32;
+        unsafe { Transfer::new_write_raw(&mut *self, request, words, din, TransferOptions::default()) }.blocking_wait();
+    }
+}
+
+/// The HASH peripheral and the DMA channel that feeds it, see [`Flash::set_hash`](super::Flash::set_hash).
+#[cfg(any(hash_v2, hash_v3, hash_v4))]
+pub(super) type HashParts<'d> = (PeripheralRef<'d, peripherals::HASH>, &'d mut dyn FeedHash);
+
+/// Incremental SHA-256 on the HASH peripheral, fed in chunks like the software `Sha256Context`.
+///
+/// Flash contents are fed to the HASH by DMA, straight from the memory-mapped flash. Data in RAM is fed by the
+/// CPU, as the DMA can't reach every RAM of every family.
+///
+/// Only one hash can be computed at a time, the context owns the peripheral until it is finalized. The HASH of the
+/// oldest parts (`hash_v1`) has no SHA-256.
+#[cfg(any(hash_v2, hash_v3, hash_v4))]
+pub struct HashSha256Context<'d> {
+    _hash: PeripheralRef<'d, peripherals::HASH>,
+    dma: &'d mut dyn FeedHash,
+    /// Bytes of the word that is not complete yet, the HASH is fed whole words.
+    partial: [u8; 4],
+    partial_len: usize,
+    /// Words written since the start, to wait for the input FIFO at each block.
+    words: usize,
+}
+
+#[cfg(any(hash_v2, hash_v3, hash_v4))]
+impl<'d> HashSha256Context<'d> {
+    /// Starts a SHA-256 on the HASH peripheral, which is enabled and reset first. `dma` feeds the flash contents.
+    pub fn new(
+        hash: impl Peripheral<P = peripherals::HASH> + 'd,
+        dma: &'d mut impl HashDma<peripherals::HASH>,
+    ) -> Self {
+        into_ref!(hash);
+        Self::start(hash, dma)
+    }
+
+    pub(super) fn start(hash: PeripheralRef<'d, peripherals::HASH>, dma: &'d mut dyn FeedHash) -> Self {
+        <peripherals::HASH as crate::rcc::sealed::RccPeripheral>::enable();
+        <peripherals::HASH as crate::rcc::sealed::RccPeripheral>::reset();
+
+        unsafe {
+            pac::HASH.cr().write(|w| {
+                // The bytes are swapped, so every word is hashed in memory order
+                w.set_datatype(0b10);
+                #[cfg(hash_v2)]
+                {
+                    w.set_algo0(true);
+                    w.set_algo1(true);
+                }
+                #[cfg(any(hash_v3, hash_v4))]
+                w.set_algo(0b11);
+                // The digest is only calculated once it is finalized, not at the end of each transfer
+                w.set_mdmat(true);
+                w.set_init(true);
+            });
+        }
+
+        Self {
+            _hash: hash,
+            dma,
+            partial: [0; 4],
+            partial_len: 0,
+            words: 0,
+        }
+    }
+
+    /// Hash the next chunk of data, which is fed by the CPU.
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = core::cmp::min(4 - self.partial_len, data.len());
+            self.partial[self.partial_len..self.partial_len + n].copy_from_slice(&data[..n]);
+            self.partial_len += n;
+            data = &data[n..];
+
+            if self.partial_len == 4 {
+                self.write_word();
+            }
+        }
+    }
+
+    /// Hash `range` of `flash`, given as offsets from the flash base. The bounds are checked like in
+    /// [`Flash::map`](super::Flash::map).
+    ///
+    /// The words are fed by DMA. The bytes before the first aligned word and after the last one are fed by the
+    /// CPU, and so is the whole range if it doesn't continue the hashed data at an aligned word, or an erase
+    /// started with [`Flash::try_start_erase`](super::Flash::try_start_erase) is pending.
+    pub fn update_from_flash(&mut self, flash: &mut super::Flash<'_>, range: Range<u32>) -> Result<(), super::Error> {
+        if flash.has_pending() {
+            return read_chunks(flash, range, |data| self.update(data));
+        }
+        let mapped = flash.map(range.start, range.end.saturating_sub(range.start) as usize)?;
+        self.update_dma(&mapped);
+        Ok(())
+    }
+
+    /// The hash of all data fed so far.
+    pub fn finalize(mut self) -> [u8; 32] {
+        let regs = pac::HASH;
+        unsafe {
+            // The number of valid bits of the last word, 0 if it is complete
+            regs.str().write(|w| w.set_nblw(8 * self.partial_len as u8));
+            if self.partial_len > 0 {
+                self.partial[self.partial_len..].fill(0);
+                self.write_word();
+            }
+            regs.str().modify(|w| w.set_dcal(true));
+            while !regs.sr().read().dcis() {}
+
+            let mut digest = [0; 32];
+            for (i, bytes) in digest.chunks_exact_mut(4).enumerate() {
+                bytes.copy_from_slice(&regs.hr(i).read().to_be_bytes());
+            }
+            digest
+        }
+    }
+
+    /// Feed `data` of the memory-mapped flash, the aligned words by DMA.
+    fn update_dma(&mut self, data: &[u8]) {
+        /// The largest number of items of a single transfer.
+        const MAX_TRANSFER: usize = 0xFFFF;
+
+        let head = core::cmp::min(data.len(), data.as_ptr().align_offset(4));
+        // The DMA can only continue with a complete word from an aligned address
+        if (self.partial_len + head) % 4 != 0 {
+            return self.update(data);
+        }
+        self.update(&data[..head]);
+
+        let words = (data.len() - head) / 4;
+        let body = data[head..].as_ptr() as *const u32;
+        for start in (0..words).step_by(MAX_TRANSFER) {
+            let count = core::cmp::min(MAX_TRANSFER, words - start);
+            unsafe {
+                pac::HASH.cr().modify(|w| w.set_dmae(true));
+                self.dma.feed(core::ptr::slice_from_raw_parts(body.add(start), count));
+                pac::HASH.cr().modify(|w| w.set_dmae(false));
+            }
+            self.words += count;
+        }
+
+        self.update(&data[head + 4 * words..]);
+    }
+
+    fn write_word(&mut self) {
+        let regs = pac::HASH;
+        unsafe {
+            // The first block is processed once the word after it is written, then the FIFO takes a block at a time
+            if self.words > 16 && (self.words - 17) % 16 == 0 {
+                while !regs.sr().read().dinis() {}
+            }
+            regs.din().write_value(u32::from_ne_bytes(self.partial));
+        }
+        self.partial_len = 0;
+        self.words += 1;
+    }
+}
+
+#[cfg(all(flash, any(feature = "flash-sha256", hash_v2, hash_v3, hash_v4)))]
+impl<'d> super::Flash<'d> {
+    /// The SHA-256 of `range`, given as offsets from the flash base.
+    ///
+    /// With a HASH peripheral attached with [`Flash::set_hash`](super::Flash::set_hash), the hash is computed on
+    /// it, fed from the flash by DMA like [`HashSha256Context::update_from_flash`]. Otherwise it is computed in
+    /// software, reading the flash in small chunks.
+    ///
+    /// # Panics
+    /// Panics on a family with a HASH peripheral if none is attached and the `flash-sha256` feature is disabled.
+    pub fn sha256(&mut self, range: Range<u32>) -> Result<[u8; 32], super::Error> {
+        #[cfg(any(hash_v2, hash_v3, hash_v4))]
+        if let Some((mut hash, dma)) = self.hash.take() {
+            let mut context = HashSha256Context::start(hash.reborrow(), &mut *dma);
+            let result = context.update_from_flash(self, range).map(|_| context.finalize());
+            self.hash = Some((hash, dma));
+            return result;
+        }
+
+        software_sha256(self, range)
+    }
+
+    /// Lets [`Flash::sha256`](super::Flash::sha256) compute the hash on `hash`, fed by `dma`.
+    #[cfg(any(hash_v2, hash_v3, hash_v4))]
+    pub fn set_hash(
+        &mut self,
+        hash: impl Peripheral<P = peripherals::HASH> + 'd,
+        dma: &'d mut impl HashDma<peripherals::HASH>,
+    ) {
+        into_ref!(hash);
+        self.hash = Some((hash, dma));
+    }
+}
+
+#[cfg(all(flash, feature = "flash-sha256"))]
+fn software_sha256(flash: &mut super::Flash<'_>, range: Range<u32>) -> Result<[u8; 32], super::Error> {
+    sha256(flash, range)
+}
+
+#[cfg(all(flash, not(feature = "flash-sha256"), any(hash_v2, hash_v3, hash_v4)))]
+fn software_sha256(_flash: &mut super::Flash<'_>, _range: Range<u32>) -> Result<[u8; 32], super::Error> {
+    panic!("Attach the HASH with `Flash::set_hash` or enable the `flash-sha256` feature")
+}
+
+#[cfg(all(test, feature = "flash-sha256"))]
+mod tests {
+    use super::*;
+    use crate::flash::mem_flash::MemFlash;
+
+    #[test]
+    fn can_hash_flash_range() {
+        let mut flash = MemFlash::<256, 64, 4>::default();
+        flash.mem[10..13].copy_from_slice(b"abc");
+
+        // The FIPS 180-2 example
+        let expected = [
+            0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22, 0x23, 0xb0, 0x03,
+            0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00, 0x15, 0xad,
+        ];
+        assert_eq!(expected, sha256(&mut flash, 10..13).unwrap());
+
+        // Fed in chunks of any length, the result is the same as when hashing the range at once
+        let hash = sha256(&mut flash, 0..200).unwrap();
+        let mut context = Sha256Context::new();
+        context.update_from_flash(&mut flash, 0..7).unwrap();
+        context.update(&flash.mem[7..130]);
+        context.update_from_flash(&mut flash, 130..200).unwrap();
+        assert_eq!(hash, context.finalize());
+    }
+}
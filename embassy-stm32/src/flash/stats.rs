@@ -0,0 +1,194 @@
+use core::cell::Cell;
+use core::ops::Range;
+
+#[cfg(not(feature = "time"))]
+use atomic_polyfill::{AtomicU32, Ordering};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+#[cfg(feature = "time")]
+use embassy_time::{Duration, Instant};
+
+use super::Error;
+
+const ERROR_KINDS: usize = 18;
+
+/// Counters of the operations executed by the flash driver since boot or the last reset.
+///
+/// The counters are shared by [`Flash`](super::Flash) and all regions created from it, and wrap on overflow.
+/// Only operations that reached the controller are counted, not requests rejected by the argument checks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FlashStats {
+    /// Bytes programmed successfully.
+    pub bytes_written: u32,
+    /// Sectors erased successfully.
+    pub sectors_erased: u32,
+    /// The sector that was erased most often and its erase count, counting the sectors of all regions from the flash
+    /// base, or `None` if [`Flash::track_wear`](super::Flash::track_wear) was not called. Unlike the other counters,
+    /// this persists across resets.
+    pub most_worn: Option<(usize, u32)>,
+    /// Duration of the slowest single program or erase operation.
+    #[cfg(feature = "time")]
+    pub worst_case: Duration,
+    /// Status register polls of the slowest single program or erase operation.
+    #[cfg(not(feature = "time"))]
+    pub worst_case_polls: u32,
+    errors: [u32; ERROR_KINDS],
+}
+
+impl FlashStats {
+    const fn new() -> Self {
+        Self {
+            bytes_written: 0,
+            sectors_erased: 0,
+            most_worn: None,
+            #[cfg(feature = "time")]
+            worst_case: Duration::from_ticks(0),
+            #[cfg(not(feature = "time"))]
+            worst_case_polls: 0,
+            errors: [0; ERROR_KINDS],
+        }
+    }
+
+    /// The number of failed operations that returned `error`.
+    pub fn errors(&self, error: Error) -> u32 {
+        self.errors[error_index(error)]
+    }
+
+    /// The number of failed operations of any kind.
+    pub fn total_errors(&self) -> u32 {
+        self.errors.iter().fold(0, |sum, &n| sum.wrapping_add(n))
+    }
+}
+
+fn error_index(error: Error) -> usize {
+    match error {
+        Error::Prog => 0,
+        Error::Size => 1,
+        Error::Miss => 2,
+        Error::Seq => 3,
+        Error::Protected => 4,
+        Error::Unaligned => 5,
+        Error::Parallelism => 6,
+        Error::OutOfBounds => 7,
+        Error::OptionByteVerify { .. } => 8,
+        Error::NotErased { .. } => 9,
+        Error::Busy => 10,
+        Error::IncompatibleRegions => 11,
+        Error::WouldEraseSelf => 12,
+        Error::ClockNotReady { .. } => 13,
+        Error::Verify { .. } => 14,
+        Error::Timeout => 15,
+        Error::Overlap => 16,
+        Error::Erase { .. } => 17,
+    }
+}
+
+static STATS: Mutex<CriticalSectionRawMutex, Cell<FlashStats>> = Mutex::new(Cell::new(FlashStats::new()));
+
+#[cfg(not(feature = "time"))]
+static POLLS: AtomicU32 = AtomicU32::new(0);
+
+pub(crate) fn stats() -> FlashStats {
+    STATS.lock(|stats| stats.get())
+}
+
+pub(crate) fn reset_stats() {
+    STATS.lock(|stats| stats.set(FlashStats::new()));
+}
+
+/// The absolute start and end of the area set with [`Flash::track_wear`](super::Flash::track_wear).
+static WEAR_AREA: Mutex<CriticalSectionRawMutex, Cell<Option<(u32, u32)>>> = Mutex::new(Cell::new(None));
+
+pub(crate) fn wear_area() -> Option<Range<u32>> {
+    WEAR_AREA.lock(|area| area.get()).map(|(start, end)| start..end)
+}
+
+pub(crate) fn set_wear_area(area: Range<u32>) {
+    WEAR_AREA.lock(|cell| cell.set(Some((area.start, area.end))));
+}
+
+/// Count one poll of the status register, used to rate operations if embassy-time is not available.
+#[inline(always)]
+pub(crate) fn count_poll() {
+    #[cfg(not(feature = "time"))]
+    POLLS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Measures a single program or erase operation.
+pub(crate) struct Measurement {
+    #[cfg(feature = "time")]
+    start: Instant,
+    #[cfg(not(feature = "time"))]
+    start: u32,
+}
+
+impl Measurement {
+    pub(crate) fn start() -> Self {
+        Self {
+            #[cfg(feature = "time")]
+            start: Instant::now(),
+            #[cfg(not(feature = "time"))]
+            start: POLLS.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Account the operation, which programmed `bytes_written` bytes or erased `sectors_erased` sectors if it succeeded.
+    pub(crate) fn finish(self, result: &Result<(), Error>, bytes_written: u32, sectors_erased: u32) {
+        #[cfg(feature = "time")]
+        let elapsed = self.start.elapsed();
+        #[cfg(not(feature = "time"))]
+        let elapsed = POLLS.load(Ordering::Relaxed).wrapping_sub(self.start);
+
+        STATS.lock(|stats| {
+            let mut s = stats.get();
+            match result {
+                Ok(()) => {
+                    s.bytes_written = s.bytes_written.wrapping_add(bytes_written);
+                    s.sectors_erased = s.sectors_erased.wrapping_add(sectors_erased);
+                }
+                Err(error) => {
+                    let count = &mut s.errors[error_index(*error)];
+                    *count = count.wrapping_add(1);
+                }
+            }
+            #[cfg(feature = "time")]
+            {
+                s.worst_case = core::cmp::max(s.worst_case, elapsed);
+            }
+            #[cfg(not(feature = "time"))]
+            {
+                s.worst_case_polls = core::cmp::max(s.worst_case_polls, elapsed);
+            }
+            stats.set(s);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(not(feature = "time"))]
+    fn can_count_operations() {
+        reset_stats();
+
+        Measurement::start().finish(&Ok(()), 8, 0);
+        Measurement::start().finish(&Ok(()), 0, 1);
+        Measurement::start().finish(&Err(Error::Protected), 8, 0);
+        Measurement::start().finish(&Err(Error::Protected), 0, 1);
+        Measurement::start().finish(&Err(Error::Seq), 0, 1);
+
+        let stats = stats();
+        assert_eq!(8, stats.bytes_written);
+        assert_eq!(1, stats.sectors_erased);
+        assert_eq!(2, stats.errors(Error::Protected));
+        assert_eq!(1, stats.errors(Error::Seq));
+        assert_eq!(0, stats.errors(Error::Prog));
+        assert_eq!(3, stats.total_errors());
+
+        reset_stats();
+        assert_eq!(FlashStats::new(), super::stats());
+    }
+}
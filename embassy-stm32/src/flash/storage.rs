@@ -0,0 +1,206 @@
+use embedded_storage::nor_flash::NorFlash;
+use embedded_storage::{ReadStorage, Storage};
+
+/// Byte-addressable [`Storage`] on top of NOR flash, using read-modify-write of whole pages.
+///
+/// Writes are merged into a RAM copy of the affected page of `PAGE_SIZE` bytes, which must be a multiple
+/// of the erase size of `F`. Consecutive writes to the same page are combined, the page is only erased and
+/// programmed again when a write touches a different page, or when [`RmwStorage::flush`] is called.
+/// Pages whose content didn't change are not erased.
+///
+/// Data is not persisted until it is flushed: call [`RmwStorage::flush`] after the last write, dropping the
+/// adapter discards a pending page.
+///
+/// # Wear
+/// Every flushed page costs one erase cycle of all sectors in it, no matter how few bytes changed. Writing
+/// a single byte at a time to different pages in turn erases a page per byte, so group writes by page where
+/// possible. A power loss between erasing and programming a page loses the whole page, including the bytes
+/// that were not written. All other pages stay intact.
+pub struct RmwStorage<F, const PAGE_SIZE: usize> {
+    flash: F,
+    buf: [u8; PAGE_SIZE],
+    page: Option<u32>,
+    dirty: bool,
+}
+
+impl<F: NorFlash, const PAGE_SIZE: usize> RmwStorage<F, PAGE_SIZE> {
+    /// # Panics
+    /// Panics if `PAGE_SIZE` is not a multiple of the erase size of `flash`.
+    pub fn new(flash: F) -> Self {
+        assert!(PAGE_SIZE > 0 && PAGE_SIZE % F::ERASE_SIZE == 0);
+        assert_eq!(0, PAGE_SIZE % F::WRITE_SIZE);
+        Self {
+            flash,
+            buf: [0; PAGE_SIZE],
+            page: None,
+            dirty: false,
+        }
+    }
+
+    /// Release the underlying flash. Unflushed writes are lost.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Erase and program the cached page, if it was written since it was loaded.
+    pub fn flush(&mut self) -> Result<(), F::Error> {
+        let Some(page) = self.page else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let start = page * PAGE_SIZE as u32;
+        self.flash.erase(start, start + PAGE_SIZE as u32)?;
+        // The page is erased now, so it has to be written again even if programming fails
+        self.flash.write(start, &self.buf)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Make `page` the cached page, flushing the previous one.
+    fn load(&mut self, page: u32) -> Result<(), F::Error> {
+        if self.page == Some(page) {
+            return Ok(());
+        }
+        self.flush()?;
+
+        self.page = None;
+        self.flash.read(page * PAGE_SIZE as u32, &mut self.buf)?;
+        self.page = Some(page);
+        Ok(())
+    }
+}
+
+impl<F: NorFlash, const PAGE_SIZE: usize> ReadStorage for RmwStorage<F, PAGE_SIZE> {
+    type Error = F::Error;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.read(offset, bytes)?;
+
+        // Unflushed writes take precedence over the flash content
+        if let Some(page) = self.page {
+            let page_start = page * PAGE_SIZE as u32;
+            let start = core::cmp::max(offset, page_start);
+            let end = core::cmp::min(offset + bytes.len() as u32, page_start + PAGE_SIZE as u32);
+            if start < end {
+                bytes[(start - offset) as usize..(end - offset) as usize]
+                    .copy_from_slice(&self.buf[(start - page_start) as usize..(end - page_start) as usize]);
+            }
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.capacity() / PAGE_SIZE * PAGE_SIZE
+    }
+}
+
+impl<F: NorFlash, const PAGE_SIZE: usize> Storage for RmwStorage<F, PAGE_SIZE> {
+    fn write(&mut self, mut offset: u32, mut bytes: &[u8]) -> Result<(), Self::Error> {
+        while !bytes.is_empty() {
+            let page = offset / PAGE_SIZE as u32;
+            let in_page = offset as usize % PAGE_SIZE;
+            let n = core::cmp::min(bytes.len(), PAGE_SIZE - in_page);
+            self.load(page)?;
+
+            let (chunk, rest) = bytes.split_at(n);
+            if self.buf[in_page..in_page + n] != *chunk {
+                self.buf[in_page..in_page + n].copy_from_slice(chunk);
+                self.dirty = true;
+            }
+
+            offset += n as u32;
+            bytes = rest;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mem_flash::{MemFlash, Operation, Recorder};
+
+    type Flash = MemFlash<512, 64, 4>;
+
+    #[test]
+    fn can_write_across_pages() {
+        let mut storage = RmwStorage::<_, 128>::new(Flash::new(0x11));
+        let data: [u8; 20] = core::array::from_fn(|i| i as u8);
+        storage.write(118, &data).unwrap();
+
+        // Reads see the unflushed data
+        let mut buf = [0; 20];
+        storage.read(118, &mut buf).unwrap();
+        assert_eq!(data, buf);
+
+        storage.flush().unwrap();
+        let flash = storage.into_inner();
+        assert_eq!(data, flash.mem[118..138]);
+        assert!(flash.mem[..118].iter().all(|&b| b == 0x11));
+        assert!(flash.mem[138..].iter().all(|&b| b == 0x11));
+    }
+
+    #[test]
+    fn can_combine_sequential_writes() {
+        let mut flash = Flash::new(0x11);
+        // Only two program operations succeed
+        flash.pending_write_successes = Some(2);
+        let mut storage = RmwStorage::<_, 128>::new(flash);
+
+        for i in 0..128u32 {
+            storage.write(i, &[i as u8]).unwrap();
+        }
+        storage.write(128, &[0xAA; 128]).unwrap();
+        storage.flush().unwrap();
+        // Unchanged pages are not programmed
+        storage.write(0, &[0]).unwrap();
+        storage.flush().unwrap();
+
+        let flash = storage.into_inner();
+        assert!(flash.mem[..128].iter().enumerate().all(|(i, &b)| b == i as u8));
+        assert!(flash.mem[128..256].iter().all(|&b| b == 0xAA));
+    }
+
+    #[test]
+    fn can_rewrite_each_page_once() {
+        static RECORDER: Recorder = Recorder::new();
+        let mut flash = Flash::new(0x11);
+        flash.observer = Some(&RECORDER);
+        let mut storage = RmwStorage::<_, 128>::new(flash);
+
+        storage.write(120, &[0x22; 16]).unwrap();
+        storage.write(136, &[0x33; 16]).unwrap();
+        storage.flush().unwrap();
+        storage.flush().unwrap();
+
+        RECORDER.assert_and_clear(&[
+            Operation::Erase(0),
+            Operation::Erase(64),
+            Operation::Write(0, 128),
+            Operation::Erase(128),
+            Operation::Erase(192),
+            Operation::Write(128, 128),
+        ]);
+    }
+
+    #[test]
+    fn loses_only_last_page_on_power_loss() {
+        let mut flash = Flash::new(0x11);
+        // The power is cut while programming the second page
+        flash.pending_write_successes = Some(1);
+        let mut storage = RmwStorage::<_, 128>::new(flash);
+
+        storage.write(120, &[0x22; 16]).unwrap();
+        assert!(storage.flush().is_err());
+
+        let flash = storage.into_inner();
+        assert!(flash.mem[..120].iter().all(|&b| b == 0x11));
+        assert!(flash.mem[120..128].iter().all(|&b| b == 0x22));
+        // The page that was erased but not programmed lost everything
+        assert!(flash.mem[128..256].iter().all(|&b| b == 0xFF));
+        assert!(flash.mem[256..].iter().all(|&b| b == 0x11));
+    }
+}
@@ -0,0 +1,316 @@
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+
+use super::MAX_WRITE_SIZE;
+
+/// Size of the `seq` + `!seq` header of an accounting sector, before rounding up to the write size.
+const HEADER_LEN: usize = 8;
+
+/// Erase-cycle accounting for the sectors of a flash area.
+///
+/// Wraps the tracked flash `F` and counts every erase of each of its sectors in a separate accounting
+/// area `A`, which must consist of at least two sectors. All other operations are passed through.
+///
+/// An accounting sector starts with a snapshot of all counters, followed by a log with one write unit
+/// per erase. Recording an erase is therefore a single program operation. Only when the log is full,
+/// the next accounting sector is erased and started with a new snapshot, whose header is written last.
+/// An erase is recorded after it succeeded, so a power loss loses at most the one count in progress.
+///
+/// The driver can also count the erases of the internal flash itself, whichever method erases them, see
+/// `Flash::track_wear`.
+pub struct WearTracking<F, A> {
+    flash: F,
+    log: WearLog<A>,
+}
+
+impl<F: NorFlash, A: NorFlash> WearTracking<F, A> {
+    /// Track the erases of all sectors of `flash`, using all of `accounting`.
+    ///
+    /// # Panics
+    /// Panics if `accounting` has less than two sectors, or its sectors can't hold the counters of all
+    /// tracked sectors.
+    pub fn new(flash: F, accounting: A) -> Self {
+        let tracked_sectors = flash.capacity() / F::ERASE_SIZE;
+        Self {
+            flash,
+            log: WearLog::new(accounting, tracked_sectors),
+        }
+    }
+
+    /// Release the tracked and the accounting flash.
+    pub fn into_inner(self) -> (F, A) {
+        (self.flash, self.log.accounting)
+    }
+
+    /// The number of times `sector` of the tracked flash was erased.
+    pub fn wear(&mut self, sector: usize) -> Result<u32, A::Error> {
+        self.log.wear(sector)
+    }
+
+    /// The sector of the tracked flash that was erased most often, and its erase count.
+    pub fn most_worn(&mut self) -> Result<(usize, u32), A::Error> {
+        self.log.most_worn()
+    }
+}
+
+struct Current {
+    sector: usize,
+    seq: u32,
+    used: usize,
+}
+
+/// The erase counters of `tracked_sectors` sectors in the accounting area `A`, see [`WearTracking`].
+pub(crate) struct WearLog<A> {
+    accounting: A,
+    tracked_sectors: usize,
+}
+
+impl<A: NorFlash> WearLog<A> {
+    /// Count the erases of `tracked_sectors` sectors in all of `accounting`.
+    ///
+    /// # Panics
+    /// Panics if `accounting` has less than two sectors, or its sectors can't hold `tracked_sectors` counters.
+    pub(crate) fn new(accounting: A, tracked_sectors: usize) -> Self {
+        assert!(A::WRITE_SIZE >= 2 && A::WRITE_SIZE <= MAX_WRITE_SIZE);
+        assert!(accounting.capacity() / A::ERASE_SIZE >= 2);
+        let this = Self {
+            accounting,
+            tracked_sectors,
+        };
+        assert!(this.tracked_sectors < 0xFFFF);
+        assert!(this.log_capacity() > 0);
+        this
+    }
+
+    /// The number of times `sector` was erased.
+    pub(crate) fn wear(&mut self, sector: usize) -> Result<u32, A::Error> {
+        assert!(sector < self.tracked_sectors);
+        match self.current()? {
+            Some(current) => self.count(&current, sector),
+            None => Ok(0),
+        }
+    }
+
+    /// The sector that was erased most often, and its erase count.
+    pub(crate) fn most_worn(&mut self) -> Result<(usize, u32), A::Error> {
+        let Some(current) = self.current()? else {
+            return Ok((0, 0));
+        };
+
+        let mut most_worn = (0, 0);
+        for sector in 0..self.tracked_sectors {
+            let count = self.count(&current, sector)?;
+            if count > most_worn.1 {
+                most_worn = (sector, count);
+            }
+        }
+        Ok(most_worn)
+    }
+
+    fn accounting_sectors(&self) -> usize {
+        self.accounting.capacity() / A::ERASE_SIZE
+    }
+
+    fn round_up(len: usize) -> usize {
+        (len + A::WRITE_SIZE - 1) / A::WRITE_SIZE * A::WRITE_SIZE
+    }
+
+    fn snapshot_offset(&self) -> usize {
+        Self::round_up(HEADER_LEN)
+    }
+
+    fn log_offset(&self) -> usize {
+        self.snapshot_offset() + Self::round_up(self.tracked_sectors * 4)
+    }
+
+    fn log_capacity(&self) -> usize {
+        A::ERASE_SIZE.saturating_sub(self.log_offset()) / A::WRITE_SIZE
+    }
+
+    fn entry_offset(&self, sector: usize, entry: usize) -> u32 {
+        (sector * A::ERASE_SIZE + self.log_offset() + entry * A::WRITE_SIZE) as u32
+    }
+
+    /// Find the accounting sector with the newest snapshot.
+    fn current(&mut self) -> Result<Option<Current>, A::Error> {
+        let mut current: Option<Current> = None;
+
+        for sector in 0..self.accounting_sectors() {
+            let mut header = [0; HEADER_LEN];
+            self.accounting.read((sector * A::ERASE_SIZE) as u32, &mut header)?;
+            let seq = u32::from_le_bytes(header[..4].try_into().unwrap());
+            let check = u32::from_le_bytes(header[4..].try_into().unwrap());
+            if seq != !check || current.as_ref().map_or(false, |c| c.seq > seq) {
+                continue;
+            }
+            current = Some(Current { sector, seq, used: 0 });
+        }
+
+        if let Some(current) = current.as_mut() {
+            let mut entry = [0; MAX_WRITE_SIZE];
+            while current.used < self.log_capacity() {
+                let offset = self.entry_offset(current.sector, current.used);
+                self.accounting.read(offset, &mut entry[..A::WRITE_SIZE])?;
+                if entry[..A::WRITE_SIZE].iter().all(|&b| b == 0xFF) {
+                    break;
+                }
+                current.used += 1;
+            }
+        }
+
+        Ok(current)
+    }
+
+    /// Count the erases of `sector` in the snapshot and log of `current`.
+    fn count(&mut self, current: &Current, sector: usize) -> Result<u32, A::Error> {
+        let mut count = [0; 4];
+        let offset = current.sector * A::ERASE_SIZE + self.snapshot_offset() + sector * 4;
+        self.accounting.read(offset as u32, &mut count)?;
+        let mut count = u32::from_le_bytes(count);
+
+        let mut entry = [0; MAX_WRITE_SIZE];
+        for i in 0..current.used {
+            self.accounting
+                .read(self.entry_offset(current.sector, i), &mut entry[..A::WRITE_SIZE])?;
+            if u16::from_le_bytes([entry[0], entry[1]]) as usize == sector {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Start the next accounting sector with a snapshot of the counters in `previous`.
+    fn start_sector(&mut self, previous: Option<&Current>) -> Result<Current, A::Error> {
+        let (sector, seq) = match previous {
+            Some(previous) => ((previous.sector + 1) % self.accounting_sectors(), previous.seq + 1),
+            None => (0, 0),
+        };
+        let start = sector * A::ERASE_SIZE;
+        self.accounting.erase(start as u32, (start + A::ERASE_SIZE) as u32)?;
+
+        const COUNTS_PER_WRITE: usize = MAX_WRITE_SIZE / 4;
+        let mut first = 0;
+        while first < self.tracked_sectors {
+            let n = core::cmp::min(COUNTS_PER_WRITE, self.tracked_sectors - first);
+            let mut buf = [0xFF; MAX_WRITE_SIZE];
+            for i in 0..n {
+                let count = match previous {
+                    Some(previous) => self.count(previous, first + i)?,
+                    None => 0,
+                };
+                buf[i * 4..i * 4 + 4].copy_from_slice(&count.to_le_bytes());
+            }
+
+            let offset = start + self.snapshot_offset() + first * 4;
+            self.accounting.write(offset as u32, &buf[..Self::round_up(n * 4)])?;
+            first += n;
+        }
+
+        // The header commits the snapshot
+        let mut header = [0xFF; HEADER_LEN + MAX_WRITE_SIZE];
+        header[..4].copy_from_slice(&seq.to_le_bytes());
+        header[4..8].copy_from_slice(&(!seq).to_le_bytes());
+        self.accounting.write(start as u32, &header[..self.snapshot_offset()])?;
+
+        Ok(Current { sector, seq, used: 0 })
+    }
+
+    /// Record one erase of `sector`.
+    pub(crate) fn record(&mut self, sector: usize) -> Result<(), A::Error> {
+        let current = match self.current()? {
+            None => self.start_sector(None)?,
+            Some(current) if current.used == self.log_capacity() => self.start_sector(Some(&current))?,
+            Some(current) => current,
+        };
+
+        let mut entry = [0; MAX_WRITE_SIZE];
+        entry[..2].copy_from_slice(&(sector as u16).to_le_bytes());
+        let offset = self.entry_offset(current.sector, current.used);
+        self.accounting.write(offset, &entry[..A::WRITE_SIZE])
+    }
+}
+
+impl<F: NorFlash, A: NorFlash<Error = F::Error>> ErrorType for WearTracking<F, A> {
+    type Error = F::Error;
+}
+
+impl<F: NorFlash, A: NorFlash<Error = F::Error>> ReadNorFlash for WearTracking<F, A> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.capacity()
+    }
+}
+
+impl<F: NorFlash, A: NorFlash<Error = F::Error>> NorFlash for WearTracking<F, A> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.flash.write(offset, bytes)
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.flash.erase(from, to)?;
+        for sector in from as usize / F::ERASE_SIZE..to as usize / F::ERASE_SIZE {
+            self.log.record(sector)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::mem_flash::MemFlash;
+
+    type Tracked = MemFlash<1024, 128, 4>;
+    type Accounting = MemFlash<256, 128, 4>;
+
+    #[test]
+    fn can_count_erases() {
+        let mut flash = WearTracking::new(Tracked::default(), Accounting::default());
+        assert_eq!((0, 0), flash.most_worn().unwrap());
+
+        // 22 log entries per accounting sector, so this compacts several times
+        for _ in 0..50 {
+            flash.erase(0, 128).unwrap();
+        }
+        for _ in 0..70 {
+            flash.erase(256, 512).unwrap();
+        }
+
+        let (tracked, accounting) = flash.into_inner();
+        let mut flash = WearTracking::new(tracked, accounting);
+        assert_eq!(50, flash.wear(0).unwrap());
+        assert_eq!(0, flash.wear(1).unwrap());
+        assert_eq!(70, flash.wear(2).unwrap());
+        assert_eq!(70, flash.wear(3).unwrap());
+        assert_eq!((2, 70), flash.most_worn().unwrap());
+    }
+
+    #[test]
+    fn loses_at_most_one_count_on_power_loss() {
+        let mut accounting = Accounting::default();
+        let mut tracked = Tracked::default();
+        let mut recorded = 0;
+
+        for successes in (0..4).cycle().take(60) {
+            // Cut the power after `successes` accounting writes
+            accounting.pending_write_successes = Some(successes);
+            let mut flash = WearTracking::new(tracked, accounting);
+            while flash.erase(128, 256).is_ok() {
+                recorded += 1;
+            }
+            (tracked, accounting) = flash.into_inner();
+            accounting.pending_write_successes = None;
+
+            let mut flash = WearTracking::new(tracked, accounting);
+            assert_eq!(recorded, flash.wear(1).unwrap());
+            (tracked, accounting) = flash.into_inner();
+        }
+    }
+}
@@ -91,6 +91,17 @@ pub(crate) unsafe fn get_freqs() -> &'static Clocks {
     &*CLOCK_FREQS.as_ptr()
 }
 
+/// Whether the HSI oscillator is enabled and stable.
+///
+/// The flash controller of these families needs it to program and erase.
+#[cfg(any(rcc_f0, rcc_f3, rcc_l0, rcc_l1))]
+pub fn hsi_ready() -> bool {
+    #[cfg(rcc_l0)]
+    return unsafe { crate::pac::RCC.cr().read().hsi16rdyf() };
+    #[cfg(not(rcc_l0))]
+    return unsafe { crate::pac::RCC.cr().read().hsirdy() };
+}
+
 #[cfg(feature = "unstable-pac")]
 pub mod low_level {
     pub use super::sealed::*;
@@ -0,0 +1,39 @@
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+use core::future::pending;
+
+use embassy_executor::Spawner;
+use embassy_nrf::gpio::{Level, Output, OutputDrive};
+use embassy_nrf::gpiote::{OutputChannel, OutputChannelPolarity};
+use embassy_nrf::ppi::Ppi;
+use embassy_nrf::timerv2::{Frequency, NotConfigured, Timer};
+use {defmt_rtt as _, panic_probe as _};
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_nrf::init(Default::default());
+
+    let led = OutputChannel::new(
+        p.GPIOTE_CH0,
+        Output::new(p.P0_13, Level::Low, OutputDrive::Standard),
+        OutputChannelPolarity::Toggle,
+    );
+
+    let timer = Timer::<NotConfigured>::new(p.TIMER1)
+        .into_timer()
+        .with_frequency(Frequency::F125kHz);
+
+    // Toggle the LED every 300 ms. The short restarts the period, without any CPU involvement.
+    let (timer, channels) = timer.split();
+    let cc = channels.cc0;
+    cc.write(37_500);
+    cc.short_compare_clear();
+
+    let mut ppi = Ppi::new_one_to_one(p.PPI_CH0, cc.event_compare(), led.task_out());
+    ppi.enable();
+    timer.start();
+
+    pending::<()>().await;
+}
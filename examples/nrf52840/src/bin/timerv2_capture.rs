@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+use defmt::{info, warn};
+use embassy_executor::Spawner;
+use embassy_nrf::gpio::{Input, Pull};
+use embassy_nrf::gpiote::{InputChannel, InputChannelPolarity};
+use embassy_nrf::timerv2::{Frequency, InputCapture, NotConfigured, Timer};
+use {defmt_rtt as _, panic_probe as _};
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_nrf::init(Default::default());
+
+    let button = InputChannel::new(
+        p.GPIOTE_CH0,
+        Input::new(p.P0_11, Pull::Up),
+        InputChannelPolarity::HiToLo,
+    );
+
+    let timer = Timer::<NotConfigured>::new(p.TIMER1)
+        .into_timer()
+        .with_frequency(Frequency::F1MHz);
+
+    let mut capture = InputCapture::new(timer, button, p.PPI_CH0, p.PPI_CH1, p.PPI_GROUP0, 0, 1);
+
+    let mut last = None;
+    loop {
+        match capture.next_capture().await {
+            Ok(ticks) => {
+                if let Some(last) = last {
+                    info!("{} us since the last press", ticks.wrapping_sub(last) & 0xFF_FFFF);
+                }
+                last = Some(ticks);
+            }
+            Err(_) => {
+                warn!("missed a press");
+                last = None;
+            }
+        }
+    }
+}
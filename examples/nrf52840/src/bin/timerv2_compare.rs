@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+use defmt::{info, unwrap};
+use embassy_executor::Spawner;
+use embassy_nrf::timerv2::{self, Frequency, NotConfigured, Timer};
+use embassy_nrf::{bind_interrupts, peripherals};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    TIMER1 => timerv2::InterruptHandler<peripherals::TIMER1>;
+});
+
+#[embassy_executor::task]
+async fn compare(timer: peripherals::TIMER1) {
+    let timer = Timer::<NotConfigured>::new_with_irq(timer, Irqs)
+        .into_timer()
+        .with_frequency(Frequency::F1MHz);
+
+    // Half a second at 1 MHz
+    let cc = unwrap!(timer.cc(0));
+    cc.write(500_000);
+
+    loop {
+        timer.clear();
+        timer.start();
+        cc.wait().await;
+        timer.stop();
+        info!("compare");
+    }
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let p = embassy_nrf::init(Default::default());
+    unwrap!(spawner.spawn(compare(p.TIMER1)));
+}
@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+use defmt::{info, unwrap};
+use embassy_executor::Spawner;
+use embassy_nrf::gpio::{Input, Pull};
+use embassy_nrf::gpiote::{InputChannel, InputChannelPolarity};
+use embassy_nrf::ppi::Ppi;
+use embassy_nrf::timerv2::{self, CounterMode, NotConfigured, Timer};
+use embassy_nrf::{bind_interrupts, peripherals};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    TIMER1 => timerv2::InterruptHandler<peripherals::TIMER1>;
+});
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_nrf::init(Default::default());
+
+    let button = InputChannel::new(
+        p.GPIOTE_CH0,
+        Input::new(p.P0_11, Pull::Up),
+        InputChannelPolarity::HiToLo,
+    );
+
+    let mut counter = Timer::<NotConfigured>::new_with_irq(p.TIMER1, Irqs).into_counter(CounterMode::Normal);
+
+    // Every press increments the counter, without CPU involvement
+    let mut ppi = Ppi::new_one_to_one(p.PPI_CH0, button.event_in(), counter.task_count());
+    ppi.enable();
+    counter.start();
+
+    loop {
+        counter.clear();
+        unwrap!(counter.wait_for_count(5).await);
+        info!("5 presses");
+    }
+}
@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+use defmt::unwrap;
+use embassy_executor::Spawner;
+use embassy_nrf::gpio::{Level, Output, OutputDrive};
+use embassy_nrf::gpiote::{OutputChannel, OutputChannelPolarity};
+use embassy_nrf::timerv2::{Frequency, NotConfigured, SoftPwm, Timer};
+use embassy_time::{Duration, Timer as Delay};
+use {defmt_rtt as _, panic_probe as _};
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_nrf::init(Default::default());
+
+    let led = OutputChannel::new(
+        p.GPIOTE_CH0,
+        Output::new(p.P0_13, Level::Low, OutputDrive::Standard),
+        OutputChannelPolarity::Toggle,
+    );
+
+    let timer = Timer::<NotConfigured>::new(p.TIMER1)
+        .into_timer()
+        .with_frequency(Frequency::F1MHz);
+
+    // A period of 1 ms at 1 MHz
+    let mut pwm = unwrap!(SoftPwm::new(timer, led, p.PPI_CH0, p.PPI_CH1, 1000));
+
+    // Fade the LED in and out
+    loop {
+        for duty in (0..=1000).step_by(10).chain((0..=1000).rev().step_by(10)) {
+            pwm.set_duty(duty);
+            Delay::after(Duration::from_millis(10)).await;
+        }
+    }
+}
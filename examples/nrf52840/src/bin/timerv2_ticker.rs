@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+use defmt::{info, unwrap};
+use embassy_executor::Spawner;
+use embassy_nrf::timerv2::{self, Frequency, NotConfigured, Timer};
+use embassy_nrf::{bind_interrupts, peripherals};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    TIMER1 => timerv2::InterruptHandler<peripherals::TIMER1>;
+});
+
+#[embassy_executor::task]
+async fn tick(timer: peripherals::TIMER1) {
+    let timer = Timer::<NotConfigured>::new_with_irq(timer, Irqs)
+        .into_timer()
+        .with_frequency(Frequency::F1MHz);
+
+    // A period of 250 ms at 1 MHz
+    let mut ticker = unwrap!(timer.into_ticker(250_000));
+
+    loop {
+        let periods = ticker.next().await;
+        if periods > 1 {
+            info!("tick, missed {} periods", periods - 1);
+        } else {
+            info!("tick");
+        }
+    }
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let p = embassy_nrf::init(Default::default());
+    unwrap!(spawner.spawn(tick(p.TIMER1)));
+}
@@ -0,0 +1,23 @@
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+use defmt::info;
+use embassy_executor::Spawner;
+use embassy_nrf::timerv2::{Frequency, NotConfigured, Timer, WideTimer};
+use embassy_time::{Duration, Timer as Delay};
+use {defmt_rtt as _, panic_probe as _};
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_nrf::init(Default::default());
+
+    let low = Timer::<NotConfigured>::new(p.TIMER1);
+    let high = Timer::<NotConfigured>::new(p.TIMER2);
+    let wide = WideTimer::new(low, high, p.PPI_CH0, Frequency::F16MHz);
+
+    loop {
+        Delay::after(Duration::from_secs(1)).await;
+        info!("{} ticks, {} us", wide.now(), wide.now_micros());
+    }
+}
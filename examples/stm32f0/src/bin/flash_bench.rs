@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+use defmt::{info, unwrap};
+use embassy_executor::Spawner;
+use embassy_stm32::flash::{Flash, FLASH_SIZE};
+use embassy_time::Instant;
+use {defmt_rtt as _, panic_probe as _};
+
+/// Size of the benchmarked area at the end of the flash, two pages on the F07x and F09x.
+const LEN: u32 = 4 * 1024;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_stm32::init(Default::default());
+    info!("Flash write benchmark");
+
+    let mut f = Flash::new(p.FLASH);
+    let offset = FLASH_SIZE as u32 - LEN;
+    let data = [0x5A; 256];
+
+    // A single write per 256 bytes, with one unlock each
+    unwrap!(f.blocking_erase(offset, offset + LEN));
+    let start = Instant::now();
+    for chunk in (0..LEN).step_by(data.len()) {
+        unwrap!(f.blocking_write(offset + chunk, &data));
+    }
+    report("256 byte writes", start);
+
+    // A write per halfword, the worst case
+    unwrap!(f.blocking_erase(offset, offset + LEN));
+    let start = Instant::now();
+    for halfword in (0..LEN).step_by(2) {
+        unwrap!(f.blocking_write(offset + halfword, &data[..2]));
+    }
+    report("halfword writes", start);
+
+    unwrap!(f.blocking_erase(offset, offset + LEN));
+}
+
+fn report(name: &str, start: Instant) {
+    let micros = start.elapsed().as_micros();
+    info!(
+        "{}: {} bytes in {} us, {} bytes/s",
+        name,
+        LEN,
+        micros,
+        LEN as u64 * 1_000_000 / micros
+    );
+}
@@ -0,0 +1,73 @@
+// required-features: flash-dma
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+#[path = "../example_common.rs"]
+mod example_common;
+use defmt::assert_eq;
+use embassy_executor::Spawner;
+use embassy_stm32::flash::Flash;
+use embassy_time::Instant;
+use example_common::*;
+
+/// Bytes read in each pass, from the start of this program.
+const LEN: usize = 64 * 1024;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_stm32::init(config());
+    info!("Hello World!");
+
+    #[cfg(feature = "stm32f429zi")]
+    let mut dma = p.DMA2_CH0;
+    #[cfg(feature = "stm32h755zi")]
+    let mut dma = p.DMA1_CH0;
+
+    let mut flash = Flash::new(p.FLASH);
+    let mut cpu = [0; 4096];
+    let mut buf = [0; 4096];
+
+    // Throughput of the CPU and the DMA path over the same range
+    let start = Instant::now();
+    for offset in (0..LEN).step_by(cpu.len()) {
+        unwrap!(flash.blocking_read(offset as u32, &mut cpu));
+    }
+    let cpu_time = start.elapsed();
+
+    let start = Instant::now();
+    for offset in (0..LEN).step_by(buf.len()) {
+        unwrap!(flash.read_dma(&mut dma, offset as u32, &mut buf).await);
+    }
+    let dma_time = start.elapsed();
+    info!(
+        "Reading {} bytes took {} us by CPU, {} us by DMA",
+        LEN,
+        cpu_time.as_micros(),
+        dma_time.as_micros()
+    );
+    info!(
+        "Throughput: {} KB/s by CPU, {} KB/s by DMA",
+        LEN as u64 * 1000 / 1024 / cpu_time.as_millis().max(1),
+        LEN as u64 * 1000 / 1024 / dma_time.as_millis().max(1)
+    );
+
+    // Both paths agree, also for ranges that are not aligned
+    for (offset, range) in [(0, 0..4096), (3, 1..4001), (1, 4..4000), (2, 0..7), (4093, 8..11)] {
+        let len = range.len();
+        unwrap!(flash.blocking_read(offset, &mut cpu[..len]));
+        buf.fill(0);
+        unwrap!(flash.read_dma(&mut dma, offset, &mut buf[range.clone()]).await);
+        assert_eq!(&cpu[..len], &buf[range]);
+    }
+
+    // Short reads are done by the CPU
+    unwrap!(flash.blocking_read(5, &mut cpu[..16]));
+    buf.fill(0);
+    unwrap!(flash.read_dma(&mut dma, 5, &mut buf[..16]).await);
+    assert_eq!(&cpu[..16], &buf[..16]);
+    assert!(flash.read_dma(&mut dma, u32::MAX, &mut buf[..4]).await.is_err());
+
+    info!("Test OK");
+    cortex_m::asm::bkpt();
+}
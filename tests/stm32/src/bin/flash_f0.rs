@@ -0,0 +1,188 @@
+// required-features: flash-f0
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+#[path = "../example_common.rs"]
+mod example_common;
+use defmt::{assert, assert_eq};
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_futures::yield_now;
+use embassy_stm32::flash::{
+    ConfirmMassErase, EraseError, Error, Flash, FlashBank, FLASH_BASE, FLASH_REGIONS, FLASH_SIZE,
+};
+use embassy_stm32::{interrupt, pac};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use embedded_storage_async::nor_flash::{NorFlash as AsyncNorFlash, ReadNorFlash as AsyncReadNorFlash};
+use example_common::*;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_stm32::init(config());
+    info!("Hello World!");
+
+    let mut flash = Flash::new_with_irq(p.FLASH, interrupt::take!(FLASH));
+
+    let last = FLASH_REGIONS
+        .iter()
+        .filter(|r| r.bank != FlashBank::Otp)
+        .last()
+        .unwrap();
+    let page_size = last.erase_size;
+    let last_page = FLASH_SIZE as u32 - page_size;
+    info!(
+        "Page size {}, last page at 0x{:x}",
+        page_size,
+        FLASH_BASE as u32 + last_page
+    );
+
+    // Erase and blank check the last page
+    unwrap!(flash.blocking_erase(last_page, FLASH_SIZE as u32));
+    assert_blank(&mut flash, last_page, page_size);
+
+    // Halfword writes, and programming zeros over a programmed halfword
+    unwrap!(flash.blocking_write(last_page, &[0x34, 0x12]));
+    unwrap!(flash.blocking_write(last_page + 2, &[0x78, 0x56]));
+    let mut buf = [0; 4];
+    unwrap!(flash.blocking_read(last_page, &mut buf));
+    assert_eq!([0x34, 0x12, 0x78, 0x56], buf);
+
+    unwrap!(flash.blocking_write(last_page, &[0x00, 0x00]));
+    unwrap!(flash.blocking_read(last_page, &mut buf));
+    assert_eq!([0x00, 0x00, 0x78, 0x56], buf);
+
+    // Any other value can't be programmed over a programmed halfword. The tests don't enable `flash-erase-check`,
+    // so the write is not rejected up front but fails with PGERR after unlocking.
+    let result = flash.blocking_write(last_page + 2, &[0xCD, 0xAB]);
+    info!("Overwrite: {}", result);
+    assert_eq!(Err(Error::Seq), result);
+    // The failed write left the controller locked, with programming disabled
+    let cr = unsafe { pac::FLASH.cr().read() };
+    assert!(cr.lock());
+    assert!(!cr.pg());
+    unwrap!(flash.blocking_read(last_page, &mut buf));
+    assert_eq!([0x00, 0x00, 0x78, 0x56], buf);
+
+    // A write over the boundary into the last page, starting 32 bytes before it. If the
+    // flash is modelled as more than one region, the last region boundary is crossed instead.
+    let boundary = match FLASH_REGIONS.iter().filter(|r| r.bank != FlashBank::Otp).count() {
+        1 => last_page,
+        _ => last.base - FLASH_BASE as u32,
+    };
+    let start = boundary - page_size;
+    unwrap!(flash.blocking_erase(start, boundary + page_size));
+    assert_blank(&mut flash, start, 2 * page_size);
+
+    let data: [u8; 64] = core::array::from_fn(|i| i as u8);
+    unwrap!(flash.blocking_write(boundary - 32, &data));
+    let mut read = [0; 64];
+    unwrap!(flash.blocking_read(boundary - 32, &mut read));
+    assert_eq!(data, read);
+
+    // A stream of odd sized chunks is programmed in one session, the final byte padded with 0xFF
+    let mut writer = unwrap!(flash.writer(start));
+    for chunk in data[..21].chunks(7) {
+        unwrap!(writer.write(chunk));
+    }
+    assert_eq!(start + 21, writer.offset());
+    unwrap!(writer.finish());
+    unwrap!(flash.blocking_read(start, &mut read[..22]));
+    assert_eq!(data[..21], read[..21]);
+    assert_eq!(0xFF, read[21]);
+
+    // A misaligned range is rejected at its start, before anything is erased
+    assert_eq!(
+        Err(EraseError {
+            offset: start + 2,
+            error: Error::Unaligned
+        }),
+        flash.blocking_erase_range(start + 2, boundary + page_size)
+    );
+    unwrap!(flash.blocking_read(boundary - 32, &mut read));
+    assert_eq!(data, read);
+
+    // The test runs from flash, so a mass erase is refused
+    let confirm = unsafe { ConfirmMassErase::new() };
+    assert_eq!(Err(Error::WouldEraseSelf), flash.blocking_erase_all(confirm));
+
+    // Programming the loaded user options back keeps them, and the readout protection
+    let options = flash.read_user_options();
+    unwrap!(flash.write_user_options(options));
+    assert_eq!(options, flash.read_user_options());
+
+    // Round trips through the embedded-storage traits
+    round_trip(&mut flash, last_page);
+    round_trip_async(&mut flash, last_page).await;
+
+    // An erase future dropped mid-operation. The erase can't be aborted, so the next operation waits for it to
+    // complete before programming, and the page ends up erased and then written.
+    unwrap!(flash.blocking_write(last_page, &[0x34, 0x12]));
+    match select(flash.erase_sector(last_page), yield_now()).await {
+        Either::First(result) => defmt::panic!("Erase completed before the first yield: {}", result),
+        Either::Second(()) => {}
+    }
+    unwrap!(flash.blocking_write(last_page + 2, &[0x78, 0x56]));
+    unwrap!(flash.blocking_read(last_page, &mut buf));
+    assert_eq!([0xFF, 0xFF, 0x78, 0x56], buf);
+
+    // The same for a write future, dropped while its first halfword is programmed
+    unwrap!(flash.blocking_erase(last_page, FLASH_SIZE as u32));
+    let _ = select(flash.write(last_page, &data), yield_now()).await;
+    unwrap!(flash.blocking_write(last_page + 32, &[0xCD, 0xAB]));
+    unwrap!(flash.blocking_read(last_page + 32, &mut buf[..2]));
+    assert_eq!([0xCD, 0xAB], buf[..2]);
+    unwrap!(flash.blocking_erase(last_page, FLASH_SIZE as u32));
+    assert_blank(&mut flash, last_page, page_size);
+
+    // Leave the pages erased
+    unwrap!(flash.blocking_erase(start, boundary + page_size));
+    if boundary != last_page {
+        unwrap!(flash.blocking_erase(last_page, FLASH_SIZE as u32));
+    }
+
+    info!("Test OK");
+    cortex_m::asm::bkpt();
+}
+
+fn round_trip<F: NorFlash>(flash: &mut F, offset: u32) {
+    assert_eq!(2, F::WRITE_SIZE);
+    assert_eq!(FLASH_SIZE, flash.capacity());
+    let page_size = F::ERASE_SIZE as u32;
+    unwrap!(flash.erase(offset, offset + page_size).ok());
+    assert!(flash.erase(offset + 2, offset + page_size).is_err());
+
+    let data: [u8; 16] = core::array::from_fn(|i| 0xA0 + i as u8);
+    unwrap!(flash.write(offset, &data).ok());
+    assert!(flash.write(offset + 17, &data[..2]).is_err());
+    let mut read = [0; 16];
+    unwrap!(flash.read(offset, &mut read).ok());
+    assert_eq!(data, read);
+
+    unwrap!(flash.erase(offset, offset + page_size).ok());
+    unwrap!(flash.read(offset, &mut read).ok());
+    assert!(read.iter().all(|&b| b == 0xFF));
+}
+
+async fn round_trip_async<F: AsyncNorFlash>(flash: &mut F, offset: u32) {
+    let page_size = F::ERASE_SIZE as u32;
+    unwrap!(flash.erase(offset, offset + page_size).await.ok());
+
+    let data: [u8; 16] = core::array::from_fn(|i| 0x50 + i as u8);
+    unwrap!(flash.write(offset, &data).await.ok());
+    let mut read = [0; 16];
+    unwrap!(flash.read(offset, &mut read).await.ok());
+    assert_eq!(data, read);
+
+    unwrap!(flash.erase(offset, offset + page_size).await.ok());
+    unwrap!(flash.read(offset, &mut read).await.ok());
+    assert!(read.iter().all(|&b| b == 0xFF));
+}
+
+fn assert_blank(flash: &mut Flash<'_>, offset: u32, len: u32) {
+    let mut buf = [0; 64];
+    for chunk in (offset..offset + len).step_by(buf.len()) {
+        unwrap!(flash.blocking_read(chunk, &mut buf));
+        assert!(buf.iter().all(|&b| b == 0xFF));
+    }
+}
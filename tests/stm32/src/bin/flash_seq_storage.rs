@@ -0,0 +1,57 @@
+// required-features: flash-f0,sequential-storage
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+#[path = "../example_common.rs"]
+mod example_common;
+use defmt::assert_eq;
+use embassy_executor::Spawner;
+use embassy_stm32::flash::{Flash, ZeroRewrite, FLASH_SIZE};
+use embedded_storage::nor_flash::NorFlash;
+use example_common::*;
+use sequential_storage::queue::{peek, pop, push};
+
+/// Two pages before the scratch granule of the write protection test.
+const RANGE: core::ops::Range<u32> = FLASH_SIZE as u32 - 8 * 1024..FLASH_SIZE as u32 - 4 * 1024;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_stm32::init(config());
+    info!("Hello World!");
+
+    let mut flash = ZeroRewrite(Flash::new(p.FLASH));
+    unwrap!(flash.erase(RANGE.start, RANGE.end));
+
+    // Items of an odd length don't fill their last halfword, popping them programs zeros over their header
+    let mut buf = [0; 32];
+    for len in 1..=9u8 {
+        let data = [len; 9];
+        unwrap!(push(&mut flash, RANGE, &data[..len as usize], false));
+    }
+    for len in 1..=9u8 {
+        let data = [len; 9];
+        assert_eq!(
+            Some(&data[..len as usize]),
+            unwrap!(peek(&mut flash, RANGE, &mut buf)).as_deref()
+        );
+        assert_eq!(
+            Some(&data[..len as usize]),
+            unwrap!(pop(&mut flash, RANGE, &mut buf)).as_deref()
+        );
+    }
+    assert_eq!(None, unwrap!(pop(&mut flash, RANGE, &mut buf)).as_deref());
+
+    // Wrap around the pages a few times
+    for i in 0..600u32 {
+        unwrap!(push(&mut flash, RANGE, &i.to_le_bytes(), false));
+        assert_eq!(
+            Some(&i.to_le_bytes()[..]),
+            unwrap!(pop(&mut flash, RANGE, &mut buf)).as_deref()
+        );
+    }
+
+    unwrap!(flash.erase(RANGE.start, RANGE.end));
+    info!("Test OK");
+    cortex_m::asm::bkpt();
+}
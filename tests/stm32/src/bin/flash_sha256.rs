@@ -0,0 +1,75 @@
+// required-features: flash-sha256
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+#[path = "../example_common.rs"]
+mod example_common;
+use defmt::assert_eq;
+use embassy_executor::Spawner;
+#[cfg(feature = "hash")]
+use embassy_stm32::flash::HashSha256Context;
+use embassy_stm32::flash::{sha256, Flash, Sha256Context};
+use example_common::*;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_stm32::init(config());
+    info!("Hello World!");
+
+    // The HASH and its DMA channel outlive the flash driver they are attached to
+    #[cfg(feature = "hash")]
+    let (mut peri, mut dma) = (p.HASH, p.DMA1_CH0);
+    let mut flash = Flash::new(p.FLASH);
+
+    // The FIPS 180-2 example
+    let mut context = Sha256Context::new();
+    context.update(b"abc");
+    assert_eq!(
+        [
+            0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22, 0x23, 0xb0, 0x03,
+            0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00, 0x15, 0xad,
+        ],
+        context.finalize()
+    );
+
+    // Hash the start of this program, once directly and once from chunks read into RAM
+    let hash = unwrap!(flash.sha256(0..4096));
+    let mut context = Sha256Context::new();
+    let mut buf = [0; 100];
+    for offset in (0..4096).step_by(buf.len()) {
+        let len = core::cmp::min(buf.len(), 4096 - offset);
+        unwrap!(flash.blocking_read(offset as u32, &mut buf[..len]));
+        context.update(&buf[..len]);
+    }
+    assert_eq!(hash, context.finalize());
+
+    // The HASH peripheral gives the same results, also for data that doesn't fill the last word or block
+    #[cfg(feature = "hash")]
+    {
+        let mut context = HashSha256Context::new(&mut peri, &mut dma);
+        context.update(b"abc");
+        let mut software = Sha256Context::new();
+        software.update(b"abc");
+        assert_eq!(software.finalize(), context.finalize());
+
+        // A context continued at an offset that doesn't match its partial word is fed by the CPU
+        let mut context = HashSha256Context::new(&mut peri, &mut dma);
+        context.update(b"ab");
+        unwrap!(context.update_from_flash(&mut flash, 4..4096));
+        let mut software = Sha256Context::new();
+        software.update(b"ab");
+        unwrap!(software.update_from_flash(&mut flash, 4..4096));
+        assert_eq!(software.finalize(), context.finalize());
+
+        // With the HASH attached, the words are fed by DMA and the unaligned edges by the CPU
+        flash.set_hash(&mut peri, &mut dma);
+        let ranges = [0..0, 0..1, 0..63, 0..64, 0..65, 0..68, 0..4096, 1..4096, 3..70, 4..4093];
+        for range in ranges {
+            assert_eq!(unwrap!(sha256(&mut flash, range.clone())), unwrap!(flash.sha256(range)));
+        }
+    }
+
+    info!("Test OK");
+    cortex_m::asm::bkpt();
+}
@@ -0,0 +1,80 @@
+// required-features: flash-f0
+#![no_std]
+#![no_main]
+#![feature(type_alias_impl_trait)]
+
+#[path = "../example_common.rs"]
+mod example_common;
+use defmt::{assert, assert_eq};
+use embassy_executor::Spawner;
+use embassy_stm32::flash::{Error, Flash, FlashBank, ProtectionCause, WrpMask, FLASH_REGIONS, FLASH_SIZE};
+use example_common::*;
+
+/// The write protection only takes effect once the option bytes are reloaded, which resets the device. The stage
+/// of the test is kept in a halfword of the page before the protected one, and advanced by programming zeros.
+const STAGE_UNPROTECTED: u16 = 0xFFFF;
+const STAGE_PROTECTED: u16 = 0x00FF;
+const STAGE_DONE: u16 = 0x0000;
+
+/// The last 4 KB WRP granule is the scratch area, the stage is kept right before it.
+const SCRATCH: u32 = FLASH_SIZE as u32 - 4 * 1024;
+const STAGE: u32 = SCRATCH - 2;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_stm32::init(config());
+    info!("Hello World!");
+
+    let mut flash = Flash::new(p.FLASH);
+    let scratch = WrpMask::covering(SCRATCH, FLASH_SIZE as u32);
+
+    let mut buf = [0; 2];
+    unwrap!(flash.blocking_read(STAGE, &mut buf));
+    match u16::from_le_bytes(buf) {
+        STAGE_UNPROTECTED => {
+            info!("Protecting the scratch granule");
+            assert!(!flash.write_protection().is_protected(SCRATCH));
+            unwrap!(flash.blocking_erase(SCRATCH, FLASH_SIZE as u32));
+            unwrap!(flash.blocking_write(STAGE, &STAGE_PROTECTED.to_le_bytes()));
+            unwrap!(flash.set_write_protection(scratch));
+            flash.launch_option_bytes();
+        }
+        STAGE_PROTECTED => {
+            info!("Writing to the protected scratch granule");
+            assert_eq!(scratch, flash.write_protection());
+            assert_eq!(Err(Error::Protected), flash.blocking_write(SCRATCH, &[0x34, 0x12]));
+            assert_eq!(Err(Error::Protected), flash.blocking_erase(SCRATCH, FLASH_SIZE as u32));
+            assert_eq!(Some(ProtectionCause::WriteProtection), flash.protection_cause(SCRATCH));
+
+            // The page before the granule is not protected
+            assert_eq!(None, flash.protection_cause(STAGE));
+            unwrap!(flash.blocking_write(STAGE, &STAGE_DONE.to_le_bytes()));
+            unwrap!(flash.set_write_protection(WrpMask::NONE));
+            flash.launch_option_bytes();
+        }
+        STAGE_DONE => {
+            info!("Writing to the unprotected scratch granule");
+            assert_eq!(WrpMask::NONE, flash.write_protection());
+            assert_eq!(None, flash.protection_cause(SCRATCH));
+            unwrap!(flash.blocking_write(SCRATCH, &[0x34, 0x12]));
+            unwrap!(flash.blocking_read(SCRATCH, &mut buf));
+            assert_eq!([0x34, 0x12], buf);
+
+            // Leave the stage erased for the next run
+            let page_size = FLASH_REGIONS
+                .iter()
+                .filter(|r| r.bank != FlashBank::Otp)
+                .last()
+                .unwrap()
+                .erase_size;
+            unwrap!(flash.blocking_erase(SCRATCH - page_size, FLASH_SIZE as u32));
+        }
+        stage => defmt::panic!(
+            "Unknown stage 0x{:x}, erase the last pages to run the test again",
+            stage
+        ),
+    }
+
+    info!("Test OK");
+    cortex_m::asm::bkpt();
+}
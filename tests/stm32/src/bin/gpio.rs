@@ -34,6 +34,8 @@ async fn main(_spawner: Spawner) {
     let (mut a, mut b) = (p.PB6, p.PB7);
     #[cfg(feature = "stm32c031c6")]
     let (mut a, mut b) = (p.PB6, p.PB7);
+    #[cfg(feature = "stm32f091rc")]
+    let (mut a, mut b) = (p.PA3, p.PA2);
 
     // Test initial output
     {
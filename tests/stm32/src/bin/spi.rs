@@ -34,6 +34,8 @@ async fn main(_spawner: Spawner) {
     let (spi, sck, mosi, miso) = (p.SPI4, p.PE12, p.PE14, p.PE13);
     #[cfg(feature = "stm32c031c6")]
     let (spi, sck, mosi, miso) = (p.SPI1, p.PA5, p.PA7, p.PA6);
+    #[cfg(feature = "stm32f091rc")]
+    let (spi, sck, mosi, miso) = (p.SPI1, p.PA5, p.PA7, p.PA6);
 
     let mut spi = Spi::new(
         spi,
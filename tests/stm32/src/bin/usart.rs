@@ -37,6 +37,8 @@ async fn main(_spawner: Spawner) {
     let (mut tx, mut rx, mut usart, mut irq) = (p.PB6, p.PB7, p.LPUART1, interrupt::take!(LPUART1));
     #[cfg(feature = "stm32c031c6")]
     let (mut tx, mut rx, mut usart, mut irq) = (p.PB6, p.PB7, p.USART1, interrupt::take!(USART1));
+    #[cfg(feature = "stm32f091rc")]
+    let (mut tx, mut rx, mut usart, mut irq) = (p.PA2, p.PA3, p.USART2, interrupt::take!(USART2));
 
     {
         let config = Config::default();
@@ -63,6 +63,12 @@ mod board {
     pub type TxDma = embassy_stm32::peripherals::DMA1_CH1;
     pub type RxDma = embassy_stm32::peripherals::DMA1_CH2;
 }
+#[cfg(feature = "stm32f091rc")]
+mod board {
+    pub type Uart = embassy_stm32::peripherals::USART2;
+    pub type TxDma = embassy_stm32::peripherals::DMA1_CH4;
+    pub type RxDma = embassy_stm32::peripherals::DMA1_CH5;
+}
 
 const DMA_BUF_SIZE: usize = 256;
 
@@ -121,6 +127,9 @@ async fn main(spawner: Spawner) {
     #[cfg(feature = "stm32c031c6")]
     let (tx, rx, usart, irq, tx_dma, rx_dma) =
         (p.PB6, p.PB7, p.USART1, interrupt::take!(USART1), p.DMA1_CH1, p.DMA1_CH2);
+    #[cfg(feature = "stm32f091rc")]
+    let (tx, rx, usart, irq, tx_dma, rx_dma) =
+        (p.PA2, p.PA3, p.USART2, interrupt::take!(USART2), p.DMA1_CH4, p.DMA1_CH5);
 
     // To run this test, use the saturating_serial test utility to saturate the serial port
 